@@ -0,0 +1,131 @@
+use std::time::{Duration, Instant};
+
+use serde_json::json;
+
+use log::*;
+
+use crate::imu::{self, Imu};
+use crate::settings::Settings;
+use crate::telemetry::Signal;
+use crate::Config;
+
+const PERIOD: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Activity {
+    Moving,
+    Still,
+}
+
+impl Activity {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Moving => "moving",
+            Self::Still => "still",
+        }
+    }
+}
+
+/// Zero-motion detection: once the device lay still for a while, readings are only published
+/// at the keep-alive interval, until it moves again
+pub struct ActivityMonitor {
+    still_after: Duration,
+    keepalive: Duration,
+    /// Deviation of the acceleration magnitude from 1g that counts as motion
+    acc_threshold: f32,
+    /// In rad/s
+    gyro_threshold: f32,
+    sampled_at: Option<Instant>,
+    moved_at: Instant,
+    activity: Activity,
+    /// Set when motion ended a still phase, so the next reading goes out right away
+    resumed: bool,
+}
+
+impl ActivityMonitor {
+    /// `None` unless `activity_still_secs` is set
+    pub fn from_config(app_config: &Config) -> Option<Self> {
+        if app_config.activity_still_secs == 0 {
+            return None;
+        }
+
+        Some(Self {
+            still_after: Duration::from_secs(app_config.activity_still_secs),
+            keepalive: Duration::from_secs(app_config.activity_keepalive_secs),
+            acc_threshold: app_config.activity_acc_threshold_g,
+            gyro_threshold: app_config.activity_gyro_threshold_dps.to_radians(),
+            sampled_at: None,
+            moved_at: Instant::now(),
+            activity: Activity::Moving,
+            resumed: false,
+        })
+    }
+
+    /// Samples when due; returns the time until the next sample
+    pub fn poll(&mut self, mpu: &mut Imu, settings: &Settings) -> Duration {
+        let due = self
+            .sampled_at
+            .map_or(Duration::ZERO, |at| PERIOD.saturating_sub(at.elapsed()));
+        if !due.is_zero() {
+            return due;
+        }
+
+        let now = Instant::now();
+        self.sampled_at = Some(now);
+
+        match (imu::acc(mpu, settings), imu::gyro(mpu, settings)) {
+            (Ok([x, y, z]), Ok(gyro)) => {
+                let moving = ((x * x + y * y + z * z).sqrt() - 1.0).abs() > self.acc_threshold
+                    || gyro.iter().any(|rate| rate.abs() > self.gyro_threshold);
+                self.update(moving, now);
+            }
+            (Err(e), _) | (_, Err(e)) => {
+                warn!("Failed to read the sensor for activity detection: {e:?}")
+            }
+        }
+
+        PERIOD
+    }
+
+    fn update(&mut self, moving: bool, now: Instant) {
+        if moving {
+            self.moved_at = now;
+            if self.activity == Activity::Still {
+                info!("Moving again, back to the publish interval");
+                self.activity = Activity::Moving;
+                self.resumed = true;
+            }
+        } else if self.activity == Activity::Moving && now - self.moved_at >= self.still_after {
+            info!(
+                "Still for {:?}, publishing every {:?}",
+                self.still_after, self.keepalive
+            );
+            self.activity = Activity::Still;
+        }
+    }
+
+    /// The publish interval to use for now
+    pub fn interval(&self, publish_interval: Duration) -> Duration {
+        match self.activity {
+            Activity::Moving => publish_interval,
+            Activity::Still => self.keepalive.max(publish_interval),
+        }
+    }
+
+    /// Whether the device started moving after a still phase since the last call
+    pub fn take_resumed(&mut self) -> bool {
+        core::mem::take(&mut self.resumed)
+    }
+
+    /// `"activity": "moving"` or `"still"`
+    pub fn signal(&self) -> Signal {
+        Signal {
+            field: "activity",
+            kind: "activity",
+            value: json!(self.activity.as_str()),
+            range: None,
+            axes: vec![(self.activity == Activity::Moving) as u8 as f32],
+            unit: None,
+        }
+    }
+}
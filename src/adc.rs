@@ -0,0 +1,230 @@
+use core::ptr;
+use std::sync::Mutex;
+
+use esp_idf_svc::sys::{self, esp, EspError};
+use serde_json::json;
+
+use log::*;
+
+use crate::sensor::{Reading, Sensor};
+use crate::telemetry::Signal;
+use crate::Config;
+
+/// The ADC1 driver, created by the first channel and kept for good, so channels can share it
+struct Unit(sys::adc_oneshot_unit_handle_t);
+
+// Safety: only handed out while the lock is held, and the driver may be used from any task
+unsafe impl Send for Unit {}
+
+static ADC1: Mutex<Option<Unit>> = Mutex::new(None);
+
+fn adc1() -> Result<sys::adc_oneshot_unit_handle_t, EspError> {
+    let mut unit = ADC1.lock().unwrap();
+    if let Some(Unit(handle)) = *unit {
+        return Ok(handle);
+    }
+
+    let mut handle = ptr::null_mut();
+    esp!(unsafe {
+        sys::adc_oneshot_new_unit(
+            &sys::adc_oneshot_unit_init_cfg_t {
+                unit_id: sys::adc_unit_t_ADC_UNIT_1,
+                ..Default::default()
+            },
+            &mut handle,
+        )
+    })?;
+    *unit = Some(Unit(handle));
+
+    Ok(handle)
+}
+
+/// One ADC1 pin (GPIO1-10) at up to about 3.1V. ADC2 is left alone, as Wi-Fi needs it.
+pub struct Channel {
+    unit: sys::adc_oneshot_unit_handle_t,
+    channel: sys::adc_channel_t,
+    /// Converts to mV with the eFuse calibration, raw readings are returned without it
+    cali: Option<sys::adc_cali_handle_t>,
+}
+
+// Safety: the oneshot driver may be used from any task, just not from several at once,
+// which the `Mutex` the owner is kept in takes care of
+unsafe impl Send for Channel {}
+
+impl Channel {
+    /// `None` if `gpio` is not an ADC1 pin
+    pub fn open(gpio: i32) -> Result<Option<Self>, EspError> {
+        let mut unit_id = 0;
+        let mut channel = 0;
+        esp!(unsafe { sys::adc_oneshot_io_to_channel(gpio, &mut unit_id, &mut channel) })?;
+        if unit_id != sys::adc_unit_t_ADC_UNIT_1 {
+            warn!("GPIO{gpio} is not an ADC1 pin");
+            return Ok(None);
+        }
+
+        let unit = adc1()?;
+
+        // Up to about 3.1V
+        let atten = sys::adc_atten_t_ADC_ATTEN_DB_12;
+        let bitwidth = sys::adc_bitwidth_t_ADC_BITWIDTH_DEFAULT;
+        esp!(unsafe {
+            sys::adc_oneshot_config_channel(
+                unit,
+                channel,
+                &sys::adc_oneshot_chan_cfg_t { atten, bitwidth },
+            )
+        })?;
+
+        let mut cali = ptr::null_mut();
+        let cali = match esp!(unsafe {
+            sys::adc_cali_create_scheme_curve_fitting(
+                &sys::adc_cali_curve_fitting_config_t {
+                    unit_id,
+                    chan: channel,
+                    atten,
+                    bitwidth,
+                },
+                &mut cali,
+            )
+        }) {
+            Ok(()) => Some(cali),
+            Err(e) => {
+                warn!("No ADC calibration for GPIO{gpio}, reading raw values: {e}");
+                None
+            }
+        };
+
+        Ok(Some(Self {
+            unit,
+            channel,
+            cali,
+        }))
+    }
+
+    /// Average of `samples` readings in mV, or raw without calibration
+    pub fn read(&mut self, samples: u32) -> Result<f32, EspError> {
+        let samples = samples.max(1);
+        let mut sum = 0;
+        for _ in 0..samples {
+            let mut raw = 0;
+            esp!(unsafe { sys::adc_oneshot_read(self.unit, self.channel, &mut raw) })?;
+
+            if let Some(cali) = self.cali {
+                let mut mv = 0;
+                esp!(unsafe { sys::adc_cali_raw_to_voltage(cali, raw, &mut mv) })?;
+                raw = mv;
+            }
+            sum += raw as i64;
+        }
+
+        Ok(sum as f32 / samples as f32)
+    }
+}
+
+impl Drop for Channel {
+    fn drop(&mut self) {
+        if let Some(cali) = self.cali {
+            unsafe { sys::adc_cali_delete_scheme_curve_fitting(cali) };
+        }
+    }
+}
+
+/// An analog sensor on an ADC1 pin, e.g. soil moisture or light on the Grove port
+pub struct AnalogChannel {
+    channel: Channel,
+    samples: u32,
+    /// Points of a piecewise linear curve from mV to the published value, ordered by mV
+    curve: Vec<(f32, f32)>,
+    field: &'static str,
+}
+
+impl AnalogChannel {
+    /// `None` unless `adc_gpio` is set
+    pub fn from_config(app_config: &Config) -> Result<Option<Self>, EspError> {
+        if app_config.adc_gpio < 0 {
+            return Ok(None);
+        }
+
+        let Some(channel) = Channel::open(app_config.adc_gpio)? else {
+            warn!("Analog channel disabled");
+            return Ok(None);
+        };
+
+        let curve = parse_curve(app_config.adc_curve);
+        info!(
+            "Analog channel \"{}\" on GPIO{}",
+            app_config.adc_field, app_config.adc_gpio
+        );
+
+        Ok(Some(Self {
+            channel,
+            samples: app_config.adc_samples,
+            curve,
+            field: app_config.adc_field,
+        }))
+    }
+}
+
+impl Sensor for AnalogChannel {
+    fn name(&self) -> &'static str {
+        "analog channel"
+    }
+
+    /// The averaged reading mapped through the calibration curve
+    fn sample(&mut self) -> Result<Reading, String> {
+        let mv = self.channel.read(self.samples).map_err(|e| e.to_string())?;
+        let value = apply_curve(&self.curve, mv);
+
+        Ok(vec![Signal {
+            field: self.field,
+            kind: self.field,
+            value: json!(value),
+            range: None,
+            axes: vec![value],
+            // Whatever the calibration curve maps to
+            unit: None,
+        }])
+    }
+}
+
+/// `"0:0, 1500:40, 2800:100"`, pairs of mV and value; malformed points are skipped
+fn parse_curve(curve: &str) -> Vec<(f32, f32)> {
+    let mut points: Vec<(f32, f32)> = curve
+        .split(',')
+        .map(str::trim)
+        .filter(|point| !point.is_empty())
+        .filter_map(|point| {
+            let parsed = point.split_once(':').and_then(|(mv, value)| {
+                Some((mv.trim().parse().ok()?, value.trim().parse().ok()?))
+            });
+            if parsed.is_none() {
+                warn!("Ignoring malformed ADC curve point \"{point}\"");
+            }
+            parsed
+        })
+        .collect();
+    points.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    points
+}
+
+/// Interpolates linearly between the points around `mv`, extrapolating the outer segments;
+/// without a curve the value stays in mV, a single point shifts it
+fn apply_curve(curve: &[(f32, f32)], mv: f32) -> f32 {
+    match curve {
+        [] => mv,
+        [(x, y)] => mv - x + y,
+        _ => {
+            let i = curve
+                .windows(2)
+                .position(|pair| mv < pair[1].0)
+                .unwrap_or(curve.len() - 2);
+            let ((x0, y0), (x1, y1)) = (curve[i], curve[i + 1]);
+            if x1 == x0 {
+                return y0;
+            }
+
+            y0 + (mv - x0) * (y1 - y0) / (x1 - x0)
+        }
+    }
+}
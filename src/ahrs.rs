@@ -0,0 +1,181 @@
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use serde_json::json;
+
+use log::*;
+
+use crate::imu::{self, Imu};
+use crate::settings::Settings;
+use crate::telemetry::Signal;
+use crate::Config;
+
+/// What the `orientation` signal is published with
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AhrsMode {
+    Off,
+    /// Orientation next to the raw gyro and accelerometer readings
+    Alongside,
+    /// Orientation in place of the raw readings
+    Only,
+}
+
+impl AhrsMode {
+    /// `"off"`, `"alongside"` or `"only"`
+    pub fn from_config(mode: &str) -> Self {
+        match mode {
+            "off" => Self::Off,
+            "alongside" => Self::Alongside,
+            "only" => Self::Only,
+            _ => {
+                warn!("Unknown AHRS mode \"{mode}\", using \"off\"");
+                Self::Off
+            }
+        }
+    }
+}
+
+/// Value of the `orientation` signal, angles in degrees
+#[derive(Serialize)]
+struct Fused {
+    q: [f32; 4],
+    roll: f32,
+    pitch: f32,
+    yaw: f32,
+}
+
+/// Madgwick filter fusing gyro and accelerometer into an orientation quaternion; without a
+/// magnetometer yaw is relative to the orientation at start and drifts slowly
+pub struct Ahrs {
+    mode: AhrsMode,
+    /// Gyro measurement error in rad/s; higher trusts the accelerometer more
+    beta: f32,
+    period: Duration,
+    /// `[w, x, y, z]`
+    q: [f32; 4],
+    updated_at: Option<Instant>,
+}
+
+impl Ahrs {
+    /// `None` with `ahrs_mode = "off"`
+    pub fn from_config(app_config: &Config) -> Option<Self> {
+        let mode = AhrsMode::from_config(app_config.ahrs_mode);
+        if mode == AhrsMode::Off {
+            return None;
+        }
+
+        Some(Self {
+            mode,
+            beta: app_config.ahrs_beta,
+            period: Duration::from_secs(1) / app_config.ahrs_hz.max(1),
+            q: [1.0, 0.0, 0.0, 0.0],
+            updated_at: None,
+        })
+    }
+
+    pub fn mode(&self) -> AhrsMode {
+        self.mode
+    }
+
+    /// Updates the filter when due; returns the time until the next update
+    pub fn poll(&mut self, mpu: &mut Imu, settings: &Settings) -> Duration {
+        let due = self.updated_at.map_or(Duration::ZERO, |at| {
+            self.period.saturating_sub(at.elapsed())
+        });
+        if !due.is_zero() {
+            return due;
+        }
+
+        match (imu::gyro(mpu, settings), imu::acc(mpu, settings)) {
+            (Ok(gyro), Ok(acc)) => {
+                let now = Instant::now();
+                let dt = self
+                    .updated_at
+                    .map_or(self.period, |at| now - at)
+                    .as_secs_f32();
+                self.updated_at = Some(now);
+
+                self.update(gyro, acc, dt);
+            }
+            (Err(e), _) | (_, Err(e)) => warn!("Failed to read the sensor for AHRS: {e:?}"),
+        }
+
+        self.period
+    }
+
+    /// One Madgwick step with gyro in rad/s and acceleration in any unit
+    fn update(&mut self, gyro: [f32; 3], acc: [f32; 3], dt: f32) {
+        let [q0, q1, q2, q3] = self.q;
+        let [gx, gy, gz] = gyro;
+
+        // Rate of change from the gyro alone
+        let mut q_dot = [
+            0.5 * (-q1 * gx - q2 * gy - q3 * gz),
+            0.5 * (q0 * gx + q2 * gz - q3 * gy),
+            0.5 * (q0 * gy - q1 * gz + q3 * gx),
+            0.5 * (q0 * gz + q1 * gy - q2 * gx),
+        ];
+
+        // Corrected along the gradient towards gravity, unless in free fall
+        let norm = (acc[0] * acc[0] + acc[1] * acc[1] + acc[2] * acc[2]).sqrt();
+        if norm > 0.0 {
+            let [ax, ay, az] = acc.map(|a| a / norm);
+
+            let f = [
+                2.0 * (q1 * q3 - q0 * q2) - ax,
+                2.0 * (q0 * q1 + q2 * q3) - ay,
+                1.0 - 2.0 * (q1 * q1 + q2 * q2) - az,
+            ];
+            let step = [
+                -2.0 * q2 * f[0] + 2.0 * q1 * f[1],
+                2.0 * q3 * f[0] + 2.0 * q0 * f[1] - 4.0 * q1 * f[2],
+                -2.0 * q0 * f[0] + 2.0 * q3 * f[1] - 4.0 * q2 * f[2],
+                2.0 * q1 * f[0] + 2.0 * q2 * f[1],
+            ];
+
+            let step_norm = step.iter().map(|s| s * s).sum::<f32>().sqrt();
+            if step_norm > 0.0 {
+                for (q_dot, s) in q_dot.iter_mut().zip(step) {
+                    *q_dot -= self.beta * s / step_norm;
+                }
+            }
+        }
+
+        for (q, q_dot) in self.q.iter_mut().zip(q_dot) {
+            *q += q_dot * dt;
+        }
+
+        let norm = self.q.iter().map(|q| q * q).sum::<f32>().sqrt();
+        self.q = self.q.map(|q| q / norm);
+    }
+
+    /// Roll, pitch and yaw in degrees
+    pub fn euler(&self) -> [f32; 3] {
+        let [w, x, y, z] = self.q;
+
+        let roll = (2.0 * (w * x + y * z)).atan2(1.0 - 2.0 * (x * x + y * y));
+        let pitch = (2.0 * (w * y - z * x)).clamp(-1.0, 1.0).asin();
+        let yaw = (2.0 * (w * z + x * y)).atan2(1.0 - 2.0 * (y * y + z * z));
+
+        [roll, pitch, yaw].map(f32::to_degrees)
+    }
+
+    /// `"orientation": {"q": [w, x, y, z], "roll": .., "pitch": .., "yaw": ..}` with angles in degrees
+    pub fn signal(&self) -> Signal {
+        let euler = self.euler();
+
+        Signal {
+            field: "orientation",
+            kind: "orientation",
+            value: json!(Fused {
+                q: self.q,
+                roll: euler[0],
+                pitch: euler[1],
+                yaw: euler[2],
+            }),
+            range: None,
+            axes: euler.to_vec(),
+            unit: Some("deg"),
+        }
+    }
+}
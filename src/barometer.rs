@@ -0,0 +1,298 @@
+use std::time::{Duration, Instant};
+
+use embedded_hal::i2c::I2c;
+use serde_json::json;
+
+use log::*;
+
+use crate::i2c_bus::SharedI2c;
+use crate::sensor::{Reading, Sensor};
+use crate::telemetry::Signal;
+use crate::Config;
+
+const REG_CTRL_MEAS: u8 = 0xf4;
+/// Pressure then temperature, MSB first
+const REG_DATA: u8 = 0xf7;
+/// Temperature oversampling x2, pressure x16, forced mode; the same bits on both chips
+const CTRL_MEAS_FORCED: u8 = 0b010_101_01;
+/// Conversion time at that oversampling
+const MEASURE_TIME: Duration = Duration::from_millis(50);
+/// Readings are taken at most this often, as a conversion blocks the bus
+const MIN_INTERVAL: Duration = Duration::from_secs(1);
+
+const BMP280_REG_ID: u8 = 0xd0;
+const BMP280_ID: u8 = 0x58;
+const BMP280_REG_CALIB: u8 = 0x88;
+const BMP280_ADDRESS: u8 = 0x76;
+
+const QMP6988_REG_ID: u8 = 0xd1;
+const QMP6988_ID: u8 = 0x5c;
+const QMP6988_REG_CALIB: u8 = 0xa0;
+const QMP6988_ADDRESS: u8 = 0x70;
+
+/// BMP280 trimming parameters, `dig_T1`..`dig_P9`
+struct Bmp280 {
+    t: [f64; 3],
+    p: [f64; 9],
+}
+
+impl Bmp280 {
+    fn from_calib(calib: &[u8; 24]) -> Self {
+        let word = |i: usize| [calib[2 * i], calib[2 * i + 1]];
+        let unsigned = |i| u16::from_le_bytes(word(i)) as f64;
+        let signed = |i| i16::from_le_bytes(word(i)) as f64;
+
+        Self {
+            t: [unsigned(0), signed(1), signed(2)],
+            p: [
+                unsigned(3),
+                signed(4),
+                signed(5),
+                signed(6),
+                signed(7),
+                signed(8),
+                signed(9),
+                signed(10),
+                signed(11),
+            ],
+        }
+    }
+
+    /// Pressure in Pa from the 20-bit raw values, as in the datasheet's floating-point example
+    fn compensate(&self, raw_p: u32, raw_t: u32) -> f64 {
+        let [t1, t2, t3] = self.t;
+        let [p1, p2, p3, p4, p5, p6, p7, p8, p9] = self.p;
+        let (raw_p, raw_t) = (raw_p as f64, raw_t as f64);
+
+        let var1 = (raw_t / 16384.0 - t1 / 1024.0) * t2;
+        let var2 = (raw_t / 131072.0 - t1 / 8192.0).powi(2) * t3;
+        let t_fine = var1 + var2;
+
+        let var1 = t_fine / 2.0 - 64000.0;
+        let var2 = var1 * var1 * p6 / 32768.0 + var1 * p5 * 2.0;
+        let var2 = var2 / 4.0 + p4 * 65536.0;
+        let var1 = (p3 * var1 * var1 / 524288.0 + p2 * var1) / 524288.0;
+        let var1 = (1.0 + var1 / 32768.0) * p1;
+        if var1 == 0.0 {
+            return 0.0;
+        }
+
+        let p = (1048576.0 - raw_p - var2 / 4096.0) * 6250.0 / var1;
+        let var1 = p9 * p * p / 2147483648.0;
+        let var2 = p * p8 / 32768.0;
+
+        p + (var1 + var2 + p7) / 16.0
+    }
+}
+
+/// QMP6988 conversion coefficients, already scaled from their OTP values
+struct Qmp6988 {
+    a0: f64,
+    a1: f64,
+    a2: f64,
+    b00: f64,
+    bt1: f64,
+    bt2: f64,
+    bp1: f64,
+    b11: f64,
+    bp2: f64,
+    b12: f64,
+    b21: f64,
+    bp3: f64,
+}
+
+impl Qmp6988 {
+    fn from_calib(calib: &[u8; 25]) -> Self {
+        let k = |i: usize| i16::from_be_bytes([calib[i], calib[i + 1]]) as f64 / 32767.0;
+        // 20-bit values, their low nibble is in the last byte
+        let long = |i: usize, low: u8| {
+            (i32::from_be_bytes([calib[i], calib[i + 1], low << 4, 0]) >> 12) as f64 / 16.0
+        };
+
+        Self {
+            a0: long(18, calib[24] & 0x0f),
+            a1: -6.3e-3 + 4.3e-4 * k(20),
+            a2: -1.9e-11 + 1.2e-10 * k(22),
+            b00: long(0, calib[24] >> 4),
+            bt1: 1.0e-1 + 9.1e-2 * k(2),
+            bt2: 1.2e-8 + 1.2e-6 * k(4),
+            bp1: 3.3e-2 + 1.9e-2 * k(6),
+            b11: 2.1e-7 + 1.4e-7 * k(8),
+            bp2: -6.3e-10 + 3.5e-10 * k(10),
+            b12: 2.9e-13 + 7.6e-13 * k(12),
+            b21: 2.1e-15 + 1.2e-14 * k(14),
+            bp3: 1.3e-16 + 7.9e-17 * k(16),
+        }
+    }
+
+    /// Pressure in Pa from the 24-bit raw values
+    fn compensate(&self, raw_p: u32, raw_t: u32) -> f64 {
+        let dp = raw_p as f64 - 8388608.0;
+        let dt = raw_t as f64 - 8388608.0;
+
+        // In 1/256 °C
+        let tr = self.a0 + self.a1 * dt + self.a2 * dt * dt;
+
+        self.b00
+            + self.bt1 * tr
+            + self.bp1 * dp
+            + self.b11 * dp * tr
+            + self.bt2 * tr * tr
+            + self.bp2 * dp * dp
+            + self.b12 * dp * tr * tr
+            + self.b21 * dp * dp * tr
+            + self.bp3 * dp * dp * dp
+    }
+}
+
+enum Chip {
+    Bmp280(Bmp280),
+    Qmp6988(Qmp6988),
+}
+
+/// Barometric pressure sensor on the shared bus: a BMP280 (ENV II unit) or a QMP6988 (ENV III)
+pub struct Barometer {
+    i2c: SharedI2c,
+    address: u8,
+    chip: Chip,
+    sea_level_hpa: f32,
+    measured_at: Option<Instant>,
+}
+
+impl Barometer {
+    /// `None` unless `pressure_sensor` names a chip that answers at its address
+    pub fn from_config(app_config: &Config, i2c: SharedI2c) -> Option<Self> {
+        let default_address = match app_config.pressure_sensor {
+            "none" | "" => return None,
+            "bmp280" => BMP280_ADDRESS,
+            "qmp6988" => QMP6988_ADDRESS,
+            other => {
+                warn!("Unknown pressure sensor \"{other}\"");
+                return None;
+            }
+        };
+        let address = match app_config.pressure_address {
+            0 => default_address,
+            address => address,
+        };
+
+        match probe(app_config.pressure_sensor, i2c.clone(), address) {
+            Ok(chip) => {
+                info!(
+                    "{} found at {address:#04x}",
+                    app_config.pressure_sensor.to_uppercase()
+                );
+                Some(Self {
+                    i2c,
+                    address,
+                    chip,
+                    sea_level_hpa: app_config.sea_level_hpa,
+                    measured_at: None,
+                })
+            }
+            Err(e) => {
+                warn!(
+                    "No {} at {address:#04x}, continuing without it: {e}",
+                    app_config.pressure_sensor
+                );
+                None
+            }
+        }
+    }
+
+    /// Pressure in hPa
+    fn read(&mut self) -> Result<f32, String> {
+        self.i2c
+            .write(self.address, &[REG_CTRL_MEAS, CTRL_MEAS_FORCED])
+            .map_err(|e| format!("{e:?}"))?;
+        std::thread::sleep(MEASURE_TIME);
+
+        let mut data = [0; 6];
+        self.i2c
+            .write_read(self.address, &[REG_DATA], &mut data)
+            .map_err(|e| format!("{e:?}"))?;
+
+        let pa = match &self.chip {
+            Chip::Bmp280(calib) => calib.compensate(raw(&data[0..3]) >> 4, raw(&data[3..6]) >> 4),
+            Chip::Qmp6988(calib) => calib.compensate(raw(&data[0..3]), raw(&data[3..6])),
+        };
+
+        Ok(pa as f32 / 100.0)
+    }
+
+    /// Height above sea level in m by the international barometric formula
+    fn altitude(&self, hpa: f32) -> f32 {
+        44330.0 * (1.0 - (hpa / self.sea_level_hpa).powf(1.0 / 5.255))
+    }
+}
+
+impl Sensor for Barometer {
+    fn name(&self) -> &'static str {
+        "pressure sensor"
+    }
+
+    /// `pressure` (hPa) and `altitude` (m), unless the last reading is less than a second old
+    fn sample(&mut self) -> Result<Reading, String> {
+        if self
+            .measured_at
+            .is_some_and(|at| at.elapsed() < MIN_INTERVAL)
+        {
+            return Ok(Vec::new());
+        }
+        self.measured_at = Some(Instant::now());
+
+        let hpa = self.read()?;
+        let altitude = self.altitude(hpa);
+
+        Ok(vec![
+            Signal {
+                field: "pressure",
+                kind: "pressure",
+                value: json!(hpa),
+                range: None,
+                axes: vec![hpa],
+                unit: Some("hPa"),
+            },
+            Signal {
+                field: "altitude",
+                kind: "altitude",
+                value: json!(altitude),
+                range: None,
+                axes: vec![altitude],
+                unit: Some("m"),
+            },
+        ])
+    }
+}
+
+/// Checks the chip ID and reads the calibration data
+fn probe(sensor: &str, mut i2c: SharedI2c, address: u8) -> Result<Chip, String> {
+    let (reg_id, id) = match sensor {
+        "bmp280" => (BMP280_REG_ID, BMP280_ID),
+        _ => (QMP6988_REG_ID, QMP6988_ID),
+    };
+
+    let mut found = [0];
+    i2c.write_read(address, &[reg_id], &mut found)
+        .map_err(|e| format!("{e:?}"))?;
+    if found[0] != id {
+        return Err(format!("unexpected chip id {:#04x}", found[0]));
+    }
+
+    if id == BMP280_ID {
+        let mut calib = [0; 24];
+        i2c.write_read(address, &[BMP280_REG_CALIB], &mut calib)
+            .map_err(|e| format!("{e:?}"))?;
+        Ok(Chip::Bmp280(Bmp280::from_calib(&calib)))
+    } else {
+        let mut calib = [0; 25];
+        i2c.write_read(address, &[QMP6988_REG_CALIB], &mut calib)
+            .map_err(|e| format!("{e:?}"))?;
+        Ok(Chip::Qmp6988(Qmp6988::from_calib(&calib)))
+    }
+}
+
+/// 24-bit big-endian value
+fn raw(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]])
+}
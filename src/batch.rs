@@ -0,0 +1,127 @@
+use std::time::{Duration, Instant};
+
+use crate::compression;
+use crate::delta;
+use crate::encoding::Encoding;
+use crate::Config;
+
+/// Samples collected for one topic
+struct Batch {
+    topic: String,
+    samples: Vec<Vec<u8>>,
+    first_at: Instant,
+}
+
+impl Batch {
+    fn into_message(
+        self,
+        encoding: Encoding,
+        delta_scale: Option<u32>,
+        compress_from: Option<usize>,
+    ) -> (String, Vec<u8>) {
+        let delta = match (encoding, delta_scale) {
+            (Encoding::Json, Some(scale)) if self.samples.len() > 1 => {
+                delta::encode(&self.samples, scale)
+            }
+            _ => None,
+        };
+
+        let payload = delta.unwrap_or_else(|| encoding.encoder().batch(&self.samples));
+
+        // Compressed batches go to their own topic, so consumers know to decompress them
+        if compress_from.is_some_and(|min| payload.len() >= min) {
+            let compressed = compression::compress(&payload);
+            if compressed.len() < payload.len() {
+                return (self.topic + &compression::topic_suffix(), compressed);
+            }
+        }
+
+        (self.topic, payload)
+    }
+}
+
+/// Collects telemetry samples per topic and hands them out as one message in their format,
+/// trading latency for fewer (and better filled) MQTT messages
+pub struct Batcher {
+    encoding: Encoding,
+    /// JSON batches go out delta-encoded at this scale
+    delta_scale: Option<u32>,
+    /// Batches of at least this many bytes are compressed
+    compress_from: Option<usize>,
+    size: usize,
+    max_age: Duration,
+    flush_on_alert: bool,
+    batches: Vec<Batch>,
+}
+
+impl Batcher {
+    pub fn from_config(app_config: &Config, encoding: Encoding) -> Self {
+        Self {
+            encoding,
+            delta_scale: (app_config.batch_delta_scale > 0).then_some(app_config.batch_delta_scale),
+            compress_from: (app_config.batch_compress_min_bytes > 0)
+                .then_some(app_config.batch_compress_min_bytes),
+            size: match encoding {
+                Encoding::JsonLines => app_config.batch_size.min(app_config.jsonl_max_lines),
+                _ => app_config.batch_size,
+            }
+            .max(1),
+            max_age: Duration::from_secs(app_config.batch_max_secs),
+            flush_on_alert: app_config.batch_flush_on_alert,
+            batches: Vec::new(),
+        }
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.size > 1 || !self.max_age.is_zero()
+    }
+
+    pub fn flush_on_alert(&self) -> bool {
+        self.flush_on_alert
+    }
+
+    /// Adds a sample; returns the topic and payload to publish once its batch is complete.
+    /// With batching disabled the sample itself is returned right away.
+    pub fn push(&mut self, topic: &str, sample: &[u8]) -> Option<(String, Vec<u8>)> {
+        if !self.is_enabled() {
+            return Some((topic.to_string(), sample.to_vec()));
+        }
+
+        let index = match self.batches.iter().position(|batch| batch.topic == topic) {
+            Some(index) => index,
+            None => {
+                self.batches.push(Batch {
+                    topic: topic.to_string(),
+                    samples: Vec::new(),
+                    first_at: Instant::now(),
+                });
+                self.batches.len() - 1
+            }
+        };
+
+        let batch = &mut self.batches[index];
+        batch.samples.push(sample.to_vec());
+
+        if batch.samples.len() >= self.size
+            || (!self.max_age.is_zero() && batch.first_at.elapsed() >= self.max_age)
+        {
+            return Some(self.batches.remove(index).into_message(
+                self.encoding,
+                self.delta_scale,
+                self.compress_from,
+            ));
+        }
+
+        None
+    }
+
+    /// Hands out whatever has been collected so far, one message per topic
+    pub fn flush(&mut self) -> Vec<(String, Vec<u8>)> {
+        let (encoding, delta_scale, compress_from) =
+            (self.encoding, self.delta_scale, self.compress_from);
+        self.batches
+            .drain(..)
+            .map(|batch| batch.into_message(encoding, delta_scale, compress_from))
+            .collect()
+    }
+}
@@ -0,0 +1,192 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use esp_idf_svc::sys::EspError;
+use serde::Serialize;
+use serde_json::json;
+
+use log::*;
+
+use crate::adc::Channel;
+use crate::clock::Stamp;
+use crate::sensor::{Reading, Sensor};
+use crate::telemetry::Signal;
+use crate::Config;
+
+/// Readings averaged into one, the divider output is noisy under radio load
+const SAMPLES: u32 = 32;
+/// The alert is raised again only after the charge rose this much above the threshold
+const HYSTERESIS_PCT: u8 = 5;
+
+/// The last reading, shared between the sensor and the monitor
+#[derive(Clone, Copy, Debug)]
+struct Level {
+    mv: f32,
+    percent: u8,
+}
+
+/// Battery voltage behind a resistor divider on an ADC1 pin, published as `battery_v` and
+/// `battery_pct`. The charge is interpolated linearly between the empty and full voltage,
+/// which is rough for LiPo cells but good enough to see them run down.
+pub struct Battery {
+    channel: Channel,
+    /// Battery voltage per volt at the pin
+    divider: f32,
+    empty_mv: f32,
+    full_mv: f32,
+    level: Arc<Mutex<Option<Level>>>,
+}
+
+/// Watches the readings of [`Battery`] for a low charge and a voltage close to brownout
+pub struct BatteryMonitor {
+    level: Arc<Mutex<Option<Level>>>,
+    low_pct: u8,
+    shutdown_mv: f32,
+    alerted: AtomicBool,
+}
+
+#[derive(Serialize)]
+struct LowBatteryAlert {
+    seq: u32,
+    #[serde(flatten)]
+    stamp: Stamp,
+    event: &'static str,
+    voltage: f32,
+    percent: u8,
+}
+
+/// A charge that dropped to the threshold
+pub struct LowBattery(Level);
+
+impl LowBattery {
+    /// `{"seq": 42, "ts": .., "mono_us": .., "event": "low_battery", "voltage": 3.52,
+    /// "percent": 18}`
+    pub fn to_json(&self, seq: u32) -> String {
+        serde_json::to_string(&LowBatteryAlert {
+            seq,
+            stamp: Stamp::now(),
+            event: "low_battery",
+            voltage: volts(self.0.mv),
+            percent: self.0.percent,
+        })
+        .unwrap()
+    }
+}
+
+impl Battery {
+    /// `None` unless `battery_adc_gpio` is set
+    pub fn from_config(app_config: &Config) -> Result<Option<(Self, BatteryMonitor)>, EspError> {
+        if app_config.battery_adc_gpio < 0 {
+            return Ok(None);
+        }
+
+        let Some(channel) = Channel::open(app_config.battery_adc_gpio)? else {
+            warn!("Battery monitoring disabled");
+            return Ok(None);
+        };
+
+        info!(
+            "Battery on GPIO{}, alerting at {}%, shutting down below {}mV",
+            app_config.battery_adc_gpio, app_config.battery_low_pct, app_config.battery_shutdown_mv
+        );
+
+        let level = Arc::new(Mutex::new(None));
+        Ok(Some((
+            Self {
+                channel,
+                divider: app_config.battery_divider,
+                empty_mv: app_config.battery_empty_mv as f32,
+                full_mv: app_config.battery_full_mv as f32,
+                level: level.clone(),
+            },
+            BatteryMonitor {
+                level,
+                low_pct: app_config.battery_low_pct,
+                shutdown_mv: app_config.battery_shutdown_mv as f32,
+                alerted: AtomicBool::new(false),
+            },
+        )))
+    }
+
+    fn percent(&self, mv: f32) -> u8 {
+        if self.full_mv <= self.empty_mv {
+            return 0;
+        }
+
+        ((mv - self.empty_mv) * 100.0 / (self.full_mv - self.empty_mv)).clamp(0.0, 100.0) as u8
+    }
+}
+
+impl Sensor for Battery {
+    fn name(&self) -> &'static str {
+        "battery"
+    }
+
+    fn sample(&mut self) -> Result<Reading, String> {
+        let mv = self.channel.read(SAMPLES).map_err(|e| e.to_string())? * self.divider;
+        let percent = self.percent(mv);
+        *self.level.lock().unwrap() = Some(Level { mv, percent });
+
+        let voltage = volts(mv);
+        Ok(vec![
+            Signal {
+                field: "battery_v",
+                kind: "battery_v",
+                value: json!(voltage),
+                range: None,
+                axes: vec![voltage],
+                unit: Some("V"),
+            },
+            Signal {
+                field: "battery_pct",
+                kind: "battery_pct",
+                value: json!(percent),
+                range: None,
+                axes: vec![percent as f32],
+                unit: Some("%"),
+            },
+        ])
+    }
+}
+
+impl BatteryMonitor {
+    /// The last reading, once when the charge drops to `battery_low_pct` and again only after
+    /// it recovered in between
+    pub fn take_alert(&self) -> Option<LowBattery> {
+        let level = (*self.level.lock().unwrap())?;
+        if self.low_pct == 0 {
+            return None;
+        }
+
+        if level.percent > self.low_pct.saturating_add(HYSTERESIS_PCT) {
+            self.alerted.store(false, Ordering::Relaxed);
+            return None;
+        }
+        if level.percent > self.low_pct || self.alerted.swap(true, Ordering::Relaxed) {
+            return None;
+        }
+
+        Some(LowBattery(level))
+    }
+
+    /// Charge of the last reading, `None` before the first one
+    pub fn percent(&self) -> Option<u8> {
+        self.level.lock().unwrap().map(|level| level.percent)
+    }
+
+    /// Whether the voltage fell below `battery_shutdown_mv`, so the device should go offline
+    /// before a brownout resets it half way through a flash write
+    pub fn critical(&self) -> bool {
+        self.shutdown_mv > 0.0
+            && self
+                .level
+                .lock()
+                .unwrap()
+                .is_some_and(|level| level.mv < self.shutdown_mv)
+    }
+}
+
+/// mV as V, rounded to 10mV
+fn volts(mv: f32) -> f32 {
+    (mv / 10.0).round() / 100.0
+}
@@ -16,6 +16,13 @@ use esp_idf_svc::wifi::*;
 
 use log::*;
 
+// `with_metadata` is only used by the main firmware
+#[allow(dead_code)]
+#[path = "../envelope.rs"]
+mod envelope;
+
+use envelope::Envelope;
+
 #[toml_cfg::toml_config]
 pub struct Config {
     #[default("")]
@@ -68,7 +75,15 @@ fn main() {
         info!("MQTT client created");
 
         let mut timer = timer_service.timer_async()?;
-        run(&mut client, &mut conn, &mut timer, app_config.aws_iot_topic).await
+        let envelope = Envelope::new(app_config.aws_iot_client_id, env!("CARGO_PKG_VERSION"));
+        run(
+            &mut client,
+            &mut conn,
+            &mut timer,
+            app_config.aws_iot_topic,
+            &envelope,
+        )
+        .await
     })
     .unwrap();
 }
@@ -78,6 +93,7 @@ async fn run(
     connection: &mut EspAsyncMqttConnection,
     timer: &mut EspAsyncTimer,
     topic: &str,
+    envelope: &Envelope,
 ) -> Result<(), EspError> {
     info!("About to start the MQTT client");
 
@@ -120,11 +136,12 @@ async fn run(
                 let payload = "Hello from esp-mqtt-demo!";
 
                 loop {
+                    let message = serde_json::to_string(&envelope.wrap(&payload)).unwrap();
                     client
-                        .publish(topic, QoS::AtMostOnce, false, payload.as_bytes())
+                        .publish(topic, QoS::AtMostOnce, false, message.as_bytes())
                         .await?;
 
-                    info!("Published \"{payload}\" to topic \"{topic}\"");
+                    info!("Published \"{message}\" to topic \"{topic}\"");
 
                     let sleep_secs = 2;
 
@@ -13,6 +13,13 @@ use esp_idf_svc::tls::X509;
 use esp_idf_svc::wifi::*;
 
 use log::*;
+
+// `with_metadata` is only used by the main firmware
+#[allow(dead_code)]
+#[path = "../envelope.rs"]
+mod envelope;
+
+use envelope::Envelope;
 use mpu6886::Mpu6886;
 
 #[toml_cfg::toml_config]
@@ -81,13 +88,16 @@ fn main() {
     .unwrap();
     info!("MQTT client created");
 
-    run(&mut client, &mut conn, app_config.aws_iot_topic).unwrap();
+    let envelope = Envelope::new(app_config.aws_iot_client_id, env!("CARGO_PKG_VERSION"));
+
+    run(&mut client, &mut conn, app_config.aws_iot_topic, &envelope).unwrap();
 }
 
 fn run(
     client: &mut EspMqttClient<'_>,
     connection: &mut EspMqttConnection,
     topic: &str,
+    envelope: &Envelope,
 ) -> Result<(), EspError> {
     std::thread::scope(|s| {
         info!("About to start the MQTT client");
@@ -130,9 +140,10 @@ fn run(
             let payload = "Hello from esp-mqtt-demo!";
 
             loop {
-                client.enqueue(topic, QoS::AtMostOnce, false, payload.as_bytes())?;
+                let message = serde_json::to_string(&envelope.wrap(&payload)).unwrap();
+                client.enqueue(topic, QoS::AtMostOnce, false, message.as_bytes())?;
 
-                info!("Published \"{payload}\" to topic \"{topic}\"");
+                info!("Published \"{message}\" to topic \"{topic}\"");
 
                 let sleep_secs = 2;
 
@@ -0,0 +1,84 @@
+use serde::Serialize;
+
+use esp_idf_svc::nvs::{EspDefaultNvs, EspDefaultNvsPartition};
+use esp_idf_svc::sys::{
+    esp_reset_reason, esp_reset_reason_t_ESP_RST_BROWNOUT, esp_reset_reason_t_ESP_RST_DEEPSLEEP,
+    esp_reset_reason_t_ESP_RST_EXT, esp_reset_reason_t_ESP_RST_INT_WDT,
+    esp_reset_reason_t_ESP_RST_PANIC, esp_reset_reason_t_ESP_RST_POWERON,
+    esp_reset_reason_t_ESP_RST_SDIO, esp_reset_reason_t_ESP_RST_SW,
+    esp_reset_reason_t_ESP_RST_TASK_WDT, esp_reset_reason_t_ESP_RST_WDT,
+    esp_sleep_get_wakeup_cause, esp_sleep_source_t_ESP_SLEEP_WAKEUP_EXT0,
+    esp_sleep_source_t_ESP_SLEEP_WAKEUP_EXT1, esp_sleep_source_t_ESP_SLEEP_WAKEUP_TIMER, EspError,
+};
+
+use log::*;
+
+const NVS_NAMESPACE: &str = "boot";
+const COUNT_KEY: &str = "count";
+
+/// Why and how often the device started: `{"reset_reason": "brownout", "boot_count": 17}`,
+/// after deep sleep with `"wake_cause": "motion"` or `"timer"`. The count survives a factory
+/// reset, so it adds up over the life of the device.
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct Boot {
+    pub reset_reason: &'static str,
+    pub boot_count: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub wake_cause: Option<&'static str>,
+}
+
+impl Boot {
+    /// Reads the reset reason and counts this boot in NVS; call once at startup
+    pub fn record(partition: EspDefaultNvsPartition) -> Result<Self, EspError> {
+        let nvs = EspDefaultNvs::new(partition, NVS_NAMESPACE, true)?;
+        let boot_count = nvs.get_u32(COUNT_KEY)?.unwrap_or(0).wrapping_add(1);
+        nvs.set_u32(COUNT_KEY, boot_count)?;
+
+        let reset_reason = reset_reason();
+        let boot = Self {
+            reset_reason,
+            boot_count,
+            wake_cause: (reset_reason == "deep_sleep").then(wake_cause),
+        };
+        match boot.reset_reason {
+            "power_on" | "software" => info!("Boot #{boot_count} after {}", boot.reset_reason),
+            "deep_sleep" => info!(
+                "Boot #{boot_count} after deep sleep, woken by {}",
+                boot.wake_cause.unwrap_or("unknown")
+            ),
+            reason => warn!("Boot #{boot_count} after {reason}"),
+        }
+
+        Ok(boot)
+    }
+}
+
+#[allow(non_upper_case_globals)]
+fn reset_reason() -> &'static str {
+    match unsafe { esp_reset_reason() } {
+        esp_reset_reason_t_ESP_RST_POWERON => "power_on",
+        esp_reset_reason_t_ESP_RST_EXT => "external_pin",
+        esp_reset_reason_t_ESP_RST_SW => "software",
+        esp_reset_reason_t_ESP_RST_PANIC => "panic",
+        esp_reset_reason_t_ESP_RST_INT_WDT => "interrupt_watchdog",
+        esp_reset_reason_t_ESP_RST_TASK_WDT => "task_watchdog",
+        esp_reset_reason_t_ESP_RST_WDT => "watchdog",
+        esp_reset_reason_t_ESP_RST_DEEPSLEEP => "deep_sleep",
+        esp_reset_reason_t_ESP_RST_BROWNOUT => "brownout",
+        esp_reset_reason_t_ESP_RST_SDIO => "sdio",
+        // Reasons newer IDF versions added, such as USB or JTAG resets
+        _ => "unknown",
+    }
+}
+
+/// What ended deep sleep: the sensor's motion interrupt on EXT0/EXT1, or the timer
+#[allow(non_upper_case_globals)]
+fn wake_cause() -> &'static str {
+    match unsafe { esp_sleep_get_wakeup_cause() } {
+        esp_sleep_source_t_ESP_SLEEP_WAKEUP_EXT0 | esp_sleep_source_t_ESP_SLEEP_WAKEUP_EXT1 => {
+            "motion"
+        }
+        esp_sleep_source_t_ESP_SLEEP_WAKEUP_TIMER => "timer",
+        _ => "unknown",
+    }
+}
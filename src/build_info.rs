@@ -0,0 +1,18 @@
+use serde::Serialize;
+
+/// What a device is running, so the fleet can be told apart by firmware:
+/// `{"firmware_version": "0.1.0", "git_hash": "1a2b3c4", "built_at": "2024-06-10T12:34:56Z"}`
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct BuildInfo {
+    pub firmware_version: &'static str,
+    /// Short hash of the commit built, "unknown" outside a git checkout
+    pub git_hash: &'static str,
+    pub built_at: &'static str,
+}
+
+/// Filled in by `build.rs`
+pub const BUILD_INFO: BuildInfo = BuildInfo {
+    firmware_version: env!("CARGO_PKG_VERSION"),
+    git_hash: env!("GIT_HASH"),
+    built_at: env!("BUILD_TIMESTAMP"),
+};
@@ -0,0 +1,40 @@
+use std::time::{Duration, Instant};
+
+use esp_idf_svc::hal::gpio::{Gpio42, Input, PinDriver};
+
+/// How long the button has to be held to count as a long press
+const LONG_PRESS: Duration = Duration::from_secs(3);
+
+/// The button on GPIO42 (active low), polled for long presses
+pub struct Button {
+    pin: PinDriver<'static, Gpio42, Input>,
+    pressed_at: Option<Instant>,
+    reported: bool,
+}
+
+impl Button {
+    pub fn new(pin: PinDriver<'static, Gpio42, Input>) -> Self {
+        Self {
+            pin,
+            pressed_at: None,
+            reported: false,
+        }
+    }
+
+    /// Call regularly; returns `true` once per press held for at least 3s
+    pub fn long_pressed(&mut self) -> bool {
+        if self.pin.is_high() {
+            self.pressed_at = None;
+            self.reported = false;
+            return false;
+        }
+
+        let pressed_at = *self.pressed_at.get_or_insert_with(Instant::now);
+        if !self.reported && pressed_at.elapsed() >= LONG_PRESS {
+            self.reported = true;
+            return true;
+        }
+
+        false
+    }
+}
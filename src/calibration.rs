@@ -0,0 +1,205 @@
+use std::time::{Duration, Instant};
+
+use esp_idf_svc::timer::EspAsyncTimer;
+
+use log::*;
+
+use crate::imu::Imu;
+use crate::settings::Settings;
+use crate::watchdog;
+
+/// Readings averaged per calibration
+const SAMPLES: u32 = 100;
+/// Time between readings while learning the temperature model
+const TEMP_SAMPLE_PERIOD: Duration = Duration::from_millis(200);
+/// Temperature change needed for a meaningful slope
+const MIN_TEMP_SPAN_C: f32 = 2.0;
+
+/// Offsets subtracted from every gyro and accelerometer reading
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Offsets {
+    pub gyro: [f32; 3],
+    pub acc: [f32; 3],
+}
+
+impl Offsets {
+    /// `[gyro x, y, z, acc x, y, z]` as f32 LE
+    pub fn to_bytes(self) -> [u8; 24] {
+        let mut data = [0; 24];
+        for (chunk, value) in data.chunks_mut(4).zip(self.gyro.iter().chain(&self.acc)) {
+            chunk.copy_from_slice(&value.to_le_bytes());
+        }
+
+        data
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Option<Self> {
+        if data.len() != 24 {
+            return None;
+        }
+
+        let mut values = data
+            .chunks(4)
+            .map(|chunk| f32::from_le_bytes(chunk.try_into().unwrap()));
+        let mut next = || values.next().unwrap();
+
+        Some(Self {
+            gyro: [next(), next(), next()],
+            acc: [next(), next(), next()],
+        })
+    }
+}
+
+/// Linear model of the gyro bias over the chip temperature: the offsets hold the bias at
+/// `ref_c`, which changes by `slope` rad/s per °C away from it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TempModel {
+    pub ref_c: f32,
+    pub slope: [f32; 3],
+}
+
+impl TempModel {
+    /// `[ref, slope x, y, z]` as f32 LE
+    pub fn to_bytes(self) -> [u8; 16] {
+        let mut data = [0; 16];
+        for (chunk, value) in data
+            .chunks_mut(4)
+            .zip([self.ref_c].iter().chain(&self.slope))
+        {
+            chunk.copy_from_slice(&value.to_le_bytes());
+        }
+
+        data
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Option<Self> {
+        if data.len() != 16 {
+            return None;
+        }
+
+        let mut values = data
+            .chunks(4)
+            .map(|chunk| f32::from_le_bytes(chunk.try_into().unwrap()));
+        let mut next = || values.next().unwrap();
+
+        Some(Self {
+            ref_c: next(),
+            slope: [next(), next(), next()],
+        })
+    }
+
+    /// The gyro bias at `temp_c`, given the bias at the reference temperature
+    pub fn bias(&self, ref_bias: [f32; 3], temp_c: f32) -> [f32; 3] {
+        [0, 1, 2].map(|axis| ref_bias[axis] + self.slope[axis] * (temp_c - self.ref_c))
+    }
+}
+
+/// Averages readings of the device lying still and stores the result as the new offsets.
+/// Gravity is expected on whichever axis the accelerometer sees it most strongly.
+pub fn calibrate(mpu: &mut Imu, settings: &Settings) -> Result<Offsets, String> {
+    let mut gyro_sum = [0.0f32; 3];
+    let mut acc_sum = [0.0f32; 3];
+
+    for _ in 0..SAMPLES {
+        let gyro = mpu.get_gyro().map_err(|e| format!("{e:?}"))?;
+        let acc = mpu.get_acc().map_err(|e| format!("{e:?}"))?;
+        for (sum, value) in gyro_sum.iter_mut().zip([gyro.x, gyro.y, gyro.z]) {
+            *sum += value;
+        }
+        for (sum, value) in acc_sum.iter_mut().zip([acc.x, acc.y, acc.z]) {
+            *sum += value;
+        }
+        std::thread::sleep(Duration::from_millis(5));
+    }
+
+    let mut gyro = gyro_sum.map(|sum| sum / SAMPLES as f32);
+    // Keep the gyro offsets at the temperature model's reference temperature
+    if let Some(model) = settings.temp_model() {
+        let temp = mpu.get_temp().map_err(|e| format!("{e:?}"))?;
+        let drift = model.bias([0.0; 3], temp);
+        gyro = [0, 1, 2].map(|axis| gyro[axis] - drift[axis]);
+    }
+    let mut acc = acc_sum.map(|sum| sum / SAMPLES as f32);
+
+    // Leave 1g on the axis pointing up (or down)
+    let (up, _) = acc
+        .iter()
+        .enumerate()
+        .max_by(|(_, a), (_, b)| a.abs().total_cmp(&b.abs()))
+        .unwrap();
+    acc[up] -= acc[up].signum();
+
+    let offsets = Offsets { gyro, acc };
+    info!("Calibrated: {offsets:?}");
+    settings.set_offsets(offsets);
+
+    Ok(offsets)
+}
+
+/// Fits the gyro bias over the chip temperature from readings taken for `duration` while the
+/// device lies still and warms up (or cools down), e.g. right after power-on. Stores the model,
+/// and the bias at its reference temperature as the new gyro offsets. Waits on `timer` between
+/// readings so the MQTT connection keeps being served.
+pub async fn calibrate_temp(
+    mpu: &mut Imu,
+    settings: &Settings,
+    timer: &mut EspAsyncTimer,
+    duration: Duration,
+) -> Result<(TempModel, Offsets), String> {
+    let mut temps = Vec::new();
+    let mut gyros = Vec::new();
+
+    let started = Instant::now();
+    while started.elapsed() < duration {
+        // Takes up to 10 minutes, far longer than the watchdog waits
+        watchdog::feed();
+        temps.push(mpu.get_temp().map_err(|e| format!("{e:?}"))?);
+        let gyro = mpu.get_gyro().map_err(|e| format!("{e:?}"))?;
+        gyros.push([gyro.x, gyro.y, gyro.z]);
+        timer
+            .after(TEMP_SAMPLE_PERIOD)
+            .await
+            .map_err(|e| e.to_string())?;
+    }
+
+    let (min, max) = temps.iter().fold((f32::MAX, f32::MIN), |(min, max), t| {
+        (min.min(*t), max.max(*t))
+    });
+    if temps.len() < 2 || max - min < MIN_TEMP_SPAN_C {
+        return Err(format!(
+            "temperature only changed by {:.1}°C, at least {MIN_TEMP_SPAN_C}°C are needed",
+            (max - min).max(0.0)
+        ));
+    }
+
+    // Least squares per axis, around the mean temperature
+    let n = temps.len() as f32;
+    let ref_c = temps.iter().sum::<f32>() / n;
+    let var: f32 = temps.iter().map(|t| (t - ref_c).powi(2)).sum();
+    let mut slope = [0.0; 3];
+    let mut bias = [0.0; 3];
+    for axis in 0..3 {
+        let mean = gyros.iter().map(|g| g[axis]).sum::<f32>() / n;
+        let cov: f32 = temps
+            .iter()
+            .zip(&gyros)
+            .map(|(t, g)| (t - ref_c) * (g[axis] - mean))
+            .sum();
+        slope[axis] = cov / var;
+        bias[axis] = mean;
+    }
+
+    let model = TempModel { ref_c, slope };
+    let offsets = Offsets {
+        gyro: bias,
+        ..settings.offsets()
+    };
+    info!(
+        "Gyro temperature model from {} readings over {min:.1}-{max:.1}°C: {model:?}",
+        temps.len()
+    );
+    settings.set_offsets(offsets);
+    settings.set_temp_model(Some(model));
+
+    Ok((model, offsets))
+}
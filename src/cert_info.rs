@@ -0,0 +1,102 @@
+/// Returns the `notAfter` date of the first certificate in a PEM file, formatted as `YYYY-MM-DD HH:MM:SS UTC`
+pub fn not_after(pem: &[u8]) -> Option<String> {
+    let der = pem_to_der(core::str::from_utf8(pem).ok()?)?;
+
+    // Certificate ::= SEQUENCE { tbsCertificate, ... }
+    let (_, cert, _) = read_tlv(&der)?;
+    // TBSCertificate ::= SEQUENCE { [0] version OPTIONAL, serialNumber, signature, issuer, validity, ... }
+    let (_, mut tbs, _) = read_tlv(cert)?;
+
+    if tbs.first() == Some(&0xa0) {
+        tbs = read_tlv(tbs)?.2;
+    }
+    for _ in 0..3 {
+        // serialNumber, signature, issuer
+        tbs = read_tlv(tbs)?.2;
+    }
+
+    let (_, validity, _) = read_tlv(tbs)?;
+    let (_, _, validity) = read_tlv(validity)?;
+    let (tag, time, _) = read_tlv(validity)?;
+    let time = core::str::from_utf8(time).ok()?;
+
+    // UTCTime is YYMMDDHHMMSSZ, GeneralizedTime is YYYYMMDDHHMMSSZ
+    let time = match tag {
+        0x17 => format!("20{time}"),
+        0x18 => time.to_string(),
+        _ => return None,
+    };
+    if time.len() < 14 || !time.is_ascii() {
+        return None;
+    }
+
+    Some(format!(
+        "{}-{}-{} {}:{}:{} UTC",
+        &time[0..4],
+        &time[4..6],
+        &time[6..8],
+        &time[8..10],
+        &time[10..12],
+        &time[12..14]
+    ))
+}
+
+/// Splits a DER element into its tag, contents and the bytes following it
+fn read_tlv(data: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let tag = *data.first()?;
+    let first = *data.get(1)? as usize;
+
+    let (len, header) = if first & 0x80 == 0 {
+        (first, 2)
+    } else {
+        let count = first & 0x7f;
+        if count == 0 || count > 4 {
+            return None;
+        }
+
+        let len = data
+            .get(2..2 + count)?
+            .iter()
+            .fold(0usize, |len, byte| (len << 8) | *byte as usize);
+        (len, 2 + count)
+    };
+
+    let contents = data.get(header..header + len)?;
+
+    Some((tag, contents, &data[header + len..]))
+}
+
+fn pem_to_der(pem: &str) -> Option<Vec<u8>> {
+    let body = pem
+        .lines()
+        .skip_while(|line| !line.starts_with("-----BEGIN"))
+        .skip(1)
+        .take_while(|line| !line.starts_with("-----END"))
+        .flat_map(|line| line.trim().bytes());
+
+    let mut der = Vec::new();
+    let mut acc = 0u32;
+    let mut bits = 0;
+
+    for c in body {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            b'=' => break,
+            _ => return None,
+        };
+
+        acc = (acc << 6) | value as u32;
+        bits += 6;
+
+        if bits >= 8 {
+            bits -= 8;
+            der.push((acc >> bits) as u8);
+        }
+    }
+
+    Some(der)
+}
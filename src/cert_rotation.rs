@@ -0,0 +1,135 @@
+use core::cell::Cell;
+use core::pin::pin;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Result};
+use embassy_futures::select::{select, Either};
+use serde_json::{json, Map, Value};
+
+use esp_idf_svc::mqtt::client::*;
+use esp_idf_svc::timer::EspAsyncTimer;
+use esp_idf_svc::tls::X509;
+
+use log::*;
+
+use crate::cert_info;
+use crate::commands::CommandContext;
+use crate::convert_certificate;
+use crate::credentials::Credentials;
+use crate::mqtt::{mqtt_create, MqttAuth, OFFLINE_PAYLOAD};
+
+/// How long the test connection with the new certificate may take
+const VERIFY_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// `{"command": "rotate_cert", "certificate": "<PEM>", "private_key": "<PEM>"}`.
+/// Only stages the pair; it is tested and stored once the current session has ended.
+pub fn rotate_cert(
+    ctx: &mut CommandContext<'_>,
+    args: &Map<String, Value>,
+) -> Result<Value, String> {
+    let certificate = args
+        .get("certificate")
+        .and_then(Value::as_str)
+        .ok_or("missing \"certificate\"")?;
+    let private_key = args
+        .get("private_key")
+        .and_then(Value::as_str)
+        .ok_or("missing \"private_key\"")?;
+
+    let not_after = cert_info::not_after(certificate.as_bytes())
+        .ok_or("\"certificate\" is not a PEM certificate")?;
+    if !private_key.contains("PRIVATE KEY-----") {
+        return Err("\"private_key\" is not a PEM private key".to_string());
+    }
+
+    ctx.rotation = Some(Credentials {
+        certificate: certificate.as_bytes().to_vec(),
+        private_key: private_key.as_bytes().to_vec(),
+        thing_name: None,
+    });
+
+    Ok(json!({ "staged": true, "not_after": not_after }))
+}
+
+/// Payload for the `cert-rotation` status topic
+pub fn report(res: &Result<String>) -> String {
+    match res {
+        Ok(not_after) => json!({ "state": "rotated", "not_after": not_after }),
+        Err(e) => json!({ "state": "failed", "error": format!("{e:#}") }),
+    }
+    .to_string()
+}
+
+/// Connects with the new credentials and publishes the success report through that connection,
+/// proving AWS IoT accepts the pair before it replaces the current one
+pub async fn verify(
+    url: &str,
+    client_id: &str,
+    status_topic: &str,
+    report_topic: &str,
+    server_cert: X509<'static>,
+    credentials: &Credentials,
+    timer: &mut EspAsyncTimer,
+) -> Result<String> {
+    let not_after = cert_info::not_after(&credentials.certificate).unwrap_or_default();
+
+    let (mut client, mut connection) = mqtt_create(
+        url,
+        client_id,
+        status_topic,
+        OFFLINE_PAYLOAD,
+        Some(server_cert),
+        MqttAuth::Certificate {
+            client_cert: convert_certificate(credentials.certificate.clone()),
+            private_key: convert_certificate(credentials.private_key.clone()),
+        },
+    )?;
+
+    let connected = Cell::new(false);
+    let published = Cell::new(None);
+
+    let res = select(
+        pin!(async {
+            while let Ok(event) = connection.next().await {
+                match event.payload() {
+                    EventPayload::Connected(_) => connected.set(true),
+                    EventPayload::Published(id) => published.set(Some(id)),
+                    EventPayload::Disconnected => break,
+                    EventPayload::Error(e) => warn!("Certificate test connection: {e:?}"),
+                    _ => (),
+                }
+            }
+
+            Err(anyhow!("connection refused with the new certificate"))
+        }),
+        pin!(async {
+            let started = Instant::now();
+            while !connected.get() {
+                if started.elapsed() >= VERIFY_TIMEOUT {
+                    bail!("timed out connecting with the new certificate");
+                }
+                timer.after(Duration::from_millis(100)).await?;
+            }
+
+            let payload = report(&Ok(not_after.clone()));
+            let id = client
+                .publish(report_topic, QoS::AtLeastOnce, false, payload.as_bytes())
+                .await?;
+
+            while published.get() != Some(id) {
+                if started.elapsed() >= VERIFY_TIMEOUT {
+                    bail!("timed out publishing with the new certificate");
+                }
+                timer.after(Duration::from_millis(100)).await?;
+            }
+
+            Ok(not_after.clone())
+        }),
+    )
+    .await;
+
+    match res {
+        Either::First(res) => res,
+        Either::Second(res) => res,
+    }
+}
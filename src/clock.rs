@@ -0,0 +1,36 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+/// Seconds since the epoch before which the clock is taken as not set yet (2024-01-01)
+const CLOCK_SET_SECS: u64 = 1_704_067_200;
+
+/// Milliseconds since the epoch, `None` until SNTP set the clock
+pub fn epoch_ms() -> Option<u64> {
+    let since = SystemTime::now().duration_since(UNIX_EPOCH).ok()?;
+
+    (since.as_secs() >= CLOCK_SET_SECS).then_some(since.as_millis() as u64)
+}
+
+/// Microseconds since boot; unlike the wall clock it never jumps
+pub fn uptime_us() -> u64 {
+    unsafe { esp_idf_svc::sys::esp_timer_get_time() as u64 }
+}
+
+/// When a sample was taken, flattened into its payload: `"ts": 1718000000000, "mono_us": 12345678`.
+/// `ts` is left out while the clock is not set; `mono_us` orders samples within a batch.
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct Stamp {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ts: Option<u64>,
+    pub mono_us: u64,
+}
+
+impl Stamp {
+    pub fn now() -> Self {
+        Self {
+            ts: epoch_ms(),
+            mono_us: uptime_us(),
+        }
+    }
+}
@@ -0,0 +1,346 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+
+use log::*;
+
+use crate::calibration;
+use crate::control::Buzzer;
+use crate::credentials::Credentials;
+use crate::fifo::{self, Capture};
+use crate::i2c_bus::{self, SharedI2c};
+use crate::imu::{self, Imu};
+use crate::log_sink;
+use crate::settings::Settings;
+use crate::shadow::Shadow;
+
+/// Bounds how long `calibrate_temp` holds off sampling and publishing; the connection is still
+/// served meanwhile
+const MAX_TEMP_CALIBRATION_SECS: u64 = 600;
+
+/// What command handlers may act on
+pub struct CommandContext<'a> {
+    pub mpu: &'a mut Imu,
+    pub buses: &'a [SharedI2c],
+    pub buzzer: &'a Buzzer,
+    pub settings: &'a Settings,
+    pub shadow: &'a Shadow,
+    /// Set by `reboot`; the caller restarts once the acks are out
+    pub reboot: bool,
+    /// Set by `factory_reset`; the caller wipes the stored configuration once the acks are out
+    pub factory_reset: bool,
+    /// Set by `rotate_cert`; the caller ends the session to test the new pair
+    pub rotation: Option<Credentials>,
+    /// Set by `capture`; the caller publishes it as telemetry
+    pub capture: Option<Capture>,
+    /// Set by `calibrate_temp`; the caller runs it once the acks are out and publishes the result
+    pub temp_calibration: Option<Duration>,
+}
+
+/// Runs a command with its arguments, returning the result for the ack or an error message
+pub type Handler = fn(&mut CommandContext<'_>, &Map<String, Value>) -> Result<Value, String>;
+
+/// `{"id": ..., "command": "beep", <arguments>...}`; without `command`, the last topic level is used
+#[derive(Deserialize)]
+struct Request {
+    #[serde(default)]
+    id: Value,
+    command: Option<String>,
+    #[serde(flatten)]
+    args: Map<String, Value>,
+}
+
+/// Published on `<command topic>/ack` for every request
+#[derive(Serialize)]
+struct Ack<'a> {
+    id: &'a Value,
+    command: &'a str,
+    status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Routes JSON commands received below the command topic to registered handlers.
+/// Requests are queued by the connection loop and run by the publisher, which owns the hardware.
+pub struct Dispatcher {
+    topic_filter: String,
+    topic_prefix: String,
+    ack_topic: String,
+    handlers: HashMap<&'static str, Handler>,
+    pending: Mutex<VecDeque<(String, Vec<u8>)>>,
+}
+
+impl Dispatcher {
+    pub fn new(command_topic: &str) -> Self {
+        Self {
+            topic_filter: format!("{command_topic}/#"),
+            topic_prefix: format!("{command_topic}/"),
+            ack_topic: format!("{command_topic}/ack"),
+            handlers: HashMap::new(),
+            pending: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    pub fn register(&mut self, command: &'static str, handler: Handler) {
+        self.handlers.insert(command, handler);
+    }
+
+    pub fn subscribe_topic(&self) -> &str {
+        &self.topic_filter
+    }
+
+    pub fn ack_topic(&self) -> &str {
+        &self.ack_topic
+    }
+
+    /// Called from the connection loop; returns `true` if the message was a command
+    pub fn handle_message(&self, topic: &str, data: &[u8]) -> bool {
+        // Our own acks come back through the wildcard subscription
+        if topic == self.ack_topic {
+            return false;
+        }
+
+        let Some(suffix) = topic.strip_prefix(&self.topic_prefix) else {
+            return false;
+        };
+
+        self.pending
+            .lock()
+            .unwrap()
+            .push_back((suffix.to_string(), data.to_vec()));
+
+        true
+    }
+
+    pub fn has_pending(&self) -> bool {
+        !self.pending.lock().unwrap().is_empty()
+    }
+
+    /// Runs all queued commands, returning the ack payloads to publish
+    pub fn dispatch_pending(&self, ctx: &mut CommandContext<'_>) -> Vec<String> {
+        let pending: Vec<_> = self.pending.lock().unwrap().drain(..).collect();
+
+        pending
+            .into_iter()
+            .map(|(suffix, data)| self.dispatch(ctx, &suffix, &data))
+            .collect()
+    }
+
+    fn dispatch(&self, ctx: &mut CommandContext<'_>, suffix: &str, data: &[u8]) -> String {
+        let (id, command, res) = match serde_json::from_slice::<Request>(data) {
+            Ok(request) => {
+                let command = request.command.unwrap_or_else(|| suffix.to_string());
+                let res = match self.handlers.get(command.as_str()) {
+                    Some(handler) => handler(ctx, &request.args),
+                    None => Err(format!("unknown command \"{command}\"")),
+                };
+
+                (request.id, command, res)
+            }
+            Err(e) => (
+                Value::Null,
+                suffix.to_string(),
+                Err(format!("invalid request: {e}")),
+            ),
+        };
+
+        match &res {
+            Ok(_) => info!("Command \"{command}\" done"),
+            Err(e) => warn!("Command \"{command}\" failed: {e}"),
+        }
+
+        let (result, error) = match res {
+            Ok(result) => (Some(result), None),
+            Err(e) => (None, Some(e)),
+        };
+
+        serde_json::to_string(&Ack {
+            id: &id,
+            command: &command,
+            status: if error.is_none() { "ok" } else { "error" },
+            result,
+            error,
+        })
+        .unwrap()
+    }
+}
+
+/// `{"command": "beep", "ms": 200}`
+pub fn beep(ctx: &mut CommandContext<'_>, args: &Map<String, Value>) -> Result<Value, String> {
+    let ms = args.get("ms").and_then(Value::as_u64).unwrap_or(200);
+    let ms = ctx.buzzer.beep(ms).map_err(|e| e.to_string())?;
+
+    Ok(json!({ "ms": ms }))
+}
+
+/// `{"command": "set_interval", "secs": 10}`
+pub fn set_interval(
+    ctx: &mut CommandContext<'_>,
+    args: &Map<String, Value>,
+) -> Result<Value, String> {
+    let secs = args
+        .get("secs")
+        .and_then(Value::as_u64)
+        .ok_or("missing \"secs\"")?;
+    let secs = u32::try_from(secs).map_err(|_| "\"secs\" out of range")?;
+
+    ctx.settings.set_publish_interval_secs(secs);
+    // Keep the shadow's reported state in line with what we run with
+    ctx.shadow.request_report();
+
+    Ok(json!({ "secs": ctx.settings.publish_interval_secs() }))
+}
+
+/// `{"command": "reboot"}`
+pub fn reboot(ctx: &mut CommandContext<'_>, _args: &Map<String, Value>) -> Result<Value, String> {
+    ctx.reboot = true;
+
+    Ok(Value::Null)
+}
+
+/// `{"command": "factory_reset"}`; the device comes back with the defaults and without its
+/// certificate, so it provisions itself again
+pub fn factory_reset(
+    ctx: &mut CommandContext<'_>,
+    _args: &Map<String, Value>,
+) -> Result<Value, String> {
+    ctx.factory_reset = true;
+
+    Ok(Value::Null)
+}
+
+/// `{"command": "log_level", "target": "wifi", "level": "warn"}`; without a target every
+/// log target is changed. Targets are ESP-IDF tags or Rust module paths, e.g. `iot_tokuron::mqtt`.
+pub fn log_level(
+    _ctx: &mut CommandContext<'_>,
+    args: &Map<String, Value>,
+) -> Result<Value, String> {
+    let target = args.get("target").and_then(Value::as_str).unwrap_or("*");
+    let level: LevelFilter = args
+        .get("level")
+        .and_then(Value::as_str)
+        .ok_or("missing \"level\"")?
+        .parse()
+        .map_err(|_| "\"level\" must be one of off, error, warn, info, debug, trace")?;
+
+    esp_idf_svc::log::set_target_level(target, level).map_err(|e| e.to_string())?;
+
+    Ok(json!({ "target": target, "level": level.as_str() }))
+}
+
+/// `{"command": "log_stream", "enabled": true, "level": "warn"}`: mirrors log records at or above
+/// the level to the logs topic; without `enabled` only the level changes
+pub fn log_stream(
+    _ctx: &mut CommandContext<'_>,
+    args: &Map<String, Value>,
+) -> Result<Value, String> {
+    let enabled = match args.get("enabled") {
+        Some(enabled) => enabled.as_bool().ok_or("\"enabled\" must be a boolean")?,
+        None => log_sink::streaming(),
+    };
+    let level: Option<LevelFilter> = args
+        .get("level")
+        .map(|level| {
+            level
+                .as_str()
+                .and_then(|level| level.parse().ok())
+                .ok_or("\"level\" must be one of off, error, warn, info, debug, trace")
+        })
+        .transpose()?;
+
+    log_sink::set_streaming(enabled, level);
+
+    Ok(json!({ "enabled": enabled, "level": log_sink::level().as_str() }))
+}
+
+/// `{"command": "i2c_scan"}`: lists the devices answering on each bus
+pub fn i2c_scan(ctx: &mut CommandContext<'_>, _args: &Map<String, Value>) -> Result<Value, String> {
+    Ok(json!({ "devices": i2c_bus::scan_all(ctx.buses) }))
+}
+
+/// `{"command": "calibrate"}`: measures and stores the sensor offsets, the device must lie still
+pub fn calibrate(
+    ctx: &mut CommandContext<'_>,
+    _args: &Map<String, Value>,
+) -> Result<Value, String> {
+    let offsets = calibration::calibrate(ctx.mpu, ctx.settings)?;
+
+    Ok(json!({ "gyro_bias": offsets.gyro, "acc_bias": offsets.acc }))
+}
+
+/// `{"command": "calibrate_temp", "secs": 120}`: learns how the gyro bias changes with the chip
+/// temperature, the device must lie still while warming up; `"clear": true` drops the model. The
+/// ack only confirms the start, the model follows on the `temp-calibration` status topic.
+pub fn calibrate_temp(
+    ctx: &mut CommandContext<'_>,
+    args: &Map<String, Value>,
+) -> Result<Value, String> {
+    if args.get("clear").and_then(Value::as_bool).unwrap_or(false) {
+        ctx.settings.set_temp_model(None);
+        return Ok(json!({ "cleared": true }));
+    }
+
+    let secs = args.get("secs").and_then(Value::as_u64).unwrap_or(60);
+    if secs > MAX_TEMP_CALIBRATION_SECS {
+        return Err(format!(
+            "\"secs\" must be at most {MAX_TEMP_CALIBRATION_SECS}"
+        ));
+    }
+
+    ctx.temp_calibration = Some(Duration::from_secs(secs));
+
+    Ok(json!({ "started": true, "secs": secs }))
+}
+
+/// `{"command": "set_range", "accel_g": 8, "gyro_dps": 1000}`; either may be left out
+pub fn set_range(ctx: &mut CommandContext<'_>, args: &Map<String, Value>) -> Result<Value, String> {
+    let accel_g = match args.get("accel_g") {
+        Some(value) => value
+            .as_u64()
+            .and_then(|g| u8::try_from(g).ok())
+            .filter(|g| imu::accel_range(*g).is_some())
+            .ok_or("\"accel_g\" must be one of 2, 4, 8, 16")?,
+        None => ctx.settings.accel_range_g(),
+    };
+    let gyro_dps = match args.get("gyro_dps") {
+        Some(value) => value
+            .as_u64()
+            .and_then(|dps| u16::try_from(dps).ok())
+            .filter(|dps| imu::gyro_range(*dps).is_some())
+            .ok_or("\"gyro_dps\" must be one of 250, 500, 1000, 2000")?,
+        None => ctx.settings.gyro_range_dps(),
+    };
+
+    ctx.settings.set_ranges(accel_g, gyro_dps);
+    imu::configure(ctx.mpu, ctx.settings)?;
+
+    Ok(json!({ "accel_g": accel_g, "gyro_dps": gyro_dps }))
+}
+
+/// `{"command": "capture", "rate_hz": 500, "ms": 1000}`: burst sampling through the sensor FIFO,
+/// published on the `capture` telemetry topic
+pub fn capture(ctx: &mut CommandContext<'_>, args: &Map<String, Value>) -> Result<Value, String> {
+    let rate_hz = args.get("rate_hz").and_then(Value::as_u64).unwrap_or(500);
+    let rate_hz = u32::try_from(rate_hz).map_err(|_| "\"rate_hz\" out of range")?;
+    let ms = args.get("ms").and_then(Value::as_u64).unwrap_or(1000);
+    // Longer would only be cut off at `MAX_SAMPLES` anyway
+    let max_ms = fifo::MAX_SAMPLES as u64 * 1000 / rate_hz.max(1) as u64;
+    if ms > max_ms {
+        return Err(format!("\"ms\" must be at most {max_ms} at {rate_hz}Hz"));
+    }
+
+    let capture = fifo::capture(ctx.mpu, ctx.settings, rate_hz, Duration::from_millis(ms))?;
+    let result = json!({
+        "rate_hz": capture.rate_hz,
+        "samples": capture.acc.len(),
+        "overflows": capture.overflows,
+    });
+    ctx.capture = Some(capture);
+
+    Ok(result)
+}
@@ -0,0 +1,89 @@
+/// log2 of the window and lookahead sizes, which the decoder has to be set up with as well
+const WINDOW_BITS: u32 = 8;
+const LOOKAHEAD_BITS: u32 = 4;
+
+const WINDOW: usize = 1 << WINDOW_BITS;
+const LOOKAHEAD: usize = 1 << LOOKAHEAD_BITS;
+
+/// A back-reference costs its tag, index and count bits, a literal 9 bits
+const MIN_MATCH: usize = (1 + WINDOW_BITS + LOOKAHEAD_BITS) as usize / 9 + 1;
+
+/// Appended to the topic of compressed messages, naming the format and its parameters
+pub fn topic_suffix() -> String {
+    format!("/heatshrink/w{WINDOW_BITS}l{LOOKAHEAD_BITS}")
+}
+
+/// Compresses `data` into the heatshrink format, LZSS with a small window that needs no memory
+/// besides its output, unlike deflate next to Wi-Fi and TLS. Repetitive JSON such as a batch of samples with
+/// the same keys shrinks to about half.
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    let mut bits = BitWriter::default();
+
+    let mut pos = 0;
+    while pos < data.len() {
+        let (distance, length) = longest_match(data, pos);
+        if length >= MIN_MATCH {
+            bits.push(0, 1);
+            bits.push(distance as u32 - 1, WINDOW_BITS);
+            bits.push(length as u32 - 1, LOOKAHEAD_BITS);
+            pos += length;
+        } else {
+            bits.push(1, 1);
+            bits.push(data[pos] as u32, 8);
+            pos += 1;
+        }
+    }
+
+    bits.finish()
+}
+
+/// Distance back and length of the longest earlier match for the bytes at `pos`; matches may
+/// run into the bytes they repeat
+fn longest_match(data: &[u8], pos: usize) -> (usize, usize) {
+    let max_length = LOOKAHEAD.min(data.len() - pos);
+    let mut best = (0, 0);
+
+    for distance in 1..=WINDOW.min(pos) {
+        let start = pos - distance;
+        let length = (0..max_length)
+            .take_while(|&i| data[start + i] == data[pos + i])
+            .count();
+        if length > best.1 {
+            best = (distance, length);
+            if length == max_length {
+                break;
+            }
+        }
+    }
+
+    best
+}
+
+/// Packs bits MSB first; the last byte is padded with zeros
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    current: u8,
+    used: u32,
+}
+
+impl BitWriter {
+    fn push(&mut self, value: u32, count: u32) {
+        for shift in (0..count).rev() {
+            self.current = (self.current << 1) | ((value >> shift) & 1) as u8;
+            self.used += 1;
+            if self.used == 8 {
+                self.bytes.push(self.current);
+                self.current = 0;
+                self.used = 0;
+            }
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.used > 0 {
+            self.bytes.push(self.current << (8 - self.used));
+        }
+        self.bytes
+    }
+}
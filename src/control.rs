@@ -0,0 +1,222 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use esp_idf_svc::hal::gpio::{AnyOutputPin, Gpio2, Output, PinDriver};
+use esp_idf_svc::sys::EspError;
+use esp_idf_svc::timer::{EspTaskTimerService, EspTimer};
+
+use log::*;
+
+use crate::settings::Settings;
+use crate::shadow::Shadow;
+use crate::Config;
+
+/// Longest beep anyone may ask for
+const MAX_BEEP_MS: u64 = 5000;
+
+/// Buzzer on GPIO2, switched off again by a timer so a beep never blocks the caller. Its state
+/// is reported through the shadow's `buzzer_on`.
+pub struct Buzzer {
+    pin: Arc<Mutex<PinDriver<'static, Gpio2, Output>>>,
+    off_timer: EspTimer<'static>,
+    settings: Arc<Settings>,
+    shadow: Arc<Shadow>,
+}
+
+impl Buzzer {
+    pub fn new(
+        pin: PinDriver<'static, Gpio2, Output>,
+        timer_service: &EspTaskTimerService,
+        settings: Arc<Settings>,
+        shadow: Arc<Shadow>,
+    ) -> Result<Self, EspError> {
+        let pin = Arc::new(Mutex::new(pin));
+
+        let off_pin = pin.clone();
+        let off_settings = settings.clone();
+        let off_shadow = shadow.clone();
+        let off_timer = timer_service.timer(move || {
+            if let Err(e) = off_pin.lock().unwrap().set_low() {
+                warn!("Failed to switch the buzzer off: {e}");
+                return;
+            }
+            if off_settings.set_buzzer_on(false) {
+                off_shadow.request_report();
+            }
+        })?;
+
+        Ok(Self {
+            pin,
+            off_timer,
+            settings,
+            shadow,
+        })
+    }
+
+    /// Returns the duration actually used, which is capped at 5s
+    pub fn beep(&self, ms: u64) -> Result<u64, EspError> {
+        let ms = ms.min(MAX_BEEP_MS);
+
+        self.pin.lock().unwrap().set_high()?;
+        if self.settings.set_buzzer_on(true) {
+            self.shadow.request_report();
+        }
+        self.off_timer.after(Duration::from_millis(ms))?;
+
+        Ok(ms)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum LedColor {
+    Off,
+    Red,
+    Green,
+    Blue,
+    Yellow,
+    Cyan,
+    Magenta,
+    White,
+}
+
+impl LedColor {
+    fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "off" => Self::Off,
+            "red" => Self::Red,
+            "green" => Self::Green,
+            "blue" => Self::Blue,
+            "yellow" => Self::Yellow,
+            "cyan" => Self::Cyan,
+            "magenta" => Self::Magenta,
+            "white" => Self::White,
+            _ => return None,
+        })
+    }
+
+    /// Which of the red, green and blue channels are lit
+    fn channels(self) -> [bool; 3] {
+        match self {
+            Self::Off => [false, false, false],
+            Self::Red => [true, false, false],
+            Self::Green => [false, true, false],
+            Self::Blue => [false, false, true],
+            Self::Yellow => [true, true, false],
+            Self::Cyan => [false, true, true],
+            Self::Magenta => [true, false, true],
+            Self::White => [true, true, true],
+        }
+    }
+}
+
+/// Status LED with one active-high GPIO per color channel; unconfigured channels are skipped
+pub struct StatusLed {
+    channels: [Option<PinDriver<'static, AnyOutputPin, Output>>; 3],
+}
+
+impl StatusLed {
+    pub fn from_config(app_config: &Config) -> Result<Self, EspError> {
+        let channel = |gpio: i32| -> Result<_, EspError> {
+            if gpio < 0 {
+                return Ok(None);
+            }
+
+            // Safety: the pin number comes from the board configuration and is not used elsewhere
+            let mut pin = PinDriver::output(unsafe { AnyOutputPin::new(gpio) })?;
+            pin.set_low()?;
+
+            Ok(Some(pin))
+        };
+
+        Ok(Self {
+            channels: [
+                channel(app_config.led_red_gpio)?,
+                channel(app_config.led_green_gpio)?,
+                channel(app_config.led_blue_gpio)?,
+            ],
+        })
+    }
+
+    pub fn set(&mut self, color: LedColor) -> Result<(), EspError> {
+        for (pin, on) in self.channels.iter_mut().zip(color.channels()) {
+            if let Some(pin) = pin {
+                if on {
+                    pin.set_high()?;
+                } else {
+                    pin.set_low()?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// `{"buzzer": {"ms": 500}}`, `{"led": "red"}` or both
+#[derive(Deserialize)]
+struct ControlMessage {
+    buzzer: Option<BuzzerControl>,
+    led: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct BuzzerControl {
+    ms: u64,
+}
+
+/// Drives the buzzer and status LED from messages on the control topic
+pub struct Control {
+    topic: String,
+}
+
+impl Control {
+    pub fn new(topic: String) -> Self {
+        Self { topic }
+    }
+
+    pub fn topic(&self) -> &str {
+        &self.topic
+    }
+
+    /// Called from the connection loop; returns `true` if the message was for the control topic
+    pub fn handle_message(
+        &self,
+        topic: &str,
+        data: &[u8],
+        buzzer: &Buzzer,
+        led: &Mutex<StatusLed>,
+    ) -> bool {
+        if topic != self.topic {
+            return false;
+        }
+
+        let message: ControlMessage = match serde_json::from_slice(data) {
+            Ok(message) => message,
+            Err(e) => {
+                warn!("Invalid control message: {e}");
+                return true;
+            }
+        };
+
+        if let Some(control) = message.buzzer {
+            match buzzer.beep(control.ms) {
+                Ok(ms) => info!("Buzzer on for {ms}ms"),
+                Err(e) => warn!("Failed to drive the buzzer: {e}"),
+            }
+        }
+
+        if let Some(name) = message.led {
+            match LedColor::from_name(&name) {
+                Some(color) => match led.lock().unwrap().set(color) {
+                    Ok(()) => info!("Status LED set to {color:?}"),
+                    Err(e) => warn!("Failed to drive the status LED: {e}"),
+                },
+                None => warn!("Unknown LED color \"{name}\""),
+            }
+        }
+
+        true
+    }
+}
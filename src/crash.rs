@@ -0,0 +1,129 @@
+use std::panic;
+use std::sync::{Mutex, OnceLock};
+
+use esp_idf_svc::nvs::{EspDefaultNvs, EspDefaultNvsPartition};
+use esp_idf_svc::sys::{
+    esp_backtrace_frame_t, esp_backtrace_get_next_frame, esp_backtrace_get_start, EspError,
+};
+use serde::Serialize;
+
+use log::*;
+
+use crate::build_info::{BuildInfo, BUILD_INFO};
+use crate::clock;
+
+const NVS_NAMESPACE: &str = "crash";
+const KEY: &str = "panic";
+/// Keeps the record within what NVS stores comfortably
+const MAX_MESSAGE_LEN: usize = 256;
+const MAX_FRAMES: usize = 16;
+const MAX_RECORD_LEN: usize = 1024;
+
+/// Written by the panic hook, which can't be handed anything
+static NVS: OnceLock<Mutex<EspDefaultNvs>> = OnceLock::new();
+
+/// `{"message": "index out of bounds: ..", "location": "src/imu.rs:42:9", "backtrace":
+/// ["0x42012345", ..], "uptime_ms": 81234, "firmware_version": .., "git_hash": .., ..}`.
+/// The addresses resolve with `xtensa-esp32s3-elf-addr2line -e <elf>` of the same build.
+#[derive(Serialize)]
+struct Crash {
+    message: String,
+    location: Option<String>,
+    backtrace: Vec<String>,
+    uptime_ms: u64,
+    #[serde(flatten)]
+    build: BuildInfo,
+}
+
+/// Chains a panic hook in front of the default one which keeps the panic in NVS, so it can be
+/// published after the restart instead of only going to the serial console
+pub fn install(partition: EspDefaultNvsPartition) -> Result<(), EspError> {
+    let nvs = EspDefaultNvs::new(partition, NVS_NAMESPACE, true)?;
+    if NVS.set(Mutex::new(nvs)).is_err() {
+        return Ok(());
+    }
+
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        let payload = info.payload();
+        let message = payload
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("unknown panic");
+
+        let crash = Crash {
+            message: message.chars().take(MAX_MESSAGE_LEN).collect(),
+            location: info.location().map(ToString::to_string),
+            backtrace: backtrace(),
+            uptime_ms: clock::uptime_us() / 1000,
+            build: BUILD_INFO,
+        };
+        save(&crash);
+
+        default_hook(info);
+    }));
+
+    Ok(())
+}
+
+/// The record of the last panic, until [`clear`] is called
+pub fn saved() -> Option<String> {
+    let nvs = NVS.get()?.lock().unwrap();
+    let mut buf = [0; MAX_RECORD_LEN];
+    match nvs.get_blob(KEY, &mut buf) {
+        Ok(record) => record.map(|record| String::from_utf8_lossy(record).into_owned()),
+        Err(e) => {
+            warn!("Failed to read the crash record: {e}");
+            None
+        }
+    }
+}
+
+pub fn clear() {
+    if let Some(nvs) = NVS.get() {
+        if let Err(e) = nvs.lock().unwrap().remove(KEY) {
+            warn!("Failed to clear the crash record: {e}");
+        }
+    }
+}
+
+fn save(crash: &Crash) {
+    // The panic may have struck while the lock was held
+    let Some(Ok(nvs)) = NVS.get().map(Mutex::try_lock) else {
+        return;
+    };
+
+    if let Ok(record) = serde_json::to_vec(crash) {
+        if record.len() <= MAX_RECORD_LEN {
+            let _ = nvs.set_blob(KEY, &record);
+        }
+    }
+}
+
+/// Return addresses of the panicking task, innermost first
+fn backtrace() -> Vec<String> {
+    let mut frame = esp_backtrace_frame_t::default();
+    unsafe { esp_backtrace_get_start(&mut frame.pc, &mut frame.sp, &mut frame.next_pc) };
+
+    let mut frames = vec![format!("{:#010x}", process_pc(frame.pc))];
+    while frames.len() < MAX_FRAMES
+        && frame.next_pc != 0
+        && unsafe { esp_backtrace_get_next_frame(&mut frame) }
+    {
+        frames.push(format!("{:#010x}", process_pc(frame.pc)));
+    }
+
+    frames
+}
+
+/// Turns a windowed-ABI return address into the address of the call, as `esp_cpu_process_stack_pc`
+fn process_pc(pc: u32) -> u32 {
+    let pc = if pc & 0x8000_0000 != 0 {
+        (pc & 0x3fff_ffff) | 0x4000_0000
+    } else {
+        pc
+    };
+
+    pc.wrapping_sub(3)
+}
@@ -0,0 +1,77 @@
+use esp_idf_svc::nvs::{EspDefaultNvs, EspDefaultNvsPartition};
+use esp_idf_svc::sys::EspError;
+
+pub const NVS_NAMESPACE: &str = "creds";
+
+/// Certificate and key blob names of the two slots; `active` selects the one in use
+const SLOTS: [(&str, &str); 2] = [("cert", "key"), ("cert1", "key1")];
+
+/// Device certificate, private key (both PEM) and the thing they belong to
+pub struct Credentials {
+    pub certificate: Vec<u8>,
+    pub private_key: Vec<u8>,
+    /// Overrides the configured client ID and thing name when present
+    pub thing_name: Option<String>,
+}
+
+/// Per-device credentials kept in NVS, so one firmware image serves the whole fleet.
+/// They come from fleet provisioning or are flashed as an NVS partition image with
+/// the blobs `cert`, `key` and optionally `thing` in the `creds` namespace.
+pub struct CredentialStore {
+    nvs: EspDefaultNvs,
+    active: u8,
+}
+
+impl CredentialStore {
+    pub fn new(partition: EspDefaultNvsPartition) -> Result<Self, EspError> {
+        let nvs = EspDefaultNvs::new(partition, NVS_NAMESPACE, true)?;
+        let active = nvs.get_u8("active")?.unwrap_or(0) % 2;
+
+        Ok(Self { nvs, active })
+    }
+
+    pub fn load(&self) -> Result<Option<Credentials>, EspError> {
+        let (cert_key, key_key) = SLOTS[self.active as usize];
+        let (Some(certificate), Some(private_key)) =
+            (self.read_blob(cert_key)?, self.read_blob(key_key)?)
+        else {
+            return Ok(None);
+        };
+
+        Ok(Some(Credentials {
+            certificate,
+            private_key,
+            thing_name: self
+                .read_blob("thing")?
+                .map(|thing_name| String::from_utf8_lossy(&thing_name).into_owned()),
+        }))
+    }
+
+    /// Writes the pair into the inactive slot and only then switches over to it,
+    /// so a reset half way leaves the previous credentials in place.
+    /// The stored thing name is kept when `credentials` has none.
+    pub fn save(&mut self, credentials: &Credentials) -> Result<(), EspError> {
+        let inactive = 1 - self.active;
+        let (cert_key, key_key) = SLOTS[inactive as usize];
+
+        self.nvs.set_blob(cert_key, &credentials.certificate)?;
+        self.nvs.set_blob(key_key, &credentials.private_key)?;
+        if let Some(thing_name) = &credentials.thing_name {
+            self.nvs.set_blob("thing", thing_name.as_bytes())?;
+        }
+
+        self.nvs.set_u8("active", inactive)?;
+        self.active = inactive;
+
+        Ok(())
+    }
+
+    fn read_blob(&self, key: &str) -> Result<Option<Vec<u8>>, EspError> {
+        let Some(len) = self.nvs.blob_len(key)? else {
+            return Ok(None);
+        };
+
+        let mut buf = vec![0; len];
+        Ok(self.nvs.get_blob(key, &mut buf)?.map(|blob| blob.to_vec()))
+    }
+}
@@ -0,0 +1,137 @@
+use serde_json::json;
+
+use log::*;
+
+use crate::telemetry::Signal;
+use crate::Config;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    /// The last reading of every group
+    Keep,
+    /// The mean of every group
+    Average,
+}
+
+/// Reduces a high-rate stream by `factor` before it's published, so sampling and FIFO captures
+/// can run much faster than what goes to the cloud
+#[derive(Clone, Copy, Debug)]
+pub struct Decimation {
+    factor: usize,
+    mode: Mode,
+}
+
+impl Decimation {
+    /// `None` unless `decimate_factor` is above 1
+    pub fn from_config(app_config: &Config) -> Option<Self> {
+        if app_config.decimate_factor <= 1 {
+            return None;
+        }
+
+        let mode = match app_config.decimate_mode {
+            "keep" => Mode::Keep,
+            "average" => Mode::Average,
+            other => {
+                warn!("Unknown decimation mode \"{other}\", keeping 1 of every group");
+                Mode::Keep
+            }
+        };
+
+        Some(Self {
+            factor: app_config.decimate_factor as usize,
+            mode,
+        })
+    }
+
+    pub fn factor(&self) -> usize {
+        self.factor
+    }
+
+    /// A whole series, e.g. a FIFO capture; a trailing incomplete group is dropped
+    pub fn series(&self, samples: &[[f32; 3]]) -> Vec<[f32; 3]> {
+        samples
+            .chunks_exact(self.factor)
+            .map(|group| match self.mode {
+                Mode::Keep => group[group.len() - 1],
+                Mode::Average => {
+                    let n = group.len() as f32;
+                    [0, 1, 2].map(|axis| group.iter().map(|v| v[axis]).sum::<f32>() / n)
+                }
+            })
+            .collect()
+    }
+}
+
+/// Decimates readings one at a time, between the sampler and the aggregator
+pub struct Decimator {
+    decimation: Decimation,
+    group: Vec<Vec<Signal>>,
+}
+
+impl Decimator {
+    pub fn new(decimation: Decimation) -> Self {
+        Self {
+            decimation,
+            group: Vec::with_capacity(decimation.factor),
+        }
+    }
+
+    /// Returns one reading for every `factor` pushed. Signals that don't come with every
+    /// reading, like those of the slower sensors, are kept if they occur anywhere in the group.
+    pub fn push(&mut self, signals: Vec<Signal>) -> Option<Vec<Signal>> {
+        self.group.push(signals);
+        if self.group.len() < self.decimation.factor {
+            return None;
+        }
+
+        let signals = self.group.drain(..).flatten();
+
+        Some(match self.decimation.mode {
+            Mode::Keep => keep_last(signals),
+            Mode::Average => average(signals),
+        })
+    }
+}
+
+fn keep_last(signals: impl Iterator<Item = Signal>) -> Vec<Signal> {
+    let mut kept: Vec<Signal> = Vec::new();
+
+    for signal in signals {
+        match kept.iter_mut().find(|kept| kept.field == signal.field) {
+            Some(kept) => *kept = signal,
+            None => kept.push(signal),
+        }
+    }
+
+    kept
+}
+
+fn average(signals: impl Iterator<Item = Signal>) -> Vec<Signal> {
+    let mut sums: Vec<(Signal, u32)> = Vec::new();
+
+    for signal in signals {
+        match sums.iter_mut().find(|(sum, _)| sum.field == signal.field) {
+            Some((sum, count)) => {
+                for (sum, value) in sum.axes.iter_mut().zip(&signal.axes) {
+                    *sum += value;
+                }
+                sum.range = signal.range;
+                *count += 1;
+            }
+            None => sums.push((signal, 1)),
+        }
+    }
+
+    sums.into_iter()
+        .map(|(mut signal, count)| {
+            for value in &mut signal.axes {
+                *value /= count as f32;
+            }
+            signal.value = match signal.axes.as_slice() {
+                [value] => json!(value),
+                axes => json!(axes),
+            };
+            signal
+        })
+        .collect()
+}
@@ -0,0 +1,120 @@
+use std::mem;
+use std::sync::Mutex;
+
+use esp_idf_svc::nvs::EspDefaultNvsPartition;
+use esp_idf_svc::sys::EspError;
+use serde::Serialize;
+
+use crate::net_stats::NetStats;
+use crate::sequence::Sequence;
+
+/// Port of the diagnostics web server while its access point is up
+const DIAGNOSTICS_HTTP_PORT: u16 = 80;
+
+/// Device Defender metrics document, with the metrics this firmware can tell
+#[derive(Serialize)]
+struct Report {
+    header: Header,
+    metrics: Metrics,
+}
+
+#[derive(Serialize)]
+struct Header {
+    report_id: u32,
+    version: &'static str,
+}
+
+#[derive(Serialize)]
+struct Metrics {
+    listening_tcp_ports: Ports,
+    tcp_connections: TcpConnections,
+    network_stats: NetworkStats,
+}
+
+#[derive(Serialize)]
+struct Ports {
+    ports: Vec<Port>,
+    total: u32,
+}
+
+#[derive(Serialize)]
+struct Port {
+    port: u16,
+}
+
+#[derive(Serialize)]
+struct TcpConnections {
+    established_connections: Count,
+}
+
+#[derive(Serialize)]
+struct Count {
+    total: u32,
+}
+
+#[derive(Serialize)]
+struct NetworkStats {
+    bytes_in: u32,
+    bytes_out: u32,
+}
+
+/// Builds AWS IoT Device Defender metrics reports for the reserved defender topic
+pub struct Defender {
+    topic: String,
+    report_ids: Sequence,
+    /// Bytes received and sent as of the previous report, as Defender expects per-period counts
+    reported: Mutex<(u32, u32)>,
+}
+
+impl Defender {
+    pub fn new(thing_name: &str, partition: EspDefaultNvsPartition) -> Result<Self, EspError> {
+        Ok(Self {
+            topic: format!("$aws/things/{thing_name}/defender/metrics/json"),
+            // Defender wants increasing report ids and there is no wall clock to take them from
+            report_ids: Sequence::new(partition, "defender")?,
+            reported: Mutex::new((0, 0)),
+        })
+    }
+
+    pub fn topic(&self) -> &str {
+        &self.topic
+    }
+
+    /// Metrics document covering the time since the previous report. The MQTT session is the
+    /// only TCP connection the firmware keeps, and bytes are counted at the MQTT payload level.
+    pub fn report(&self, stats: &NetStats, diagnostics_ap: bool) -> String {
+        let (bytes_in, bytes_out) = (stats.bytes_received(), stats.bytes_sent());
+        let (last_in, last_out) = {
+            let mut reported = self.reported.lock().unwrap();
+            mem::replace(&mut *reported, (bytes_in, bytes_out))
+        };
+
+        let ports: Vec<_> = diagnostics_ap
+            .then_some(Port {
+                port: DIAGNOSTICS_HTTP_PORT,
+            })
+            .into_iter()
+            .collect();
+
+        serde_json::to_string(&Report {
+            header: Header {
+                report_id: self.report_ids.next(),
+                version: "1.0",
+            },
+            metrics: Metrics {
+                listening_tcp_ports: Ports {
+                    total: ports.len() as u32,
+                    ports,
+                },
+                tcp_connections: TcpConnections {
+                    established_connections: Count { total: 1 },
+                },
+                network_stats: NetworkStats {
+                    bytes_in: bytes_in.wrapping_sub(last_in),
+                    bytes_out: bytes_out.wrapping_sub(last_out),
+                },
+            },
+        })
+        .unwrap()
+    }
+}
@@ -0,0 +1,94 @@
+use serde::Serialize;
+use serde_json::{Number, Value};
+
+/// How to get the samples back, sent along in every delta-encoded batch
+const SPEC: &str = "samples[0] is complete, every later sample holds the changes to the one \
+    before. Integers are plain differences, other numbers round((value - previous) * scale), so \
+    previous + number / scale restores them. New fields and anything but numbers are sent as is.";
+
+#[derive(Serialize)]
+struct Header {
+    scale: u32,
+    spec: &'static str,
+}
+
+/// `{"delta": {"scale": 1000, "spec": ".."}, "samples": [{..}, {..}, ..]}`
+#[derive(Serialize)]
+struct DeltaBatch {
+    delta: Header,
+    samples: Vec<Value>,
+}
+
+/// Encodes a batch of JSON samples as deltas to their predecessors, which for slowly changing
+/// readings are mostly small integers. `None` if a sample isn't valid JSON.
+pub fn encode(samples: &[Vec<u8>], scale: u32) -> Option<Vec<u8>> {
+    let mut values = samples
+        .iter()
+        .map(|sample| serde_json::from_slice::<Value>(sample).ok())
+        .collect::<Option<Vec<_>>>()?
+        .into_iter();
+
+    let Some(first) = values.next() else {
+        return Some(b"[]".to_vec());
+    };
+
+    // Deltas are taken against what the receiver reconstructs, so rounding doesn't add up
+    let mut previous = first.clone();
+    let mut encoded = vec![first];
+    for value in values {
+        encoded.push(delta(&value, &mut previous, scale as f64));
+    }
+
+    Some(
+        serde_json::to_vec(&DeltaBatch {
+            delta: Header { scale, spec: SPEC },
+            samples: encoded,
+        })
+        .unwrap(),
+    )
+}
+
+/// The delta of `value` to `previous`, which is advanced to the reconstructed value
+fn delta(value: &Value, previous: &mut Value, scale: f64) -> Value {
+    match (value, &mut *previous) {
+        (Value::Number(number), Value::Number(prev)) => {
+            if let (Some(current), Some(last)) = (number.as_i64(), prev.as_i64()) {
+                *prev = number.clone();
+                return Value::from(current - last);
+            }
+
+            let (Some(current), Some(last)) = (number.as_f64(), prev.as_f64()) else {
+                *previous = value.clone();
+                return value.clone();
+            };
+            let steps = ((current - last) * scale).round();
+            if let Some(reconstructed) = Number::from_f64(last + steps / scale) {
+                *prev = reconstructed;
+            }
+            Value::from(steps as i64)
+        }
+        (Value::Array(values), Value::Array(prev)) if values.len() == prev.len() => values
+            .iter()
+            .zip(prev.iter_mut())
+            .map(|(value, prev)| delta(value, prev, scale))
+            .collect(),
+        (Value::Object(map), Value::Object(prev)) => {
+            let mut deltas = serde_json::Map::new();
+            for (key, value) in map {
+                let delta = match prev.get_mut(key) {
+                    Some(prev) => delta(value, prev, scale),
+                    None => {
+                        prev.insert(key.clone(), value.clone());
+                        value.clone()
+                    }
+                };
+                deltas.insert(key.clone(), delta);
+            }
+            Value::Object(deltas)
+        }
+        _ => {
+            *previous = value.clone();
+            value.clone()
+        }
+    }
+}
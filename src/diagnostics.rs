@@ -0,0 +1,118 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use esp_idf_svc::http::server::{Configuration as HttpConfiguration, EspHttpServer};
+use esp_idf_svc::http::Method;
+use esp_idf_svc::io::{EspIOError, Write};
+use esp_idf_svc::sys::EspError;
+use esp_idf_svc::wifi::*;
+
+use log::*;
+
+use crate::wifi;
+
+/// What the device knows about its own health, shown on the diagnostics page
+pub struct DiagnosticsState {
+    pub mqtt_connected: bool,
+    pub offline_since: Option<Instant>,
+    pub last_error: Option<String>,
+    pub last_reading: Option<String>,
+    pub cert_expiry: Option<String>,
+}
+
+impl DiagnosticsState {
+    pub fn new(cert_expiry: Option<String>) -> Self {
+        Self {
+            mqtt_connected: false,
+            offline_since: Some(Instant::now()),
+            last_error: None,
+            last_reading: None,
+            cert_expiry,
+        }
+    }
+
+    pub fn set_connected(&mut self, connected: bool) {
+        self.mqtt_connected = connected;
+        self.offline_since = match (connected, self.offline_since) {
+            (true, _) => None,
+            (false, None) => Some(Instant::now()),
+            (false, since) => since,
+        };
+    }
+
+    pub fn offline_for(&self) -> Duration {
+        self.offline_since
+            .map(|since| since.elapsed())
+            .unwrap_or_default()
+    }
+
+    fn render(&self) -> String {
+        let rssi = wifi::sta_rssi()
+            .map(|rssi| format!("connected, RSSI {rssi} dBm"))
+            .unwrap_or_else(|| "disconnected".to_string());
+
+        format!(
+            "<!DOCTYPE html><html><head><meta http-equiv=\"refresh\" content=\"5\"><title>iot-tokuron diagnostics</title></head><body><h1>iot-tokuron diagnostics</h1><pre>\
+WiFi:             {rssi}\n\
+MQTT:             {}\n\
+Offline for:      {}s\n\
+Last error:       {}\n\
+Last reading:     {}\n\
+Certificate until: {}\n\
+</pre></body></html>",
+            if self.mqtt_connected { "connected" } else { "disconnected" },
+            self.offline_for().as_secs(),
+            html_escape(self.last_error.as_deref().unwrap_or("-")),
+            html_escape(self.last_reading.as_deref().unwrap_or("-")),
+            self.cert_expiry.as_deref().unwrap_or("unknown"),
+        )
+    }
+}
+
+/// Switches WiFi into AP+STA mode and serves a read-only status page on the SoftAP,
+/// so a device which cannot reach the cloud can be inspected with just a phone
+pub fn start_ap(
+    esp_wifi: &mut EspWifi<'static>,
+    ssid: &str,
+    password: &str,
+    state: Arc<Mutex<DiagnosticsState>>,
+) -> Result<EspHttpServer<'static>, EspError> {
+    let client_config = match esp_wifi.get_configuration()? {
+        Configuration::Client(client) | Configuration::Mixed(client, _) => client,
+        _ => ClientConfiguration::default(),
+    };
+
+    esp_wifi.set_configuration(&Configuration::Mixed(
+        client_config,
+        AccessPointConfiguration {
+            ssid: ssid.try_into().unwrap(),
+            password: password.try_into().unwrap(),
+            auth_method: if password.is_empty() {
+                AuthMethod::None
+            } else {
+                AuthMethod::WPA2Personal
+            },
+            ..Default::default()
+        },
+    ))?;
+    info!("Diagnostics AP \"{ssid}\" started");
+
+    let mut server = EspHttpServer::new(&HttpConfiguration::default()).map_err(|e| e.0)?;
+    server.fn_handler("/", Method::Get, move |req| -> Result<(), EspIOError> {
+        let page = state.lock().unwrap().render();
+
+        let mut resp = req.into_response(200, None, &[("Content-Type", "text/html")])?;
+        resp.write_all(page.as_bytes())?;
+
+        Ok(())
+    })?;
+    info!("Diagnostics page served on http://192.168.71.1/");
+
+    Ok(server)
+}
+
+fn html_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
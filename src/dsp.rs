@@ -0,0 +1,132 @@
+use core::f32::consts::PI;
+
+use serde::Serialize;
+
+use crate::clock::Stamp;
+use crate::fifo::Capture;
+
+/// FFT length; a capture is analysed over its first `FFT_LEN` samples
+pub const FFT_LEN: usize = 256;
+
+/// Vibration features of one capture window
+#[derive(Serialize)]
+pub struct Vibration {
+    /// When the capture started
+    #[serde(flatten)]
+    pub stamp: Stamp,
+    pub rate_hz: u32,
+    /// RMS acceleration per axis with gravity (the mean) removed, in g
+    pub rms: [f32; 3],
+    /// Upper edge of each band in Hz
+    pub band_edges_hz: Vec<f32>,
+    /// Energy of the acceleration magnitude per band, in g²
+    pub bands: Vec<f32>,
+}
+
+#[derive(Serialize)]
+struct VibrationReport<'a> {
+    seq: u32,
+    #[serde(flatten)]
+    vibration: &'a Vibration,
+}
+
+impl Vibration {
+    /// `{"seq": 42, "rate_hz": 1000, "rms": [x, y, z], "band_edges_hz": [..], "bands": [..]}`,
+    /// with the [`Stamp`] of the capture after `seq`
+    pub fn to_json(&self, seq: u32) -> String {
+        serde_json::to_string(&VibrationReport {
+            seq,
+            vibration: self,
+        })
+        .unwrap()
+    }
+}
+
+/// RMS per axis and the spectrum of the acceleration magnitude summed up into `bands` equally
+/// wide bands up to the Nyquist frequency; `None` if the capture is shorter than [`FFT_LEN`]
+pub fn analyse(capture: &Capture, bands: usize) -> Option<Vibration> {
+    if capture.acc.len() < FFT_LEN || bands == 0 {
+        return None;
+    }
+    let acc = &capture.acc[..FFT_LEN];
+
+    let mut rms = [0.0; 3];
+    for (axis, rms) in rms.iter_mut().enumerate() {
+        let mean = acc.iter().map(|a| a[axis]).sum::<f32>() / FFT_LEN as f32;
+        let power = acc.iter().map(|a| (a[axis] - mean).powi(2)).sum::<f32>() / FFT_LEN as f32;
+        *rms = power.sqrt();
+    }
+
+    let magnitude: Vec<f32> = acc
+        .iter()
+        .map(|[x, y, z]| (x * x + y * y + z * z).sqrt())
+        .collect();
+    let mean = magnitude.iter().sum::<f32>() / FFT_LEN as f32;
+
+    // Hann window against leakage from the window edges
+    let mut re: Vec<f32> = magnitude
+        .iter()
+        .enumerate()
+        .map(|(i, m)| (m - mean) * 0.5 * (1.0 - (2.0 * PI * i as f32 / FFT_LEN as f32).cos()))
+        .collect();
+    let mut im = vec![0.0; FFT_LEN];
+    fft(&mut re, &mut im);
+
+    // Bins 1..=N/2, DC is gone with the mean
+    let bins = FFT_LEN / 2;
+    let bin_hz = capture.rate_hz as f32 / FFT_LEN as f32;
+    let mut energies = vec![0.0; bands];
+    for bin in 1..=bins {
+        let band = ((bin - 1) * bands / bins).min(bands - 1);
+        energies[band] += (re[bin] * re[bin] + im[bin] * im[bin]) / (FFT_LEN * FFT_LEN) as f32;
+    }
+
+    Some(Vibration {
+        stamp: capture.stamp,
+        rate_hz: capture.rate_hz,
+        rms,
+        band_edges_hz: (1..=bands)
+            .map(|band| (band * bins / bands) as f32 * bin_hz)
+            .collect(),
+        bands: energies,
+    })
+}
+
+/// In-place iterative radix-2 FFT; the length must be a power of two
+fn fft(re: &mut [f32], im: &mut [f32]) {
+    let n = re.len();
+
+    // Bit-reversed order
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+
+    let mut len = 2;
+    while len <= n {
+        let angle = -2.0 * PI / len as f32;
+        for start in (0..n).step_by(len) {
+            for k in 0..len / 2 {
+                let (w_im, w_re) = (angle * k as f32).sin_cos();
+                let (a, b) = (start + k, start + k + len / 2);
+
+                let t_re = re[b] * w_re - im[b] * w_im;
+                let t_im = re[b] * w_im + im[b] * w_re;
+                re[b] = re[a] - t_re;
+                im[b] = im[a] - t_im;
+                re[a] += t_re;
+                im[a] += t_im;
+            }
+        }
+        len <<= 1;
+    }
+}
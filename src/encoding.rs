@@ -0,0 +1,250 @@
+use log::*;
+
+use crate::influx;
+#[cfg(feature = "protobuf")]
+use crate::proto;
+use crate::senml;
+use crate::sitewise;
+use crate::telemetry::Reading;
+use crate::Config;
+
+/// Start of a CBOR array whose length isn't known up front, closed by [`CBOR_BREAK`]
+const CBOR_ARRAY_START: u8 = 0x9f;
+const CBOR_BREAK: u8 = 0xff;
+
+/// Turns readings into telemetry payloads of one format, so the code publishing them doesn't
+/// need to know which
+pub trait TelemetryEncoder: Sync {
+    fn encode(&self, reading: &Reading) -> Vec<u8>;
+
+    /// Samples encoded one by one as a single message
+    fn batch(&self, samples: &[Vec<u8>]) -> Vec<u8>;
+
+    /// MIME type of the payloads, reported in the status message
+    fn content_type(&self) -> &'static str;
+
+    /// Appended to telemetry topics, so consumers subscribe to the schema versions they know
+    fn topic_suffix(&self) -> String {
+        String::new()
+    }
+}
+
+/// `{"seq": 42, "ts": .., "gyro": [..], ..}`, inside the envelope if there is one
+pub struct Json;
+
+impl TelemetryEncoder for Json {
+    fn encode(&self, reading: &Reading) -> Vec<u8> {
+        serde_json::to_vec(reading).unwrap()
+    }
+
+    fn batch(&self, samples: &[Vec<u8>]) -> Vec<u8> {
+        let mut bytes = vec![b'['];
+        for (i, sample) in samples.iter().enumerate() {
+            if i > 0 {
+                bytes.extend_from_slice(b", ");
+            }
+            bytes.extend_from_slice(sample);
+        }
+        bytes.push(b']');
+        bytes
+    }
+
+    fn content_type(&self) -> &'static str {
+        "application/json"
+    }
+}
+
+/// JSON records each ending in a newline (NDJSON), so Kinesis Firehose can concatenate them
+/// into S3 objects line by line
+pub struct JsonLines;
+
+impl TelemetryEncoder for JsonLines {
+    fn encode(&self, reading: &Reading) -> Vec<u8> {
+        let mut bytes = serde_json::to_vec(reading).unwrap();
+        bytes.push(b'\n');
+        bytes
+    }
+
+    fn batch(&self, samples: &[Vec<u8>]) -> Vec<u8> {
+        samples.concat()
+    }
+
+    fn content_type(&self) -> &'static str {
+        "application/x-ndjson"
+    }
+}
+
+/// The JSON fields in roughly half the bytes
+pub struct Cbor;
+
+impl TelemetryEncoder for Cbor {
+    fn encode(&self, reading: &Reading) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        // Writing into a Vec can't fail
+        ciborium::into_writer(reading, &mut bytes).unwrap();
+        bytes
+    }
+
+    fn batch(&self, samples: &[Vec<u8>]) -> Vec<u8> {
+        let mut bytes = vec![CBOR_ARRAY_START];
+        bytes.extend(samples.iter().flatten());
+        bytes.push(CBOR_BREAK);
+        bytes
+    }
+
+    fn content_type(&self) -> &'static str {
+        "application/cbor"
+    }
+}
+
+/// RFC 8428 records in JSON, without the envelope
+pub struct Senml;
+
+impl TelemetryEncoder for Senml {
+    fn encode(&self, reading: &Reading) -> Vec<u8> {
+        senml::pack(
+            reading.topics.client_id(),
+            reading.seq,
+            reading.stamp,
+            reading.signals,
+        )
+    }
+
+    fn batch(&self, samples: &[Vec<u8>]) -> Vec<u8> {
+        senml::merge(samples)
+    }
+
+    fn content_type(&self) -> &'static str {
+        "application/senml+json"
+    }
+}
+
+/// InfluxDB line protocol, for Telegraf to ingest as is
+pub struct LineProtocol;
+
+impl TelemetryEncoder for LineProtocol {
+    fn encode(&self, reading: &Reading) -> Vec<u8> {
+        influx::line(
+            reading.kind,
+            reading.topics.client_id(),
+            reading.seq,
+            reading.stamp,
+            reading.signals,
+        )
+    }
+
+    fn batch(&self, samples: &[Vec<u8>]) -> Vec<u8> {
+        influx::join(samples)
+    }
+
+    fn content_type(&self) -> &'static str {
+        "text/plain"
+    }
+}
+
+/// AWS IoT SiteWise `BatchPutAssetPropertyValue` entries
+pub struct Sitewise;
+
+impl TelemetryEncoder for Sitewise {
+    fn encode(&self, reading: &Reading) -> Vec<u8> {
+        sitewise::entries(
+            &reading.topics.sitewise_alias(reading.kind),
+            reading.stamp,
+            reading.signals,
+        )
+    }
+
+    fn batch(&self, samples: &[Vec<u8>]) -> Vec<u8> {
+        sitewise::merge(samples)
+    }
+
+    fn content_type(&self) -> &'static str {
+        "application/json"
+    }
+}
+
+/// `Telemetry` messages of proto/telemetry.proto
+#[cfg(feature = "protobuf")]
+pub struct Protobuf;
+
+#[cfg(feature = "protobuf")]
+impl TelemetryEncoder for Protobuf {
+    fn encode(&self, reading: &Reading) -> Vec<u8> {
+        proto::telemetry(
+            reading.seq,
+            reading.stamp,
+            reading.signals,
+            reading.envelope,
+        )
+    }
+
+    fn batch(&self, samples: &[Vec<u8>]) -> Vec<u8> {
+        proto::batch(samples)
+    }
+
+    fn content_type(&self) -> &'static str {
+        "application/x-protobuf"
+    }
+
+    fn topic_suffix(&self) -> String {
+        format!("/pb/v{}", proto::SCHEMA_VERSION)
+    }
+}
+
+/// The telemetry format picked in the config
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Encoding {
+    Json,
+    JsonLines,
+    Cbor,
+    Senml,
+    Influx,
+    Sitewise,
+    #[cfg(feature = "protobuf")]
+    Protobuf,
+}
+
+impl Encoding {
+    pub fn from_config(app_config: &Config) -> Self {
+        match app_config.payload_encoding {
+            "json" => Self::Json,
+            "jsonl" => Self::JsonLines,
+            "cbor" => Self::Cbor,
+            "senml" => Self::Senml,
+            "influx" => Self::Influx,
+            "sitewise" => Self::Sitewise,
+            #[cfg(feature = "protobuf")]
+            "protobuf" => Self::Protobuf,
+            #[cfg(not(feature = "protobuf"))]
+            "protobuf" => {
+                warn!("Built without the protobuf feature, using JSON");
+                Self::Json
+            }
+            other => {
+                warn!("Unknown payload encoding \"{other}\", using JSON");
+                Self::Json
+            }
+        }
+    }
+
+    pub fn encoder(self) -> &'static dyn TelemetryEncoder {
+        match self {
+            Self::Json => &Json,
+            Self::JsonLines => &JsonLines,
+            Self::Cbor => &Cbor,
+            Self::Senml => &Senml,
+            Self::Influx => &LineProtocol,
+            Self::Sitewise => &Sitewise,
+            #[cfg(feature = "protobuf")]
+            Self::Protobuf => &Protobuf,
+        }
+    }
+}
+
+/// A payload for the log: JSON as is, anything binary by its size
+pub fn display(payload: &[u8]) -> String {
+    match core::str::from_utf8(payload) {
+        Ok(text) => text.to_string(),
+        Err(_) => format!("<{} bytes>", payload.len()),
+    }
+}
@@ -0,0 +1,104 @@
+use esp_idf_svc::sys::{
+    esp_chip_info, esp_chip_info_t, esp_chip_model_t_CHIP_ESP32, esp_chip_model_t_CHIP_ESP32C3,
+    esp_chip_model_t_CHIP_ESP32S2, esp_chip_model_t_CHIP_ESP32S3, esp_efuse_mac_get_default,
+    esp_timer_get_time,
+};
+use serde::Serialize;
+use serde_json::Value;
+
+/// Version of the envelope layout, raised whenever its fields change
+pub const SCHEMA_VERSION: u32 = 2;
+
+/// What identifies the hardware a message came from
+#[derive(Clone, Debug, Serialize)]
+pub struct DeviceInfo {
+    /// Factory base MAC, e.g. `"24:0a:c4:12:34:56"`
+    pub mac: String,
+    pub chip: &'static str,
+    /// `major * 100 + minor`
+    pub revision: u16,
+}
+
+impl DeviceInfo {
+    pub fn read() -> Self {
+        let mut mac = [0u8; 6];
+        // The base MAC is burnt into eFuse at the factory, reading it can't fail
+        unsafe { esp_efuse_mac_get_default(mac.as_mut_ptr()) };
+
+        let mut info = esp_chip_info_t::default();
+        unsafe { esp_chip_info(&mut info) };
+
+        #[allow(non_upper_case_globals)]
+        let chip = match info.model {
+            esp_chip_model_t_CHIP_ESP32 => "esp32",
+            esp_chip_model_t_CHIP_ESP32S2 => "esp32s2",
+            esp_chip_model_t_CHIP_ESP32S3 => "esp32s3",
+            esp_chip_model_t_CHIP_ESP32C3 => "esp32c3",
+            _ => "unknown",
+        };
+
+        Self {
+            mac: mac
+                .iter()
+                .map(|byte| format!("{byte:02x}"))
+                .collect::<Vec<_>>()
+                .join(":"),
+            chip,
+            revision: info.revision,
+        }
+    }
+}
+
+/// A payload inside its envelope, ready to be serialized
+#[derive(Serialize)]
+pub struct Wrapped<'a, T: Serialize> {
+    pub schema_version: u32,
+    pub client_id: &'a str,
+    pub firmware_version: &'a str,
+    pub device: &'a DeviceInfo,
+    pub uptime_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<&'a Value>,
+    pub payload: &'a T,
+}
+
+/// Wraps payloads into `{"schema_version": 2, "client_id": .., "firmware_version": ..,
+/// "device": {"mac": .., "chip": .., "revision": ..}, "uptime_ms": .., "metadata": {..},
+/// "payload": {..}}`, with `metadata` only if given.
+/// Only depends on esp-idf and serde, so the example binaries share it through `#[path]`.
+pub struct Envelope {
+    client_id: String,
+    firmware_version: &'static str,
+    device: DeviceInfo,
+    metadata: Option<Value>,
+}
+
+impl Envelope {
+    /// `firmware_version` is the binary's `CARGO_PKG_VERSION`
+    pub fn new(client_id: &str, firmware_version: &'static str) -> Self {
+        Self {
+            client_id: client_id.to_string(),
+            firmware_version,
+            device: DeviceInfo::read(),
+            metadata: None,
+        }
+    }
+
+    /// Adds static metadata, such as where the device is installed, to every message
+    pub fn with_metadata<T: Serialize>(mut self, metadata: &T) -> Self {
+        self.metadata = serde_json::to_value(metadata).ok();
+        self
+    }
+
+    pub fn wrap<'a, T: Serialize>(&'a self, payload: &'a T) -> Wrapped<'a, T> {
+        Wrapped {
+            schema_version: SCHEMA_VERSION,
+            client_id: &self.client_id,
+            firmware_version: self.firmware_version,
+            device: &self.device,
+            uptime_ms: unsafe { esp_timer_get_time() } as u64 / 1000,
+            metadata: self.metadata.as_ref(),
+            payload,
+        }
+    }
+}
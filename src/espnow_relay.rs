@@ -0,0 +1,170 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use esp_idf_svc::espnow::{EspNow, PeerInfo};
+use esp_idf_svc::sys::{self, esp, EspError, ESP_ERR_INVALID_SIZE};
+
+use log::*;
+
+/// Maximum payload of a single ESP-NOW frame
+const MAX_FRAME_LEN: usize = 250;
+
+/// Frames kept on the relay side while waiting to be forwarded to MQTT
+const MAX_PENDING_FRAMES: usize = 32;
+
+/// A sensor message carried over ESP-NOW: `[kind len][kind][payload]`. The sender only names the
+/// telemetry kind, e.g. `imu`; the relay picks the topic, so a frame can't reach other topics.
+pub struct RelayFrame {
+    /// MAC of the sender, already checked against the allowed peers
+    pub peer: [u8; 6],
+    pub kind: String,
+    pub payload: Vec<u8>,
+}
+
+impl RelayFrame {
+    fn encode(kind: &str, payload: &[u8]) -> Result<Vec<u8>, EspError> {
+        let len = 1 + kind.len() + payload.len();
+        if kind.len() > u8::MAX as usize || len > MAX_FRAME_LEN {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_SIZE>());
+        }
+
+        let mut frame = Vec::with_capacity(len);
+        frame.push(kind.len() as u8);
+        frame.extend_from_slice(kind.as_bytes());
+        frame.extend_from_slice(payload);
+
+        Ok(frame)
+    }
+
+    fn decode(peer: [u8; 6], data: &[u8]) -> Option<Self> {
+        let (&kind_len, rest) = data.split_first()?;
+        if rest.len() < kind_len as usize {
+            return None;
+        }
+
+        let (kind, payload) = rest.split_at(kind_len as usize);
+        let kind = core::str::from_utf8(kind).ok()?;
+        // A single topic level, without wildcards or separators
+        if kind.is_empty()
+            || !kind
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-')
+        {
+            return None;
+        }
+
+        Some(Self {
+            peer,
+            kind: kind.to_string(),
+            payload: payload.to_vec(),
+        })
+    }
+
+    /// The kind the relay publishes under, e.g. `imu/aabbccddeeff`
+    pub fn relayed_kind(&self) -> String {
+        let mac: String = self.peer.iter().map(|byte| format!("{byte:02x}")).collect();
+
+        format!("{}/{mac}", self.kind)
+    }
+}
+
+/// Sends sensor frames to a relay peer when the device has no WiFi uplink of its own
+pub struct EspNowSender {
+    espnow: EspNow<'static>,
+    peer: [u8; 6],
+}
+
+impl EspNowSender {
+    /// WiFi must already be started but not connected; the radio is tuned to `channel`, the one
+    /// the relay's AP is on, since there is no AP of our own to take it from
+    pub fn new(peer: [u8; 6], channel: u8) -> Result<Self, EspError> {
+        esp!(unsafe {
+            sys::esp_wifi_set_channel(channel, sys::wifi_second_chan_t_WIFI_SECOND_CHAN_NONE)
+        })?;
+
+        let espnow = EspNow::take()?;
+
+        espnow.add_peer(PeerInfo {
+            peer_addr: peer,
+            channel,
+            ifidx: sys::wifi_interface_t_WIFI_IF_STA,
+            encrypt: false,
+            ..Default::default()
+        })?;
+
+        espnow.register_send_cb(|mac, status| {
+            debug!("ESP-NOW frame to {:02x?}: {:?}", mac, status);
+        })?;
+
+        Ok(Self { espnow, peer })
+    }
+
+    pub fn send(&self, kind: &str, payload: &[u8]) -> Result<(), EspError> {
+        let frame = RelayFrame::encode(kind, payload)?;
+
+        self.espnow.send(self.peer, &frame)
+    }
+}
+
+/// Collects frames from sender peers so the MQTT publisher can forward them to AWS IoT
+pub struct EspNowReceiver {
+    _espnow: EspNow<'static>,
+    frames: Arc<Mutex<VecDeque<RelayFrame>>>,
+}
+
+impl EspNowReceiver {
+    /// Frames are forwarded under this device's identity, so only `peers` are listened to
+    pub fn new(peers: Vec<[u8; 6]>) -> Result<Self, EspError> {
+        let espnow = EspNow::take()?;
+        let frames = Arc::new(Mutex::new(VecDeque::new()));
+
+        let queue = frames.clone();
+        espnow.register_recv_cb(move |mac, data| {
+            let Some(peer) = peers.iter().find(|peer| peer[..] == *mac) else {
+                debug!("Ignored ESP-NOW frame from unknown peer {:02x?}", mac);
+                return;
+            };
+
+            match RelayFrame::decode(*peer, data) {
+                Some(frame) => {
+                    let mut queue = queue.lock().unwrap();
+                    if queue.len() >= MAX_PENDING_FRAMES {
+                        queue.pop_front();
+                    }
+                    queue.push_back(frame);
+                }
+                None => warn!("Malformed ESP-NOW frame from {:02x?}", mac),
+            }
+        })?;
+
+        Ok(Self {
+            _espnow: espnow,
+            frames,
+        })
+    }
+
+    pub fn take_frames(&self) -> Vec<RelayFrame> {
+        self.frames.lock().unwrap().drain(..).collect()
+    }
+}
+
+/// Parses a MAC address written as `aa:bb:cc:dd:ee:ff`
+pub fn parse_mac(mac: &str) -> Option<[u8; 6]> {
+    let mut addr = [0; 6];
+    let mut parts = mac.split(':');
+
+    for byte in addr.iter_mut() {
+        *byte = u8::from_str_radix(parts.next()?, 16).ok()?;
+    }
+
+    parts.next().is_none().then_some(addr)
+}
+
+/// Parses a comma-separated list of MAC addresses; `None` if any of them is malformed
+pub fn parse_macs(macs: &str) -> Option<Vec<[u8; 6]>> {
+    macs.split(',')
+        .map(str::trim)
+        .filter(|mac| !mac.is_empty())
+        .map(parse_mac)
+        .collect()
+}
@@ -0,0 +1,100 @@
+use std::time::{Duration, Instant};
+
+use crate::telemetry::Signal;
+use crate::units::Units;
+use crate::Config;
+
+/// Limits for one signal; zero disables the check
+struct Limits {
+    field: &'static str,
+    threshold: f32,
+    delta: f32,
+}
+
+/// Report-by-exception: readings are only published when a gyro or accelerometer axis crosses
+/// its threshold (either way) or moved by more than its delta since the last published reading,
+/// and otherwise once per keep-alive interval
+pub struct ReportByException {
+    limits: [Limits; 2],
+    keepalive: Duration,
+    /// Axes per field as last published
+    published: Vec<(&'static str, Vec<f32>)>,
+    published_at: Option<Instant>,
+}
+
+impl ReportByException {
+    /// `None` unless `report_by_exception` is set
+    pub fn from_config(app_config: &Config) -> Option<Self> {
+        if !app_config.report_by_exception {
+            return None;
+        }
+
+        // Readings come in the configured units
+        let units = Units::from_config(app_config);
+        let acc = units.acc_factor();
+        let gyro = units.gyro_factor();
+
+        Some(Self {
+            limits: [
+                Limits {
+                    field: "acc",
+                    threshold: app_config.rbe_acc_threshold_g * acc,
+                    delta: app_config.rbe_acc_delta_g * acc,
+                },
+                Limits {
+                    field: "gyro",
+                    threshold: app_config.rbe_gyro_threshold_dps.to_radians() * gyro,
+                    delta: app_config.rbe_gyro_delta_dps.to_radians() * gyro,
+                },
+            ],
+            keepalive: Duration::from_secs(app_config.rbe_keepalive_secs),
+            published: Vec::new(),
+            published_at: None,
+        })
+    }
+
+    /// Whether these readings are to be published; if so they become the new reference
+    pub fn should_publish(&mut self, signals: &[Signal]) -> bool {
+        let due = self
+            .published_at
+            .map_or(true, |at| at.elapsed() >= self.keepalive);
+
+        if !due && !signals.iter().any(|signal| self.is_exception(signal)) {
+            return false;
+        }
+
+        self.published = signals
+            .iter()
+            .map(|signal| (signal.field, signal.axes.clone()))
+            .collect();
+        self.published_at = Some(Instant::now());
+
+        true
+    }
+
+    fn is_exception(&self, signal: &Signal) -> bool {
+        let Some(limits) = self
+            .limits
+            .iter()
+            .find(|limits| limits.field == signal.field)
+        else {
+            return false;
+        };
+        let Some((_, published)) = self
+            .published
+            .iter()
+            .find(|(field, _)| *field == signal.field)
+        else {
+            // Not published before, e.g. just enabled
+            return true;
+        };
+
+        signal.axes.iter().zip(published).any(|(now, before)| {
+            let crossed = limits.threshold > 0.0
+                && (now.abs() > limits.threshold) != (before.abs() > limits.threshold);
+            let moved = limits.delta > 0.0 && (now - before).abs() > limits.delta;
+
+            crossed || moved
+        })
+    }
+}
@@ -0,0 +1,97 @@
+use std::time::{Duration, Instant};
+
+use esp_idf_svc::hal::gpio::{Gpio2, Gpio42, Input, Output, PinDriver};
+use esp_idf_svc::sys::{
+    esp, nvs_close, nvs_commit, nvs_erase_all, nvs_handle_t, nvs_open,
+    nvs_open_mode_t_NVS_READWRITE, EspError, ESP_ERR_NVS_NOT_FOUND,
+};
+
+use log::*;
+
+use crate::credentials;
+use crate::settings;
+use crate::signing;
+
+/// How long the button has to be held at boot to reset the device
+const HOLD: Duration = Duration::from_secs(10);
+
+/// What a new owner mustn't inherit: the settings, the device certificate, the payload signing
+/// key and the station config ESP-IDF keeps for Wi-Fi. Counters such as the telemetry sequence
+/// stay, and the claim certificate for fleet provisioning is built into the firmware.
+const NAMESPACES: [&str; 4] = [
+    settings::NVS_NAMESPACE,
+    credentials::NVS_NAMESPACE,
+    signing::NVS_NAMESPACE,
+    "nvs.net80211",
+];
+
+/// Erases configuration and credentials from NVS. Without a certificate the device goes
+/// through fleet provisioning again on the next boot.
+pub fn wipe() -> Result<(), EspError> {
+    for namespace in NAMESPACES {
+        let name = format!("{namespace}\0");
+        let mut handle: nvs_handle_t = 0;
+
+        match esp!(unsafe {
+            nvs_open(
+                name.as_ptr() as _,
+                nvs_open_mode_t_NVS_READWRITE,
+                &mut handle,
+            )
+        }) {
+            Ok(()) => (),
+            // Never written to
+            Err(e) if e.code() == ESP_ERR_NVS_NOT_FOUND as i32 => continue,
+            Err(e) => return Err(e),
+        }
+
+        let res = esp!(unsafe { nvs_erase_all(handle) })
+            .and_then(|_| esp!(unsafe { nvs_commit(handle) }));
+        unsafe { nvs_close(handle) };
+        res?;
+
+        info!("Erased NVS namespace \"{namespace}\"");
+    }
+
+    Ok(())
+}
+
+/// Whether the button (active low) is held for 10s right after boot, for resetting a device
+/// without a serial connection. The buzzer ticks every second while it is held and sounds long
+/// once the reset is due; letting go earlier boots normally.
+pub fn held_at_boot(
+    button: &PinDriver<'static, Gpio42, Input>,
+    buzzer: &mut PinDriver<'static, Gpio2, Output>,
+) -> Result<bool, EspError> {
+    if button.is_high() {
+        return Ok(false);
+    }
+
+    warn!("Button held, keep it down for {}s to reset", HOLD.as_secs());
+    let pressed_at = Instant::now();
+    let mut ticks = 0;
+    while pressed_at.elapsed() < HOLD {
+        if button.is_high() {
+            info!("Button released, booting normally");
+            return Ok(false);
+        }
+
+        if pressed_at.elapsed() >= Duration::from_secs(ticks) {
+            ticks += 1;
+            beep(buzzer, Duration::from_millis(50))?;
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+
+    beep(buzzer, Duration::from_secs(1))?;
+    Ok(true)
+}
+
+fn beep(
+    buzzer: &mut PinDriver<'static, Gpio2, Output>,
+    duration: Duration,
+) -> Result<(), EspError> {
+    buzzer.set_high()?;
+    std::thread::sleep(duration);
+    buzzer.set_low()
+}
@@ -0,0 +1,233 @@
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use mpu6886::device::{CONFIG, GYRO_SENS, INT_STATUS, TEMP_OFFSET, TEMP_SENSITIVITY};
+use mpu6886::PI_180;
+
+use log::*;
+
+use crate::clock::Stamp;
+use crate::decimate::Decimation;
+use crate::imu::{self, Imu};
+use crate::settings::Settings;
+
+const SMPLRT_DIV: u8 = 0x19;
+/// Register 35: `GYRO_FIFO_EN` in bit 4, `ACCEL_FIFO_EN` in bit 3
+const FIFO_ENABLE: u8 = 0x23;
+const GYRO_ACCEL_FIFO_EN: u8 = 0x18;
+/// Register 106: `FIFO_EN` in bit 6, `FIFO_RST` in bit 2
+const USER_CTRL: u8 = 0x6a;
+const USER_FIFO_EN: u8 = 0x40;
+const USER_FIFO_RST: u8 = 0x04;
+const FIFO_COUNTH: u8 = 0x72;
+const FIFO_R_W: u8 = 0x74;
+
+/// With gyro and accelerometer enabled every sample is accel x/y/z, temp, gyro x/y/z as i16 BE
+const PACKET_LEN: usize = 14;
+/// Bytes read per I2C transaction while draining
+const BURST_LEN: usize = PACKET_LEN * 16;
+/// Internal sample rate with the low-pass filter in the signal path
+const INTERNAL_HZ: u32 = 1000;
+/// Keeps a capture within what fits into one MQTT message
+pub const MAX_SAMPLES: usize = 1000;
+/// The FIFO holds 1024 bytes, about 146ms at 500Hz; draining well before that avoids overflows
+const DRAIN_INTERVAL: Duration = Duration::from_millis(40);
+
+/// Readings captured through the FIFO at a fixed rate, offsets already subtracted
+pub struct Capture {
+    /// When sampling started
+    pub stamp: Stamp,
+    pub rate_hz: u32,
+    /// Accelerometer readings in g
+    pub acc: Vec<[f32; 3]>,
+    /// Gyro readings in rad/s
+    pub gyro: Vec<[f32; 3]>,
+    /// Times the FIFO filled up before it was drained; samples are missing after each
+    pub overflows: u32,
+}
+
+#[derive(Serialize)]
+struct CaptureReport<'a> {
+    seq: u32,
+    #[serde(flatten)]
+    stamp: Stamp,
+    rate_hz: u32,
+    overflows: u32,
+    acc: &'a [[f32; 3]],
+    gyro: &'a [[f32; 3]],
+}
+
+impl Capture {
+    /// Reduces the samples and their rate by the decimation factor
+    pub fn decimate(&mut self, decimation: &Decimation) {
+        self.acc = decimation.series(&self.acc);
+        self.gyro = decimation.series(&self.gyro);
+        self.rate_hz /= decimation.factor() as u32;
+    }
+
+    /// `{"seq": 42, "rate_hz": 500, "overflows": 0, "acc": [[x, y, z], ..], "gyro": [..]}`,
+    /// with the [`Stamp`] of the start after `seq`
+    pub fn to_json(&self, seq: u32) -> String {
+        serde_json::to_string(&CaptureReport {
+            seq,
+            stamp: self.stamp,
+            rate_hz: self.rate_hz,
+            overflows: self.overflows,
+            acc: &self.acc,
+            gyro: &self.gyro,
+        })
+        .unwrap()
+    }
+}
+
+/// Samples at `rate_hz` into the sensor FIFO for `duration`, draining it in bursts.
+/// The rate divides the 1kHz internal rate, which needs a gyro low-pass filter below 250Hz.
+pub fn capture(
+    mpu: &mut Imu,
+    settings: &Settings,
+    rate_hz: u32,
+    duration: Duration,
+) -> Result<Capture, String> {
+    if rate_hz == 0 || rate_hz > INTERNAL_HZ {
+        return Err(format!("rate must be 1..={INTERNAL_HZ}Hz"));
+    }
+    if imu::gyro_dlpf(settings.gyro_dlpf_hz()) == Some(0) {
+        return Err("FIFO capture needs a gyro DLPF below 250Hz".into());
+    }
+
+    let divider = u8::try_from(INTERNAL_HZ / rate_hz - 1).map_err(|_| "rate too low")?;
+    let rate_hz = INTERNAL_HZ / (divider as u32 + 1);
+    let max_samples = (duration.as_millis() as u64).saturating_mul(rate_hz as u64) / 1000;
+    let max_samples = max_samples.min(MAX_SAMPLES as u64) as usize;
+
+    let mut capture = Capture {
+        stamp: Stamp::now(),
+        rate_hz,
+        acc: Vec::with_capacity(max_samples),
+        gyro: Vec::with_capacity(max_samples),
+        overflows: 0,
+    };
+
+    start(mpu, divider)?;
+    let res = drain_until(mpu, settings, &mut capture, max_samples);
+    let stopped = stop(mpu);
+    res?;
+    stopped?;
+
+    info!(
+        "Captured {} samples at {}Hz, {} overflows",
+        capture.acc.len(),
+        capture.rate_hz,
+        capture.overflows
+    );
+
+    Ok(capture)
+}
+
+fn start(mpu: &mut Imu, divider: u8) -> Result<(), String> {
+    write_byte(mpu, SMPLRT_DIV, divider)?;
+    // Stop writing once full instead of overwriting, so overflows show up in INT_STATUS
+    mpu.write_bit(CONFIG::ADDR, CONFIG::FIFO_MODE, true)
+        .map_err(|e| format!("{e:?}"))?;
+    write_byte(mpu, FIFO_ENABLE, GYRO_ACCEL_FIFO_EN)?;
+    reset(mpu)?;
+    // Reading INT_STATUS clears a stale overflow flag
+    read_byte(mpu, INT_STATUS::ADDR)?;
+
+    Ok(())
+}
+
+fn stop(mpu: &mut Imu) -> Result<(), String> {
+    write_byte(mpu, USER_CTRL, 0)?;
+    write_byte(mpu, FIFO_ENABLE, 0)?;
+    write_byte(mpu, SMPLRT_DIV, 0)
+}
+
+/// Empties the FIFO and starts filling it again
+fn reset(mpu: &mut Imu) -> Result<(), String> {
+    write_byte(mpu, USER_CTRL, USER_FIFO_RST)?;
+    write_byte(mpu, USER_CTRL, USER_FIFO_EN)
+}
+
+fn drain_until(
+    mpu: &mut Imu,
+    settings: &Settings,
+    capture: &mut Capture,
+    max_samples: usize,
+) -> Result<(), String> {
+    let acc_sens = 32768.0 / settings.accel_range_g() as f32;
+    let gyro_sens = match settings.gyro_range_dps() {
+        250 => GYRO_SENS.0,
+        500 => GYRO_SENS.1,
+        1000 => GYRO_SENS.2,
+        _ => GYRO_SENS.3,
+    };
+    let offsets = settings.offsets();
+    let temp_model = settings.temp_model();
+    let remap = settings.axis_remap();
+    // Bounds the capture should the FIFO stop filling up
+    let deadline = Instant::now()
+        + Duration::from_millis((max_samples as u64 * 1000 / capture.rate_hz as u64) * 2 + 500);
+    let mut buf = [0; BURST_LEN];
+
+    while capture.acc.len() < max_samples {
+        if Instant::now() >= deadline {
+            warn!("FIFO capture timed out");
+            break;
+        }
+
+        std::thread::sleep(DRAIN_INTERVAL);
+
+        if read_byte(mpu, INT_STATUS::ADDR)? & (1 << INT_STATUS::FIFO_OFLOW_INT) != 0 {
+            // The FIFO stopped at a packet boundary, so what is in there is still aligned
+            capture.overflows += 1;
+            warn!("FIFO overflow after {} samples", capture.acc.len());
+        }
+
+        let mut count = [0; 2];
+        mpu.read_bytes(FIFO_COUNTH, &mut count)
+            .map_err(|e| format!("{e:?}"))?;
+        let mut available = (u16::from_be_bytes(count) & 0x1fff) as usize / PACKET_LEN;
+
+        while available > 0 && capture.acc.len() < max_samples {
+            let packets = available.min(BURST_LEN / PACKET_LEN);
+            let burst = &mut buf[..packets * PACKET_LEN];
+            mpu.read_bytes(FIFO_R_W, burst)
+                .map_err(|e| format!("{e:?}"))?;
+
+            for packet in burst.chunks(PACKET_LEN) {
+                let value = |i: usize| i16::from_be_bytes([packet[i], packet[i + 1]]) as f32;
+                let gyro_bias = match temp_model {
+                    Some(model) => {
+                        model.bias(offsets.gyro, value(6) / TEMP_SENSITIVITY + TEMP_OFFSET)
+                    }
+                    None => offsets.gyro,
+                };
+
+                capture.acc.push(
+                    remap.apply(
+                        [0, 1, 2].map(|axis| value(axis * 2) / acc_sens - offsets.acc[axis]),
+                    ),
+                );
+                capture.gyro.push(
+                    remap
+                        .apply([0, 1, 2].map(|axis| {
+                            value(8 + axis * 2) * PI_180 / gyro_sens - gyro_bias[axis]
+                        })),
+                );
+            }
+            available -= packets;
+        }
+    }
+
+    Ok(())
+}
+
+fn write_byte(mpu: &mut Imu, reg: u8, byte: u8) -> Result<(), String> {
+    mpu.write_byte(reg, byte).map_err(|e| format!("{e:?}"))
+}
+
+fn read_byte(mpu: &mut Imu, reg: u8) -> Result<u8, String> {
+    mpu.read_byte(reg).map_err(|e| format!("{e:?}"))
+}
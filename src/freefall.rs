@@ -0,0 +1,121 @@
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use log::*;
+
+use crate::clock::Stamp;
+use crate::imu::{self, Imu};
+use crate::settings::Settings;
+use crate::Config;
+
+const PERIOD: Duration = Duration::from_millis(10);
+
+/// Detects free fall: the acceleration magnitude stays near zero while the device drops
+pub struct FreeFallDetector {
+    threshold: f32,
+    min_duration: Duration,
+    sampled_at: Option<Instant>,
+    /// Since when the magnitude is below the threshold
+    low_since: Option<Instant>,
+    /// Lowest magnitude during the current fall
+    min_g: f32,
+    reported: bool,
+    alert: Option<FreeFall>,
+}
+
+/// A detected fall, reported while it is still going on
+pub struct FreeFall {
+    pub duration: Duration,
+    pub min_g: f32,
+}
+
+#[derive(Serialize)]
+struct FreeFallAlert {
+    seq: u32,
+    #[serde(flatten)]
+    stamp: Stamp,
+    event: &'static str,
+    ms: u64,
+    min_g: f32,
+}
+
+impl FreeFall {
+    /// `{"seq": 42, "ts": .., "mono_us": .., "event": "free_fall", "ms": 350, "min_g": 0.08}`
+    pub fn to_json(&self, seq: u32) -> String {
+        serde_json::to_string(&FreeFallAlert {
+            seq,
+            stamp: Stamp::now(),
+            event: "free_fall",
+            ms: self.duration.as_millis() as u64,
+            min_g: self.min_g,
+        })
+        .unwrap()
+    }
+}
+
+impl FreeFallDetector {
+    /// `None` with `freefall_ms = 0`
+    pub fn from_config(app_config: &Config) -> Option<Self> {
+        if app_config.freefall_ms == 0 {
+            return None;
+        }
+
+        Some(Self {
+            threshold: app_config.freefall_threshold_g,
+            min_duration: Duration::from_millis(app_config.freefall_ms),
+            sampled_at: None,
+            low_since: None,
+            min_g: f32::MAX,
+            reported: false,
+            alert: None,
+        })
+    }
+
+    /// Samples when due; returns the time until the next sample
+    pub fn poll(&mut self, mpu: &mut Imu, settings: &Settings) -> Duration {
+        let due = self
+            .sampled_at
+            .map_or(Duration::ZERO, |at| PERIOD.saturating_sub(at.elapsed()));
+        if !due.is_zero() {
+            return due;
+        }
+
+        let now = Instant::now();
+        self.sampled_at = Some(now);
+
+        match imu::acc(mpu, settings) {
+            Ok([x, y, z]) => {
+                self.update((x * x + y * y + z * z).sqrt(), now);
+            }
+            Err(e) => warn!("Failed to read the accelerometer for free fall: {e:?}"),
+        }
+
+        PERIOD
+    }
+
+    fn update(&mut self, magnitude: f32, now: Instant) {
+        if magnitude >= self.threshold {
+            self.low_since = None;
+            self.min_g = f32::MAX;
+            self.reported = false;
+            return;
+        }
+
+        let since = *self.low_since.get_or_insert(now);
+        self.min_g = self.min_g.min(magnitude);
+
+        // Once per fall, as soon as it lasted long enough
+        if !self.reported && now - since >= self.min_duration {
+            self.reported = true;
+            self.alert = Some(FreeFall {
+                duration: now - since,
+                min_g: self.min_g,
+            });
+        }
+    }
+
+    pub fn take_alert(&mut self) -> Option<FreeFall> {
+        self.alert.take()
+    }
+}
@@ -0,0 +1,175 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use log::*;
+
+use crate::clock::Stamp;
+use crate::imu::{self, Imu};
+use crate::settings::Settings;
+use crate::Config;
+
+/// Thresholds below are tuned for sampling at 100Hz
+const PERIOD: Duration = Duration::from_millis(10);
+/// A peak lasting longer than this is a push, not a tap
+const TAP_MAX: Duration = Duration::from_millis(100);
+/// Peaks closer than this to a tap are its ringing
+const TAP_DEBOUNCE: Duration = Duration::from_millis(80);
+/// A second tap within this makes a double tap; a single tap is only reported after it
+const DOUBLE_TAP_WINDOW: Duration = Duration::from_millis(400);
+/// Peaks needed within `SHAKE_WINDOW` to count as shaking
+const SHAKE_PEAKS: usize = 4;
+const SHAKE_WINDOW: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Gesture {
+    Tap,
+    DoubleTap,
+    Shake,
+}
+
+impl Gesture {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Tap => "tap",
+            Self::DoubleTap => "double_tap",
+            Self::Shake => "shake",
+        }
+    }
+
+    /// `{"seq": 42, "ts": .., "mono_us": .., "event": "tap"}`
+    pub fn to_json(self, seq: u32) -> String {
+        serde_json::to_string(&GestureEvent {
+            seq,
+            stamp: Stamp::now(),
+            event: self.as_str(),
+        })
+        .unwrap()
+    }
+}
+
+#[derive(Serialize)]
+struct GestureEvent {
+    seq: u32,
+    #[serde(flatten)]
+    stamp: Stamp,
+    event: &'static str,
+}
+
+/// Detects gestures from the acceleration magnitude's deviation from 1g: short peaks are taps,
+/// repeated peaks within a second are a shake
+pub struct GestureDetector {
+    tap_threshold: f32,
+    shake_threshold: f32,
+    sampled_at: Option<Instant>,
+    /// Since when the deviation is above the tap threshold
+    peak_since: Option<Instant>,
+    /// A single tap waiting to see whether a second one follows
+    pending_tap: Option<Instant>,
+    last_tap: Option<Instant>,
+    above_shake: bool,
+    shake_peaks: VecDeque<Instant>,
+    events: Vec<Gesture>,
+}
+
+impl GestureDetector {
+    /// `None` unless `gestures` is set
+    pub fn from_config(app_config: &Config) -> Option<Self> {
+        if !app_config.gestures {
+            return None;
+        }
+
+        Some(Self {
+            tap_threshold: app_config.tap_threshold_g,
+            shake_threshold: app_config.shake_threshold_g,
+            sampled_at: None,
+            peak_since: None,
+            pending_tap: None,
+            last_tap: None,
+            above_shake: false,
+            shake_peaks: VecDeque::new(),
+            events: Vec::new(),
+        })
+    }
+
+    /// Samples when due; returns the time until the next sample
+    pub fn poll(&mut self, mpu: &mut Imu, settings: &Settings) -> Duration {
+        let due = self
+            .sampled_at
+            .map_or(Duration::ZERO, |at| PERIOD.saturating_sub(at.elapsed()));
+        if !due.is_zero() {
+            return due;
+        }
+
+        let now = Instant::now();
+        self.sampled_at = Some(now);
+
+        match imu::acc(mpu, settings) {
+            Ok([x, y, z]) => {
+                self.update((x * x + y * y + z * z).sqrt() - 1.0, now);
+            }
+            Err(e) => warn!("Failed to read the accelerometer for gestures: {e:?}"),
+        }
+
+        PERIOD
+    }
+
+    fn update(&mut self, deviation: f32, now: Instant) {
+        let deviation = deviation.abs();
+
+        if deviation >= self.shake_threshold {
+            if !self.above_shake {
+                self.shake_peaks.push_back(now);
+            }
+            self.above_shake = true;
+        } else {
+            self.above_shake = false;
+        }
+        while self
+            .shake_peaks
+            .front()
+            .is_some_and(|at| now - *at > SHAKE_WINDOW)
+        {
+            self.shake_peaks.pop_front();
+        }
+
+        if self.shake_peaks.len() >= SHAKE_PEAKS {
+            self.shake_peaks.clear();
+            // The peaks of a shake are no taps
+            self.pending_tap = None;
+            self.events.push(Gesture::Shake);
+            return;
+        }
+
+        if deviation >= self.tap_threshold {
+            self.peak_since.get_or_insert(now);
+        } else if let Some(since) = self.peak_since.take() {
+            let debounced = self.last_tap.map_or(true, |at| since - at >= TAP_DEBOUNCE);
+
+            if now - since <= TAP_MAX && debounced {
+                self.last_tap = Some(now);
+                if self.pending_tap.take().is_some() {
+                    self.events.push(Gesture::DoubleTap);
+                } else {
+                    self.pending_tap = Some(now);
+                }
+            }
+        }
+
+        // Hold a single tap back while a shake is building up
+        if self
+            .pending_tap
+            .is_some_and(|at| now - at > DOUBLE_TAP_WINDOW)
+            && self.shake_peaks.len() < 2
+        {
+            self.pending_tap = None;
+            self.events.push(Gesture::Tap);
+        }
+    }
+
+    /// Gestures detected since the last call
+    pub fn take_events(&mut self) -> Vec<Gesture> {
+        std::mem::take(&mut self.events)
+    }
+}
@@ -0,0 +1,123 @@
+use anyhow::{anyhow, bail, Result};
+use serde::Deserialize;
+
+use esp_idf_svc::http::client::{Configuration as HttpConfiguration, EspHttpConnection};
+use esp_idf_svc::http::Method;
+use esp_idf_svc::tls::X509;
+
+use log::*;
+
+use crate::reachability;
+
+/// Largest discovery response we are willing to buffer
+const MAX_RESPONSE_LEN: usize = 16 * 1024;
+
+/// Payload of `GET /greengrass/discover/thing/<thing name>`
+#[derive(Deserialize)]
+struct DiscoverResponse {
+    #[serde(rename = "GGGroups")]
+    groups: Vec<GroupInfo>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct GroupInfo {
+    cores: Vec<CoreInfo>,
+    #[serde(rename = "CAs")]
+    cas: Vec<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct CoreInfo {
+    connectivity: Vec<ConnectivityInfo>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ConnectivityInfo {
+    host_address: String,
+    port_number: u16,
+}
+
+/// A Greengrass core on the LAN, with the group CA its server certificate is signed by
+pub struct GreengrassCore {
+    pub url: String,
+    pub ca: Vec<u8>,
+}
+
+/// Asks the Greengrass discovery API of the endpoint's region which core serves this thing,
+/// and returns the first of its addresses which answers a TCP probe
+pub fn discover(
+    endpoint: &str,
+    thing_name: &str,
+    client_cert: X509<'static>,
+    private_key: X509<'static>,
+) -> Result<GreengrassCore> {
+    let region = region(endpoint).ok_or_else(|| anyhow!("no region in \"{endpoint}\""))?;
+    let url = format!(
+        "https://greengrass-ats.iot.{region}.amazonaws.com:8443/greengrass/discover/thing/{thing_name}"
+    );
+
+    let mut conn = EspHttpConnection::new(&HttpConfiguration {
+        client_certificate: Some(client_cert),
+        private_key: Some(private_key),
+        crt_bundle_attach: Some(esp_idf_sys::esp_crt_bundle_attach),
+        ..Default::default()
+    })?;
+    conn.initiate_request(Method::Get, &url, &[])?;
+    conn.initiate_response()?;
+
+    if conn.status() != 200 {
+        bail!("discovery answered with HTTP {}", conn.status());
+    }
+
+    let mut body = Vec::new();
+    let mut buf = [0; 512];
+    loop {
+        let len = conn.read(&mut buf)?;
+        if len == 0 {
+            break;
+        }
+        if body.len() + len > MAX_RESPONSE_LEN {
+            bail!("discovery response too large");
+        }
+        body.extend_from_slice(&buf[..len]);
+    }
+
+    let response: DiscoverResponse = serde_json::from_slice(&body)?;
+
+    for group in response.groups {
+        let Some(ca) = group.cas.into_iter().next() else {
+            continue;
+        };
+
+        for connectivity in group.cores.iter().flat_map(|core| &core.connectivity) {
+            let url = format!(
+                "mqtts://{}:{}",
+                connectivity.host_address, connectivity.port_number
+            );
+
+            match reachability::check(&url) {
+                Ok(_) => {
+                    return Ok(GreengrassCore {
+                        url,
+                        ca: ca.into_bytes(),
+                    })
+                }
+                Err(e) => warn!("Greengrass core {url} unreachable: {e}"),
+            }
+        }
+    }
+
+    bail!("no reachable Greengrass core for \"{thing_name}\"")
+}
+
+/// `xxx-ats.iot.<region>.amazonaws.com` -> `<region>`
+fn region(endpoint: &str) -> Option<&str> {
+    let (host, _) = reachability::endpoint_host_port(endpoint)?;
+    let mut labels = host.split('.');
+    labels.find(|label| *label == "iot")?;
+
+    labels.next()
+}
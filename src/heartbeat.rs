@@ -0,0 +1,22 @@
+use serde::Serialize;
+
+use crate::wifi;
+
+#[derive(Serialize)]
+struct Heartbeat {
+    uptime_secs: i64,
+    free_heap: u32,
+    rssi: Option<i8>,
+}
+
+/// Small liveness message, published regardless of the sensor so a dashboard can tell a
+/// broken sensor from a dead device: `{"uptime_secs": 120, "free_heap": 81234, "rssi": -61}`
+pub fn payload() -> String {
+    let heartbeat = Heartbeat {
+        uptime_secs: unsafe { esp_idf_svc::sys::esp_timer_get_time() } / 1_000_000,
+        free_heap: unsafe { esp_idf_svc::sys::esp_get_free_heap_size() },
+        rssi: wifi::sta_rssi(),
+    };
+
+    serde_json::to_string(&heartbeat).unwrap()
+}
@@ -0,0 +1,328 @@
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use embedded_hal::i2c::{ErrorType, I2c, Operation, SevenBitAddress};
+use embedded_hal_0_2::blocking::i2c::{Read, Write, WriteRead};
+use esp_idf_svc::hal::delay::BLOCK;
+use esp_idf_svc::hal::gpio::AnyIOPin;
+use esp_idf_svc::hal::i2c::{self, I2cConfig, I2cDriver, I2cError, I2C0, I2C1};
+use esp_idf_svc::hal::peripheral::Peripheral;
+use esp_idf_svc::hal::prelude::*;
+use esp_idf_svc::nvs::{EspDefaultNvs, EspDefaultNvsPartition};
+use esp_idf_svc::sys::{EspError, ESP_ERR_INVALID_ARG};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+
+use log::*;
+
+use crate::settings;
+use crate::Config;
+
+/// 7-bit addresses that are not reserved
+const SCAN_ADDRESSES: core::ops::RangeInclusive<u8> = 0x08..=0x77;
+
+/// Pins and clock of a bus: `cfg.toml`, unless changed on the config topic, which is
+/// persisted to NVS and applies from the next boot
+#[derive(Clone, Copy, Debug)]
+pub struct BusConfig {
+    pub port: u8,
+    pub sda_gpio: i32,
+    pub scl_gpio: i32,
+    pub baudrate_khz: u32,
+}
+
+/// Changes to a [`BusConfig`], as in the config document; fields left out stay as they are
+#[derive(Default, Deserialize)]
+pub struct BusOverride {
+    pub port: Option<u8>,
+    pub sda_gpio: Option<i32>,
+    pub scl_gpio: Option<i32>,
+    pub baudrate_khz: Option<u32>,
+}
+
+/// NVS keys of the primary or the secondary bus: port, SDA, SCL and baudrate
+pub fn nvs_keys(secondary: bool) -> [&'static str; 4] {
+    if secondary {
+        ["i2c1_port", "i2c1_sda", "i2c1_scl", "i2c1_khz"]
+    } else {
+        ["i2c_port", "i2c_sda", "i2c_scl", "i2c_khz"]
+    }
+}
+
+/// Whether a bus may use the GPIO on the ESP32-S3: 22-25 don't exist and 26-32 are wired to
+/// the flash
+pub fn usable_gpio(gpio: i32) -> bool {
+    matches!(gpio, 0..=21 | 33..=48)
+}
+
+/// Whether the buses can be opened at all: existing pins, none used twice
+fn usable_pins(primary: &BusConfig, secondary: Option<&BusConfig>) -> bool {
+    let mut pins = vec![primary.sda_gpio, primary.scl_gpio];
+    if let Some(secondary) = secondary {
+        pins.extend([secondary.sda_gpio, secondary.scl_gpio]);
+    }
+
+    pins.iter().all(|&gpio| usable_gpio(gpio))
+        && pins
+            .iter()
+            .enumerate()
+            .all(|(i, gpio)| !pins[i + 1..].contains(gpio))
+}
+
+impl BusConfig {
+    /// The bus of the MPU6886, i2c0 on GPIO13/15 at 400kHz unless configured otherwise
+    pub fn primary(
+        app_config: &Config,
+        partition: &EspDefaultNvsPartition,
+    ) -> Result<Self, EspError> {
+        Self::primary_from_cfg(app_config).with_overrides(partition, false)
+    }
+
+    /// A second bus for the other sensors, on the other port; `None` unless both pins are set
+    pub fn secondary(
+        app_config: &Config,
+        partition: &EspDefaultNvsPartition,
+        primary: &Self,
+    ) -> Result<Option<Self>, EspError> {
+        let bus = Self::secondary_from_cfg(app_config, primary).with_overrides(partition, true)?;
+
+        Ok(bus.enabled().then_some(bus))
+    }
+
+    fn primary_from_cfg(app_config: &Config) -> Self {
+        Self {
+            port: app_config.i2c_port,
+            sda_gpio: app_config.i2c_sda_gpio,
+            scl_gpio: app_config.i2c_scl_gpio,
+            baudrate_khz: app_config.i2c_baudrate_khz,
+        }
+    }
+
+    fn secondary_from_cfg(app_config: &Config, primary: &Self) -> Self {
+        Self {
+            port: 1 - primary.port,
+            sda_gpio: app_config.i2c1_sda_gpio,
+            scl_gpio: app_config.i2c1_scl_gpio,
+            baudrate_khz: app_config.i2c1_baudrate_khz,
+        }
+    }
+
+    fn enabled(&self) -> bool {
+        self.sda_gpio >= 0 && self.scl_gpio >= 0
+    }
+
+    fn with_overrides(
+        mut self,
+        partition: &EspDefaultNvsPartition,
+        secondary: bool,
+    ) -> Result<Self, EspError> {
+        let nvs = EspDefaultNvs::new(partition.clone(), settings::NVS_NAMESPACE, true)?;
+        let [port, sda, scl, khz] = nvs_keys(secondary);
+
+        // The secondary bus always takes the port the primary one leaves
+        if !secondary {
+            self.port = nvs.get_u8(port)?.unwrap_or(self.port).min(1);
+        }
+        self.sda_gpio = nvs.get_i32(sda)?.unwrap_or(self.sda_gpio);
+        self.scl_gpio = nvs.get_i32(scl)?.unwrap_or(self.scl_gpio);
+        self.baudrate_khz = nvs.get_u32(khz)?.unwrap_or(self.baudrate_khz);
+
+        Ok(self)
+    }
+
+    /// Installs the driver on `i2c`, which has to be the peripheral of `port`
+    pub fn open<I2C: i2c::I2c>(
+        &self,
+        i2c: impl Peripheral<P = I2C> + 'static,
+    ) -> Result<SharedI2c, EspError> {
+        let config = I2cConfig::new().baudrate(self.baudrate_khz.kHz().into());
+        // Safety: the pins come from the config and are not used for anything else
+        let (sda, scl) = unsafe { (AnyIOPin::new(self.sda_gpio), AnyIOPin::new(self.scl_gpio)) };
+        let driver = I2cDriver::new(i2c, sda, scl, &config)?;
+        info!(
+            "I2C{} on SDA GPIO{}, SCL GPIO{} at {}kHz",
+            self.port, self.sda_gpio, self.scl_gpio, self.baudrate_khz
+        );
+
+        Ok(SharedI2c::new(driver))
+    }
+}
+
+/// Opens the MPU6886's bus and, if configured, the one for the other sensors. Should the
+/// overrides from the config topic leave them unusable, it falls back to `cfg.toml`, so a bad
+/// config document can't keep the device from booting.
+pub fn open_buses(
+    app_config: &Config,
+    partition: &EspDefaultNvsPartition,
+    mut i2c0: I2C0,
+    mut i2c1: I2C1,
+) -> Result<(SharedI2c, Option<SharedI2c>), EspError> {
+    let primary = BusConfig::primary(app_config, partition)?;
+    let secondary = BusConfig::secondary(app_config, partition, &primary)?;
+
+    match open_pair(&primary, secondary.as_ref(), &mut i2c0, &mut i2c1) {
+        Ok(buses) => Ok(buses),
+        Err(e) => {
+            warn!("Failed to open the configured I2C buses ({e}), using the ones from cfg.toml");
+
+            let primary = BusConfig::primary_from_cfg(app_config);
+            let secondary = Some(BusConfig::secondary_from_cfg(app_config, &primary))
+                .filter(BusConfig::enabled);
+            open_pair(&primary, secondary.as_ref(), &mut i2c0, &mut i2c1)
+        }
+    }
+}
+
+fn open_pair(
+    primary: &BusConfig,
+    secondary: Option<&BusConfig>,
+    i2c0: &mut I2C0,
+    i2c1: &mut I2C1,
+) -> Result<(SharedI2c, Option<SharedI2c>), EspError> {
+    // Flash pins would hang the chip rather than fail
+    if !usable_pins(primary, secondary) {
+        return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+    }
+
+    // Safety: a driver that failed to install is dropped before the peripheral is taken again
+    let (i2c0, i2c1) = unsafe { (i2c0.clone_unchecked(), i2c1.clone_unchecked()) };
+    if primary.port == 0 {
+        Ok((
+            primary.open(i2c0)?,
+            secondary.map(|bus| bus.open(i2c1)).transpose()?,
+        ))
+    } else {
+        Ok((
+            primary.open(i2c1)?,
+            secondary.map(|bus| bus.open(i2c0)).transpose()?,
+        ))
+    }
+}
+
+/// Handle to an I2C bus shared by several devices; every transfer holds the bus for its
+/// duration, so the MPU6886 and other sensors on its bus can be driven from different places
+/// without interleaving their transactions
+#[derive(Clone)]
+pub struct SharedI2c {
+    bus: Arc<Mutex<I2cDriver<'static>>>,
+}
+
+impl SharedI2c {
+    pub fn new(driver: I2cDriver<'static>) -> Self {
+        Self {
+            bus: Arc::new(Mutex::new(driver)),
+        }
+    }
+
+    pub fn port(&self) -> u8 {
+        self.lock().port() as u8
+    }
+
+    /// Addresses that ACK an empty write, to check the wiring
+    fn scan(&self) -> Vec<u8> {
+        let mut bus = self.lock();
+        let found: Vec<u8> = SCAN_ADDRESSES
+            .filter(|&address| bus.write(address, &[], BLOCK).is_ok())
+            .collect();
+
+        for address in &found {
+            info!(
+                "I2C{} device at {address:#04x}: {}",
+                bus.port(),
+                device_name(*address).unwrap_or("unknown")
+            );
+        }
+        if found.is_empty() {
+            warn!(
+                "No devices found on I2C{}, check the wiring and pull-ups",
+                bus.port()
+            );
+        }
+
+        found
+    }
+
+    fn lock(&self) -> MutexGuard<'_, I2cDriver<'static>> {
+        // A panic mid-transfer leaves nothing behind that a later transfer would trip over
+        self.bus.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// What usually sits at an address on our boards and units
+fn device_name(address: u8) -> Option<&'static str> {
+    Some(match address {
+        0x44 => "SHT30",
+        0x68 => "MPU6886",
+        0x70 => "QMP6988",
+        0x76 | 0x77 => "BMP280",
+        _ => return None,
+    })
+}
+
+/// Scans every bus: `{"i2c0": [{"address": "0x68", "device": "MPU6886"}, ...], "i2c1": [..]}`,
+/// `device` is null when unknown
+pub fn scan_all(buses: &[SharedI2c]) -> Value {
+    let mut result = Map::new();
+
+    for bus in buses {
+        let devices = bus
+            .scan()
+            .into_iter()
+            .map(|address| {
+                json!({
+                    "address": format!("{address:#04x}"),
+                    "device": device_name(address),
+                })
+            })
+            .collect();
+        result.insert(format!("i2c{}", bus.port()), devices);
+    }
+
+    Value::Object(result)
+}
+
+// embedded-hal 0.2, used by the mpu6886 crate
+
+impl Read for SharedI2c {
+    type Error = I2cError;
+
+    fn read(&mut self, address: u8, buffer: &mut [u8]) -> Result<(), Self::Error> {
+        Read::read(&mut *self.lock(), address, buffer)
+    }
+}
+
+impl Write for SharedI2c {
+    type Error = I2cError;
+
+    fn write(&mut self, address: u8, bytes: &[u8]) -> Result<(), Self::Error> {
+        Write::write(&mut *self.lock(), address, bytes)
+    }
+}
+
+impl WriteRead for SharedI2c {
+    type Error = I2cError;
+
+    fn write_read(
+        &mut self,
+        address: u8,
+        bytes: &[u8],
+        buffer: &mut [u8],
+    ) -> Result<(), Self::Error> {
+        WriteRead::write_read(&mut *self.lock(), address, bytes, buffer)
+    }
+}
+
+// embedded-hal 1.0
+
+impl ErrorType for SharedI2c {
+    type Error = I2cError;
+}
+
+impl I2c<SevenBitAddress> for SharedI2c {
+    fn transaction(
+        &mut self,
+        address: u8,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), Self::Error> {
+        I2c::transaction(&mut *self.lock(), address, operations)
+    }
+}
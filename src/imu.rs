@@ -0,0 +1,228 @@
+use esp_idf_svc::hal::i2c::I2cError;
+use mpu6886::device::{AccelRange, GyroRange, CONFIG, GYRO_CONFIG, PWR_MGMT_1, PWR_MGMT_2};
+use mpu6886::{Mpu6886, Mpu6886Error};
+
+use log::*;
+
+use crate::i2c_bus::SharedI2c;
+use crate::settings::Settings;
+
+/// The MPU6886 on the shared i2c0 bus
+pub type Imu = Mpu6886<SharedI2c>;
+
+/// Register 29, accelerometer DLPF: `ACCEL_FCHOICE_B` in bit 3, `A_DLPF_CFG` in bits 2:0
+const ACCEL_CONFIG2: u8 = 0x1d;
+const ACCEL_FCHOICE_B: u8 = 3;
+const A_DLPF_CFG_BIT: u8 = 2;
+/// `DEC2_CFG` in bits 5:4, samples averaged per reading in low-power mode (0 = 4 samples)
+const DEC2_CFG_BIT: u8 = 5;
+/// Register 25, in low-power mode the accelerometer wakes at 1kHz / (1 + divider)
+const SMPLRT_DIV: u8 = 0x19;
+/// `STBY_XG`, `STBY_YG` and `STBY_ZG`
+const GYRO_STANDBY_AXES: u8 = 0x07;
+
+/// Maps the sensor's axes onto the enclosure's: row `i` holds the weights of the sensor's
+/// x, y and z in output axis `i`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AxisRemap([[f32; 3]; 3]);
+
+impl Default for AxisRemap {
+    fn default() -> Self {
+        Self([[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]])
+    }
+}
+
+impl AxisRemap {
+    /// Either the sensor axis for each output axis, e.g. `"y, -x, z"` for a board mounted
+    /// rotated by 90°, or all nine matrix elements row by row
+    pub fn parse(remap: &str) -> Option<Self> {
+        let parts: Vec<&str> = remap.split(',').map(str::trim).collect();
+        let mut matrix = [[0.0; 3]; 3];
+
+        match parts.len() {
+            3 => {
+                for (row, part) in matrix.iter_mut().zip(&parts) {
+                    let (sign, axis) = match part.strip_prefix('-') {
+                        Some(axis) => (-1.0, axis),
+                        None => (1.0, part.strip_prefix('+').unwrap_or(part)),
+                    };
+                    let column = ["x", "y", "z"].iter().position(|name| *name == axis)?;
+                    row[column] = sign;
+                }
+            }
+            9 => {
+                for (i, part) in parts.iter().enumerate() {
+                    matrix[i / 3][i % 3] = part.parse().ok()?;
+                }
+            }
+            _ => return None,
+        }
+
+        Some(Self(matrix))
+    }
+
+    pub fn apply(&self, v: [f32; 3]) -> [f32; 3] {
+        self.0
+            .map(|row| row[0] * v[0] + row[1] * v[1] + row[2] * v[2])
+    }
+}
+
+/// Gyro reading in rad/s, calibrated and in the enclosure's axes; zero while the gyro is off
+/// in low-power mode
+pub fn gyro(mpu: &mut Imu, settings: &Settings) -> Result<[f32; 3], Mpu6886Error<I2cError>> {
+    if settings.low_power() {
+        return Ok([0.0; 3]);
+    }
+
+    let gyro = mpu.get_gyro()?;
+    let bias = gyro_bias(mpu, settings)?;
+
+    Ok(settings
+        .axis_remap()
+        .apply([gyro.x - bias[0], gyro.y - bias[1], gyro.z - bias[2]]))
+}
+
+/// Gyro offsets in the sensor's axes, at the current chip temperature if there is a
+/// temperature model
+pub fn gyro_bias(mpu: &mut Imu, settings: &Settings) -> Result<[f32; 3], Mpu6886Error<I2cError>> {
+    let offsets = settings.offsets().gyro;
+
+    match settings.temp_model() {
+        Some(model) => Ok(model.bias(offsets, mpu.get_temp()?)),
+        None => Ok(offsets),
+    }
+}
+
+/// Acceleration in g, calibrated and in the enclosure's axes
+pub fn acc(mpu: &mut Imu, settings: &Settings) -> Result<[f32; 3], Mpu6886Error<I2cError>> {
+    let acc = mpu.get_acc()?;
+    let offsets = settings.offsets().acc;
+
+    Ok(settings
+        .axis_remap()
+        .apply([acc.x - offsets[0], acc.y - offsets[1], acc.z - offsets[2]]))
+}
+
+/// Accelerometer full-scale range for ±`g`
+pub fn accel_range(g: u8) -> Option<AccelRange> {
+    Some(match g {
+        2 => AccelRange::G2,
+        4 => AccelRange::G4,
+        8 => AccelRange::G8,
+        16 => AccelRange::G16,
+        _ => return None,
+    })
+}
+
+/// Gyro full-scale range for ±`dps` degrees per second
+pub fn gyro_range(dps: u16) -> Option<GyroRange> {
+    Some(match dps {
+        250 => GyroRange::D250,
+        500 => GyroRange::D500,
+        1000 => GyroRange::D1000,
+        2000 => GyroRange::D2000,
+        _ => return None,
+    })
+}
+
+/// `DLPF_CFG` for a gyro low-pass bandwidth in Hz
+pub fn gyro_dlpf(hz: u16) -> Option<u8> {
+    Some(match hz {
+        250 => 0,
+        176 => 1,
+        92 => 2,
+        41 => 3,
+        20 => 4,
+        10 => 5,
+        5 => 6,
+        _ => return None,
+    })
+}
+
+/// `A_DLPF_CFG` for an accelerometer low-pass bandwidth in Hz
+pub fn accel_dlpf(hz: u16) -> Option<u8> {
+    Some(match hz {
+        218 => 1,
+        99 => 2,
+        45 => 3,
+        21 => 4,
+        10 => 5,
+        5 => 6,
+        _ => return None,
+    })
+}
+
+/// Programs ranges and low-pass filters from the settings into the sensor; `mpu.init` always
+/// starts out at ±2g and ±250dps
+pub fn configure(mpu: &mut Imu, settings: &Settings) -> Result<(), String> {
+    let accel_g = settings.accel_range_g();
+    let gyro_dps = settings.gyro_range_dps();
+
+    let accel = accel_range(accel_g).ok_or_else(|| format!("unsupported range ±{accel_g}g"))?;
+    let gyro = gyro_range(gyro_dps).ok_or_else(|| format!("unsupported range ±{gyro_dps}dps"))?;
+
+    mpu.set_accel_range(accel).map_err(|e| format!("{e:?}"))?;
+    mpu.set_gyro_range(gyro).map_err(|e| format!("{e:?}"))?;
+    info!("Sensor ranges: ±{accel_g}g, ±{gyro_dps}dps");
+
+    let gyro_hz = settings.gyro_dlpf_hz();
+    let accel_hz = settings.accel_dlpf_hz();
+
+    let gyro_cfg =
+        gyro_dlpf(gyro_hz).ok_or_else(|| format!("unsupported gyro DLPF {gyro_hz}Hz"))?;
+    let accel_cfg =
+        accel_dlpf(accel_hz).ok_or_else(|| format!("unsupported accel DLPF {accel_hz}Hz"))?;
+
+    // FCHOICE_B = 0 puts the DLPF in the signal path
+    write_bits(
+        mpu,
+        GYRO_CONFIG::ADDR,
+        GYRO_CONFIG::FCHOICE_B.bit,
+        GYRO_CONFIG::FCHOICE_B.length,
+        0,
+    )?;
+    write_bits(
+        mpu,
+        CONFIG::ADDR,
+        CONFIG::DLPF_CFG.bit,
+        CONFIG::DLPF_CFG.length,
+        gyro_cfg,
+    )?;
+    write_bits(mpu, ACCEL_CONFIG2, ACCEL_FCHOICE_B, 1, 0)?;
+    write_bits(mpu, ACCEL_CONFIG2, A_DLPF_CFG_BIT, 3, accel_cfg)?;
+    info!("Sensor low-pass filters: gyro {gyro_hz}Hz, accel {accel_hz}Hz");
+
+    set_power_mode(mpu, settings)
+}
+
+/// Either everything running, or only the accelerometer, sampled in cycle mode at
+/// `low_power_accel_hz` with the gyro and temperature sensor off
+fn set_power_mode(mpu: &mut Imu, settings: &Settings) -> Result<(), String> {
+    if settings.low_power() {
+        let hz = settings.low_power_hz().clamp(4, 500);
+        let divider = (1000 / hz - 1) as u8;
+
+        write_byte(mpu, SMPLRT_DIV, divider)?;
+        write_bits(mpu, ACCEL_CONFIG2, DEC2_CFG_BIT, 2, 0)?;
+        write_byte(mpu, PWR_MGMT_2::ADDR, GYRO_STANDBY_AXES)?;
+        write_bits(mpu, PWR_MGMT_1::ADDR, PWR_MGMT_1::TEMP_DIS, 1, 1)?;
+        write_bits(mpu, PWR_MGMT_1::ADDR, PWR_MGMT_1::CYCLE, 1, 1)?;
+        info!("Sensor in low-power mode: accelerometer only at {hz}Hz");
+    } else {
+        write_bits(mpu, PWR_MGMT_1::ADDR, PWR_MGMT_1::CYCLE, 1, 0)?;
+        write_bits(mpu, PWR_MGMT_1::ADDR, PWR_MGMT_1::TEMP_DIS, 1, 0)?;
+        write_byte(mpu, PWR_MGMT_2::ADDR, 0)?;
+        write_byte(mpu, SMPLRT_DIV, 0)?;
+    }
+
+    Ok(())
+}
+
+fn write_byte(mpu: &mut Imu, reg: u8, byte: u8) -> Result<(), String> {
+    mpu.write_byte(reg, byte).map_err(|e| format!("{e:?}"))
+}
+
+fn write_bits(mpu: &mut Imu, reg: u8, bit: u8, length: u8, data: u8) -> Result<(), String> {
+    mpu.write_bits(reg, bit, length, data)
+        .map_err(|e| format!("{e:?}"))
+}
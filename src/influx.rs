@@ -0,0 +1,69 @@
+use core::fmt::Write;
+
+use serde_json::Value;
+
+use crate::clock::Stamp;
+use crate::telemetry::Signal;
+
+/// One round of readings as an InfluxDB line, e.g.
+/// `imu,device=m5stack seq=42i,acc_x=0.01,acc_y=-0.02,acc_z=1,acc_range_g=8i 1718000000123000000`.
+/// The timestamp is in ns and left out while the clock isn't set, so the server assigns one.
+pub fn line(
+    measurement: &str,
+    client_id: &str,
+    seq: u32,
+    stamp: Stamp,
+    signals: &[Signal],
+) -> Vec<u8> {
+    let mut line = format!(
+        "{},device={} seq={seq}i",
+        escape(measurement, ", "),
+        escape(client_id, ", =")
+    );
+
+    for signal in signals {
+        let field = escape(signal.field, ", =");
+
+        if let Value::String(text) = &signal.value {
+            let text = text.replace('\\', "\\\\").replace('"', "\\\"");
+            let _ = write!(line, ",{field}=\"{text}\"");
+            continue;
+        }
+
+        let names = signal.axis_names();
+        for (i, value) in signal.axes.iter().enumerate() {
+            let _ = match (signal.axes.len(), names.get(i)) {
+                (1, _) => write!(line, ",{field}={value}"),
+                (_, Some(name)) => write!(line, ",{field}_{name}={value}"),
+                (_, None) => write!(line, ",{field}_{i}={value}"),
+            };
+        }
+
+        if let Some((name, range)) = signal.range {
+            let _ = write!(line, ",{name}={range}i");
+        }
+    }
+
+    if let Some(ts) = stamp.ts {
+        let _ = write!(line, " {}", ts * 1_000_000);
+    }
+
+    line.into_bytes()
+}
+
+/// Several lines as one message
+pub fn join(lines: &[Vec<u8>]) -> Vec<u8> {
+    lines.join(&b'\n')
+}
+
+/// Backslash-escapes the characters line protocol gives a meaning in this position
+fn escape(text: &str, special: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if special.contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
@@ -0,0 +1,103 @@
+use std::sync::Mutex;
+
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+
+use log::*;
+
+/// A job execution for this thing, as AWS IoT Jobs hands it out
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Execution {
+    pub job_id: String,
+    /// "QUEUED", or "IN_PROGRESS" if the device took it up before, e.g. ahead of a reboot
+    pub status: String,
+    #[serde(default)]
+    pub job_document: Value,
+}
+
+/// Payload of `.../jobs/notify-next` and `.../jobs/$next/get/accepted`
+#[derive(Deserialize)]
+struct NextDocument {
+    #[serde(default)]
+    execution: Option<Execution>,
+}
+
+/// AWS IoT Jobs over MQTT, one execution at a time: the next pending execution is announced on
+/// `notify-next` and fetched once per session with `$next/get`
+pub struct Jobs {
+    prefix: String,
+    notify_next_topic: String,
+    get_next_topic: String,
+    get_next_accepted_topic: String,
+    pending: Mutex<Option<Execution>>,
+}
+
+impl Jobs {
+    pub fn new(thing_name: &str) -> Self {
+        let prefix = format!("$aws/things/{thing_name}/jobs");
+
+        Self {
+            notify_next_topic: format!("{prefix}/notify-next"),
+            get_next_topic: format!("{prefix}/$next/get"),
+            get_next_accepted_topic: format!("{prefix}/$next/get/accepted"),
+            prefix,
+            pending: Mutex::new(None),
+        }
+    }
+
+    pub fn subscribe_topics(&self) -> [&str; 2] {
+        [&self.notify_next_topic, &self.get_next_accepted_topic]
+    }
+
+    /// Publishing an empty message here makes AWS IoT answer on `$next/get/accepted`
+    pub fn get_next_topic(&self) -> &str {
+        &self.get_next_topic
+    }
+
+    /// Stores the execution announced on one of the jobs topics; returns `false` for unrelated
+    /// topics
+    pub fn handle_message(&self, topic: &str, data: &[u8]) -> bool {
+        if topic != self.notify_next_topic && topic != self.get_next_accepted_topic {
+            return false;
+        }
+
+        match serde_json::from_slice::<NextDocument>(data) {
+            Ok(NextDocument {
+                execution: Some(execution),
+            }) => {
+                info!("Job \"{}\" is {}", execution.job_id, execution.status);
+                *self.pending.lock().unwrap() = Some(execution);
+            }
+            Ok(_) => (),
+            Err(e) => warn!("Malformed job execution on \"{topic}\": {e}"),
+        }
+
+        true
+    }
+
+    /// The execution to work on next, if one came in since the last call
+    pub fn take_pending(&self) -> Option<Execution> {
+        self.pending.lock().unwrap().take()
+    }
+
+    /// Topic and payload updating the status of a job execution, e.g. to "IN_PROGRESS" or
+    /// "SUCCEEDED", with details for the console such as `[("progress", "42%")]`
+    pub fn update(
+        &self,
+        job_id: &str,
+        status: &str,
+        details: &[(&str, String)],
+    ) -> (String, String) {
+        // The Jobs API only takes string values in the details
+        let details: Map<String, Value> = details
+            .iter()
+            .map(|(key, value)| (key.to_string(), Value::from(value.as_str())))
+            .collect();
+
+        (
+            format!("{}/{job_id}/update", self.prefix),
+            json!({ "status": status, "statusDetails": details }).to_string(),
+        )
+    }
+}
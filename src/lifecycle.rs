@@ -0,0 +1,72 @@
+use std::sync::Mutex;
+use std::time::Duration;
+
+use esp_idf_svc::sys::{
+    esp, esp_ota_get_running_partition, esp_ota_get_state_partition, esp_ota_img_states_t,
+    esp_ota_img_states_t_ESP_OTA_IMG_PENDING_VERIFY, esp_ota_mark_app_invalid_rollback_and_reboot,
+    esp_ota_mark_app_valid_cancel_rollback, EspError,
+};
+use esp_idf_svc::timer::{EspTaskTimerService, EspTimer};
+
+use log::*;
+
+/// Keeps a freshly installed firmware on probation: unless [`Lifecycle::mark_app_valid`] is
+/// called within the confirmation window, e.g. because it never reaches the broker, the device
+/// reboots and the bootloader rolls back to the previous one. Without a pending update, and
+/// when booting from a serial flash, there is nothing to confirm.
+pub struct Lifecycle {
+    /// Fires the rollback; dropped, and with that cancelled, once the firmware is confirmed
+    rollback: Mutex<Option<EspTimer<'static>>>,
+}
+
+impl Lifecycle {
+    pub fn start(timer_service: &EspTaskTimerService, window: Duration) -> Result<Self, EspError> {
+        if !pending_verify() {
+            return Ok(Self {
+                rollback: Mutex::new(None),
+            });
+        }
+
+        warn!(
+            "Running a new firmware, rolling back unless confirmed within {}s",
+            window.as_secs()
+        );
+        let rollback = timer_service.timer(|| {
+            error!("New firmware not confirmed in time, rolling back");
+            unsafe { esp_ota_mark_app_invalid_rollback_and_reboot() };
+        })?;
+        rollback.after(window)?;
+
+        Ok(Self {
+            rollback: Mutex::new(Some(rollback)),
+        })
+    }
+
+    /// Whether the running firmware still waits for confirmation
+    pub fn is_pending(&self) -> bool {
+        self.rollback.lock().unwrap().is_some()
+    }
+
+    /// Keeps the running firmware for good; does nothing if it needs no confirmation
+    pub fn mark_app_valid(&self) {
+        let mut rollback = self.rollback.lock().unwrap();
+        if rollback.is_none() {
+            return;
+        }
+
+        match esp!(unsafe { esp_ota_mark_app_valid_cancel_rollback() }) {
+            Ok(()) => {
+                info!("New firmware confirmed");
+                *rollback = None;
+            }
+            Err(e) => error!("Failed to confirm the new firmware: {e}"),
+        }
+    }
+}
+
+fn pending_verify() -> bool {
+    let mut state: esp_ota_img_states_t = 0;
+    let res = unsafe { esp_ota_get_state_partition(esp_ota_get_running_partition(), &mut state) };
+
+    esp!(res).is_ok() && state == esp_ota_img_states_t_ESP_OTA_IMG_PENDING_VERIFY
+}
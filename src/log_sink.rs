@@ -0,0 +1,179 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use esp_idf_svc::log::EspLogger;
+use serde::Serialize;
+use serde_json::json;
+
+use log::*;
+
+use crate::clock;
+use crate::Config;
+
+/// Records kept between two batches; once full, new records are counted as dropped
+const MAX_QUEUED: usize = 100;
+/// Records per published batch, the rest waits for the next one
+const MAX_BATCH: usize = 20;
+const MAX_MESSAGE_LEN: usize = 200;
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+static SINK: LogSink = LogSink;
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static LEVEL: AtomicUsize = AtomicUsize::new(LevelFilter::Info as usize);
+static QUEUE: Mutex<Queue> = Mutex::new(Queue {
+    records: VecDeque::new(),
+    dropped: 0,
+    rate_per_min: 0,
+    window_start: None,
+    window_count: 0,
+});
+
+#[derive(Serialize)]
+struct Entry {
+    uptime_ms: u64,
+    level: &'static str,
+    target: String,
+    message: String,
+}
+
+struct Queue {
+    records: VecDeque<Entry>,
+    /// Records lost to the rate limit or a full queue since the last batch
+    dropped: u32,
+    /// 0 disables the limit
+    rate_per_min: u32,
+    window_start: Option<Instant>,
+    window_count: u32,
+}
+
+impl Queue {
+    fn push(&mut self, entry: Entry) {
+        if self.rate_per_min > 0 {
+            if self
+                .window_start
+                .map_or(true, |start| start.elapsed() >= RATE_WINDOW)
+            {
+                self.window_start = Some(Instant::now());
+                self.window_count = 0;
+            }
+            if self.window_count >= self.rate_per_min {
+                self.dropped += 1;
+                return;
+            }
+            self.window_count += 1;
+        }
+
+        if self.records.len() >= MAX_QUEUED {
+            self.dropped += 1;
+            return;
+        }
+        self.records.push_back(entry);
+    }
+}
+
+/// Writes to the console like `EspLogger` and, while streaming is on, also queues the record to
+/// be published. Only covers the Rust `log` records, not the ESP-IDF C components' output.
+struct LogSink;
+
+impl Log for LogSink {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        EspLogger.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        EspLogger.log(record);
+
+        if !ENABLED.load(Ordering::Relaxed)
+            || record.level() as usize > LEVEL.load(Ordering::Relaxed)
+        {
+            return;
+        }
+
+        let mut message = record.args().to_string();
+        if message.len() > MAX_MESSAGE_LEN {
+            let mut end = MAX_MESSAGE_LEN;
+            while !message.is_char_boundary(end) {
+                end -= 1;
+            }
+            message.truncate(end);
+        }
+
+        let entry = Entry {
+            uptime_ms: clock::uptime_us() / 1000,
+            level: record.level().as_str(),
+            target: record.target().to_string(),
+            message,
+        };
+
+        // A record logged while the queue is being drained is dropped rather than waited for
+        if let Ok(mut queue) = QUEUE.try_lock() {
+            queue.push(entry);
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Replaces `EspLogger::initialize_default`, streaming starts as `log_stream` says
+pub fn initialize(app_config: &Config) {
+    log::set_logger(&SINK)
+        .map(|()| EspLogger.initialize())
+        .unwrap();
+
+    QUEUE.lock().unwrap().rate_per_min = app_config.log_stream_rate;
+
+    let level = app_config.log_stream_level.parse().unwrap_or_else(|_| {
+        warn!(
+            "Unknown log_stream_level \"{}\", using info",
+            app_config.log_stream_level
+        );
+        LevelFilter::Info
+    });
+    set_streaming(app_config.log_stream, Some(level));
+}
+
+pub fn streaming() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+pub fn level() -> LevelFilter {
+    match LEVEL.load(Ordering::Relaxed) {
+        0 => LevelFilter::Off,
+        1 => LevelFilter::Error,
+        2 => LevelFilter::Warn,
+        3 => LevelFilter::Info,
+        4 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    }
+}
+
+/// Turns streaming on or off, optionally changing the level; turning it off drops what's queued
+pub fn set_streaming(enabled: bool, level: Option<LevelFilter>) {
+    if let Some(level) = level {
+        LEVEL.store(level as usize, Ordering::Relaxed);
+    }
+    ENABLED.store(enabled, Ordering::Relaxed);
+
+    if !enabled {
+        let mut queue = QUEUE.lock().unwrap();
+        queue.records.clear();
+        queue.dropped = 0;
+    }
+}
+
+/// `{"records": [{"uptime_ms": 81234, "level": "WARN", "target": "iot_tokuron::mqtt",
+/// "message": ".."}, ..], "dropped": 0}`, or `None` when there is nothing to publish
+pub fn take_batch() -> Option<String> {
+    let mut queue = QUEUE.lock().unwrap();
+    if queue.records.is_empty() && queue.dropped == 0 {
+        return None;
+    }
+
+    let count = queue.records.len().min(MAX_BATCH);
+    let records: Vec<Entry> = queue.records.drain(..count).collect();
+    let dropped = std::mem::take(&mut queue.dropped);
+
+    Some(json!({ "records": records, "dropped": dropped }).to_string())
+}
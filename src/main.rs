@@ -2,33 +2,168 @@ use core::pin::pin;
 use core::slice;
 use core::time::Duration;
 use std::mem;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
 
 use embassy_futures::select::{select, Either};
 
 use esp_idf_svc::eventloop::EspSystemEventLoop;
 use esp_idf_svc::hal::delay::Delay;
-use esp_idf_svc::hal::i2c::{I2cConfig, I2cDriver};
-use esp_idf_svc::hal::modem::Modem;
 use esp_idf_svc::hal::peripherals::Peripherals;
-use esp_idf_svc::hal::prelude::*;
+use esp_idf_svc::http::server::EspHttpServer;
 use esp_idf_svc::mqtt::client::*;
 use esp_idf_svc::nvs::EspDefaultNvsPartition;
+use esp_idf_svc::ota::EspOta;
+use esp_idf_svc::sntp::EspSntp;
 use esp_idf_svc::sys::EspError;
-use esp_idf_svc::timer::{EspAsyncTimer, EspTaskTimerService, EspTimerService};
+use esp_idf_svc::timer::{EspAsyncTimer, EspTimerService};
 use esp_idf_svc::tls::X509;
-use esp_idf_svc::wifi::*;
+use esp_idf_svc::wifi::EspWifi;
 
 use esp_idf_svc::hal::{
     gpio::{InterruptType, PinDriver, Pull},
     task::notification::Notification,
 };
 use mpu6886::Mpu6886;
+use serde::Serialize;
 use std::num::NonZeroU32;
 
 use log::*;
 
 use anyhow::Result;
 
+mod activity;
+mod adc;
+mod ahrs;
+mod barometer;
+mod batch;
+mod battery;
+mod boot;
+mod build_info;
+mod button;
+mod calibration;
+mod cert_info;
+mod cert_rotation;
+mod clock;
+mod commands;
+mod compression;
+mod control;
+mod crash;
+mod credentials;
+mod decimate;
+mod defender;
+mod delta;
+mod diagnostics;
+mod dsp;
+mod encoding;
+mod envelope;
+mod espnow_relay;
+mod exception;
+mod factory_reset;
+mod fifo;
+mod freefall;
+mod gestures;
+mod greengrass;
+mod heartbeat;
+mod i2c_bus;
+mod imu;
+mod influx;
+mod jobs;
+mod lifecycle;
+mod log_sink;
+mod memory;
+mod metadata;
+mod motion;
+mod mqtt;
+mod net_stats;
+mod offline_buffer;
+mod orientation;
+mod ota;
+mod pedometer;
+mod power;
+mod power_policy;
+mod power_profile;
+#[cfg(feature = "protobuf")]
+mod proto;
+#[cfg(feature = "fleet-provisioning")]
+mod provisioning;
+mod quiet_hours;
+mod rate_limit;
+mod reachability;
+mod remote_config;
+mod selection;
+mod self_test;
+mod senml;
+mod sensor;
+mod sensor_health;
+mod sequence;
+mod settings;
+mod shadow;
+mod sht30;
+mod signing;
+mod sitewise;
+#[cfg(feature = "protobuf")]
+mod sparkplug;
+mod status_server;
+mod telemetry;
+mod topics;
+mod units;
+mod watchdog;
+mod wifi;
+
+use activity::ActivityMonitor;
+use ahrs::{Ahrs, AhrsMode};
+use batch::Batcher;
+use boot::Boot;
+use build_info::{BuildInfo, BUILD_INFO};
+use button::Button;
+use commands::{CommandContext, Dispatcher};
+use control::{Buzzer, Control, StatusLed};
+use credentials::{CredentialStore, Credentials};
+use decimate::{Decimation, Decimator};
+use defender::Defender;
+use diagnostics::DiagnosticsState;
+use encoding::{Encoding, TelemetryEncoder};
+use envelope::Envelope;
+use espnow_relay::{EspNowReceiver, EspNowSender};
+use exception::ReportByException;
+use freefall::FreeFallDetector;
+use gestures::GestureDetector;
+use i2c_bus::SharedI2c;
+use imu::Imu;
+use jobs::Jobs;
+use lifecycle::Lifecycle;
+use memory::MemoryReport;
+use metadata::Metadata;
+use motion::MotionWake;
+use mqtt::{
+    mqtt_create, Backoff, Broker, MessageKind, MqttAuth, PubAcks, PublishContext, Publisher,
+    Reassembler, Subscriptions, OFFLINE_PAYLOAD,
+};
+use net_stats::{NetReport, NetStats};
+use offline_buffer::{BufferedMessage, OfflineBuffer};
+use orientation::Orientation;
+use pedometer::Pedometer;
+use power_profile::PowerProfile;
+use quiet_hours::QuietHours;
+use rate_limit::RateLimiter;
+use remote_config::RemoteConfig;
+use selection::Selection;
+use self_test::{SelfTest, SelfTestReport};
+use sensor::Registry;
+use sensor_health::SensorHealth;
+use sequence::Sequence;
+use settings::Settings;
+use shadow::Shadow;
+use signing::Signer;
+#[cfg(feature = "protobuf")]
+use sparkplug::Sparkplug;
+use topics::Topics;
+use wifi::{wifi_create, PowerSave};
+
+/// How often pending commands are picked up while waiting for the next publish
+const COMMAND_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
 #[toml_cfg::toml_config]
 pub struct Config {
     #[default("")]
@@ -39,13 +174,462 @@ pub struct Config {
     aws_iot_endpoint: &'static str,
     #[default("")]
     aws_iot_client_id: &'static str,
+    /// One of `aws`, `generic` (Mosquitto, EMQX, ...) or `soracom` (Beam/Funnel). Other than
+    /// AWS, `aws_iot_endpoint` may be an `mqtt://` URL, the broker is verified against the
+    /// certificate bundle instead of the Amazon root CA, no client certificate is sent, and
+    /// shadow, Device Defender, basic ingest, Greengrass and fleet provisioning are left out.
+    /// Authenticates with `mqtt_username` when set, anonymously otherwise. For `soracom` the
+    /// endpoint defaults to Beam; use `mqtt://funnel.soracom.io:1883` for Funnel.
+    #[default("aws")]
+    broker: &'static str,
+    /// Username for brokers without mutual TLS, e.g. a local test broker; when set, the client
+    /// certificate is not used. AWS IoT custom authorizers also need `mqtt_authorizer`.
+    #[default("")]
+    mqtt_username: &'static str,
+    #[default("")]
+    mqtt_password: &'static str,
+    /// Name of the AWS IoT custom authorizer checking the username and password
+    #[default("")]
+    mqtt_authorizer: &'static str,
+    /// Telemetry topic; this and the other topic templates may use `{client_id}`, `{thing_name}` and `{kind}`
     #[default("")]
     aws_iot_topic: &'static str,
+    /// Topic template for status messages, `{kind}` is e.g. `status` or `net-stats`
+    #[default("{client_id}/{kind}")]
+    status_topic: &'static str,
+    /// Root of the command topics
+    #[default("{client_id}/cmd")]
+    command_topic: &'static str,
+    /// Topic for buzzer and status LED messages
+    #[default("{client_id}/control")]
+    control_topic: &'static str,
+    /// Retained runtime configuration, see `remote_config`
+    #[default("{client_id}/config")]
+    config_topic: &'static str,
+    /// Topic for discrete events such as gestures
+    #[default("{client_id}/events")]
+    events_topic: &'static str,
+    /// MAC of a relay device used when WiFi is unreachable, e.g. `aa:bb:cc:dd:ee:ff` (empty disables the fallback)
+    #[default("")]
+    espnow_peer: &'static str,
+    /// WiFi channel of the relay's AP, which the fallback sends on
+    #[default(1)]
+    espnow_channel: u8,
+    /// How long the fallback runs before WiFi is tried again
+    #[default(600)]
+    espnow_retry_secs: u64,
+    /// Forward ESP-NOW frames from nearby devices to AWS IoT
+    #[default(false)]
+    espnow_relay: bool,
+    /// Comma-separated MACs of the devices whose frames are relayed; frames from any other MAC
+    /// are dropped. Each is published under the telemetry topic as `<kind>/<mac>`.
+    #[default("")]
+    espnow_relay_peers: &'static str,
+    #[default(300)]
+    net_stats_interval_secs: u64,
+    /// How often to publish a heartbeat, independent of the sensor (0 disables it)
+    #[default(60)]
+    heartbeat_interval_secs: u64,
+    /// How often to send AWS IoT Device Defender metrics, 0 disables them; AWS throttles
+    /// reports sent more often than every 300s
+    #[default(0)]
+    defender_interval_secs: u64,
+    /// One of `none`, `min_modem` or `max_modem`
+    #[default("none")]
+    wifi_power_save: &'static str,
+    /// Start the diagnostics SoftAP after MQTT has been unreachable for this long (0 disables it)
+    #[default(5)]
+    diagnostics_ap_after_mins: u64,
+    #[default("iot-tokuron-diag")]
+    diagnostics_ap_ssid: &'static str,
+    /// Empty for an open AP; the page is read-only
+    #[default("")]
+    diagnostics_ap_password: &'static str,
+    /// QoS (0 or 1) used for each kind of message
+    #[default(0)]
+    telemetry_qos: u8,
+    #[default(1)]
+    alert_qos: u8,
+    #[default(1)]
+    status_qos: u8,
+    /// Duty-cycled operation: after each publish round, spend this long in deep sleep instead of
+    /// staying connected (0 disables it). Unsent messages are kept in RTC memory meanwhile.
+    #[default(0)]
+    deep_sleep_secs: u64,
+    /// Messages kept in RAM while offline
+    #[default(32)]
+    offline_buffer_len: usize,
+    /// Additional messages spilled to NVS once RAM is full (0 disables spilling)
+    #[default(0)]
+    offline_buffer_nvs_len: u32,
+    /// Thing name used for the device shadow; defaults to the client ID when empty
+    #[default("")]
+    aws_iot_thing_name: &'static str,
+    /// Initial publish interval, can be changed later through the shadow
+    #[default(2)]
+    publish_interval_secs: u32,
+    /// Initial interval for sampling into the offline buffer while MQTT is down
+    #[default(3)]
+    sample_interval_secs: u32,
+    /// Sample the sensor at this rate and publish mean/min/max of the samples every publish
+    /// interval (0 takes one reading per publish)
+    #[default(0)]
+    sample_hz: u32,
+    /// Statistics published per axis for the samples taken at `sample_hz`: any of mean, min,
+    /// max and stddev
+    #[default("mean,min,max")]
+    aggregate_stats: &'static str,
+    /// Length of an aggregation window; 0 aggregates over each publish interval
+    #[default(0)]
+    aggregate_window_secs: u64,
+    /// Publish the samples themselves next to their statistics
+    #[default(false)]
+    aggregate_include_raw: bool,
+    /// Publish gyro, accel and temp on `{kind}` topics of their own instead of one `imu` message
+    #[default(false)]
+    split_telemetry: bool,
+    /// AWS IoT rule to send telemetry to through basic ingest (`$aws/rules/<rule>/<topic>`), which
+    /// avoids messaging charges; leave empty to use the normal topic, e.g. for the MQTT test client
+    #[default("")]
+    basic_ingest_rule: &'static str,
+    /// Fleet provisioning template used with the `fleet-provisioning` feature
+    #[default("")]
+    aws_iot_provisioning_template: &'static str,
+    /// Connect to a Greengrass core found via the discovery API, falling back to `aws_iot_endpoint`
+    #[default(false)]
+    greengrass_discovery: bool,
+    /// Telemetry samples per published JSON array (1 disables batching)
+    #[default(1)]
+    batch_size: usize,
+    /// Publish an incomplete batch once its first sample is this old (0 disables it)
+    #[default(0)]
+    batch_max_secs: u64,
+    /// Publish the pending batch before any alert
+    #[default(true)]
+    batch_flush_on_alert: bool,
+    /// Publishes per second allowed on average (0 disables the limit); AWS IoT allows 100 per connection
+    #[default(0)]
+    publish_rate_limit: u32,
+    /// Publishes allowed back to back before the rate limit kicks in
+    #[default(10)]
+    publish_burst: u32,
+    /// Drop telemetry over the rate limit instead of waiting; other messages always wait
+    #[default(true)]
+    publish_rate_drop: bool,
+    /// Accelerometer full-scale range: 2, 4, 8 or 16 (±g)
+    #[default(2)]
+    accel_range_g: u8,
+    /// Gyro full-scale range: 250, 500, 1000 or 2000 (±degrees per second)
+    #[default(250)]
+    gyro_range_dps: u16,
+    /// Gyro low-pass filter bandwidth: 250, 176, 92, 41, 20, 10 or 5 (Hz)
+    #[default(250)]
+    gyro_dlpf_hz: u16,
+    /// Accelerometer low-pass filter bandwidth: 218, 99, 45, 21, 10 or 5 (Hz)
+    #[default(218)]
+    accel_dlpf_hz: u16,
+    /// GPIOs of the status LED's color channels (-1 if not connected)
+    #[default(-1)]
+    led_red_gpio: i32,
+    #[default(-1)]
+    led_green_gpio: i32,
+    #[default(-1)]
+    led_blue_gpio: i32,
+    /// Publish only once the device moved (or `motion_max_idle_secs` passed) instead of every
+    /// publish interval
+    #[default(false)]
+    motion_wake: bool,
+    /// Change in acceleration on any axis that counts as motion, in 4mg steps up to 1020mg
+    #[default(100)]
+    motion_threshold_mg: u16,
+    /// GPIO wired to the sensor's INT pin, which then wakes the chip from light sleep (-1 if
+    /// not connected, the interrupt is polled over I2C then). With `deep_sleep_secs`, an RTC GPIO
+    /// (0-21) here wakes the chip from deep sleep too, and the timer falls back to
+    /// `motion_max_idle_secs`.
+    #[default(-1)]
+    motion_int_gpio: i32,
+    /// Longest time without a publish while the device lies still
+    #[default(3600)]
+    motion_max_idle_secs: u64,
+    /// Publish an `orientation` fused from gyro and accelerometer: "off", "alongside" the raw
+    /// readings or "only" instead of them
+    #[default("off")]
+    ahrs_mode: &'static str,
+    /// Madgwick filter gain, e.g. 0.1; higher follows the accelerometer faster but is noisier
+    #[default(0.1)]
+    ahrs_beta: f32,
+    /// How often the filter is updated between publishes
+    #[default(50)]
+    ahrs_hz: u32,
+    /// Time constant of the complementary filter publishing smoothed roll/pitch `angles`
+    /// (0 disables it)
+    #[default(0)]
+    angles_tau_ms: u32,
+    /// How often the complementary filter is updated between publishes
+    #[default(50)]
+    angles_hz: u32,
+    /// Publish `tap`, `double_tap` and `shake` events detected from the accelerometer
+    #[default(false)]
+    gestures: bool,
+    /// Deviation from 1g of a short peak that counts as a tap
+    #[default(1.5)]
+    tap_threshold_g: f32,
+    /// Deviation from 1g of the repeated peaks that count as shaking
+    #[default(1.0)]
+    shake_threshold_g: f32,
+    /// How long the acceleration has to stay below `freefall_threshold_g` to raise a free fall
+    /// alert (0 disables it)
+    #[default(0)]
+    freefall_ms: u64,
+    #[default(0.3)]
+    freefall_threshold_g: f32,
+    /// Count steps and publish today's total on the `steps` telemetry topic
+    #[default(false)]
+    pedometer: bool,
+    /// Smoothed acceleration magnitude a footfall has to reach
+    #[default(1.2)]
+    step_threshold_g: f32,
+    #[default(60)]
+    pedometer_publish_secs: u64,
+    /// How often RMS and spectrum band energies of a 256-sample capture are published on the
+    /// `vibration` telemetry topic (0 disables it); needs a gyro DLPF below 250Hz
+    #[default(0)]
+    vibration_interval_secs: u64,
+    #[default(1000)]
+    vibration_rate_hz: u32,
+    /// Number of equally wide bands up to half the sample rate
+    #[default(8)]
+    vibration_bands: usize,
+    /// Publish readings only when an axis crosses a threshold or moves by more than a delta
+    /// (0 disables either check), and otherwise every `rbe_keepalive_secs`
+    #[default(false)]
+    report_by_exception: bool,
+    #[default(0.0)]
+    rbe_acc_threshold_g: f32,
+    #[default(0.1)]
+    rbe_acc_delta_g: f32,
+    #[default(0.0)]
+    rbe_gyro_threshold_dps: f32,
+    #[default(10.0)]
+    rbe_gyro_delta_dps: f32,
+    #[default(300)]
+    rbe_keepalive_secs: u64,
+    /// Add `ambient_temp` (°C) and `humidity` (%) from an M5Stack ENV unit (SHT30)
+    #[default(false)]
+    sht30: bool,
+    #[default(0x44)]
+    sht30_address: u8,
+    /// Add `pressure` (hPa) and `altitude` (m) from a "bmp280" (ENV II) or "qmp6988" (ENV III),
+    /// or "none"
+    #[default("none")]
+    pressure_sensor: &'static str,
+    /// 0 for the chip's default, 0x76 for the BMP280 and 0x70 for the QMP6988
+    #[default(0)]
+    pressure_address: u8,
+    /// Pressure at sea level the altitude is calculated against
+    #[default(1013.25)]
+    sea_level_hpa: f32,
+    /// ADC1 pin (GPIO1-10) of an analog sensor to publish as `adc_field` (-1 for none)
+    #[default(-1)]
+    adc_gpio: i32,
+    #[default("analog")]
+    adc_field: &'static str,
+    /// Readings averaged into one value
+    #[default(16)]
+    adc_samples: u32,
+    /// Calibration curve as `mV:value` points, e.g. "0:0, 1500:40, 2800:100"; empty publishes mV
+    #[default("")]
+    adc_curve: &'static str,
+    /// Probe the I2C buses at boot and publish the devices found on `{status}/i2c`
+    #[default(false)]
+    i2c_scan: bool,
+    /// Port (0 or 1), pins and clock of the MPU6886's bus
+    #[default(0)]
+    i2c_port: u8,
+    #[default(13)]
+    i2c_sda_gpio: i32,
+    #[default(15)]
+    i2c_scl_gpio: i32,
+    #[default(400)]
+    i2c_baudrate_khz: u32,
+    /// Pins of a second bus on the other port, for the SHT30 and the pressure sensor
+    /// (-1 puts them on the MPU6886's bus)
+    #[default(-1)]
+    i2c1_sda_gpio: i32,
+    #[default(-1)]
+    i2c1_scl_gpio: i32,
+    #[default(100)]
+    i2c1_baudrate_khz: u32,
+    /// How the board is mounted: the sensor axis for each output axis, e.g. "y, -x, z", or a
+    /// 3x3 matrix as nine numbers row by row; applied before fusion and publishing
+    #[default("x, y, z")]
+    axis_remap: &'static str,
+    /// Units of published readings: "g" or "m/s^2", "rad/s" or "deg/s", "C" or "F"
+    #[default("g")]
+    acc_unit: &'static str,
+    #[default("rad/s")]
+    gyro_unit: &'static str,
+    #[default("C")]
+    temp_unit: &'static str,
+    /// After lying still this long, readings are only published every
+    /// `activity_keepalive_secs` until the device moves again (0 disables it)
+    #[default(0)]
+    activity_still_secs: u64,
+    #[default(300)]
+    activity_keepalive_secs: u64,
+    /// Deviation of the acceleration magnitude from 1g that counts as motion
+    #[default(0.05)]
+    activity_acc_threshold_g: f32,
+    #[default(5.0)]
+    activity_gyro_threshold_dps: f32,
+    /// Runs only the accelerometer, in cycle mode at `low_power_accel_hz` (4-500), with the
+    /// gyro and temperature sensor off; can be switched from the shadow
+    #[default(false)]
+    low_power_accel: bool,
+    #[default(10)]
+    low_power_accel_hz: u16,
+    /// Reduces readings taken at `sample_hz` and FIFO captures by this factor before they are
+    /// published (1 publishes them all)
+    #[default(1)]
+    decimate_factor: u32,
+    /// "keep" publishes the last reading of every group, "average" their mean
+    #[default("keep")]
+    decimate_mode: &'static str,
+    /// Wraps telemetry in an envelope with the client id, firmware version, chip and uptime
+    #[default(false)]
+    telemetry_envelope: bool,
+    /// "json", "jsonl" for one record per line, "cbor", which carries the same fields in about
+    /// half the size, "senml", "influx" for line protocol, "sitewise" or "protobuf" with the
+    /// `protobuf` feature
+    #[default("json")]
+    payload_encoding: &'static str,
+    /// SiteWise property aliases are this followed by e.g. `/gyro_x`, with the topic placeholders
+    #[default("/{thing_name}/{kind}")]
+    sitewise_alias_prefix: &'static str,
+    /// Publishes telemetry as a Sparkplug B edge node of this group instead (needs the `protobuf`
+    /// feature); the node is named after the thing
+    #[default("")]
+    sparkplug_group_id: &'static str,
+    #[default("imu")]
+    sparkplug_device_id: &'static str,
+    /// Wraps every message with an HMAC-SHA256 signature, for paths through brokers without TLS.
+    /// Binary encodings are then sent as hex inside the JSON wrapper; Sparkplug and AWS service
+    /// topics stay unsigned.
+    #[default(false)]
+    payload_signing: bool,
+    /// Signing key as hex, stored in NVS on first boot; later boots use the stored key
+    #[default("")]
+    payload_signing_key: &'static str,
+    /// Fields published in telemetry, e.g. "acc, temp"; empty publishes all
+    #[default("")]
+    payload_fields: &'static str,
+    /// Decimal places per field, e.g. "gyro:3, acc:3, *:2" with `*` for the others
+    #[default("")]
+    payload_precision: &'static str,
+    /// JSON batches carry every sample after the first as integer deltas, with fractional values
+    /// multiplied by this first (0 sends them in full)
+    #[default(0)]
+    batch_delta_scale: u32,
+    /// Batches of at least this many bytes are compressed with heatshrink and published with
+    /// `/heatshrink/w8l4` appended to the topic (0 never compresses)
+    #[default(0)]
+    batch_compress_min_bytes: usize,
+    /// With "jsonl", batches are published once they have this many lines even if `batch_size`
+    /// is larger
+    #[default(500)]
+    jsonl_max_lines: usize,
+    /// Identifies what the device is attached to, sent in the status message
+    #[default("")]
+    asset_id: &'static str,
+    /// Where the device is installed, sent in the status message; any of these can stay empty
+    #[default("")]
+    location_building: &'static str,
+    #[default("")]
+    location_room: &'static str,
+    /// "lat,lon" in decimal degrees, e.g. "35.68,139.76"
+    #[default("")]
+    location_coordinates: &'static str,
+    /// Also puts the asset id and location into every telemetry envelope
+    #[default(false)]
+    telemetry_metadata: bool,
+    /// Installs firmware updates handed out as AWS IoT jobs; needs the OTA partitions of
+    /// `partitions.csv`
+    #[default(false)]
+    ota_jobs: bool,
+    /// A new firmware that hasn't passed the sensor self-test and connected to the broker within
+    /// this time is rolled back
+    #[default(300)]
+    ota_confirm_secs: u64,
+    /// ADC1 pin (GPIO1-10) behind the battery's voltage divider, published as `battery_v` and
+    /// `battery_pct` (-1 for none)
+    #[default(-1)]
+    battery_adc_gpio: i32,
+    /// Battery voltage per volt at the pin, 2 for two equal resistors
+    #[default(2.0)]
+    battery_divider: f32,
+    /// Voltages taken as 0% and 100% charge
+    #[default(3300)]
+    battery_empty_mv: u32,
+    #[default(4200)]
+    battery_full_mv: u32,
+    /// Charge at which a `low_battery` event is published (0 disables it)
+    #[default(20)]
+    battery_low_pct: u8,
+    /// Below this the device publishes "offline" and sleeps before a brownout hits (0 disables
+    /// it)
+    #[default(3350)]
+    battery_shutdown_mv: u32,
+    /// Deep sleep after such a shutdown, waking only to check whether the battery was charged
+    #[default(3600)]
+    battery_shutdown_sleep_secs: u64,
+    /// Publish interval by battery charge as `%:secs` steps, e.g. "50:2, 20:10, 0:30"; empty
+    /// keeps `publish_interval_secs`. The shadow's `battery_intervals` overrides it.
+    #[default("")]
+    battery_intervals: &'static str,
+    /// Let the chip light-sleep whenever it waits, unless `deep_sleep_secs` is set. Takes a
+    /// `wifi_power_save` other than "none" to sleep while connected.
+    #[default(false)]
+    light_sleep: bool,
+    /// "performance", "balanced" or "low_power" to set the CPU clock, light sleep, Wi-Fi power
+    /// saving and the accelerometer mode together; empty leaves them to their own settings
+    #[default("")]
+    power_profile: &'static str,
+    /// Daily window without publishing, spent in deep sleep, e.g. "22:00-06:00" in local time;
+    /// empty disables it
+    #[default("")]
+    quiet_hours: &'static str,
+    /// Local time minus UTC, e.g. 540 for JST
+    #[default(0)]
+    utc_offset_mins: i32,
+    /// How often the status message is published again with fresh heap and stack watermarks
+    /// (0 only publishes it on connect)
+    #[default(300)]
+    memory_interval_secs: u64,
+    /// Restart with a backtrace once the main loop hangs for this long (0 disables it). Has to
+    /// outlast the longest wait, e.g. the 60s between OTA download retries.
+    #[default(90)]
+    task_watchdog_secs: u64,
+    /// Mirror log records to the logs topic from boot on; the `log_stream` command toggles it
+    #[default(false)]
+    log_stream: bool,
+    /// Lowest level mirrored: "error", "warn", "info", "debug" or "trace"
+    #[default("info")]
+    log_stream_level: &'static str,
+    /// Records mirrored per minute at most, the rest is only counted (0 disables the limit)
+    #[default(60)]
+    log_stream_rate: u32,
+    /// How often the mirrored records are published as one batch
+    #[default(10)]
+    log_stream_interval_secs: u64,
+    /// Port serving `/status`, `/config` and `/metrics` on the LAN, e.g. 8080 (0 disables it).
+    /// Not 80, which the diagnostics AP takes.
+    #[default(0)]
+    http_port: u16,
 }
 
 fn main() {
     esp_idf_svc::sys::link_patches();
-    esp_idf_svc::log::EspLogger::initialize_default();
+    log_sink::initialize(&CONFIG);
 
     let peripherals = Peripherals::take().unwrap();
 
@@ -68,21 +652,55 @@ fn main() {
     }
     info!("sensor initialized");
 
-    // 1. Instanciate the SDA and SCL pins, correct pins are in the training material.
-    let sda = peripherals.pins.gpio13;
-    let scl = peripherals.pins.gpio15;
-    // 2. Instanciate the i2c peripheral
-    let config = I2cConfig::new().baudrate(400.kHz().into());
-    let i2c = I2cDriver::new(peripherals.i2c0, sda, scl, &config).unwrap();
+    let nvs = EspDefaultNvsPartition::take().unwrap();
+    let boot = Boot::record(nvs.clone()).unwrap();
+    crash::install(nvs.clone()).unwrap();
+
+    // The MPU6886's bus, and optionally a second one for the other sensors
+    let (i2c, sensor_i2c) =
+        i2c_bus::open_buses(&CONFIG, &nvs, peripherals.i2c0, peripherals.i2c1).unwrap();
+    let buses: Vec<SharedI2c> = [Some(i2c.clone()), sensor_i2c.clone()]
+        .into_iter()
+        .flatten()
+        .collect();
+    // Without a bus of their own, further sensors share the MPU6886's
+    let sensor_i2c = sensor_i2c.unwrap_or_else(|| i2c.clone());
     info!("I2C initialized");
 
+    // Before the sensor init, which fails less helpfully on wiring problems
+    let i2c_scan = CONFIG
+        .i2c_scan
+        .then(|| i2c_bus::scan_all(&buses).to_string());
+
     let mut delay = Delay::default();
-    let mut mpu = Mpu6886::new(i2c);
+    let mut mpu = Mpu6886::new(i2c.clone());
 
-    mpu.init(&mut delay).unwrap();
-    info!("sensor initialized");
+    // A sensor that doesn't answer yet is retried by the health check
+    match mpu.init(&mut delay) {
+        Ok(()) => info!("sensor initialized"),
+        Err(e) => warn!("Failed to initialize the sensor: {e:?}"),
+    }
+    // Before anything is configured, the self-test needs the sensor at its default ranges
+    let self_test = self_test::run(&mut mpu);
+
+    let mut app_config = CONFIG;
+
+    let broker = Broker::from_config(app_config.broker);
+    if !broker.is_aws() {
+        info!("Using a {broker:?} MQTT broker");
+
+        if app_config.aws_iot_endpoint.is_empty() {
+            if let Some(endpoint) = broker.default_endpoint() {
+                app_config.aws_iot_endpoint = endpoint;
+            }
+        }
+
+        // AWS-only features
+        app_config.basic_ingest_rule = "";
+        app_config.greengrass_discovery = false;
+        app_config.defender_interval_secs = 0;
+    }
 
-    let app_config = CONFIG;
     info!("WIFI SSID = {}", app_config.wifi_ssid);
     info!("WIFI PASS = {}", app_config.wifi_password);
     info!("AWS IoT Endpoint = {}", app_config.aws_iot_endpoint);
@@ -91,66 +709,532 @@ fn main() {
 
     let sys_loop = EspSystemEventLoop::take().unwrap();
     let timer_service = EspTimerService::new().unwrap();
-    let nvs = EspDefaultNvsPartition::take().unwrap();
+    let lifecycle = Lifecycle::start(
+        &timer_service,
+        Duration::from_secs(app_config.ota_confirm_secs),
+    )
+    .unwrap();
 
     info!("ESP IDF SVC initialized");
 
-    let mut buzzer = PinDriver::output(peripherals.pins.gpio2).unwrap();
-    buzzer.set_low().unwrap();
-    buzzer.set_high().unwrap();
-    std::thread::sleep(std::time::Duration::from_secs(3));
-    buzzer.set_low().unwrap();
+    let mut buzzer_pin = PinDriver::output(peripherals.pins.gpio2).unwrap();
+    buzzer_pin.set_low().unwrap();
+
+    // Before connecting, so a broken configuration or certificate can't get in the way
+    if factory_reset::held_at_boot(&button, &mut buzzer_pin).unwrap() {
+        warn!("Factory reset by button");
+        factory_reset::wipe().unwrap();
+        esp_idf_svc::hal::reset::restart();
+    }
 
     esp_idf_svc::hal::task::block_on(async {
-        let _wifi = wifi_create(
-            peripherals.modem,
+        let mut timer = timer_service.timer_async()?;
+        let settings = Arc::new(Settings::new(&app_config, nvs.clone())?);
+
+        // Before the sensor is configured, so it starts out in the profile's mode
+        match settings.power_profile() {
+            Some(profile) => {
+                settings.set_low_power(profile.low_power_accel());
+                if let Err(e) = profile.configure_cpu() {
+                    warn!("Failed to set the CPU clock: {e}");
+                }
+            }
+            None if app_config.light_sleep && app_config.deep_sleep_secs == 0 => {
+                if settings.wifi_power_save() == PowerSave::None {
+                    warn!("Light sleep without Wi-Fi power saving only happens while disconnected");
+                }
+                if let Err(e) = power::enable_light_sleep() {
+                    warn!("Failed to enable light sleep: {e}");
+                }
+            }
+            None => (),
+        }
+
+        if let Err(e) = imu::configure(&mut mpu, &settings) {
+            warn!("Failed to configure the sensor: {e}");
+        }
+        let sensors = Registry::from_config(&app_config, &sensor_i2c)?;
+
+        let mut esp_wifi = EspWifi::new(peripherals.modem, sys_loop.clone(), Some(nvs.clone()))?;
+        let power_save = settings.wifi_power_save();
+        while let Err(e) = wifi_create(
+            &mut esp_wifi,
             &app_config,
+            power_save,
             &sys_loop,
             &timer_service,
-            &nvs,
         )
-        .await?;
+        .await
+        {
+            let Some(peer) = espnow_relay::parse_mac(app_config.espnow_peer) else {
+                return Err(e);
+            };
+
+            warn!("Wifi unreachable ({e}), falling back to ESP-NOW relay {peer:02x?}");
+            run_espnow_fallback(
+                &mut esp_wifi,
+                &mut mpu,
+                &mut timer,
+                peer,
+                &app_config,
+                &sensors,
+                &settings,
+            )
+            .await?;
+            info!("Trying WiFi again");
+        }
         info!("Wifi created");
 
+        // Sets the system clock, e.g. for the pedometer to start a new count every day
+        let _sntp = EspSntp::new_default()?;
+
+        let relay = if app_config.espnow_relay {
+            let peers = espnow_relay::parse_macs(app_config.espnow_relay_peers).unwrap_or_default();
+            if peers.is_empty() {
+                warn!("No valid espnow_relay_peers, no ESP-NOW frame will be forwarded");
+            }
+
+            let relay = EspNowReceiver::new(peers)?;
+            info!("ESP-NOW relay enabled");
+            Some(relay)
+        } else {
+            None
+        };
+
         let server_cert =
             convert_certificate(include_bytes!("../certificates/AmazonRootCA1.pem").to_vec());
-        let client_cert = convert_certificate(
-            include_bytes!("../certificates/sender-certificate.pem.crt").to_vec(),
-        );
-        let private_key =
-            convert_certificate(include_bytes!("../certificates/sender-private.pem.key").to_vec());
-
-        let (mut client, mut conn) = mqtt_create(
-            app_config.aws_iot_endpoint,
-            app_config.aws_iot_client_id,
-            server_cert,
-            client_cert,
-            private_key,
+
+        let mut credential_store = CredentialStore::new(nvs.clone())?;
+        #[allow(unused_mut)]
+        let mut credentials = credential_store.load()?;
+
+        #[cfg(feature = "fleet-provisioning")]
+        if credentials.is_none() && broker.is_aws() {
+            info!("No device certificate in NVS, starting fleet provisioning");
+
+            match provisioning::provision(
+                app_config.aws_iot_endpoint,
+                server_cert,
+                convert_certificate(
+                    include_bytes!("../certificates/claim-certificate.pem.crt").to_vec(),
+                ),
+                convert_certificate(
+                    include_bytes!("../certificates/claim-private.pem.key").to_vec(),
+                ),
+                app_config.aws_iot_provisioning_template,
+                &wifi::sta_mac_hex(),
+                &mut timer,
+            )
+            .await
+            {
+                Ok(provisioned) => {
+                    credential_store.save(&provisioned)?;
+                    credentials = Some(provisioned);
+                }
+                Err(e) => {
+                    error!("Fleet provisioning failed: {e:#}, restarting in 30s");
+                    timer.after(Duration::from_secs(30)).await?;
+                    esp_idf_svc::hal::reset::restart();
+                }
+            }
+        }
+
+        let (client_cert_pem, private_key_pem) = match credentials {
+            Some(credentials) => {
+                info!("Using the device certificate from NVS");
+
+                // A provisioned thing name doubles as the MQTT client ID
+                if let Some(thing_name) = credentials.thing_name {
+                    info!("Thing name from NVS: \"{thing_name}\"");
+
+                    let thing_name: &'static str = thing_name.leak();
+                    app_config.aws_iot_client_id = thing_name;
+                    app_config.aws_iot_thing_name = thing_name;
+                }
+
+                (credentials.certificate, credentials.private_key)
+            }
+            None => (
+                include_bytes!("../certificates/sender-certificate.pem.crt").to_vec(),
+                include_bytes!("../certificates/sender-private.pem.key").to_vec(),
+            ),
+        };
+
+        let diagnostics = Arc::new(Mutex::new(DiagnosticsState::new(cert_info::not_after(
+            &client_cert_pem,
+        ))));
+
+        let client_cert = convert_certificate(client_cert_pem);
+        let private_key = convert_certificate(private_key_pem);
+
+        let (endpoint, server_cert) = if app_config.greengrass_discovery {
+            match greengrass::discover(
+                app_config.aws_iot_endpoint,
+                thing_name(&app_config),
+                client_cert,
+                private_key,
+            ) {
+                Ok(core) => {
+                    info!("Connecting to Greengrass core {}", core.url);
+                    let url: &'static str = core.url.leak();
+                    (url, convert_certificate(core.ca))
+                }
+                Err(e) => {
+                    warn!("Greengrass discovery failed, using the cloud endpoint: {e:#}");
+                    (app_config.aws_iot_endpoint, server_cert)
+                }
+            }
+        } else {
+            (app_config.aws_iot_endpoint, server_cert)
+        };
+
+        let server_cert = broker.is_aws().then_some(server_cert);
+
+        let auth = if !app_config.mqtt_username.is_empty() {
+            info!(
+                "Authenticating as \"{}\" instead of with the client certificate",
+                app_config.mqtt_username
+            );
+            MqttAuth::Password {
+                username: app_config.mqtt_username,
+                password: app_config.mqtt_password,
+                authorizer: app_config.mqtt_authorizer,
+            }
+        } else if !broker.is_aws() {
+            MqttAuth::Anonymous
+        } else {
+            MqttAuth::Certificate {
+                client_cert,
+                private_key,
+            }
+        };
+
+        let topics = Topics::new(&app_config);
+        let encoding = Encoding::from_config(&app_config);
+        let motion = MotionWake::from_config(&app_config, &mut mpu)?;
+
+        let mut dispatcher = Dispatcher::new(&topics.command());
+        dispatcher.register("beep", commands::beep);
+        dispatcher.register("set_interval", commands::set_interval);
+        dispatcher.register("reboot", commands::reboot);
+        dispatcher.register("factory_reset", commands::factory_reset);
+        dispatcher.register("calibrate", commands::calibrate);
+        dispatcher.register("calibrate_temp", commands::calibrate_temp);
+        dispatcher.register("set_range", commands::set_range);
+        dispatcher.register("capture", commands::capture);
+        dispatcher.register("log_level", commands::log_level);
+        dispatcher.register("log_stream", commands::log_stream);
+        dispatcher.register("i2c_scan", commands::i2c_scan);
+        dispatcher.register("rotate_cert", cert_rotation::rotate_cert);
+
+        let shadow = Arc::new(Shadow::new(thing_name(&app_config)));
+
+        let metadata = Metadata::from_config(&app_config);
+        let envelope = app_config.telemetry_envelope.then(|| {
+            let envelope = Envelope::new(app_config.aws_iot_client_id, env!("CARGO_PKG_VERSION"));
+            match &metadata {
+                Some(metadata) if app_config.telemetry_metadata => envelope.with_metadata(metadata),
+                _ => envelope,
+            }
+        });
+
+        let ctx = Context {
+            stats: Arc::new(NetStats::default()),
+            acks: PubAcks::default(),
+            subscriptions: Subscriptions::default(),
+            offline: OfflineBuffer::new(
+                app_config.offline_buffer_len,
+                app_config.offline_buffer_nvs_len,
+                &nvs,
+            )?,
+            diagnostics,
+            diagnostics_ap: Mutex::new(None),
+            relay,
+            buzzer: Buzzer::new(buzzer_pin, &timer_service, settings.clone(), shadow.clone())?,
+            settings,
+            shadow,
+            commands: dispatcher,
+            led: Mutex::new(StatusLed::from_config(&app_config)?),
+            control: Control::new(topics.control()),
+            remote_config: RemoteConfig::new(topics.config()),
+            rotation: Mutex::new(None),
+            rotation_report: Mutex::new(None),
+            batch: Mutex::new(Batcher::from_config(&app_config, encoding)),
+            limiter: Mutex::new(RateLimiter::from_config(&app_config)),
+            sequence: Sequence::new(nvs.clone(), "telemetry")?,
+            defender: Defender::new(thing_name(&app_config), nvs.clone())?,
+            broker,
+            button: Mutex::new(Button::new(button)),
+            motion,
+            pedometer: Pedometer::from_config(&app_config, nvs.clone())?.map(Mutex::new),
+            sensor_health: SensorHealth::new(i2c.port()),
+            self_test,
+            sensors,
+            buses,
+            i2c_scan: Mutex::new(i2c_scan),
+            decimation: Decimation::from_config(&app_config),
+            envelope,
+            encoder: encoding.encoder(),
+            signer: Signer::from_config(&app_config, nvs.clone())?,
+            selection: Selection::from_config(&app_config),
+            metadata,
+            quiet_hours: QuietHours::from_config(&app_config),
+            boot,
+            lifecycle,
+            jobs: app_config
+                .ota_jobs
+                .then(|| Jobs::new(thing_name(&app_config))),
+            #[cfg(feature = "protobuf")]
+            sparkplug: Sparkplug::from_config(&app_config),
+            topics,
+        };
+
+        // Cheap DNS + TCP probe before the TLS handshake, to tell network problems from certificate ones
+        let reachability = reachability::check(endpoint);
+        reachability::log_result(&reachability);
+        if let Ok(reachability) = &reachability {
+            ctx.stats.set_dns_duration(reachability.dns);
+        }
+
+        let _status_server = status_server::start(
+            &app_config,
+            ctx.stats.clone(),
+            ctx.diagnostics.clone(),
+            ctx.settings.clone(),
         )?;
-        info!("MQTT client created");
 
-        let mut timer = timer_service.timer_async()?;
-        run(
-            &mut mpu,
-            &mut client,
-            &mut conn,
-            &mut timer,
-            app_config.aws_iot_topic,
-        )
-        .await
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(120));
+
+        // Sampling and publishing both run in this task, so watching it covers either hanging
+        if app_config.task_watchdog_secs > 0 {
+            watchdog::watch_current_task(Duration::from_secs(app_config.task_watchdog_secs))?;
+        }
+
+        // Every pass is one MQTT session; when it ends for whatever reason, start a new one
+        loop {
+            watchdog::feed();
+            let connects = ctx.stats.connects();
+            let (will_topic, will_payload) = last_will(&ctx);
+
+            match mqtt_create(
+                endpoint,
+                app_config.aws_iot_client_id,
+                &will_topic,
+                &will_payload,
+                server_cert,
+                auth,
+            ) {
+                Ok((mut client, mut conn)) => {
+                    info!("MQTT client created");
+
+                    match run(
+                        &mut mpu,
+                        &mut client,
+                        &mut conn,
+                        &mut timer,
+                        &mut esp_wifi,
+                        &app_config,
+                        &ctx,
+                    )
+                    .await
+                    {
+                        Ok(()) => warn!("MQTT session ended"),
+                        Err(e) => warn!("MQTT session failed: {e}"),
+                    }
+                }
+                Err(e) => error!("Failed to create MQTT client: {e}"),
+            }
+
+            let rotation = ctx.rotation.lock().unwrap().take();
+            if let Some(credentials) = rotation {
+                let report_topic = ctx.topics.status("cert-rotation");
+                let res = cert_rotation::verify(
+                    endpoint,
+                    app_config.aws_iot_client_id,
+                    &ctx.topics.status("status"),
+                    &report_topic,
+                    server_cert,
+                    &credentials,
+                    &mut timer,
+                )
+                .await;
+
+                match res {
+                    Ok(not_after) => {
+                        credential_store.save(&credentials)?;
+                        info!("Certificate rotated, valid until {not_after}; restarting");
+                        esp_idf_svc::hal::reset::restart();
+                    }
+                    Err(e) => {
+                        error!("New certificate rejected, keeping the current one: {e:#}");
+                        *ctx.rotation_report.lock().unwrap() = Some(cert_rotation::report(&Err(e)));
+                    }
+                }
+            }
+
+            // Only back off further while we keep failing to connect at all
+            if ctx.stats.connects() > connects {
+                backoff.reset();
+            }
+
+            let delay = backoff.next_delay();
+            info!("Reconnecting in {}ms...", delay.as_millis());
+            sample_offline(&mut mpu, &mut timer, &ctx, delay).await?;
+        }
     })
     .unwrap();
 }
 
+/// State shared between the connection and the publisher loops
+struct Context {
+    stats: Arc<NetStats>,
+    acks: PubAcks,
+    subscriptions: Subscriptions,
+    offline: OfflineBuffer,
+    diagnostics: Arc<Mutex<DiagnosticsState>>,
+    diagnostics_ap: Mutex<Option<EspHttpServer<'static>>>,
+    relay: Option<EspNowReceiver>,
+    settings: Arc<Settings>,
+    shadow: Arc<Shadow>,
+    commands: Dispatcher,
+    buzzer: Buzzer,
+    led: Mutex<StatusLed>,
+    control: Control,
+    remote_config: RemoteConfig,
+    topics: Topics,
+    /// New credentials waiting to be tested once the session has ended
+    rotation: Mutex<Option<Credentials>>,
+    /// Outcome of a failed rotation, published in the next session
+    rotation_report: Mutex<Option<String>>,
+    batch: Mutex<Batcher>,
+    limiter: Mutex<RateLimiter>,
+    sequence: Sequence,
+    defender: Defender,
+    broker: Broker,
+    /// Held for 3s, it starts a calibration
+    button: Mutex<Button>,
+    motion: Option<MotionWake>,
+    /// Kept across sessions, so no steps are lost while reconnecting
+    pedometer: Option<Mutex<Pedometer>>,
+    sensor_health: SensorHealth,
+    /// Result of the boot-time self-test, reported with the status
+    self_test: SelfTest,
+    /// Sensors besides the MPU6886 that are configured and present
+    sensors: Registry,
+    /// Every bus set up, for the scan command
+    buses: Vec<SharedI2c>,
+    /// Devices found by the boot-time scan, published in the first session
+    i2c_scan: Mutex<Option<String>>,
+    /// Applied to sampled readings and captures before they are published
+    decimation: Option<Decimation>,
+    /// Device metadata telemetry is wrapped in, if enabled
+    envelope: Option<Envelope>,
+    /// How telemetry payloads are serialized
+    encoder: &'static dyn TelemetryEncoder,
+    /// Signs every message, if enabled
+    signer: Option<Signer>,
+    /// Signals left out of telemetry and rounding, if configured
+    selection: Option<Selection>,
+    /// Installation location and asset id for the status message, if configured
+    metadata: Option<Metadata>,
+    /// Daily window spent asleep, if configured
+    quiet_hours: Option<QuietHours>,
+    /// Reset reason and boot count, reported with the status
+    boot: Boot,
+    /// Confirms a new firmware once it is connected
+    lifecycle: Lifecycle,
+    /// AWS IoT Jobs, if firmware updates are taken from there
+    jobs: Option<Jobs>,
+    /// Takes over telemetry when the device is a Sparkplug B edge node
+    #[cfg(feature = "protobuf")]
+    sparkplug: Option<Sparkplug>,
+}
+
+/// Retained on the status topic while connected
+#[derive(Serialize)]
+struct OnlineStatus {
+    state: &'static str,
+    #[serde(flatten)]
+    build: BuildInfo,
+    wifi_power_save: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    power_profile: Option<&'static str>,
+    dropped: u32,
+    /// MIME type of the telemetry payloads
+    content_type: &'static str,
+    self_test: SelfTestReport,
+    #[serde(flatten)]
+    metadata: Option<Metadata>,
+    /// Why the device last started, so brownouts and watchdog resets show up fleet-wide
+    #[serde(flatten)]
+    boot: Boot,
+    memory: MemoryReport,
+    /// Reconnects, subscribe failures and PUBACK latency so far
+    net: NetReport,
+}
+
+fn online_status(ctx: &Context) -> String {
+    serde_json::to_string(&OnlineStatus {
+        state: "online",
+        build: BUILD_INFO,
+        wifi_power_save: ctx.settings.wifi_power_save().as_str(),
+        power_profile: ctx.settings.power_profile().map(PowerProfile::as_str),
+        dropped: ctx.offline.dropped(),
+        content_type: ctx.encoder.content_type(),
+        self_test: ctx.self_test.report(),
+        metadata: ctx.metadata.clone(),
+        boot: ctx.boot,
+        memory: memory::report(),
+        net: ctx.stats.report(),
+    })
+    .unwrap()
+}
+
+/// Topic and payload of the last will: "offline" on the status topic, or a Sparkplug NDEATH
+fn last_will(ctx: &Context) -> (String, Vec<u8>) {
+    #[cfg(feature = "protobuf")]
+    if let Some(sparkplug) = &ctx.sparkplug {
+        return sparkplug.will();
+    }
+
+    (ctx.topics.status("status"), OFFLINE_PAYLOAD.to_vec())
+}
+
+/// The AWS IoT thing name, which is usually the same as the client ID
+fn thing_name(app_config: &Config) -> &'static str {
+    if app_config.aws_iot_thing_name.is_empty() {
+        app_config.aws_iot_client_id
+    } else {
+        app_config.aws_iot_thing_name
+    }
+}
+
 async fn run(
-    mpu: &mut Mpu6886<I2cDriver<'_>>,
+    mpu: &mut Imu,
     client: &mut EspAsyncMqttClient,
     connection: &mut EspAsyncMqttConnection,
     timer: &mut EspAsyncTimer,
-    topic: &str,
+    esp_wifi: &mut EspWifi<'static>,
+    app_config: &Config,
+    ctx: &Context,
 ) -> Result<(), EspError> {
     info!("About to start the MQTT client");
 
+    let topic = &ctx.topics.telemetry(topics::TELEMETRY_IMU);
+    let net_stats_topic = ctx.topics.status("net-stats");
+    let status_topic = ctx.topics.status("status");
+    let net_stats_interval = Duration::from_secs(app_config.net_stats_interval_secs);
+    let memory_interval = Duration::from_secs(app_config.memory_interval_secs);
+    let logs_topic = ctx.topics.status("logs");
+    let logs_interval = Duration::from_secs(app_config.log_stream_interval_secs);
+    let defender_interval = Duration::from_secs(app_config.defender_interval_secs);
+    let heartbeat_topic = ctx.topics.status("heartbeat");
+    let heartbeat_interval = Duration::from_secs(app_config.heartbeat_interval_secs);
+    let vibration_topic = ctx.topics.telemetry("vibration");
+    let sensor_topic = ctx.topics.status("sensor");
+    let vibration_interval = Duration::from_secs(app_config.vibration_interval_secs);
+    let connecting_since = Instant::now();
+
     let res = select(
         // Need to immediately start pumping the connection for messages, or else subscribe() and publish() below will not work
         // Note that when using the alternative structure and the alternative constructor - `EspMqttClient::new_cb` - you don't need to
@@ -162,7 +1246,42 @@ async fn run(
         pin!(async move {
             info!("MQTT Listening for messages");
 
+            // Job documents with a presigned download URL can outgrow the receive buffer
+            let mut reassembler = Reassembler::default();
+
             while let Ok(event) = connection.next().await {
+                match event.payload() {
+                    EventPayload::Connected(_) => {
+                        if ctx.stats.record_connected() {
+                            ctx.stats.set_tls_duration(connecting_since.elapsed());
+                        }
+                        ctx.diagnostics.lock().unwrap().set_connected(true);
+                    }
+                    EventPayload::Disconnected => {
+                        ctx.stats.record_disconnected();
+                        ctx.diagnostics.lock().unwrap().set_connected(false);
+
+                        // Hand over to the reconnect loop in `main`, which sets up a fresh client
+                        break;
+                    }
+                    EventPayload::Published(id) => ctx.acks.record(id),
+                    EventPayload::Received {
+                        topic,
+                        data,
+                        details,
+                        ..
+                    } => {
+                        if let Some((topic, data)) = reassembler.push(topic, data, details) {
+                            ctx.stats.record_received(data.len());
+                            route_message(ctx, &topic, &data);
+                        }
+                    }
+                    EventPayload::Error(e) => {
+                        ctx.diagnostics.lock().unwrap().last_error = Some(format!("{e:?}"));
+                    }
+                    _ => (),
+                }
+
                 info!("[Queue] Event: {}", event.payload());
             }
 
@@ -172,9 +1291,87 @@ async fn run(
         }),
         pin!(async move {
             // Using `pin!` is optional, but it optimizes the memory size of the Futures
+            let mut publisher = Publisher::new(
+                client,
+                PublishContext {
+                    acks: &ctx.acks,
+                    stats: &ctx.stats,
+                    subscriptions: &ctx.subscriptions,
+                    offline: &ctx.offline,
+                    batch: &ctx.batch,
+                    limiter: &ctx.limiter,
+                    qos: ctx.settings.qos(),
+                    signer: ctx.signer.as_ref(),
+                },
+            );
+
+            let diagnostics_ap_after =
+                Duration::from_secs(app_config.diagnostics_ap_after_mins * 60);
+            let mut offline_sampled = Instant::now();
+
+            // Basic ingest messages never reach subscribers
+            if !ctx.topics.basic_ingest() {
+                ctx.subscriptions.track(topic, QoS::AtMostOnce);
+            }
+            // The shadow is an AWS IoT service, other brokers have nothing answering it
+            let aws = ctx.broker.is_aws();
+
+            if aws {
+                for shadow_topic in ctx.shadow.subscribe_topics() {
+                    ctx.subscriptions.track(shadow_topic, QoS::AtLeastOnce);
+                }
+                for jobs_topic in ctx.jobs.iter().flat_map(Jobs::subscribe_topics) {
+                    ctx.subscriptions.track(jobs_topic, QoS::AtLeastOnce);
+                }
+            }
+            ctx.subscriptions
+                .track(ctx.commands.subscribe_topic(), QoS::AtLeastOnce);
+            ctx.subscriptions
+                .track(ctx.control.topic(), QoS::AtLeastOnce);
+            ctx.subscriptions
+                .track(ctx.remote_config.topic(), QoS::AtLeastOnce);
+            #[cfg(feature = "protobuf")]
+            if let Some(sparkplug) = &ctx.sparkplug {
+                ctx.subscriptions
+                    .track(&sparkplug.command_topic(), QoS::AtLeastOnce);
+            }
+
             loop {
-                if let Err(e) = client.subscribe(topic, QoS::AtMostOnce).await {
-                    error!("Failed to subscribe to topic \"{topic}\": {e}, retrying...");
+                watchdog::feed();
+
+                if let Err(e) = publisher.resubscribe_all().await {
+                    error!("Failed to subscribe: {e}, retrying...");
+
+                    let offline_for = {
+                        let mut diagnostics = ctx.diagnostics.lock().unwrap();
+                        diagnostics.last_error = Some(format!("subscribe failed: {e}"));
+                        diagnostics.offline_for()
+                    };
+
+                    let mut diagnostics_ap = ctx.diagnostics_ap.lock().unwrap();
+                    if diagnostics_ap.is_none()
+                        && !diagnostics_ap_after.is_zero()
+                        && offline_for >= diagnostics_ap_after
+                    {
+                        warn!(
+                            "MQTT unreachable for {}s, starting diagnostics AP",
+                            offline_for.as_secs()
+                        );
+                        diagnostics_ap = Some(diagnostics::start_ap(
+                            esp_wifi,
+                            app_config.diagnostics_ap_ssid,
+                            app_config.diagnostics_ap_password,
+                            ctx.diagnostics.clone(),
+                        )?);
+                    }
+
+                    drop(diagnostics_ap);
+
+                    // Keep sampling while offline, the buffer is replayed once we get through
+                    if offline_sampled.elapsed() >= ctx.settings.sample_interval() {
+                        buffer_sample(mpu, ctx);
+                        offline_sampled = Instant::now();
+                    }
 
                     // Re-try in 0.5s
                     timer.after(Duration::from_millis(500)).await?;
@@ -182,94 +1379,917 @@ async fn run(
                     continue;
                 }
 
-                info!("Subscribed to topic \"{topic}\"");
-
                 // Just to give a chance of our connection to get even the first published message
                 timer.after(Duration::from_millis(500)).await?;
 
+                // Retained, so it replaces the "offline" last will from a previous session
+                let status = online_status(ctx);
+                publisher
+                    .publish_retained(timer, MessageKind::Status, &status_topic, status.as_bytes())
+                    .await?;
+
+                info!("Published status \"{status}\" to topic \"{status_topic}\"");
+
+                // Connected, so a new firmware only has the sensor left to prove
+                if ctx.lifecycle.is_pending() {
+                    if ctx.self_test.passed() {
+                        ctx.lifecycle.mark_app_valid();
+                    } else {
+                        warn!("Sensor self-test failed, leaving the new firmware unconfirmed");
+                    }
+                }
+
+                #[cfg(feature = "protobuf")]
+                if let Some(sparkplug) = &ctx.sparkplug {
+                    let (birth_topic, birth) = sparkplug.node_birth();
+                    publisher
+                        .publish(timer, MessageKind::Status, &birth_topic, &birth)
+                        .await?;
+
+                    info!("Published Sparkplug NBIRTH to topic \"{birth_topic}\"");
+                }
+
+                let rotation_report = ctx.rotation_report.lock().unwrap().take();
+                if let Some(report) = rotation_report {
+                    let report_topic = ctx.topics.status("cert-rotation");
+                    publisher
+                        .publish(timer, MessageKind::Status, &report_topic, report.as_bytes())
+                        .await?;
+
+                    info!("Published certificate rotation report \"{report}\"");
+                }
+
+                // The panic that caused the last reset, kept until it got out
+                if let Some(report) = crash::saved() {
+                    let crash_topic = ctx.topics.status("crash");
+                    publisher
+                        .publish(timer, MessageKind::Status, &crash_topic, report.as_bytes())
+                        .await?;
+                    crash::clear();
+
+                    warn!("Published crash report \"{report}\"");
+                }
+
+                let i2c_scan = ctx.i2c_scan.lock().unwrap().take();
+                if let Some(devices) = i2c_scan {
+                    let scan_topic = ctx.topics.status("i2c");
+                    publisher
+                        .publish(timer, MessageKind::Status, &scan_topic, devices.as_bytes())
+                        .await?;
+
+                    info!("Published I2C scan \"{devices}\"");
+                }
+
+                publisher.flush_offline(timer).await?;
+
+                // Fetch the full shadow once, deltas created while we were away are not resent
+                if aws {
+                    publisher
+                        .publish(timer, MessageKind::Status, ctx.shadow.get_topic(), &[])
+                        .await?;
+                    ctx.shadow.request_report();
+
+                    // Likewise for a job queued while we were away
+                    if let Some(jobs) = &ctx.jobs {
+                        publisher
+                            .publish(timer, MessageKind::Status, jobs.get_next_topic(), &[])
+                            .await?;
+                    }
+                }
+
+                let mut net_stats_published = Instant::now();
+                let mut status_published = Instant::now();
+                let mut logs_published = Instant::now();
+                let mut defender_published = Instant::now();
+                let mut vibration_analysed: Option<Instant> = None;
+                let mut heartbeat_published: Option<Instant> = None;
+                let mut aggregator = telemetry::Aggregator::from_config(app_config);
+                let mut decimator = ctx.decimation.map(Decimator::new);
+                let mut sampled_at = Instant::now();
+                let mut ahrs = Ahrs::from_config(app_config);
+                let mut orientation = Orientation::from_config(app_config);
+                let mut gestures = GestureDetector::from_config(app_config);
+                let mut freefall = FreeFallDetector::from_config(app_config);
+                let mut exceptions = ReportByException::from_config(app_config);
+                let mut activity = ActivityMonitor::from_config(app_config);
+                let events_topic = ctx.topics.events();
+                let steps_topic = ctx.topics.telemetry("steps");
+
                 //main loop
                 loop {
-                    // get gyro data, scaled with sensitivity
-                    let gyro = mpu.get_gyro().unwrap();
-                    println!("gyro: {:?}", gyro);
+                    watchdog::feed();
 
-                    // get accelerometer data, scaled with sensitivity
-                    let acc = mpu.get_acc().unwrap();
-                    println!("acc: {:?}", acc);
-                    std::thread::sleep(std::time::Duration::from_secs(1));
+                    if let Some(change) = ctx.sensor_health.check(mpu, &ctx.settings) {
+                        let report = ctx.sensor_health.to_json(&change);
+                        publisher
+                            .publish(timer, MessageKind::Status, &sensor_topic, report.as_bytes())
+                            .await?;
 
-                    let payload = format!("{{\"gyro\": {:?}, \"acc\": {:?}}}", gyro, acc);
+                        info!("Published sensor health \"{report}\"");
+                    }
 
-                    client
-                        .publish(topic, QoS::AtMostOnce, false, payload.as_bytes())
-                        .await?;
+                    // Aggregated windows, or a single reading when not sampling in between
+                    let mut rounds = aggregator.take();
+                    if rounds.is_empty()
+                        && !(aggregator.is_windowed() && ctx.settings.sample_period().is_some())
+                    {
+                        rounds.push(telemetry::read(mpu, &ctx.sensors, &ctx.settings));
+                    }
+                    // Fused orientation is current, so it goes with the latest round
+                    if let Some(signals) = rounds.last_mut() {
+                        if let Some(ahrs) = &mut ahrs {
+                            ahrs.poll(mpu, &ctx.settings);
+                            if ahrs.mode() == AhrsMode::Only {
+                                signals.retain(|signal| {
+                                    signal.field != "gyro" && signal.field != "acc"
+                                });
+                            }
+                            signals.push(ahrs.signal());
+                        }
+                        if let Some(orientation) = &mut orientation {
+                            orientation.poll(mpu, &ctx.settings);
+                            signals.extend(orientation.signal());
+                        }
+                        if let Some(activity) = &activity {
+                            signals.push(activity.signal());
+                        }
+                        ctx.diagnostics.lock().unwrap().last_reading =
+                            Some(telemetry::to_json(signals));
+                    }
+
+                    // Forward whatever our ESP-NOW peers sent us since the last round
+                    for frame in ctx
+                        .relay
+                        .as_ref()
+                        .map(|relay| relay.take_frames())
+                        .unwrap_or_default()
+                    {
+                        let relay_topic = ctx.topics.telemetry(&frame.relayed_kind());
+                        publisher
+                            .publish(timer, MessageKind::Telemetry, &relay_topic, &frame.payload)
+                            .await?;
+
+                        info!("Relayed ESP-NOW frame to topic \"{relay_topic}\"");
+                    }
+
+                    for signals in &mut rounds {
+                        if let Some(selection) = &ctx.selection {
+                            selection.apply(signals);
+                        }
+                        if !exceptions
+                            .as_mut()
+                            .map_or(true, |exceptions| exceptions.should_publish(signals))
+                        {
+                            continue;
+                        }
+
+                        #[cfg(feature = "protobuf")]
+                        if let Some(sparkplug) = &ctx.sparkplug {
+                            for (topic, payload) in sparkplug.data(signals) {
+                                publisher
+                                    .publish(timer, MessageKind::Telemetry, &topic, &payload)
+                                    .await?;
+                            }
+                            continue;
+                        }
+
+                        let messages = telemetry::messages(
+                            &ctx.topics,
+                            signals,
+                            &ctx.sequence,
+                            ctx.envelope.as_ref(),
+                            ctx.encoder,
+                        );
+                        for (topic, payload) in messages {
+                            publisher.publish_sample(timer, &topic, &payload).await?;
+                        }
+                    }
+
+                    if let Some(battery) = ctx.sensors.battery() {
+                        if let Some(low) = battery.take_alert() {
+                            let alert = low.to_json(ctx.sequence.next());
+                            publisher
+                                .publish(timer, MessageKind::Alert, &events_topic, alert.as_bytes())
+                                .await?;
+
+                            warn!("Published low battery alert \"{alert}\"");
+                        }
+
+                        if battery.critical() {
+                            warn!("Battery nearly empty, shutting down");
+                            publisher
+                                .publish_retained(
+                                    timer,
+                                    MessageKind::Status,
+                                    &status_topic,
+                                    OFFLINE_PAYLOAD,
+                                )
+                                .await?;
+                            power::deep_sleep(
+                                &ctx.offline,
+                                &ctx.batch,
+                                Duration::from_secs(app_config.battery_shutdown_sleep_secs),
+                            );
+                        }
+                    }
+
+                    if !vibration_interval.is_zero()
+                        && vibration_analysed.map_or(true, |at| at.elapsed() >= vibration_interval)
+                    {
+                        vibration_analysed = Some(Instant::now());
+
+                        // One sample more, in case the rate got rounded down
+                        let window = Duration::from_millis(
+                            ((dsp::FFT_LEN as u64 + 1) * 1000)
+                                .div_ceil(app_config.vibration_rate_hz.max(1) as u64),
+                        );
+                        match fifo::capture(
+                            mpu,
+                            &ctx.settings,
+                            app_config.vibration_rate_hz,
+                            window,
+                        ) {
+                            Ok(capture) => {
+                                if let Some(vibration) =
+                                    dsp::analyse(&capture, app_config.vibration_bands)
+                                {
+                                    let report = vibration.to_json(ctx.sequence.next());
+                                    publisher
+                                        .publish(
+                                            timer,
+                                            MessageKind::Telemetry,
+                                            &vibration_topic,
+                                            report.as_bytes(),
+                                        )
+                                        .await?;
+
+                                    info!("Published vibration \"{report}\"");
+                                } else {
+                                    warn!(
+                                        "Vibration window too short: {} samples",
+                                        capture.acc.len()
+                                    );
+                                }
+                            }
+                            Err(e) => warn!("Vibration capture failed: {e}"),
+                        }
+                    }
+
+                    if net_stats_published.elapsed() >= net_stats_interval {
+                        let report = ctx.stats.to_json();
+                        publisher
+                            .publish(
+                                timer,
+                                MessageKind::Status,
+                                &net_stats_topic,
+                                report.as_bytes(),
+                            )
+                            .await?;
+                        net_stats_published = Instant::now();
+
+                        info!("Published network stats \"{report}\"");
+                    }
+
+                    // Heap and stacks only run low over time, e.g. with a leak
+                    if !memory_interval.is_zero() && status_published.elapsed() >= memory_interval {
+                        let status = online_status(ctx);
+                        publisher
+                            .publish_retained(
+                                timer,
+                                MessageKind::Status,
+                                &status_topic,
+                                status.as_bytes(),
+                            )
+                            .await?;
+                        status_published = Instant::now();
+
+                        info!("Published status \"{status}\"");
+                    }
+
+                    // Not logged itself, every batch would otherwise feed the next one
+                    if log_sink::streaming() && logs_published.elapsed() >= logs_interval {
+                        if let Some(batch) = log_sink::take_batch() {
+                            publisher
+                                .publish(timer, MessageKind::Status, &logs_topic, batch.as_bytes())
+                                .await?;
+                        }
+                        logs_published = Instant::now();
+                    }
+
+                    if !defender_interval.is_zero()
+                        && defender_published.elapsed() >= defender_interval
+                    {
+                        let diagnostics_ap = ctx.diagnostics_ap.lock().unwrap().is_some();
+                        let report = ctx.defender.report(&ctx.stats, diagnostics_ap);
+                        publisher
+                            .publish(
+                                timer,
+                                MessageKind::Status,
+                                ctx.defender.topic(),
+                                report.as_bytes(),
+                            )
+                            .await?;
+                        defender_published = Instant::now();
 
-                    info!("Published \"{payload}\" to topic \"{topic}\"");
+                        info!("Published Device Defender metrics \"{report}\"");
+                    }
 
-                    let sleep_secs = 2;
+                    if let Some(report) = ctx.shadow.take_report(&ctx.settings).filter(|_| aws) {
+                        publisher
+                            .publish(
+                                timer,
+                                MessageKind::Status,
+                                ctx.shadow.update_topic(),
+                                report.as_bytes(),
+                            )
+                            .await?;
 
-                    info!("Now sleeping for {sleep_secs}s...");
-                    timer.after(Duration::from_secs(sleep_secs)).await?;
+                        info!("Reported shadow state \"{report}\"");
+                    }
+
+                    if let Some(remaining) = ctx.quiet_hours.and_then(|quiet| quiet.remaining()) {
+                        if !run_commands(&mut publisher, mpu, timer, ctx).await? {
+                            return Ok(());
+                        }
+                        info!("Quiet hours, sleeping for {}min", remaining.as_secs() / 60);
+                        publisher
+                            .publish_retained(
+                                timer,
+                                MessageKind::Status,
+                                &status_topic,
+                                OFFLINE_PAYLOAD,
+                            )
+                            .await?;
+                        power::deep_sleep(&ctx.offline, &ctx.batch, remaining);
+                    }
+
+                    if app_config.deep_sleep_secs > 0 {
+                        if !run_commands(&mut publisher, mpu, timer, ctx).await? {
+                            return Ok(());
+                        }
+                        // Woken by motion, the timer only makes sure we check in now and then
+                        let duration = match &ctx.motion {
+                            Some(motion) => match motion.enable_deep_sleep_wake(mpu) {
+                                Ok(true) => motion.max_idle(),
+                                Ok(false) => Duration::from_secs(app_config.deep_sleep_secs),
+                                Err(e) => {
+                                    warn!("Motion can't wake the chip from deep sleep: {e}");
+                                    Duration::from_secs(app_config.deep_sleep_secs)
+                                }
+                            },
+                            None => Duration::from_secs(app_config.deep_sleep_secs),
+                        };
+                        power::deep_sleep(&ctx.offline, &ctx.batch, duration);
+                    }
+
+                    // Stretched as the battery runs down
+                    let publish_interval = ctx
+                        .sensors
+                        .battery()
+                        .and_then(|battery| battery.percent())
+                        .and_then(|percent| ctx.settings.battery_policy().interval(percent))
+                        .unwrap_or(ctx.settings.publish_interval());
+
+                    // Lying still, only the keep-alive goes out
+                    let interval = activity.as_ref().map_or(publish_interval, |activity| {
+                        activity.interval(publish_interval)
+                    });
+
+                    info!("Now sleeping for {}s...", interval.as_secs());
+                    let slept_at = Instant::now();
+                    let wake_at = slept_at + interval;
+                    loop {
+                        watchdog::feed();
+
+                        // Sent from here, so it keeps coming during long publish intervals too
+                        if !heartbeat_interval.is_zero()
+                            && heartbeat_published
+                                .map_or(true, |published| published.elapsed() >= heartbeat_interval)
+                        {
+                            let heartbeat = heartbeat::payload();
+                            publisher
+                                .publish(
+                                    timer,
+                                    MessageKind::Status,
+                                    &heartbeat_topic,
+                                    heartbeat.as_bytes(),
+                                )
+                                .await?;
+                            heartbeat_published = Some(Instant::now());
+
+                            info!("Published heartbeat \"{heartbeat}\"");
+                        }
+
+                        run_jobs(&mut publisher, timer, ctx).await?;
+
+                        if !run_commands(&mut publisher, mpu, timer, ctx).await? {
+                            return Ok(());
+                        }
+
+                        // With wake-on-motion, lying still keeps us waiting past the interval
+                        let remaining = wake_at.saturating_duration_since(Instant::now());
+                        if remaining.is_zero()
+                            && ctx.motion.as_ref().map_or(true, |motion| {
+                                slept_at.elapsed() >= motion.max_idle() || motion.take_motion(mpu)
+                            })
+                        {
+                            break;
+                        }
+
+                        let mut wait = if remaining.is_zero() {
+                            COMMAND_POLL_INTERVAL
+                        } else {
+                            remaining.min(COMMAND_POLL_INTERVAL)
+                        };
+                        if let Some(period) = ctx.settings.sample_period() {
+                            if sampled_at.elapsed() >= period {
+                                let signals = telemetry::sample(mpu, &ctx.sensors, &ctx.settings);
+                                let signals = match &mut decimator {
+                                    Some(decimator) => decimator.push(signals),
+                                    None => Some(signals),
+                                };
+                                if let Some(signals) = signals {
+                                    aggregator.push(signals);
+                                }
+                                sampled_at = Instant::now();
+                            }
+                            wait = wait.min(period.saturating_sub(sampled_at.elapsed()));
+                        }
+                        if let Some(ahrs) = &mut ahrs {
+                            wait = wait.min(ahrs.poll(mpu, &ctx.settings));
+                        }
+                        if let Some(orientation) = &mut orientation {
+                            wait = wait.min(orientation.poll(mpu, &ctx.settings));
+                        }
+                        if let Some(activity) = &mut activity {
+                            wait = wait.min(activity.poll(mpu, &ctx.settings));
+
+                            // Publishes right away when the device starts moving again
+                            if activity.take_resumed() {
+                                break;
+                            }
+                        }
+                        if let Some(freefall) = &mut freefall {
+                            wait = wait.min(freefall.poll(mpu, &ctx.settings));
+
+                            if let Some(fall) = freefall.take_alert() {
+                                if let Err(e) = ctx.buzzer.beep(1000) {
+                                    warn!("Failed to drive the buzzer: {e}");
+                                }
+
+                                let alert = fall.to_json(ctx.sequence.next());
+                                publisher
+                                    .publish(
+                                        timer,
+                                        MessageKind::Alert,
+                                        &events_topic,
+                                        alert.as_bytes(),
+                                    )
+                                    .await?;
+
+                                warn!("Published free fall alert \"{alert}\"");
+                            }
+                        }
+                        if let Some(pedometer) = &ctx.pedometer {
+                            let steps = {
+                                let mut pedometer = pedometer.lock().unwrap();
+                                wait = wait.min(pedometer.poll(mpu, &ctx.settings));
+                                pedometer.take_report()
+                            };
+
+                            if let Some(steps) = steps {
+                                let report = pedometer::report_json(ctx.sequence.next(), steps);
+                                publisher
+                                    .publish(
+                                        timer,
+                                        MessageKind::Telemetry,
+                                        &steps_topic,
+                                        report.as_bytes(),
+                                    )
+                                    .await?;
+
+                                info!("Published step count \"{report}\"");
+                            }
+                        }
+                        if let Some(gestures) = &mut gestures {
+                            wait = wait.min(gestures.poll(mpu, &ctx.settings));
+
+                            for gesture in gestures.take_events() {
+                                let event = gesture.to_json(ctx.sequence.next());
+                                publisher
+                                    .publish(
+                                        timer,
+                                        MessageKind::Telemetry,
+                                        &events_topic,
+                                        event.as_bytes(),
+                                    )
+                                    .await?;
+
+                                info!("Published event \"{event}\"");
+                            }
+                        }
+                        timer.after(wait).await?;
+                    }
                 }
             }
         }),
     )
     .await;
 
+    // esp-mqtt's outbox goes with the client, so whatever it still retransmits goes to the next
+    // session by way of the offline buffer
+    for message in ctx.acks.take_unacked() {
+        ctx.offline.push_back(message);
+    }
+
     match res {
         Either::First(res) => res,
         Either::Second(res) => res,
     }
 }
 
-fn mqtt_create(
-    url: &str,
-    client_id: &str,
-    server_cert: X509<'static>,
-    client_cert: X509<'static>,
-    private_key: X509<'static>,
-) -> Result<(EspAsyncMqttClient, EspAsyncMqttConnection), EspError> {
-    let (mqtt_client, mqtt_conn) = EspAsyncMqttClient::new(
-        url,
-        &MqttClientConfiguration {
-            client_id: Some(client_id),
-            crt_bundle_attach: Some(esp_idf_sys::esp_crt_bundle_attach),
-            server_certificate: Some(server_cert),
-            client_certificate: Some(client_cert),
-            private_key: Some(private_key),
-            ..Default::default()
-        },
-    )?;
-
-    Ok((mqtt_client, mqtt_conn))
+/// Runs the commands received since the last call and publishes their acks.
+/// Returns `false` when the session has to end, e.g. to test a new certificate.
+async fn run_commands(
+    publisher: &mut Publisher<'_>,
+    mpu: &mut Imu,
+    timer: &mut EspAsyncTimer,
+    ctx: &Context,
+) -> Result<bool, EspError> {
+    if ctx.button.lock().unwrap().long_pressed() {
+        info!("Button held, calibrating");
+
+        match calibration::calibrate(mpu, &ctx.settings) {
+            Ok(_) => {
+                if let Err(e) = ctx.buzzer.beep(100) {
+                    warn!("Failed to drive the buzzer: {e}");
+                }
+            }
+            Err(e) => warn!("Calibration failed: {e}"),
+        }
+    }
+
+    if !ctx.commands.has_pending() {
+        return Ok(true);
+    }
+
+    let mut command_ctx = CommandContext {
+        mpu,
+        buses: &ctx.buses,
+        buzzer: &ctx.buzzer,
+        settings: &ctx.settings,
+        shadow: &ctx.shadow,
+        reboot: false,
+        factory_reset: false,
+        rotation: None,
+        capture: None,
+        temp_calibration: None,
+    };
+    let acks = ctx.commands.dispatch_pending(&mut command_ctx);
+    let reboot = command_ctx.reboot;
+    let factory_reset = command_ctx.factory_reset;
+    let rotation = command_ctx.rotation.take();
+    let capture = command_ctx.capture.take();
+    let temp_calibration = command_ctx.temp_calibration.take();
+
+    for ack in acks {
+        publisher
+            .publish(
+                timer,
+                MessageKind::Status,
+                ctx.commands.ack_topic(),
+                ack.as_bytes(),
+            )
+            .await?;
+
+        info!("Published command ack \"{ack}\"");
+    }
+
+    if let Some(mut capture) = capture {
+        if let Some(decimation) = &ctx.decimation {
+            capture.decimate(decimation);
+        }
+        let capture_topic = ctx.topics.telemetry("capture");
+        let payload = capture.to_json(ctx.sequence.next());
+        publisher
+            .publish(
+                timer,
+                MessageKind::Telemetry,
+                &capture_topic,
+                payload.as_bytes(),
+            )
+            .await?;
+
+        info!(
+            "Published {} captured samples to topic \"{capture_topic}\"",
+            capture.acc.len()
+        );
+    }
+
+    if let Some(duration) = temp_calibration {
+        info!("Calibrating the gyro over temperature for {duration:?}");
+
+        let result = match calibration::calibrate_temp(mpu, &ctx.settings, timer, duration).await {
+            Ok((model, offsets)) => serde_json::json!({
+                "status": "ok",
+                "ref_c": model.ref_c,
+                "slope": model.slope,
+                "gyro_bias": offsets.gyro,
+            }),
+            Err(e) => {
+                warn!("Temperature calibration failed: {e}");
+                serde_json::json!({ "status": "error", "error": e })
+            }
+        };
+        let result_topic = ctx.topics.status("temp-calibration");
+        publisher
+            .publish(
+                timer,
+                MessageKind::Status,
+                &result_topic,
+                result.to_string().as_bytes(),
+            )
+            .await?;
+    }
+
+    if factory_reset {
+        warn!("Factory reset on request");
+        factory_reset::wipe()?;
+        esp_idf_svc::hal::reset::restart();
+    }
+
+    if reboot {
+        warn!("Rebooting on request");
+        esp_idf_svc::hal::reset::restart();
+    }
+
+    if rotation.is_some() {
+        info!("Ending the session to test the new certificate");
+        *ctx.rotation.lock().unwrap() = rotation;
+
+        return Ok(false);
+    }
+
+    Ok(true)
 }
 
-async fn wifi_create(
-    modem: Modem,
+/// Works on the job execution received since the last call, reporting its progress and outcome.
+/// Only firmware updates are known; once one is installed the device reboots into it and
+/// reports success from there.
+async fn run_jobs(
+    publisher: &mut Publisher<'_>,
+    timer: &mut EspAsyncTimer,
+    ctx: &Context,
+) -> Result<(), EspError> {
+    let Some(jobs) = &ctx.jobs else {
+        return Ok(());
+    };
+    let Some(execution) = jobs.take_pending() else {
+        return Ok(());
+    };
+    let job_id = &execution.job_id;
+
+    let mut ota = EspOta::new()?;
+    let job = match ota::review(&execution, &ota) {
+        Ok(job) => job,
+        Err(verdict) => {
+            info!("Job \"{job_id}\" {}: {}", verdict.status, verdict.reason);
+            let (topic, update) =
+                jobs.update(job_id, verdict.status, &[("reason", verdict.reason)]);
+            return publisher
+                .publish(timer, MessageKind::Status, &topic, update.as_bytes())
+                .await;
+        }
+    };
+
+    info!("Job \"{job_id}\": updating to {}", job.version);
+    let (topic, update) = jobs.update(job_id, "IN_PROGRESS", &[("progress", "0%".to_string())]);
+    publisher
+        .publish(timer, MessageKind::Status, &topic, update.as_bytes())
+        .await?;
+
+    let res = install(publisher, timer, jobs, job_id, &mut ota, &job).await?;
+
+    let (topic, update) = match &res {
+        Ok(()) => jobs.update(
+            job_id,
+            "IN_PROGRESS",
+            &[
+                ("progress", "100%".to_string()),
+                ("step", "rebooting".to_string()),
+            ],
+        ),
+        Err(e) => {
+            error!("Job \"{job_id}\" failed: {e:#}");
+            jobs.update(job_id, "FAILED", &[("reason", format!("{e:#}"))])
+        }
+    };
+    publisher
+        .publish(timer, MessageKind::Status, &topic, update.as_bytes())
+        .await?;
+
+    if res.is_ok() {
+        warn!("Rebooting into {}", job.version);
+        esp_idf_svc::hal::reset::restart();
+    }
+
+    Ok(())
+}
+
+/// Downloads and installs an update, reporting progress every 10% and retrying with backoff
+/// where the transfer broke. The outer error ends the session, the inner one fails the job.
+async fn install(
+    publisher: &mut Publisher<'_>,
+    timer: &mut EspAsyncTimer,
+    jobs: &Jobs,
+    job_id: &str,
+    ota: &mut EspOta,
+    job: &ota::OtaJob,
+) -> Result<anyhow::Result<()>, EspError> {
+    let mut download = match ota::Download::start(ota, job) {
+        Ok(download) => download,
+        Err(e) => return Ok(Err(e)),
+    };
+
+    let mut backoff = Backoff::new(Duration::from_secs(2), Duration::from_secs(60));
+    let mut retries = 0;
+    let mut reported = 0;
+    loop {
+        watchdog::feed();
+
+        match download.next() {
+            Ok(true) => {
+                retries = 0;
+                backoff.reset();
+            }
+            Ok(false) => break,
+            Err(e) if download.can_resume() && retries < ota::MAX_RETRIES => {
+                retries += 1;
+                let delay = backoff.next_delay();
+                warn!(
+                    "Download interrupted: {e:#}, retrying in {}ms",
+                    delay.as_millis()
+                );
+
+                let details = [
+                    ("progress", format!("{}%", download.percent().unwrap_or(0))),
+                    ("retry", format!("{retries}/{}: {e:#}", ota::MAX_RETRIES)),
+                ];
+                let (topic, update) = jobs.update(job_id, "IN_PROGRESS", &details);
+                publisher
+                    .publish(timer, MessageKind::Status, &topic, update.as_bytes())
+                    .await?;
+
+                timer.after(delay).await?;
+                continue;
+            }
+            Err(e) => return Ok(Err(e)),
+        }
+
+        let percent = download.percent().unwrap_or(0);
+        if percent / 10 <= reported / 10 {
+            continue;
+        }
+        reported = percent;
+
+        let details = [("progress", format!("{percent}%"))];
+        let (topic, update) = jobs.update(job_id, "IN_PROGRESS", &details);
+        publisher
+            .publish(timer, MessageKind::Status, &topic, update.as_bytes())
+            .await?;
+    }
+
+    Ok(download.finish())
+}
+
+/// Samples into the offline buffer for `duration`, e.g. while waiting to reconnect
+async fn sample_offline(
+    mpu: &mut Imu,
+    timer: &mut EspAsyncTimer,
+    ctx: &Context,
+    duration: Duration,
+) -> Result<(), EspError> {
+    let started = Instant::now();
+
+    loop {
+        watchdog::feed();
+        buffer_sample(mpu, ctx);
+
+        let remaining = duration.saturating_sub(started.elapsed());
+        if remaining.is_zero() {
+            return Ok(());
+        }
+
+        timer
+            .after(remaining.min(ctx.settings.sample_interval()))
+            .await?;
+    }
+}
+
+/// Takes one round of readings straight into the offline buffer
+fn buffer_sample(mpu: &mut Imu, ctx: &Context) {
+    let mut signals = telemetry::read(mpu, &ctx.sensors, &ctx.settings);
+    ctx.diagnostics.lock().unwrap().last_reading = Some(telemetry::to_json(&signals));
+
+    // Sparkplug sequence numbers and aliases only hold within a session
+    #[cfg(feature = "protobuf")]
+    if ctx.sparkplug.is_some() {
+        return;
+    }
+
+    if let Some(selection) = &ctx.selection {
+        selection.apply(&mut signals);
+    }
+
+    let messages = telemetry::messages(
+        &ctx.topics,
+        &signals,
+        &ctx.sequence,
+        ctx.envelope.as_ref(),
+        ctx.encoder,
+    );
+    for (topic, payload) in messages {
+        ctx.offline.push_back(BufferedMessage::new(
+            MessageKind::Telemetry,
+            &topic,
+            false,
+            &payload,
+        ));
+    }
+}
+
+/// Sends readings to the relay for `espnow_retry_secs`, then returns so WiFi is tried again
+async fn run_espnow_fallback(
+    esp_wifi: &mut EspWifi<'static>,
+    mpu: &mut Imu,
+    timer: &mut EspAsyncTimer,
+    peer: [u8; 6],
     app_config: &Config,
-    sys_loop: &EspSystemEventLoop,
-    timer_service: &EspTaskTimerService,
-    nvs: &EspDefaultNvsPartition,
-) -> Result<EspWifi<'static>, EspError> {
-    let mut esp_wifi = EspWifi::new(modem, sys_loop.clone(), Some(nvs.clone()))?;
-    let mut wifi = AsyncWifi::wrap(&mut esp_wifi, sys_loop.clone(), timer_service.clone())?;
+    sensors: &Registry,
+    settings: &Settings,
+) -> Result<(), EspError> {
+    // ESP-NOW only needs the radio running, not an association with an AP
+    if !esp_wifi.is_started()? {
+        esp_wifi.start()?;
+    }
+    let _ = esp_wifi.disconnect();
+
+    let sender = EspNowSender::new(peer, app_config.espnow_channel)?;
+    info!(
+        "ESP-NOW sender ready on channel {}",
+        app_config.espnow_channel
+    );
+
+    let retry_after = Duration::from_secs(app_config.espnow_retry_secs);
+    let started = Instant::now();
+    while started.elapsed() < retry_after {
+        let payload = telemetry::to_json(&telemetry::read(mpu, sensors, settings));
 
-    wifi.set_configuration(&Configuration::Client(ClientConfiguration {
-        ssid: app_config.wifi_ssid.try_into().unwrap(),
-        password: app_config.wifi_password.try_into().unwrap(),
-        ..Default::default()
-    }))?;
+        match sender.send(topics::TELEMETRY_IMU, payload.as_bytes()) {
+            Ok(()) => info!("Sent \"{payload}\" to relay {peer:02x?}"),
+            Err(e) => warn!("Failed to send ESP-NOW frame: {e}"),
+        }
 
-    wifi.start().await?;
-    info!("Wifi started");
+        timer.after(Duration::from_secs(2)).await?;
+    }
 
-    wifi.connect().await?;
-    info!("Wifi connected");
+    Ok(())
+}
 
-    wifi.wait_netif_up().await?;
-    info!("Wifi netif up");
+/// Hands a received message to whichever part of the firmware owns its topic
+fn route_message(ctx: &Context, topic: &str, data: &[u8]) {
+    #[cfg(feature = "protobuf")]
+    if ctx
+        .sparkplug
+        .as_ref()
+        .is_some_and(|sparkplug| sparkplug.handle_message(topic, data))
+    {
+        return;
+    }
+
+    if ctx
+        .jobs
+        .as_ref()
+        .is_some_and(|jobs| jobs.handle_message(topic, data))
+    {
+        return;
+    }
+
+    if ctx.commands.handle_message(topic, data)
+        || ctx
+            .control
+            .handle_message(topic, data, &ctx.buzzer, &ctx.led)
+    {
+        return;
+    }
+
+    if ctx.remote_config.handle_message(topic, data, &ctx.settings) {
+        // The shadow reports the publish interval, which may have changed
+        ctx.shadow.request_report();
+        return;
+    }
 
-    Ok(esp_wifi)
+    ctx.shadow.handle_message(topic, data, &ctx.settings);
 }
 
 fn convert_certificate(mut certificate_bytes: Vec<u8>) -> X509<'static> {
@@ -0,0 +1,52 @@
+use std::collections::BTreeMap;
+use std::ffi::CString;
+
+use esp_idf_svc::sys::{
+    esp_get_free_heap_size, esp_get_minimum_free_heap_size, heap_caps_get_largest_free_block,
+    uxTaskGetStackHighWaterMark, xTaskGetHandle, MALLOC_CAP_8BIT,
+};
+use serde::Serialize;
+
+use log::*;
+
+/// Tasks whose stacks are watched: ours, the MQTT client (6KB by default), the lwIP and
+/// Wi-Fi stacks, and the timer and event loop tasks callbacks run in
+const TASKS: [&str; 6] = ["main", "mqtt_task", "tiT", "wifi", "esp_timer", "sys_evt"];
+/// Stack left at which a task is close to overflowing
+const STACK_WARN_BYTES: u32 = 512;
+
+/// `{"free_heap": 81234, "min_free_heap": 40123, "largest_free_block": 65536,
+/// "stack_free": {"main": 2120, "mqtt_task": 1504, ..}}`, stacks as the fewest bytes ever left
+/// unused. Tasks that don't exist, e.g. before Wi-Fi started, are left out.
+#[derive(Debug, Serialize)]
+pub struct MemoryReport {
+    free_heap: u32,
+    min_free_heap: u32,
+    largest_free_block: usize,
+    stack_free: BTreeMap<&'static str, u32>,
+}
+
+pub fn report() -> MemoryReport {
+    let mut stack_free = BTreeMap::new();
+    for task in TASKS {
+        let name = CString::new(task).unwrap();
+        let handle = unsafe { xTaskGetHandle(name.as_ptr()) };
+        if handle.is_null() {
+            continue;
+        }
+
+        // ESP-IDF counts stacks in bytes
+        let free = unsafe { uxTaskGetStackHighWaterMark(handle) };
+        if free < STACK_WARN_BYTES {
+            warn!("Task \"{task}\" has only {free} bytes of stack left");
+        }
+        stack_free.insert(task, free);
+    }
+
+    MemoryReport {
+        free_heap: unsafe { esp_get_free_heap_size() },
+        min_free_heap: unsafe { esp_get_minimum_free_heap_size() },
+        largest_free_block: unsafe { heap_caps_get_largest_free_block(MALLOC_CAP_8BIT) },
+        stack_free,
+    }
+}
@@ -0,0 +1,64 @@
+use serde::Serialize;
+
+use log::*;
+
+use crate::Config;
+
+#[derive(Clone, Debug, Serialize)]
+struct Location {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    building: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    room: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    lat: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    lon: Option<f64>,
+}
+
+/// Where the device is installed and what it is attached to, so the dashboard can place it
+/// without a registry of its own: `{"asset_id": "pump-3", "location": {"building": "B2",
+/// "room": "201", "lat": 35.68, "lon": 139.76}}`, leaving out what isn't configured
+#[derive(Clone, Debug, Serialize)]
+pub struct Metadata {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    asset_id: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    location: Option<Location>,
+}
+
+impl Metadata {
+    /// `None` unless an asset id or any part of the location is set
+    pub fn from_config(app_config: &Config) -> Option<Self> {
+        let text = |value: &'static str| (!value.is_empty()).then_some(value);
+
+        let coordinates = text(app_config.location_coordinates).and_then(|value| {
+            let parsed = value
+                .split_once(',')
+                .and_then(|(lat, lon)| Some((lat.trim().parse().ok()?, lon.trim().parse().ok()?)));
+            if parsed.is_none() {
+                warn!("Invalid location coordinates \"{value}\", expected e.g. \"35.68,139.76\"");
+            }
+            parsed
+        });
+
+        let location = Location {
+            building: text(app_config.location_building),
+            room: text(app_config.location_room),
+            lat: coordinates.map(|(lat, _)| lat),
+            lon: coordinates.map(|(_, lon)| lon),
+        };
+        let has_location =
+            location.building.is_some() || location.room.is_some() || coordinates.is_some();
+
+        let metadata = Self {
+            asset_id: text(app_config.asset_id),
+            location: has_location.then_some(location),
+        };
+        if metadata.asset_id.is_none() && metadata.location.is_none() {
+            return None;
+        }
+
+        Some(metadata)
+    }
+}
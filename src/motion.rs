@@ -0,0 +1,131 @@
+use std::time::Duration;
+
+use esp_idf_svc::hal::gpio::{AnyInputPin, Input, PinDriver};
+use esp_idf_svc::sys::{self, esp, EspError};
+
+use log::*;
+
+use crate::imu::Imu;
+use crate::Config;
+
+/// Registers 32-34, one wake-on-motion threshold per axis in 4mg steps
+const ACCEL_WOM_X_THR: u8 = 0x20;
+/// Register 55, `LATCH_INT_EN`: INT stays high until INT_STATUS is read
+const INT_PIN_CFG: u8 = 0x37;
+const INT_LATCHED: u8 = 0x20;
+/// Register 56, `WOM_X/Y/Z_INT_EN` in bits 7:5
+const INT_ENABLE: u8 = 0x38;
+const WOM_INT_EN: u8 = 0xe0;
+/// Register 58, `WOM_X/Y/Z_INT` in bits 7:5
+const INT_STATUS: u8 = 0x3a;
+/// Register 105, `ACCEL_INTEL_EN` and `ACCEL_INTEL_MODE` (compare against the previous sample)
+const ACCEL_INTEL_CTRL: u8 = 0x69;
+const ACCEL_INTEL_ON: u8 = 0xc0;
+
+/// Publishes only once the device moved: the sensor latches a motion interrupt whenever an
+/// axis changes by more than the threshold, which is polled (or wakes the chip on its INT pin)
+/// between publishes
+pub struct MotionWake {
+    pin: Option<PinDriver<'static, AnyInputPin, Input>>,
+    gpio: i32,
+    max_idle: Duration,
+}
+
+impl MotionWake {
+    /// `None` unless `motion_wake` is set
+    pub fn from_config(app_config: &Config, mpu: &mut Imu) -> Result<Option<Self>, EspError> {
+        if !app_config.motion_wake {
+            return Ok(None);
+        }
+
+        if let Err(e) = enable(mpu, app_config.motion_threshold_mg) {
+            warn!("Failed to set up wake-on-motion: {e}");
+            return Ok(None);
+        }
+
+        let pin = if app_config.motion_int_gpio < 0 {
+            None
+        } else {
+            // Safety: the pin number comes from the board configuration and is not used elsewhere
+            let pin = PinDriver::input(unsafe { AnyInputPin::new(app_config.motion_int_gpio) })?;
+            // Lets the chip leave light sleep while the interrupt is latched
+            esp!(unsafe {
+                sys::gpio_wakeup_enable(
+                    app_config.motion_int_gpio,
+                    sys::gpio_int_type_t_GPIO_INTR_HIGH_LEVEL,
+                )
+            })?;
+            esp!(unsafe { sys::esp_sleep_enable_gpio_wakeup() })?;
+
+            Some(pin)
+        };
+
+        info!(
+            "Wake-on-motion above {}mg, publishing at least every {}s",
+            app_config.motion_threshold_mg, app_config.motion_max_idle_secs
+        );
+
+        Ok(Some(Self {
+            pin,
+            gpio: app_config.motion_int_gpio,
+            max_idle: Duration::from_secs(app_config.motion_max_idle_secs),
+        }))
+    }
+
+    /// Longest time without a publish while the device lies still
+    pub fn max_idle(&self) -> Duration {
+        self.max_idle
+    }
+
+    /// Lets the INT pin wake the chip from deep sleep (EXT0), which only RTC GPIOs can. Returns
+    /// `false` if the interrupt isn't wired, so only a timer can wake the device.
+    pub fn enable_deep_sleep_wake(&self, mpu: &mut Imu) -> Result<bool, EspError> {
+        if self.pin.is_none() {
+            return Ok(false);
+        }
+
+        // A motion latched since the last publish would wake the chip right away
+        if let Err(e) = mpu.read_byte(INT_STATUS) {
+            warn!("Failed to clear the motion interrupt: {e:?}");
+        }
+        esp!(unsafe { sys::esp_sleep_enable_ext0_wakeup(self.gpio, 1) })?;
+
+        Ok(true)
+    }
+
+    /// Whether the device moved since the last call; clears the latched interrupt
+    pub fn take_motion(&self, mpu: &mut Imu) -> bool {
+        // Spares the I2C read while INT is low
+        if self.pin.as_ref().is_some_and(|pin| pin.is_low()) {
+            return false;
+        }
+
+        match mpu.read_byte(INT_STATUS) {
+            Ok(status) => status & WOM_INT_EN != 0,
+            Err(e) => {
+                warn!("Failed to read the motion interrupt: {e:?}");
+                // Rather publish once too often than miss the motion
+                true
+            }
+        }
+    }
+}
+
+fn enable(mpu: &mut Imu, threshold_mg: u16) -> Result<(), String> {
+    let threshold = (threshold_mg / 4).min(u8::MAX as u16) as u8;
+
+    for reg in ACCEL_WOM_X_THR..ACCEL_WOM_X_THR + 3 {
+        write_byte(mpu, reg, threshold)?;
+    }
+    write_byte(mpu, INT_PIN_CFG, INT_LATCHED)?;
+    write_byte(mpu, INT_ENABLE, WOM_INT_EN)?;
+    write_byte(mpu, ACCEL_INTEL_CTRL, ACCEL_INTEL_ON)?;
+    // Start out without a stale interrupt
+    mpu.read_byte(INT_STATUS).map_err(|e| format!("{e:?}"))?;
+
+    Ok(())
+}
+
+fn write_byte(mpu: &mut Imu, reg: u8, byte: u8) -> Result<(), String> {
+    mpu.write_byte(reg, byte).map_err(|e| format!("{e:?}"))
+}
@@ -0,0 +1,665 @@
+use core::sync::atomic::{AtomicU8, Ordering};
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use esp_idf_svc::mqtt::client::*;
+use esp_idf_svc::sys::EspError;
+use esp_idf_svc::timer::EspAsyncTimer;
+use esp_idf_svc::tls::X509;
+
+use log::*;
+
+use crate::batch::Batcher;
+use crate::encoding;
+use crate::net_stats::NetStats;
+use crate::offline_buffer::{BufferedMessage, OfflineBuffer};
+use crate::rate_limit::RateLimiter;
+use crate::signing::Signer;
+
+/// How long a QoS 1 publish waits for its PUBACK; esp-mqtt keeps retransmitting the message
+/// from its outbox meanwhile and after, so it is never published again by us
+const PUBACK_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// PUBACKs remembered while the publisher is not waiting for them yet
+const MAX_TRACKED_ACKS: usize = 16;
+
+/// QoS 1 messages kept until their PUBACK, to be buffered offline if the session ends first
+const MAX_UNACKED: usize = 32;
+
+/// esp-mqtt's receive buffer, 1 KB by default; larger messages arrive in chunks
+const RX_BUFFER_SIZE: usize = 4096;
+
+/// Incoming messages larger than this are dropped rather than reassembled
+const MAX_MESSAGE_LEN: usize = 16 * 1024;
+
+/// Kinds of outgoing messages, each with its own delivery guarantee
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MessageKind {
+    Telemetry,
+    Alert,
+    Status,
+}
+
+/// QoS level per message kind, changeable at runtime
+pub struct QosSettings {
+    telemetry: AtomicU8,
+    alert: AtomicU8,
+    status: AtomicU8,
+}
+
+impl QosSettings {
+    pub fn new(telemetry: u8, alert: u8, status: u8) -> Self {
+        Self {
+            telemetry: AtomicU8::new(supported_level(telemetry)),
+            alert: AtomicU8::new(supported_level(alert)),
+            status: AtomicU8::new(supported_level(status)),
+        }
+    }
+
+    fn slot(&self, kind: MessageKind) -> &AtomicU8 {
+        match kind {
+            MessageKind::Telemetry => &self.telemetry,
+            MessageKind::Alert => &self.alert,
+            MessageKind::Status => &self.status,
+        }
+    }
+
+    pub fn level(&self, kind: MessageKind) -> u8 {
+        self.slot(kind).load(Ordering::Relaxed)
+    }
+
+    pub fn set_level(&self, kind: MessageKind, level: u8) {
+        self.slot(kind)
+            .store(supported_level(level), Ordering::Relaxed);
+    }
+
+    pub fn qos(&self, kind: MessageKind) -> QoS {
+        match self.level(kind) {
+            0 => QoS::AtMostOnce,
+            _ => QoS::AtLeastOnce,
+        }
+    }
+}
+
+/// Only QoS 0 and 1 are supported by AWS IoT
+fn supported_level(level: u8) -> u8 {
+    if level > 1 {
+        warn!("Unsupported QoS {level}, using 1");
+        return 1;
+    }
+
+    level
+}
+
+#[derive(Default)]
+struct AckState {
+    acked: VecDeque<MessageId>,
+    /// Published in this session and still in esp-mqtt's outbox
+    unacked: VecDeque<(MessageId, BufferedMessage)>,
+}
+
+/// PUBACKs seen on the connection, handed over to the publisher waiting for them
+#[derive(Default)]
+pub struct PubAcks {
+    state: Mutex<AckState>,
+}
+
+impl PubAcks {
+    /// Called from the connection loop for every `Published` event
+    pub fn record(&self, id: MessageId) {
+        let mut state = self.state.lock().unwrap();
+        state.unacked.retain(|(unacked, _)| *unacked != id);
+        if state.acked.len() >= MAX_TRACKED_ACKS {
+            state.acked.pop_front();
+        }
+        state.acked.push_back(id);
+    }
+
+    /// Keeps a QoS 1 message until its PUBACK arrives
+    fn track(&self, id: MessageId, message: BufferedMessage) {
+        let mut state = self.state.lock().unwrap();
+        if state.acked.contains(&id) {
+            return;
+        }
+        if state.unacked.len() >= MAX_UNACKED {
+            if let Some((_, message)) = state.unacked.pop_front() {
+                warn!(
+                    "Too many messages without a PUBACK, no longer tracking one to \"{}\"",
+                    message.topic
+                );
+            }
+        }
+        state.unacked.push_back((id, message));
+    }
+
+    /// The messages still unacknowledged when a session ends, as esp-mqtt's outbox goes with
+    /// its client
+    pub fn take_unacked(&self) -> Vec<BufferedMessage> {
+        let mut state = self.state.lock().unwrap();
+        state.acked.clear();
+        state
+            .unacked
+            .drain(..)
+            .map(|(_, message)| message)
+            .collect()
+    }
+
+    fn take(&self, id: MessageId) -> bool {
+        let mut state = self.state.lock().unwrap();
+        match state.acked.iter().position(|acked| *acked == id) {
+            Some(index) => {
+                state.acked.remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns `false` if no PUBACK arrived within `timeout`
+    pub async fn wait(
+        &self,
+        id: MessageId,
+        timer: &mut EspAsyncTimer,
+        timeout: Duration,
+    ) -> Result<bool, EspError> {
+        let started = Instant::now();
+
+        loop {
+            if self.take(id) {
+                return Ok(true);
+            }
+
+            if started.elapsed() >= timeout {
+                return Ok(false);
+            }
+
+            timer.after(Duration::from_millis(20)).await?;
+        }
+    }
+}
+
+/// A message still missing some of its chunks
+struct Partial {
+    topic: String,
+    payload: Vec<u8>,
+    total: usize,
+}
+
+/// Puts incoming messages larger than esp-mqtt's receive buffer back together; esp-mqtt hands
+/// them out in chunks and only the first one carries the topic
+#[derive(Default)]
+pub struct Reassembler {
+    partial: Option<Partial>,
+}
+
+impl Reassembler {
+    /// Returns the topic and the whole payload once the last chunk of a message arrived
+    pub fn push(
+        &mut self,
+        topic: Option<&str>,
+        data: &[u8],
+        details: Details,
+    ) -> Option<(String, Vec<u8>)> {
+        match details {
+            Details::Complete => topic.map(|topic| (topic.to_string(), data.to_vec())),
+            Details::InitialChunk(chunk) => {
+                self.partial = None;
+                if chunk.total_data_size > MAX_MESSAGE_LEN {
+                    warn!(
+                        "Dropped a {} byte message on {topic:?}, more than {MAX_MESSAGE_LEN}",
+                        chunk.total_data_size
+                    );
+                    return None;
+                }
+
+                let mut payload = Vec::with_capacity(chunk.total_data_size);
+                payload.extend_from_slice(data);
+                self.partial = topic.map(|topic| Partial {
+                    topic: topic.to_string(),
+                    payload,
+                    total: chunk.total_data_size,
+                });
+
+                None
+            }
+            Details::SubsequentChunk(chunk) => {
+                let partial = self.partial.as_mut()?;
+                if chunk.current_data_offset != partial.payload.len() {
+                    warn!("Lost part of a message on \"{}\"", partial.topic);
+                    self.partial = None;
+                    return None;
+                }
+
+                partial.payload.extend_from_slice(data);
+                if partial.payload.len() < partial.total {
+                    return None;
+                }
+
+                self.partial
+                    .take()
+                    .map(|partial| (partial.topic, partial.payload))
+            }
+        }
+    }
+}
+
+/// Topics subscribed to again whenever a new session is established
+#[derive(Default)]
+pub struct Subscriptions {
+    topics: Mutex<Vec<(String, QoS)>>,
+}
+
+impl Subscriptions {
+    pub fn track(&self, topic: &str, qos: QoS) {
+        let mut topics = self.topics.lock().unwrap();
+        if !topics.iter().any(|(tracked, _)| tracked == topic) {
+            topics.push((topic.to_string(), qos));
+        }
+    }
+
+    fn all(&self) -> Vec<(String, QoS)> {
+        self.topics.lock().unwrap().clone()
+    }
+}
+
+/// Exponential backoff with jitter, so a fleet does not reconnect in lockstep after a broker outage
+pub struct Backoff {
+    base: Duration,
+    max: Duration,
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn new(base: Duration, max: Duration) -> Self {
+        Self {
+            base,
+            max,
+            attempt: 0,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+
+    /// Returns a random delay between half and all of the current backoff step
+    pub fn next_delay(&mut self) -> Duration {
+        let step = self
+            .base
+            .saturating_mul(1 << self.attempt.min(16))
+            .min(self.max);
+        self.attempt = self.attempt.saturating_add(1);
+
+        let half = step.as_millis() as u32 / 2;
+        let jitter = unsafe { esp_idf_svc::sys::esp_random() } % (half + 1);
+
+        Duration::from_millis((half + jitter) as u64)
+    }
+}
+
+/// State shared between sessions which every publish touches
+pub struct PublishContext<'a> {
+    pub acks: &'a PubAcks,
+    pub stats: &'a NetStats,
+    pub subscriptions: &'a Subscriptions,
+    pub offline: &'a OfflineBuffer,
+    pub batch: &'a Mutex<Batcher>,
+    pub limiter: &'a Mutex<RateLimiter>,
+    pub qos: &'a QosSettings,
+    pub signer: Option<&'a Signer>,
+}
+
+/// Wraps the MQTT client so every publish is accounted for and QoS 1 waits for its PUBACK
+pub struct Publisher<'a> {
+    client: &'a mut EspAsyncMqttClient,
+    acks: &'a PubAcks,
+    stats: &'a NetStats,
+    subscriptions: &'a Subscriptions,
+    offline: &'a OfflineBuffer,
+    batch: &'a Mutex<Batcher>,
+    limiter: &'a Mutex<RateLimiter>,
+    qos: &'a QosSettings,
+    signer: Option<&'a Signer>,
+}
+
+impl<'a> Publisher<'a> {
+    pub fn new(client: &'a mut EspAsyncMqttClient, ctx: PublishContext<'a>) -> Self {
+        let PublishContext {
+            acks,
+            stats,
+            subscriptions,
+            offline,
+            batch,
+            limiter,
+            qos,
+            signer,
+        } = ctx;
+
+        Self {
+            client,
+            acks,
+            stats,
+            subscriptions,
+            offline,
+            batch,
+            limiter,
+            qos,
+            signer,
+        }
+    }
+
+    /// What goes on the wire; buffered messages keep the unsigned payload and are signed on replay
+    fn signed<'p>(&self, topic: &str, payload: &'p [u8]) -> Cow<'p, [u8]> {
+        match self.signer {
+            Some(signer) => Cow::Owned(signer.sign(topic, payload)),
+            None => Cow::Borrowed(payload),
+        }
+    }
+
+    /// Waits for the rate limiter; returns `false` if the message is to be dropped instead
+    async fn acquire(
+        &mut self,
+        timer: &mut EspAsyncTimer,
+        kind: MessageKind,
+    ) -> Result<bool, EspError> {
+        loop {
+            let wait = {
+                let mut limiter = self.limiter.lock().unwrap();
+                if limiter.try_take() {
+                    return Ok(true);
+                }
+
+                if kind == MessageKind::Telemetry && limiter.drop_telemetry() {
+                    self.stats.record_rate_limited();
+                    return Ok(false);
+                }
+
+                limiter.wait_time()
+            };
+
+            timer.after(wait).await?;
+        }
+    }
+
+    /// Subscribes to every topic tracked so far, e.g. after a reconnect
+    pub async fn resubscribe_all(&mut self) -> Result<(), EspError> {
+        for (topic, qos) in self.subscriptions.all() {
+            if let Err(e) = self.client.subscribe(&topic, qos).await {
+                self.stats.record_subscribe_failed();
+                return Err(e);
+            }
+            info!("Subscribed to topic \"{topic}\"");
+        }
+
+        Ok(())
+    }
+
+    /// Replays whatever was buffered while offline, oldest first
+    pub async fn flush_offline(&mut self, timer: &mut EspAsyncTimer) -> Result<(), EspError> {
+        if !self.offline.is_empty() {
+            info!("Replaying {} offline messages", self.offline.len());
+        }
+
+        while let Some(message) = self.offline.pop_front() {
+            // Buffered messages are never dropped by the limiter, they just take longer to replay
+            self.acquire(timer, MessageKind::Status).await?;
+
+            let qos = self.qos.qos(message.kind);
+            let payload = message.replay_payload();
+            let payload = self.signed(&message.topic, &payload);
+
+            let id = match self
+                .client
+                .publish(&message.topic, qos, message.retain, &payload)
+                .await
+            {
+                Ok(id) => id,
+                Err(e) => {
+                    self.offline.push_front(message);
+                    return Err(e);
+                }
+            };
+            self.stats.record_sent(payload.len());
+
+            info!("Replayed offline message to topic \"{}\"", message.topic);
+            if !matches!(qos, QoS::AtMostOnce) {
+                self.acks.track(id, message);
+            }
+
+            // Give the connection loop a chance to deliver PUBACKs and other events in between
+            timer.after(Duration::from_millis(10)).await?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn publish(
+        &mut self,
+        timer: &mut EspAsyncTimer,
+        kind: MessageKind,
+        topic: &str,
+        payload: &[u8],
+    ) -> Result<(), EspError> {
+        if kind == MessageKind::Alert {
+            let pending = {
+                let mut batch = self.batch.lock().unwrap();
+                if batch.flush_on_alert() {
+                    batch.flush()
+                } else {
+                    Vec::new()
+                }
+            };
+
+            // Samples leading up to the alert go out first, so the backend sees them together
+            for (batch_topic, batch) in pending {
+                self.publish_with(timer, MessageKind::Telemetry, &batch_topic, false, &batch)
+                    .await?;
+            }
+        }
+
+        self.publish_with(timer, kind, topic, false, payload).await
+    }
+
+    /// Publishes a telemetry sample, possibly later as part of a batch
+    pub async fn publish_sample(
+        &mut self,
+        timer: &mut EspAsyncTimer,
+        topic: &str,
+        sample: &[u8],
+    ) -> Result<(), EspError> {
+        let ready = self.batch.lock().unwrap().push(topic, sample);
+
+        if let Some((topic, payload)) = ready {
+            self.publish_with(timer, MessageKind::Telemetry, &topic, false, &payload)
+                .await?;
+
+            info!(
+                "Published \"{}\" to topic \"{topic}\"",
+                encoding::display(&payload)
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Like [`Self::publish`], but the broker keeps the message for late subscribers
+    pub async fn publish_retained(
+        &mut self,
+        timer: &mut EspAsyncTimer,
+        kind: MessageKind,
+        topic: &str,
+        payload: &[u8],
+    ) -> Result<(), EspError> {
+        self.publish_with(timer, kind, topic, true, payload).await
+    }
+
+    async fn publish_with(
+        &mut self,
+        timer: &mut EspAsyncTimer,
+        kind: MessageKind,
+        topic: &str,
+        retain: bool,
+        payload: &[u8],
+    ) -> Result<(), EspError> {
+        if !self.acquire(timer, kind).await? {
+            warn!("Publish rate limit reached, dropped message to \"{topic}\"");
+            return Ok(());
+        }
+
+        let qos = self.qos.qos(kind);
+        let signed = self.signed(topic, payload);
+        let sent_at = Instant::now();
+        let id = match self.client.publish(topic, qos, retain, &signed).await {
+            Ok(id) => id,
+            Err(e) => {
+                self.offline
+                    .push_back(BufferedMessage::new(kind, topic, retain, payload));
+                return Err(e);
+            }
+        };
+        self.stats.record_sent(signed.len());
+
+        if matches!(qos, QoS::AtMostOnce) {
+            return Ok(());
+        }
+
+        self.acks
+            .track(id, BufferedMessage::new(kind, topic, retain, payload));
+        if self.acks.wait(id, timer, PUBACK_TIMEOUT).await? {
+            self.stats.record_puback(sent_at.elapsed());
+        } else {
+            // Still in the outbox; buffered offline only if the session ends before it's acked
+            warn!("No PUBACK for message {id} on \"{topic}\" within {PUBACK_TIMEOUT:?}");
+            self.stats.record_puback_timeout();
+        }
+
+        Ok(())
+    }
+}
+
+/// Kind of broker the device talks to, `broker` in `cfg.toml`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Broker {
+    /// AWS IoT Core with mutual TLS, the device shadow and the other AWS services
+    Aws,
+    /// A plain MQTT broker such as Mosquitto or EMQX, e.g. for students without an AWS account
+    Generic,
+    /// Soracom Beam or Funnel: plain MQTT inside the Soracom network, which authenticates the
+    /// device and adds TLS upstream. Beam prepends the topic prefix set in the SIM group.
+    Soracom,
+}
+
+impl Broker {
+    /// Unknown values fall back to `Aws`, which is what the firmware was built for
+    pub fn from_config(value: &str) -> Self {
+        match value {
+            "aws" | "" => Self::Aws,
+            "generic" => Self::Generic,
+            "soracom" => Self::Soracom,
+            other => {
+                warn!("Unknown broker \"{other}\", using \"aws\"");
+                Self::Aws
+            }
+        }
+    }
+
+    pub fn is_aws(self) -> bool {
+        self == Self::Aws
+    }
+
+    /// Broker URL used when `aws_iot_endpoint` is left empty
+    pub fn default_endpoint(self) -> Option<&'static str> {
+        match self {
+            Self::Soracom => Some("mqtt://beam.soracom.io:1883"),
+            Self::Aws | Self::Generic => None,
+        }
+    }
+}
+
+/// How the client proves its identity to the broker
+#[derive(Clone, Copy)]
+pub enum MqttAuth {
+    /// Mutual TLS with an X.509 client certificate, as AWS IoT expects
+    Certificate {
+        client_cert: X509<'static>,
+        private_key: X509<'static>,
+    },
+    /// Username and password instead of a client certificate, e.g. for a local test broker.
+    /// A non-empty `authorizer` selects an AWS IoT custom authorizer by name.
+    Password {
+        username: &'static str,
+        password: &'static str,
+        authorizer: &'static str,
+    },
+    /// No client authentication at all, e.g. an open Mosquitto broker or Soracom
+    Anonymous,
+}
+
+/// Retained on the status topic by the broker when the device drops off without a clean disconnect
+pub const OFFLINE_PAYLOAD: &[u8] = b"{\"state\": \"offline\"}";
+
+pub fn mqtt_create(
+    url: &str,
+    client_id: &str,
+    will_topic: &str,
+    will_payload: &[u8],
+    server_cert: Option<X509<'static>>,
+    auth: MqttAuth,
+) -> Result<(EspAsyncMqttClient, EspAsyncMqttConnection), EspError> {
+    let (client_cert, private_key, username, password) = match auth {
+        MqttAuth::Certificate {
+            client_cert,
+            private_key,
+        } => (Some(client_cert), Some(private_key), None, None),
+        MqttAuth::Password {
+            username,
+            password,
+            authorizer,
+        } => {
+            // AWS IoT reads the authorizer name from a query string on the username
+            let username = if authorizer.is_empty() {
+                username.to_string()
+            } else {
+                format!("{username}?x-amz-customauthorizer-name={authorizer}")
+            };
+
+            (None, None, Some(username), Some(password))
+        }
+        MqttAuth::Anonymous => (None, None, None, None),
+    };
+
+    let (mqtt_client, mqtt_conn) = EspAsyncMqttClient::new(
+        url,
+        &MqttClientConfiguration {
+            // Stays on MQTT 3.1.1: `MqttProtocolVersion` in esp-idf-svc 0.49 has no V5 variant and
+            // the async client offers no way to attach user properties (firmware/schema version)
+            client_id: Some(client_id),
+            lwt: Some(LwtConfiguration {
+                topic: will_topic,
+                payload: will_payload,
+                qos: QoS::AtLeastOnce,
+                retain: true,
+            }),
+            // Every session does a full TLS handshake: esp-tls can resume sessions through
+            // `client_session`, but esp-mqtt neither fills it in nor hands the session out,
+            // and esp-idf-svc 0.49 exposes neither, so there is nothing to keep across reconnects
+            crt_bundle_attach: Some(esp_idf_sys::esp_crt_bundle_attach),
+            // Without a pinned CA the bundle verifies the broker; `mqtt://` URLs skip TLS entirely
+            server_certificate: server_cert,
+            client_certificate: client_cert,
+            private_key,
+            username: username.as_deref(),
+            password,
+            // Fits a `rotate_cert` command, a PEM certificate plus its private key, in one piece
+            buffer_size: RX_BUFFER_SIZE,
+            // MQTT on port 443 (for networks blocking 8883) needs the `x-amzn-mqtt-ca` ALPN,
+            // but esp-idf-svc 0.49 does not expose `alpn_protos` here and starts the client
+            // before its raw handle could be patched; revisit once the field is available
+            ..Default::default()
+        },
+    )?;
+
+    Ok((mqtt_client, mqtt_conn))
+}
@@ -0,0 +1,141 @@
+use core::sync::atomic::{AtomicU32, Ordering};
+use core::time::Duration;
+
+use serde::Serialize;
+
+/// Counters describing how the network link behaves, published for fleet debugging
+#[derive(Default)]
+pub struct NetStats {
+    bytes_sent: AtomicU32,
+    bytes_received: AtomicU32,
+    messages_sent: AtomicU32,
+    messages_received: AtomicU32,
+    connects: AtomicU32,
+    disconnects: AtomicU32,
+    /// QoS 1 publishes whose PUBACK did not arrive in time, left to esp-mqtt's retransmission
+    puback_timeouts: AtomicU32,
+    /// Telemetry dropped by the publish rate limiter
+    rate_limited: AtomicU32,
+    subscribe_failures: AtomicU32,
+    /// QoS 1 publish to PUBACK, measured in steps of the 20ms the publisher polls at
+    pubacks: AtomicU32,
+    puback_ms_total: AtomicU32,
+    puback_ms_last: AtomicU32,
+    puback_ms_max: AtomicU32,
+    dns_ms: AtomicU32,
+    tls_ms: AtomicU32,
+}
+
+impl NetStats {
+    pub fn record_sent(&self, len: usize) {
+        self.bytes_sent.fetch_add(len as u32, Ordering::Relaxed);
+        self.messages_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_received(&self, len: usize) {
+        self.bytes_received.fetch_add(len as u32, Ordering::Relaxed);
+        self.messages_received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns `true` for the very first connection, i.e. when it was not a reconnect
+    pub fn record_connected(&self) -> bool {
+        self.connects.fetch_add(1, Ordering::Relaxed) == 0
+    }
+
+    pub fn record_disconnected(&self) {
+        self.disconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_puback_timeout(&self) {
+        self.puback_timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_rate_limited(&self) {
+        self.rate_limited.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_subscribe_failed(&self) {
+        self.subscribe_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_puback(&self, latency: Duration) {
+        let ms = latency.as_millis() as u32;
+        self.pubacks.fetch_add(1, Ordering::Relaxed);
+        self.puback_ms_total.fetch_add(ms, Ordering::Relaxed);
+        self.puback_ms_last.store(ms, Ordering::Relaxed);
+        self.puback_ms_max.fetch_max(ms, Ordering::Relaxed);
+    }
+
+    pub fn set_dns_duration(&self, duration: Duration) {
+        self.dns_ms
+            .store(duration.as_millis() as u32, Ordering::Relaxed);
+    }
+
+    pub fn set_tls_duration(&self, duration: Duration) {
+        self.tls_ms
+            .store(duration.as_millis() as u32, Ordering::Relaxed);
+    }
+
+    pub fn bytes_sent(&self) -> u32 {
+        self.bytes_sent.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes_received(&self) -> u32 {
+        self.bytes_received.load(Ordering::Relaxed)
+    }
+
+    pub fn connects(&self) -> u32 {
+        self.connects.load(Ordering::Relaxed)
+    }
+
+    pub fn reconnects(&self) -> u32 {
+        self.connects.load(Ordering::Relaxed).saturating_sub(1)
+    }
+
+    pub fn report(&self) -> NetReport {
+        let pubacks = self.pubacks.load(Ordering::Relaxed);
+
+        NetReport {
+            bytes_sent: self.bytes_sent(),
+            bytes_received: self.bytes_received(),
+            messages_sent: self.messages_sent.load(Ordering::Relaxed),
+            messages_received: self.messages_received.load(Ordering::Relaxed),
+            reconnects: self.reconnects(),
+            disconnects: self.disconnects.load(Ordering::Relaxed),
+            puback_timeouts: self.puback_timeouts.load(Ordering::Relaxed),
+            rate_limited: self.rate_limited.load(Ordering::Relaxed),
+            subscribe_failures: self.subscribe_failures.load(Ordering::Relaxed),
+            puback_ms_last: self.puback_ms_last.load(Ordering::Relaxed),
+            puback_ms_avg: self
+                .puback_ms_total
+                .load(Ordering::Relaxed)
+                .checked_div(pubacks)
+                .unwrap_or(0),
+            puback_ms_max: self.puback_ms_max.load(Ordering::Relaxed),
+            dns_ms: self.dns_ms.load(Ordering::Relaxed),
+            tls_ms: self.tls_ms.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(&self.report()).unwrap()
+    }
+}
+
+#[derive(Serialize)]
+pub struct NetReport {
+    bytes_sent: u32,
+    bytes_received: u32,
+    messages_sent: u32,
+    messages_received: u32,
+    reconnects: u32,
+    disconnects: u32,
+    puback_timeouts: u32,
+    rate_limited: u32,
+    subscribe_failures: u32,
+    puback_ms_last: u32,
+    puback_ms_avg: u32,
+    puback_ms_max: u32,
+    dns_ms: u32,
+    tls_ms: u32,
+}
@@ -0,0 +1,373 @@
+use core::sync::atomic::{AtomicU32, Ordering};
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use esp_idf_svc::nvs::{EspDefaultNvs, EspDefaultNvsPartition};
+use esp_idf_svc::sys::{EspError, ESP_ERR_INVALID_SIZE};
+
+use log::*;
+
+use crate::mqtt::MessageKind;
+
+const NVS_NAMESPACE: &str = "offline";
+
+/// Largest message spilled to NVS; anything bigger stays RAM-only
+const MAX_NVS_ENTRY_LEN: usize = 1024;
+
+/// RTC slow memory set aside for messages kept across deep sleep
+const RTC_QUEUE_LEN: usize = 4096;
+
+/// Encoded messages, each prefixed with its length as u16 LE. Lives in RTC slow memory, so it
+/// survives deep sleep; a power cycle starts over with `RTC_QUEUE_USED` at 0.
+#[link_section = ".rtc.data"]
+static mut RTC_QUEUE: [u8; RTC_QUEUE_LEN] = [0; RTC_QUEUE_LEN];
+#[link_section = ".rtc.data"]
+static mut RTC_QUEUE_USED: usize = 0;
+
+/// A message which could not be published when it was produced
+pub struct BufferedMessage {
+    pub kind: MessageKind,
+    pub topic: String,
+    pub retain: bool,
+    pub payload: Vec<u8>,
+    /// Milliseconds since the epoch at the time the message was produced
+    pub captured_at_ms: u64,
+}
+
+impl BufferedMessage {
+    pub fn new(kind: MessageKind, topic: &str, retain: bool, payload: &[u8]) -> Self {
+        Self {
+            kind,
+            topic: topic.to_string(),
+            retain,
+            payload: payload.to_vec(),
+            captured_at_ms: now_ms(),
+        }
+    }
+
+    /// The payload to publish on replay; JSON telemetry objects get a `captured_at` field
+    /// so the backend can place late samples correctly
+    pub fn replay_payload(&self) -> Vec<u8> {
+        if self.kind != MessageKind::Telemetry || self.payload.first() != Some(&b'{') {
+            return self.payload.clone();
+        }
+
+        let mut payload = format!("{{\"captured_at\": {}, ", self.captured_at_ms).into_bytes();
+        let rest = &self.payload[1..];
+        if rest.iter().all(|b| b.is_ascii_whitespace() || *b == b'}') {
+            // Empty object: drop the separator we just added
+            payload.truncate(payload.len() - 2);
+        }
+        payload.extend_from_slice(rest);
+
+        payload
+    }
+
+    /// `[captured_at u64 LE][kind][retain][topic len][topic][payload]`
+    fn encode(&self) -> Result<Vec<u8>, EspError> {
+        if self.topic.len() > u8::MAX as usize {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_SIZE>());
+        }
+
+        let mut data = Vec::with_capacity(11 + self.topic.len() + self.payload.len());
+        data.extend_from_slice(&self.captured_at_ms.to_le_bytes());
+        data.push(match self.kind {
+            MessageKind::Telemetry => 0,
+            MessageKind::Alert => 1,
+            MessageKind::Status => 2,
+        });
+        data.push(self.retain as u8);
+        data.push(self.topic.len() as u8);
+        data.extend_from_slice(self.topic.as_bytes());
+        data.extend_from_slice(&self.payload);
+
+        Ok(data)
+    }
+
+    fn decode(data: &[u8]) -> Option<Self> {
+        let captured_at_ms = u64::from_le_bytes(data.get(0..8)?.try_into().ok()?);
+        let kind = match *data.get(8)? {
+            0 => MessageKind::Telemetry,
+            1 => MessageKind::Alert,
+            _ => MessageKind::Status,
+        };
+        let retain = *data.get(9)? != 0;
+        let topic_len = *data.get(10)? as usize;
+        let topic = core::str::from_utf8(data.get(11..11 + topic_len)?).ok()?;
+
+        Some(Self {
+            kind,
+            topic: topic.to_string(),
+            retain,
+            payload: data[11 + topic_len..].to_vec(),
+            captured_at_ms,
+        })
+    }
+}
+
+/// Ring of NVS blobs `m<index>`, with `head`/`tail` indexes persisted so it survives reboots
+struct NvsSpill {
+    nvs: EspDefaultNvs,
+    head: u32,
+    tail: u32,
+    capacity: u32,
+}
+
+impl NvsSpill {
+    fn new(partition: EspDefaultNvsPartition, capacity: u32) -> Result<Self, EspError> {
+        let nvs = EspDefaultNvs::new(partition, NVS_NAMESPACE, true)?;
+        let head = nvs.get_u32("head")?.unwrap_or(0);
+        let tail = nvs.get_u32("tail")?.unwrap_or(0);
+
+        Ok(Self {
+            nvs,
+            head,
+            tail,
+            capacity,
+        })
+    }
+
+    fn len(&self) -> u32 {
+        self.tail.wrapping_sub(self.head)
+    }
+
+    /// Returns `true` if the oldest message had to be dropped to make room
+    fn push(&mut self, message: &BufferedMessage) -> Result<bool, EspError> {
+        let data = message.encode()?;
+        let full = self.len() >= self.capacity;
+        if full {
+            self.nvs.remove(&format!("m{}", self.head))?;
+            self.head = self.head.wrapping_add(1);
+            self.nvs.set_u32("head", self.head)?;
+            warn!("Offline NVS buffer full, dropped the oldest message");
+        }
+
+        self.nvs.set_blob(&format!("m{}", self.tail), &data)?;
+        self.tail = self.tail.wrapping_add(1);
+        self.nvs.set_u32("tail", self.tail)?;
+
+        Ok(full)
+    }
+
+    fn pop(&mut self) -> Result<Option<BufferedMessage>, EspError> {
+        while self.len() > 0 {
+            let key = format!("m{}", self.head);
+            let mut buf = [0; MAX_NVS_ENTRY_LEN];
+            let message = self
+                .nvs
+                .get_blob(&key, &mut buf)?
+                .and_then(BufferedMessage::decode);
+
+            self.nvs.remove(&key)?;
+            self.head = self.head.wrapping_add(1);
+            self.nvs.set_u32("head", self.head)?;
+
+            if message.is_some() {
+                return Ok(message);
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+/// Keeps messages produced while offline, so they can be replayed in order once connected.
+/// Overflowing RAM entries are spilled to NVS when it is enabled, otherwise the oldest are dropped.
+pub struct OfflineBuffer {
+    /// Messages which failed to replay, sent again before anything else
+    retry: Mutex<VecDeque<BufferedMessage>>,
+    ram: Mutex<VecDeque<BufferedMessage>>,
+    capacity: usize,
+    spill: Option<Mutex<NvsSpill>>,
+    /// Messages lost to a full buffer since boot
+    dropped: AtomicU32,
+}
+
+impl OfflineBuffer {
+    pub fn new(
+        capacity: usize,
+        nvs_capacity: u32,
+        partition: &EspDefaultNvsPartition,
+    ) -> Result<Self, EspError> {
+        let spill = if nvs_capacity > 0 {
+            let spill = NvsSpill::new(partition.clone(), nvs_capacity)?;
+            if spill.len() > 0 {
+                info!("{} offline messages waiting in NVS", spill.len());
+            }
+            Some(Mutex::new(spill))
+        } else {
+            None
+        };
+
+        let buffer = Self {
+            retry: Mutex::new(VecDeque::new()),
+            ram: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
+            spill,
+            dropped: AtomicU32::new(0),
+        };
+
+        let restored = take_rtc_queue();
+        if !restored.is_empty() {
+            info!("{} offline messages kept across deep sleep", restored.len());
+        }
+        for message in restored {
+            buffer.push_back(message);
+        }
+
+        Ok(buffer)
+    }
+
+    /// Moves what is only held in RAM to RTC memory right before deep sleep; it is picked up
+    /// again by [`Self::new`] on wake. Messages spilled to NVS stay where they are.
+    pub fn save_for_deep_sleep(&self) {
+        let messages: Vec<_> = self
+            .retry
+            .lock()
+            .unwrap()
+            .drain(..)
+            .chain(self.ram.lock().unwrap().drain(..))
+            .collect();
+
+        // Safety: only touched here and in `take_rtc_queue`, both called from the main task
+        let (queue, used) = unsafe {
+            (
+                &mut *core::ptr::addr_of_mut!(RTC_QUEUE),
+                &mut *core::ptr::addr_of_mut!(RTC_QUEUE_USED),
+            )
+        };
+
+        let mut saved = 0;
+        for message in &messages {
+            let Ok(data) = message.encode() else {
+                warn!(
+                    "Topic \"{}\" too long for RTC memory, dropped an offline message",
+                    message.topic
+                );
+                self.record_dropped();
+                continue;
+            };
+            if *used + 2 + data.len() > RTC_QUEUE_LEN {
+                warn!("RTC memory full, dropped an offline message");
+                self.record_dropped();
+                continue;
+            }
+
+            queue[*used..*used + 2].copy_from_slice(&(data.len() as u16).to_le_bytes());
+            queue[*used + 2..*used + 2 + data.len()].copy_from_slice(&data);
+            *used += 2 + data.len();
+            saved += 1;
+        }
+
+        info!("Kept {saved} offline messages in RTC memory");
+    }
+
+    pub fn push_back(&self, message: BufferedMessage) {
+        let mut ram = self.ram.lock().unwrap();
+
+        if ram.len() >= self.capacity {
+            let oldest = ram.pop_front().unwrap();
+
+            match &self.spill {
+                Some(spill)
+                    if oldest.payload.len() + oldest.topic.len() + 11 <= MAX_NVS_ENTRY_LEN =>
+                {
+                    match spill.lock().unwrap().push(&oldest) {
+                        Ok(false) => (),
+                        Ok(true) => self.record_dropped(),
+                        Err(e) => {
+                            warn!("Failed to spill offline message to NVS: {e}");
+                            self.record_dropped();
+                        }
+                    }
+                }
+                _ => {
+                    warn!("Offline buffer full, dropped the oldest message");
+                    self.record_dropped();
+                }
+            }
+        }
+
+        ram.push_back(message);
+    }
+
+    /// Puts a message which failed to replay back at the head of the queue
+    pub fn push_front(&self, message: BufferedMessage) {
+        self.retry.lock().unwrap().push_front(message);
+    }
+
+    /// Oldest message first: NVS holds what overflowed RAM earlier, so it goes out before RAM
+    pub fn pop_front(&self) -> Option<BufferedMessage> {
+        if let Some(message) = self.retry.lock().unwrap().pop_front() {
+            return Some(message);
+        }
+
+        if let Some(spill) = &self.spill {
+            match spill.lock().unwrap().pop() {
+                Ok(Some(message)) => return Some(message),
+                Ok(None) => (),
+                Err(e) => warn!("Failed to read offline message from NVS: {e}"),
+            }
+        }
+
+        self.ram.lock().unwrap().pop_front()
+    }
+
+    pub fn len(&self) -> usize {
+        let spilled = self
+            .spill
+            .as_ref()
+            .map(|spill| spill.lock().unwrap().len() as usize)
+            .unwrap_or(0);
+
+        self.retry.lock().unwrap().len() + spilled + self.ram.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn record_dropped(&self) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Messages dropped locally since boot, reported so the backend can tell them from
+    /// messages lost in transit
+    pub fn dropped(&self) -> u32 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+/// Decodes and clears whatever [`OfflineBuffer::save_for_deep_sleep`] left in RTC memory
+fn take_rtc_queue() -> Vec<BufferedMessage> {
+    // Safety: see `save_for_deep_sleep`
+    let (queue, used) = unsafe {
+        (
+            &*core::ptr::addr_of!(RTC_QUEUE),
+            &mut *core::ptr::addr_of_mut!(RTC_QUEUE_USED),
+        )
+    };
+
+    let mut messages = Vec::new();
+    let mut offset = 0;
+    while offset + 2 <= (*used).min(RTC_QUEUE_LEN) {
+        let len = u16::from_le_bytes([queue[offset], queue[offset + 1]]) as usize;
+        let Some(data) = queue.get(offset + 2..offset + 2 + len) else {
+            break;
+        };
+
+        messages.extend(BufferedMessage::decode(data));
+        offset += 2 + len;
+    }
+    *used = 0;
+
+    messages
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_millis() as u64)
+        .unwrap_or(0)
+}
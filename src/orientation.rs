@@ -0,0 +1,110 @@
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use serde_json::json;
+
+use log::*;
+
+use crate::imu::{self, Imu};
+use crate::settings::Settings;
+use crate::telemetry::Signal;
+use crate::Config;
+
+/// Value of the `angles` signal, in degrees
+#[derive(Serialize)]
+struct Tilt {
+    roll: f32,
+    pitch: f32,
+}
+
+/// Roll and pitch from a complementary filter: the gyro is integrated for fast changes, while the
+/// accelerometer angles (as in `get_acc_angles`) pull it back over `tau` so it doesn't drift
+pub struct Orientation {
+    /// Time constant in seconds; longer smooths out more vibration but corrects drift slower
+    tau: f32,
+    period: Duration,
+    /// Roll and pitch in radians
+    angles: Option<[f32; 2]>,
+    updated_at: Option<Instant>,
+}
+
+impl Orientation {
+    /// `None` with `angles_tau_ms = 0`
+    pub fn from_config(app_config: &Config) -> Option<Self> {
+        if app_config.angles_tau_ms == 0 {
+            return None;
+        }
+
+        Some(Self {
+            tau: app_config.angles_tau_ms as f32 / 1000.0,
+            period: Duration::from_secs(1) / app_config.angles_hz.max(1),
+            angles: None,
+            updated_at: None,
+        })
+    }
+
+    /// Updates the filter when due; returns the time until the next update
+    pub fn poll(&mut self, mpu: &mut Imu, settings: &Settings) -> Duration {
+        let due = self.updated_at.map_or(Duration::ZERO, |at| {
+            self.period.saturating_sub(at.elapsed())
+        });
+        if !due.is_zero() {
+            return due;
+        }
+
+        match (imu::gyro(mpu, settings), imu::acc(mpu, settings)) {
+            (Ok(gyro), Ok(acc)) => {
+                let now = Instant::now();
+                let dt = self
+                    .updated_at
+                    .map_or(self.period, |at| now - at)
+                    .as_secs_f32();
+                self.updated_at = Some(now);
+
+                self.update([gyro[0], gyro[1]], acc, dt);
+            }
+            (Err(e), _) | (_, Err(e)) => warn!("Failed to read the sensor for angles: {e:?}"),
+        }
+
+        self.period
+    }
+
+    /// Gyro rates around x and y in rad/s, acceleration in any unit
+    fn update(&mut self, gyro: [f32; 2], acc: [f32; 3], dt: f32) {
+        let [ax, ay, az] = acc;
+        let acc_angles = [
+            ay.atan2((ax * ax + az * az).sqrt()),
+            (-ax).atan2((ay * ay + az * az).sqrt()),
+        ];
+
+        let Some(angles) = &mut self.angles else {
+            // Nothing to integrate from yet
+            self.angles = Some(acc_angles);
+            return;
+        };
+
+        let alpha = self.tau / (self.tau + dt);
+        for ((angle, rate), acc_angle) in angles.iter_mut().zip(gyro).zip(acc_angles) {
+            *angle = alpha * (*angle + rate * dt) + (1.0 - alpha) * acc_angle;
+        }
+    }
+
+    /// Roll and pitch in degrees, `None` before the first update
+    pub fn fused_angles(&self) -> Option<[f32; 2]> {
+        self.angles.map(|angles| angles.map(f32::to_degrees))
+    }
+
+    /// `"angles": {"roll": .., "pitch": ..}` in degrees
+    pub fn signal(&self) -> Option<Signal> {
+        let [roll, pitch] = self.fused_angles()?;
+
+        Some(Signal {
+            field: "angles",
+            kind: "angles",
+            value: json!(Tilt { roll, pitch }),
+            range: None,
+            axes: vec![roll, pitch],
+            unit: Some("deg"),
+        })
+    }
+}
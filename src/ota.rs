@@ -0,0 +1,371 @@
+use anyhow::{anyhow, bail, Result};
+use serde::Deserialize;
+
+use esp_idf_svc::http::client::{Configuration, EspHttpConnection};
+use esp_idf_svc::http::Method;
+use esp_idf_svc::ota::{EspOta, EspOtaUpdate};
+use esp_idf_svc::sys::{
+    esp_crt_bundle_attach, mbedtls_md_context_t, mbedtls_md_finish, mbedtls_md_free,
+    mbedtls_md_info_from_type, mbedtls_md_init, mbedtls_md_setup, mbedtls_md_starts,
+    mbedtls_md_type_t_MBEDTLS_MD_SHA256, mbedtls_md_update,
+};
+#[cfg(feature = "ota-signing")]
+use esp_idf_svc::sys::{
+    mbedtls_pk_context, mbedtls_pk_free, mbedtls_pk_init, mbedtls_pk_parse_public_key,
+    mbedtls_pk_verify,
+};
+
+use log::*;
+
+use crate::jobs::Execution;
+use crate::signing::hex;
+#[cfg(feature = "ota-signing")]
+use crate::signing::parse_hex;
+
+/// `operation` of the job documents handled here
+const OPERATION: &str = "ota";
+
+const CHUNK_LEN: usize = 4096;
+
+/// Attempts to resume a broken download before the job fails
+pub const MAX_RETRIES: u32 = 8;
+
+/// Images have to be signed with the private half of this RSA or EC key
+#[cfg(feature = "ota-signing")]
+const SIGNING_KEY: &[u8] = include_bytes!("../certificates/ota-signing-public.pem");
+
+/// Job document of a firmware update: `{"operation": "ota", "url": "<S3 presigned URL>",
+/// "version": "0.2.0", "sha256": "<hex>", "force": false}`. The URL is usually the
+/// `${aws:iot:s3-presigned-url:..}` placeholder, which AWS IoT fills in for every device.
+/// With the `ota-signing` feature, `"signature": "<hex>"` has to hold the signature over the
+/// SHA-256 of the image, e.g. from `openssl dgst -sha256 -sign ota-signing-private.pem`.
+#[derive(Deserialize)]
+pub struct OtaJob {
+    operation: String,
+    url: String,
+    pub version: String,
+    #[serde(default)]
+    sha256: Option<String>,
+    #[cfg(feature = "ota-signing")]
+    #[serde(default)]
+    signature: Option<String>,
+    /// Also installs versions that aren't newer than the running one
+    #[serde(default)]
+    force: bool,
+}
+
+/// The final status of a job that isn't (or no longer) installed, with the reason
+pub struct Verdict {
+    pub status: &'static str,
+    pub reason: String,
+}
+
+impl Verdict {
+    fn new(status: &'static str, reason: impl Into<String>) -> Self {
+        Self {
+            status,
+            reason: reason.into(),
+        }
+    }
+}
+
+/// Checks a job execution against the running firmware: the update to install, or why not.
+/// An update that is in progress and matches the running version was installed before the
+/// last reboot; if instead it is the version the bootloader rolled back from, it failed.
+pub fn review(execution: &Execution, ota: &EspOta) -> Result<OtaJob, Verdict> {
+    let job: OtaJob = serde_json::from_value(execution.job_document.clone())
+        .map_err(|e| Verdict::new("FAILED", format!("invalid job document: {e}")))?;
+    if job.operation != OPERATION {
+        return Err(Verdict::new(
+            "REJECTED",
+            format!("unsupported operation \"{}\"", job.operation),
+        ));
+    }
+
+    let running = env!("CARGO_PKG_VERSION");
+    if execution.status == "IN_PROGRESS" {
+        if job.version == running {
+            return Err(Verdict::new("SUCCEEDED", format!("running {running}")));
+        }
+
+        let rolled_back = ota
+            .get_last_invalid_slot()
+            .ok()
+            .flatten()
+            .and_then(|slot| slot.firmware);
+        if rolled_back.is_some_and(|firmware| firmware.version == job.version.as_str()) {
+            return Err(Verdict::new(
+                "FAILED",
+                format!("{} was rolled back to {running}", job.version),
+            ));
+        }
+    }
+
+    let newer = match (parse_version(&job.version), parse_version(running)) {
+        (Some(version), Some(running)) => version > running,
+        _ => {
+            return Err(Verdict::new(
+                "FAILED",
+                format!("invalid version \"{}\"", job.version),
+            ))
+        }
+    };
+    if !newer && !job.force {
+        return Err(Verdict::new(
+            "REJECTED",
+            format!("{} is not newer than {running}", job.version),
+        ));
+    }
+
+    Ok(job)
+}
+
+/// `"1.2.3"` or `"v1.2.3"` as `[1, 2, 3]`, comparable part by part
+fn parse_version(version: &str) -> Option<Vec<u32>> {
+    version
+        .trim_start_matches('v')
+        .split('.')
+        .map(|part| part.parse().ok())
+        .collect()
+}
+
+/// A firmware image streamed from HTTPS into the update partition, one chunk per
+/// [`Download::next`] so progress can be reported in between. A broken transfer continues where
+/// it stopped with an HTTP range request. Dropping it before [`Download::finish`] aborts the
+/// update.
+pub struct Download<'a> {
+    url: String,
+    /// `None` until the first chunk and after a transfer broke
+    connection: Option<EspHttpConnection>,
+    update: EspOtaUpdate<'a>,
+    sha256: Sha256,
+    expected_sha256: Option<String>,
+    #[cfg(feature = "ota-signing")]
+    signature: Option<String>,
+    len: Option<usize>,
+    received: usize,
+    /// Cleared by errors a retry can't fix, such as an expired URL or a flash write failing
+    resumable: bool,
+    /// On the heap, the download runs on the main task whose stack is small
+    buf: Vec<u8>,
+}
+
+impl<'a> Download<'a> {
+    pub fn start(ota: &'a mut EspOta, job: &OtaJob) -> Result<Self> {
+        Ok(Self {
+            url: job.url.clone(),
+            connection: None,
+            update: ota.initiate_update()?,
+            sha256: Sha256::new(),
+            expected_sha256: job.sha256.as_ref().map(|hex| hex.to_lowercase()),
+            #[cfg(feature = "ota-signing")]
+            signature: job.signature.clone(),
+            len: None,
+            received: 0,
+            resumable: true,
+            buf: vec![0; CHUNK_LEN],
+        })
+    }
+
+    /// Writes the next chunk; `false` once the image is complete. After an error the next call
+    /// reconnects and resumes, unless [`Download::can_resume`] says otherwise.
+    pub fn next(&mut self) -> Result<bool> {
+        if !self.resumable {
+            bail!("the download can't be resumed");
+        }
+
+        let mut buf = std::mem::take(&mut self.buf);
+        let res = self.write_chunk(&mut buf);
+        self.buf = buf;
+
+        res
+    }
+
+    fn write_chunk(&mut self, buf: &mut [u8]) -> Result<bool> {
+        let read = self.read(buf)?;
+        if read == 0 {
+            if self.len.is_some_and(|len| self.received < len) {
+                self.connection = None;
+                bail!("connection closed at {} bytes", self.received);
+            }
+            return Ok(false);
+        }
+
+        self.sha256.update(&buf[..read]);
+        if let Err(e) = self.update.write(&buf[..read]) {
+            self.resumable = false;
+            bail!("failed to write the image: {e}");
+        }
+        self.received += read;
+
+        Ok(true)
+    }
+
+    pub fn can_resume(&self) -> bool {
+        self.resumable
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let mut connection = match self.connection.take() {
+            Some(connection) => connection,
+            None => self.connect()?,
+        };
+        let read = connection.read(buf)?;
+
+        // Only kept while it works, a broken transfer is requested anew
+        self.connection = Some(connection);
+
+        Ok(read)
+    }
+
+    /// Requests the image, or the rest of it after `received` bytes
+    fn connect(&mut self) -> Result<EspHttpConnection> {
+        let mut connection = EspHttpConnection::new(&Configuration {
+            buffer_size: Some(CHUNK_LEN),
+            crt_bundle_attach: Some(esp_crt_bundle_attach),
+            ..Default::default()
+        })?;
+
+        let range = format!("bytes={}-", self.received);
+        let headers = if self.received > 0 {
+            info!("Resuming the download at {} bytes", self.received);
+            vec![("Range", range.as_str())]
+        } else {
+            Vec::new()
+        };
+        connection.initiate_request(Method::Get, &self.url, &headers)?;
+        connection.initiate_response()?;
+
+        let len = match connection.status() {
+            200 if self.received == 0 => connection
+                .header("Content-Length")
+                .and_then(|len| len.parse().ok()),
+            // `Content-Range: bytes 1024-65535/65536`
+            206 => connection
+                .header("Content-Range")
+                .and_then(|range| range.rsplit_once('/'))
+                .and_then(|(_, len)| len.parse().ok()),
+            200 => {
+                self.resumable = false;
+                bail!("the server doesn't support resuming");
+            }
+            status @ 400..=499 => {
+                self.resumable = false;
+                bail!("download failed with HTTP {status}");
+            }
+            status => bail!("download failed with HTTP {status}"),
+        };
+        if len.is_some() {
+            self.len = len;
+        }
+
+        Ok(connection)
+    }
+
+    /// How much of the image is written, if the server told its length
+    pub fn percent(&self) -> Option<u8> {
+        self.len
+            .filter(|len| *len > 0)
+            .map(|len| (self.received * 100 / len).min(100) as u8)
+    }
+
+    /// Checks the length, checksum and signature and makes the new image the one to boot next
+    pub fn finish(self) -> Result<()> {
+        if let Some(len) = self.len.filter(|len| *len != self.received) {
+            bail!("received {} of {len} bytes", self.received);
+        }
+
+        let digest = self.sha256.finish();
+        let sha256 = hex(&digest);
+        if let Some(expected) = &self.expected_sha256 {
+            if *expected != sha256 {
+                bail!("checksum mismatch: got {sha256}, expected {expected}");
+            }
+        }
+
+        #[cfg(feature = "ota-signing")]
+        verify_signature(&digest, self.signature.as_deref())?;
+
+        self.update
+            .complete()
+            .map_err(|e| anyhow!("invalid image: {e}"))
+    }
+}
+
+/// Checks a signature over the image digest against [`SIGNING_KEY`], independent of secure
+/// boot, so whoever can write to the bucket still can't hand out firmware
+#[cfg(feature = "ota-signing")]
+fn verify_signature(digest: &[u8; 32], signature: Option<&str>) -> Result<()> {
+    let signature = signature
+        .and_then(parse_hex)
+        .ok_or_else(|| anyhow!("missing or malformed signature"))?;
+
+    // mbedTLS wants PEM NUL-terminated
+    let mut key = SIGNING_KEY.to_vec();
+    key.push(0);
+
+    let mut pk = mbedtls_pk_context::default();
+    let (parsed, verified) = unsafe {
+        mbedtls_pk_init(&mut pk);
+        let parsed = mbedtls_pk_parse_public_key(&mut pk, key.as_ptr(), key.len());
+        let verified = if parsed == 0 {
+            mbedtls_pk_verify(
+                &mut pk,
+                mbedtls_md_type_t_MBEDTLS_MD_SHA256,
+                digest.as_ptr(),
+                digest.len(),
+                signature.as_ptr(),
+                signature.len(),
+            )
+        } else {
+            parsed
+        };
+        mbedtls_pk_free(&mut pk);
+        (parsed, verified)
+    };
+
+    if parsed != 0 {
+        bail!("invalid signing key ({parsed})");
+    }
+    if verified != 0 {
+        bail!("invalid signature ({verified})");
+    }
+
+    info!("Image signature verified");
+    Ok(())
+}
+
+/// Streaming SHA-256 with mbedTLS, which is linked in for TLS anyway
+struct Sha256(mbedtls_md_context_t);
+
+impl Sha256 {
+    fn new() -> Self {
+        let mut ctx = mbedtls_md_context_t::default();
+        unsafe {
+            mbedtls_md_init(&mut ctx);
+            mbedtls_md_setup(
+                &mut ctx,
+                mbedtls_md_info_from_type(mbedtls_md_type_t_MBEDTLS_MD_SHA256),
+                0,
+            );
+            mbedtls_md_starts(&mut ctx);
+        }
+        Self(ctx)
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        unsafe { mbedtls_md_update(&mut self.0, data.as_ptr(), data.len()) };
+    }
+
+    fn finish(mut self) -> [u8; 32] {
+        let mut digest = [0; 32];
+        unsafe { mbedtls_md_finish(&mut self.0, digest.as_mut_ptr()) };
+        digest
+    }
+}
+
+impl Drop for Sha256 {
+    fn drop(&mut self) {
+        unsafe { mbedtls_md_free(&mut self.0) };
+    }
+}
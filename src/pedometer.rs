@@ -0,0 +1,182 @@
+use std::time::{Duration, Instant};
+
+use esp_idf_svc::nvs::{EspDefaultNvs, EspDefaultNvsPartition};
+use esp_idf_svc::sys::EspError;
+use serde::Serialize;
+
+use log::*;
+
+use crate::clock::{self, Stamp};
+use crate::imu::{self, Imu};
+use crate::settings::Settings;
+use crate::Config;
+
+const NVS_NAMESPACE: &str = "pedometer";
+
+const PERIOD: Duration = Duration::from_millis(20);
+/// Smoothing of the magnitude, so the ringing of a footfall is one peak
+const SMOOTHING: f32 = 0.3;
+/// Steps closer together than this are one step (over 4 steps per second)
+const MIN_STEP_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Counts steps as peaks of the acceleration magnitude; the count starts over every day (UTC)
+/// once the clock is set through SNTP and is kept in NVS
+pub struct Pedometer {
+    threshold: f32,
+    publish_interval: Duration,
+    sampled_at: Option<Instant>,
+    published_at: Option<Instant>,
+    smoothed: f32,
+    above: bool,
+    stepped_at: Option<Instant>,
+    day: u32,
+    steps: u32,
+    /// Steps not persisted yet
+    dirty: bool,
+    nvs: EspDefaultNvs,
+}
+
+impl Pedometer {
+    /// `None` unless `pedometer` is set
+    pub fn from_config(
+        app_config: &Config,
+        partition: EspDefaultNvsPartition,
+    ) -> Result<Option<Self>, EspError> {
+        if !app_config.pedometer {
+            return Ok(None);
+        }
+
+        let nvs = EspDefaultNvs::new(partition, NVS_NAMESPACE, true)?;
+        let day = nvs.get_u32("day")?.unwrap_or(0);
+        let steps = nvs.get_u32("steps")?.unwrap_or(0);
+        info!("Pedometer continues at {steps} steps");
+
+        Ok(Some(Self {
+            threshold: app_config.step_threshold_g,
+            publish_interval: Duration::from_secs(app_config.pedometer_publish_secs),
+            sampled_at: None,
+            published_at: None,
+            smoothed: 1.0,
+            above: false,
+            stepped_at: None,
+            day,
+            steps,
+            dirty: false,
+            nvs,
+        }))
+    }
+
+    /// Samples when due; returns the time until the next sample
+    pub fn poll(&mut self, mpu: &mut Imu, settings: &Settings) -> Duration {
+        let due = self
+            .sampled_at
+            .map_or(Duration::ZERO, |at| PERIOD.saturating_sub(at.elapsed()));
+        if !due.is_zero() {
+            return due;
+        }
+
+        let now = Instant::now();
+        self.sampled_at = Some(now);
+
+        match imu::acc(mpu, settings) {
+            Ok([x, y, z]) => {
+                self.update((x * x + y * y + z * z).sqrt(), now);
+            }
+            Err(e) => warn!("Failed to read the accelerometer for the pedometer: {e:?}"),
+        }
+
+        PERIOD
+    }
+
+    fn update(&mut self, magnitude: f32, now: Instant) {
+        self.smoothed += SMOOTHING * (magnitude - self.smoothed);
+
+        // Counted on the way up, re-armed once back below 1g
+        if !self.above && self.smoothed >= self.threshold {
+            self.above = true;
+
+            if self
+                .stepped_at
+                .map_or(true, |at| now - at >= MIN_STEP_INTERVAL)
+            {
+                self.stepped_at = Some(now);
+                self.roll_over();
+                self.steps += 1;
+                self.dirty = true;
+            }
+        } else if self.above && self.smoothed < 1.0 {
+            self.above = false;
+        }
+    }
+
+    /// Starts a new count when the day changed
+    fn roll_over(&mut self) {
+        let Some(today) = today() else {
+            return;
+        };
+
+        if today != self.day {
+            if self.day != 0 {
+                info!("New day, {} steps yesterday", self.steps);
+            }
+            self.day = today;
+            self.steps = 0;
+            self.dirty = true;
+        }
+    }
+
+    /// Today's steps when the publish interval passed; the count is persisted alongside,
+    /// so flash isn't written for every step
+    pub fn take_report(&mut self) -> Option<u32> {
+        if self
+            .published_at
+            .is_some_and(|at| at.elapsed() < self.publish_interval)
+        {
+            return None;
+        }
+        self.published_at = Some(Instant::now());
+
+        self.roll_over();
+        if self.dirty {
+            self.persist();
+        }
+
+        Some(self.steps)
+    }
+
+    fn persist(&mut self) {
+        if let Err(e) = self
+            .nvs
+            .set_u32("day", self.day)
+            .and_then(|_| self.nvs.set_u32("steps", self.steps))
+        {
+            warn!("Failed to persist the step count: {e}");
+            return;
+        }
+
+        self.dirty = false;
+    }
+}
+
+/// Days since the epoch (UTC), `None` while the clock is not set
+fn today() -> Option<u32> {
+    clock::epoch_ms().map(|ms| (ms / 86_400_000) as u32)
+}
+
+#[derive(Serialize)]
+struct StepReport {
+    seq: u32,
+    #[serde(flatten)]
+    stamp: Stamp,
+    steps: u32,
+}
+
+/// `{"seq": 42, "ts": .., "mono_us": .., "steps": 1234}`
+pub fn report_json(seq: u32, steps: u32) -> String {
+    serde_json::to_string(&StepReport {
+        seq,
+        stamp: Stamp::now(),
+        steps,
+    })
+    .unwrap()
+}
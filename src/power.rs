@@ -0,0 +1,52 @@
+use core::time::Duration;
+use std::sync::Mutex;
+
+use esp_idf_svc::sys::{self, esp, EspError};
+
+use log::*;
+
+use crate::batch::Batcher;
+use crate::mqtt::MessageKind;
+use crate::offline_buffer::{BufferedMessage, OfflineBuffer};
+
+/// Keeps everything not yet published in RTC memory and enters deep sleep. The device boots
+/// from scratch on wake and replays the kept messages once it is connected again.
+pub fn deep_sleep(offline: &OfflineBuffer, batch: &Mutex<Batcher>, duration: Duration) -> ! {
+    // Samples still waiting for their batch to fill up would be lost otherwise
+    for (topic, payload) in batch.lock().unwrap().flush() {
+        offline.push_back(BufferedMessage::new(
+            MessageKind::Telemetry,
+            &topic,
+            false,
+            &payload,
+        ));
+    }
+    offline.save_for_deep_sleep();
+
+    info!("Entering deep sleep for {}s", duration.as_secs());
+    unsafe { sys::esp_deep_sleep(duration.as_micros() as u64) }
+}
+
+/// Lowest CPU clock, running straight from the crystal
+pub const XTAL_MHZ: u32 = sys::CONFIG_XTAL_FREQ;
+
+/// Lets the chip enter light sleep on its own whenever every task waits, e.g. on a timer, and
+/// run from the crystal in between. Needs `CONFIG_PM_ENABLE` and tickless idle; while connected,
+/// Wi-Fi only lets it sleep with modem power saving on.
+pub fn enable_light_sleep() -> Result<(), EspError> {
+    configure(sys::CONFIG_ESP_DEFAULT_CPU_FREQ_MHZ, XTAL_MHZ, true)
+}
+
+/// Lets the CPU clock scale between `min_mhz` and `max_mhz` with the load, optionally with
+/// automatic light sleep
+pub fn configure(max_mhz: u32, min_mhz: u32, light_sleep: bool) -> Result<(), EspError> {
+    let config = sys::esp_pm_config_t {
+        max_freq_mhz: max_mhz as i32,
+        min_freq_mhz: min_mhz as i32,
+        light_sleep_enable: light_sleep,
+    };
+    esp!(unsafe { sys::esp_pm_configure(&config as *const _ as *const core::ffi::c_void) })?;
+
+    info!("CPU at {min_mhz}-{max_mhz}MHz, light sleep {light_sleep}");
+    Ok(())
+}
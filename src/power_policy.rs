@@ -0,0 +1,110 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use log::*;
+
+/// Steps kept at most, which bounds the NVS blob
+const MAX_STEPS: usize = 8;
+/// Bytes of one step in NVS: the charge, then the interval as little-endian u32
+const STEP_LEN: usize = 5;
+/// Longest [`IntervalPolicy::to_bytes`]
+pub const MAX_LEN: usize = MAX_STEPS * STEP_LEN;
+
+/// Publish interval from a battery charge on
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Step {
+    pub min_pct: u8,
+    pub secs: u32,
+}
+
+/// Stretches the publish interval as the battery runs down, e.g. 2s from 50% on, 10s from 20%
+/// and 30s below that. The configured interval applies while it is empty or the charge is
+/// unknown.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct IntervalPolicy {
+    /// Ordered by `min_pct`, highest first
+    steps: Vec<Step>,
+}
+
+impl IntervalPolicy {
+    pub fn new(mut steps: Vec<Step>) -> Self {
+        steps.sort_by(|a, b| b.min_pct.cmp(&a.min_pct));
+        steps.dedup_by_key(|step| step.min_pct);
+        if steps.len() > MAX_STEPS {
+            warn!(
+                "Keeping the first {MAX_STEPS} of {} interval steps",
+                steps.len()
+            );
+            steps.truncate(MAX_STEPS);
+        }
+        for step in &mut steps {
+            step.secs = step.secs.max(1);
+        }
+
+        Self { steps }
+    }
+
+    /// `"50:2, 20:10, 0:30"`, pairs of charge in % and interval in seconds; malformed steps are
+    /// skipped
+    pub fn parse(policy: &str) -> Self {
+        let steps = policy
+            .split(',')
+            .map(str::trim)
+            .filter(|step| !step.is_empty())
+            .filter_map(|step| {
+                let parsed = step.split_once(':').and_then(|(pct, secs)| {
+                    Some(Step {
+                        min_pct: pct.trim().parse().ok()?,
+                        secs: secs.trim().parse().ok()?,
+                    })
+                });
+                if parsed.is_none() {
+                    warn!("Ignoring malformed interval step \"{step}\"");
+                }
+                parsed
+            })
+            .collect();
+
+        Self::new(steps)
+    }
+
+    pub fn steps(&self) -> &[Step] {
+        &self.steps
+    }
+
+    /// The interval of the highest step `percent` reaches; `None` below all of them
+    pub fn interval(&self, percent: u8) -> Option<Duration> {
+        self.steps
+            .iter()
+            .find(|step| percent >= step.min_pct)
+            .map(|step| Duration::from_secs(step.secs as u64))
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.steps
+            .iter()
+            .flat_map(|step| {
+                let mut data = [0; STEP_LEN];
+                data[0] = step.min_pct;
+                data[1..].copy_from_slice(&step.secs.to_le_bytes());
+                data
+            })
+            .collect()
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Option<Self> {
+        if data.len() % STEP_LEN != 0 {
+            return None;
+        }
+
+        Some(Self::new(
+            data.chunks(STEP_LEN)
+                .map(|chunk| Step {
+                    min_pct: chunk[0],
+                    secs: u32::from_le_bytes(chunk[1..].try_into().unwrap()),
+                })
+                .collect(),
+        ))
+    }
+}
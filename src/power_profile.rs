@@ -0,0 +1,92 @@
+use esp_idf_svc::sys::EspError;
+
+use log::*;
+
+use crate::power;
+use crate::settings::Settings;
+use crate::wifi::PowerSave;
+
+/// CPU clock, Wi-Fi power saving and sensor mode switched together. Without one, each of them
+/// follows its own setting.
+///
+/// | profile       | CPU          | light sleep | Wi-Fi       | accelerometer |
+/// |---------------|--------------|-------------|-------------|---------------|
+/// | `performance` | 240MHz       | no          | `none`      | full          |
+/// | `balanced`    | 40-160MHz    | yes         | `min_modem` | full          |
+/// | `low_power`   | 40-80MHz     | yes         | `max_modem` | low-power     |
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PowerProfile {
+    Performance,
+    Balanced,
+    LowPower,
+}
+
+impl PowerProfile {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "performance" => Some(Self::Performance),
+            "balanced" => Some(Self::Balanced),
+            "low_power" => Some(Self::LowPower),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Performance => "performance",
+            Self::Balanced => "balanced",
+            Self::LowPower => "low_power",
+        }
+    }
+
+    /// Stored in NVS; 0 means no profile
+    pub fn to_u8(self) -> u8 {
+        match self {
+            Self::Performance => 1,
+            Self::Balanced => 2,
+            Self::LowPower => 3,
+        }
+    }
+
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            1 => Some(Self::Performance),
+            2 => Some(Self::Balanced),
+            3 => Some(Self::LowPower),
+            _ => None,
+        }
+    }
+
+    pub fn wifi_power_save(self) -> PowerSave {
+        match self {
+            Self::Performance => PowerSave::None,
+            Self::Balanced => PowerSave::MinModem,
+            Self::LowPower => PowerSave::MaxModem,
+        }
+    }
+
+    pub fn low_power_accel(self) -> bool {
+        self == Self::LowPower
+    }
+
+    /// Sets the CPU clock range and whether the chip may light-sleep
+    pub fn configure_cpu(self) -> Result<(), EspError> {
+        let (max_mhz, min_mhz, light_sleep) = match self {
+            Self::Performance => (240, 240, false),
+            Self::Balanced => (160, power::XTAL_MHZ, true),
+            Self::LowPower => (80, power::XTAL_MHZ, true),
+        };
+
+        power::configure(max_mhz, min_mhz, light_sleep)
+    }
+
+    /// Switches everything over while running; Wi-Fi has to be started
+    pub fn apply(self, settings: &Settings) -> Result<(), EspError> {
+        self.configure_cpu()?;
+        self.wifi_power_save().apply()?;
+        settings.set_low_power(self.low_power_accel());
+
+        info!("Power profile \"{}\" applied", self.as_str());
+        Ok(())
+    }
+}
@@ -0,0 +1,110 @@
+use prost::Message;
+
+use crate::clock::Stamp;
+use crate::envelope::Envelope;
+use crate::telemetry::Signal;
+
+/// Major version of `proto/telemetry.proto`, part of the telemetry topic
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// Tag of `TelemetryBatch.samples`: field 1, length-delimited
+const BATCH_SAMPLES_KEY: u8 = 0x0a;
+
+/// `iot_tokuron.telemetry.v1.Telemetry`
+#[derive(Clone, PartialEq, Message)]
+pub struct Telemetry {
+    #[prost(uint32, tag = "1")]
+    pub seq: u32,
+    #[prost(uint64, optional, tag = "2")]
+    pub ts: Option<u64>,
+    #[prost(uint64, tag = "3")]
+    pub mono_us: u64,
+    #[prost(message, repeated, tag = "4")]
+    pub signals: Vec<ProtoSignal>,
+    #[prost(message, optional, tag = "5")]
+    pub device: Option<Device>,
+}
+
+/// `iot_tokuron.telemetry.v1.Signal`
+#[derive(Clone, PartialEq, Message)]
+pub struct ProtoSignal {
+    #[prost(string, tag = "1")]
+    pub field: String,
+    #[prost(float, repeated, tag = "2")]
+    pub values: Vec<f32>,
+    #[prost(string, optional, tag = "3")]
+    pub unit: Option<String>,
+    #[prost(string, optional, tag = "4")]
+    pub range_name: Option<String>,
+    #[prost(uint32, optional, tag = "5")]
+    pub range: Option<u32>,
+}
+
+/// `iot_tokuron.telemetry.v1.Device`
+#[derive(Clone, PartialEq, Message)]
+pub struct Device {
+    #[prost(uint32, tag = "1")]
+    pub schema_version: u32,
+    #[prost(string, tag = "2")]
+    pub client_id: String,
+    #[prost(string, tag = "3")]
+    pub firmware_version: String,
+    #[prost(string, tag = "4")]
+    pub mac: String,
+    #[prost(string, tag = "5")]
+    pub chip: String,
+    #[prost(uint32, tag = "6")]
+    pub revision: u32,
+    #[prost(uint64, tag = "7")]
+    pub uptime_ms: u64,
+}
+
+/// One round of readings as a `Telemetry` message
+pub fn telemetry(
+    seq: u32,
+    stamp: Stamp,
+    signals: &[Signal],
+    envelope: Option<&Envelope>,
+) -> Vec<u8> {
+    let device = envelope.map(|envelope| {
+        let wrapped = envelope.wrap(&());
+        Device {
+            schema_version: wrapped.schema_version,
+            client_id: wrapped.client_id.to_string(),
+            firmware_version: wrapped.firmware_version.to_string(),
+            mac: wrapped.device.mac.clone(),
+            chip: wrapped.device.chip.to_string(),
+            revision: wrapped.device.revision as u32,
+            uptime_ms: wrapped.uptime_ms,
+        }
+    });
+
+    Telemetry {
+        seq,
+        ts: stamp.ts,
+        mono_us: stamp.mono_us,
+        signals: signals
+            .iter()
+            .map(|signal| ProtoSignal {
+                field: signal.field.to_string(),
+                values: signal.axes.clone(),
+                unit: signal.unit.map(str::to_string),
+                range_name: signal.range.map(|(name, _)| name.to_string()),
+                range: signal.range.map(|(_, range)| range as u32),
+            })
+            .collect(),
+        device,
+    }
+    .encode_to_vec()
+}
+
+/// Encoded `Telemetry` messages as one `TelemetryBatch`
+pub fn batch(samples: &[Vec<u8>]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for sample in samples {
+        bytes.push(BATCH_SAMPLES_KEY);
+        prost::encoding::encode_varint(sample.len() as u64, &mut bytes);
+        bytes.extend_from_slice(sample);
+    }
+    bytes
+}
@@ -0,0 +1,186 @@
+use core::pin::pin;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Result};
+use embassy_futures::select::{select, Either};
+use serde::Deserialize;
+use serde_json::json;
+
+use esp_idf_svc::mqtt::client::*;
+use esp_idf_svc::timer::EspAsyncTimer;
+use esp_idf_svc::tls::X509;
+
+use log::*;
+
+use crate::credentials::Credentials;
+use crate::mqtt::Reassembler;
+
+const CREATE_TOPIC: &str = "$aws/certificates/create/json";
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Payload of `$aws/certificates/create/json/accepted`
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CreateKeysAndCertificateResponse {
+    certificate_pem: String,
+    private_key: String,
+    certificate_ownership_token: String,
+}
+
+/// Payload of `$aws/provisioning-templates/<template>/provision/json/accepted`
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RegisterThingResponse {
+    thing_name: String,
+}
+
+/// Payload of any `.../rejected` topic
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ErrorResponse {
+    error_code: Option<String>,
+    error_message: Option<String>,
+}
+
+/// Runs AWS IoT fleet provisioning by claim: connects with the shared claim certificate,
+/// obtains a fresh key pair and certificate, and registers a thing for it with `template`
+pub async fn provision(
+    url: &str,
+    server_cert: X509<'static>,
+    claim_cert: X509<'static>,
+    claim_key: X509<'static>,
+    template: &str,
+    serial_number: &str,
+    timer: &mut EspAsyncTimer,
+) -> Result<Credentials> {
+    // The claim certificate's policy only allows client IDs it can't collide on
+    let client_id = format!("provision-{serial_number}");
+
+    let (mut client, mut connection) = EspAsyncMqttClient::new(
+        url,
+        &MqttClientConfiguration {
+            client_id: Some(&client_id),
+            crt_bundle_attach: Some(esp_idf_sys::esp_crt_bundle_attach),
+            server_certificate: Some(server_cert),
+            client_certificate: Some(claim_cert),
+            private_key: Some(claim_key),
+            ..Default::default()
+        },
+    )?;
+
+    let inbox = Mutex::new(VecDeque::new());
+
+    let res = select(
+        pin!(async {
+            // The new certificate and key come to about 3 KB, more than the receive buffer
+            let mut reassembler = Reassembler::default();
+
+            while let Ok(event) = connection.next().await {
+                if let EventPayload::Received {
+                    topic,
+                    data,
+                    details,
+                    ..
+                } = event.payload()
+                {
+                    if let Some(message) = reassembler.push(topic, data, details) {
+                        inbox.lock().unwrap().push_back(message);
+                    }
+                }
+            }
+
+            Err::<Credentials, _>(anyhow!("connection closed during provisioning"))
+        }),
+        pin!(async {
+            let created: CreateKeysAndCertificateResponse =
+                request(&mut client, timer, &inbox, CREATE_TOPIC, "{}").await?;
+            info!("Provisioning: received a new certificate");
+
+            let provision_topic = format!("$aws/provisioning-templates/{template}/provision/json");
+            let request_body = json!({
+                "certificateOwnershipToken": created.certificate_ownership_token,
+                "parameters": { "SerialNumber": serial_number },
+            })
+            .to_string();
+            let registered: RegisterThingResponse =
+                request(&mut client, timer, &inbox, &provision_topic, &request_body).await?;
+            info!(
+                "Provisioning: registered as thing \"{}\"",
+                registered.thing_name
+            );
+
+            Ok(Credentials {
+                certificate: created.certificate_pem.into_bytes(),
+                private_key: created.private_key.into_bytes(),
+                thing_name: Some(registered.thing_name),
+            })
+        }),
+    )
+    .await;
+
+    match res {
+        Either::First(res) => res,
+        Either::Second(res) => res,
+    }
+}
+
+/// Publishes `body` on `topic` and waits for the answer on `topic/accepted` or `topic/rejected`
+async fn request<T: for<'de> Deserialize<'de>>(
+    client: &mut EspAsyncMqttClient,
+    timer: &mut EspAsyncTimer,
+    inbox: &Mutex<VecDeque<(String, Vec<u8>)>>,
+    topic: &str,
+    body: &str,
+) -> Result<T> {
+    let accepted = format!("{topic}/accepted");
+    let rejected = format!("{topic}/rejected");
+
+    // Subscribing may fail until the connection is up
+    let started = Instant::now();
+    while client
+        .subscribe(&format!("{topic}/+"), QoS::AtLeastOnce)
+        .await
+        .is_err()
+    {
+        if started.elapsed() >= RESPONSE_TIMEOUT {
+            bail!("timed out subscribing to \"{topic}/+\"");
+        }
+        timer.after(Duration::from_millis(500)).await?;
+    }
+    timer.after(Duration::from_millis(500)).await?;
+
+    client
+        .publish(topic, QoS::AtLeastOnce, false, body.as_bytes())
+        .await?;
+
+    let started = Instant::now();
+    loop {
+        let response = {
+            let mut inbox = inbox.lock().unwrap();
+            inbox
+                .iter()
+                .position(|(received, _)| *received == accepted || *received == rejected)
+                .and_then(|index| inbox.remove(index))
+        };
+
+        match response {
+            Some((received, data)) if received == accepted => {
+                return Ok(serde_json::from_slice(&data)?);
+            }
+            Some((_, data)) => {
+                let error: ErrorResponse = serde_json::from_slice(&data)?;
+                bail!(
+                    "\"{topic}\" rejected: {} {}",
+                    error.error_code.unwrap_or_default(),
+                    error.error_message.unwrap_or_default()
+                );
+            }
+            None if started.elapsed() >= RESPONSE_TIMEOUT => {
+                bail!("timed out waiting for a response to \"{topic}\"")
+            }
+            None => timer.after(Duration::from_millis(100)).await?,
+        }
+    }
+}
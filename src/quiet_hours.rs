@@ -0,0 +1,79 @@
+use std::time::Duration;
+
+use log::*;
+
+use crate::clock;
+use crate::Config;
+
+const MINS_PER_DAY: u32 = 24 * 60;
+
+/// A daily window, e.g. overnight, in which the device neither samples nor publishes but
+/// sleeps until its end. Needs the clock set by SNTP; until then the window is ignored.
+#[derive(Clone, Copy, Debug)]
+pub struct QuietHours {
+    /// Minutes since local midnight
+    start: u32,
+    end: u32,
+    utc_offset_mins: i32,
+}
+
+impl QuietHours {
+    /// `None` unless `quiet_hours` is set
+    pub fn from_config(app_config: &Config) -> Option<Self> {
+        if app_config.quiet_hours.is_empty() {
+            return None;
+        }
+
+        let parsed = app_config
+            .quiet_hours
+            .split_once('-')
+            .and_then(|(start, end)| Some((parse_time(start)?, parse_time(end)?)))
+            .filter(|(start, end)| start != end);
+        let Some((start, end)) = parsed else {
+            warn!(
+                "Invalid quiet hours \"{}\", expected e.g. \"22:00-06:00\"",
+                app_config.quiet_hours
+            );
+            return None;
+        };
+
+        info!(
+            "Quiet hours {} (UTC{:+}min)",
+            app_config.quiet_hours, app_config.utc_offset_mins
+        );
+
+        Some(Self {
+            start,
+            end,
+            utc_offset_mins: app_config.utc_offset_mins,
+        })
+    }
+
+    /// Time left until the window ends, `None` outside of it or while the clock isn't set
+    pub fn remaining(&self) -> Option<Duration> {
+        let secs = (clock::epoch_ms()? / 1000) as i64 + self.utc_offset_mins as i64 * 60;
+        let secs_of_day = secs.rem_euclid(MINS_PER_DAY as i64 * 60) as u32;
+        let now = secs_of_day / 60;
+
+        let inside = if self.start < self.end {
+            (self.start..self.end).contains(&now)
+        } else {
+            // Across midnight
+            now >= self.start || now < self.end
+        };
+        if !inside {
+            return None;
+        }
+
+        let until_end = (self.end + MINS_PER_DAY - now) % MINS_PER_DAY * 60 - secs_of_day % 60;
+        Some(Duration::from_secs(until_end as u64))
+    }
+}
+
+/// `"06:30"` as minutes since midnight
+fn parse_time(time: &str) -> Option<u32> {
+    let (hours, mins) = time.trim().split_once(':')?;
+    let (hours, mins): (u32, u32) = (hours.parse().ok()?, mins.parse().ok()?);
+
+    (hours < 24 && mins < 60).then_some(hours * 60 + mins)
+}
@@ -0,0 +1,59 @@
+use std::time::{Duration, Instant};
+
+use crate::Config;
+
+/// Token bucket in front of every publish, so a short interval or a burst of events cannot
+/// run into the AWS IoT message quotas
+pub struct RateLimiter {
+    /// Tokens added per second, 0 disables the limiter
+    rate: f32,
+    burst: f32,
+    tokens: f32,
+    refilled_at: Instant,
+    drop_telemetry: bool,
+}
+
+impl RateLimiter {
+    pub fn from_config(app_config: &Config) -> Self {
+        let burst = app_config.publish_burst.max(1) as f32;
+
+        Self {
+            rate: app_config.publish_rate_limit as f32,
+            burst,
+            tokens: burst,
+            refilled_at: Instant::now(),
+            drop_telemetry: app_config.publish_rate_drop,
+        }
+    }
+
+    /// Whether telemetry over the limit is dropped rather than delayed; other messages always wait
+    pub fn drop_telemetry(&self) -> bool {
+        self.drop_telemetry
+    }
+
+    fn refill(&mut self) {
+        let elapsed = self.refilled_at.elapsed().as_secs_f32();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.refilled_at = Instant::now();
+    }
+
+    /// Takes a token if one is available
+    pub fn try_take(&mut self) -> bool {
+        if self.rate <= 0.0 {
+            return true;
+        }
+
+        self.refill();
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Time until the next token is available
+    pub fn wait_time(&self) -> Duration {
+        Duration::from_secs_f32(((1.0 - self.tokens) / self.rate).max(0.0))
+    }
+}
@@ -0,0 +1,86 @@
+use core::fmt;
+use core::time::Duration;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::Instant;
+
+use log::*;
+
+const TCP_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long each step of reaching the broker took
+pub struct Reachability {
+    pub dns: Duration,
+    pub tcp: Duration,
+}
+
+/// The first step which failed; anything past TCP is a TLS/certificate matter
+pub enum ReachabilityError {
+    InvalidUrl,
+    Dns(std::io::Error),
+    Tcp(SocketAddr, std::io::Error),
+}
+
+impl fmt::Display for ReachabilityError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidUrl => write!(f, "endpoint is not a valid MQTT URL"),
+            Self::Dns(e) => write!(f, "DNS lookup failed: {e}"),
+            Self::Tcp(addr, e) => write!(f, "TCP connect to {addr} failed: {e}"),
+        }
+    }
+}
+
+/// Resolves the broker and opens (then drops) a plain TCP connection to it,
+/// so WiFi/DNS/firewall problems can be told apart from TLS and certificate ones
+pub fn check(url: &str) -> Result<Reachability, ReachabilityError> {
+    let (host, port) = endpoint_host_port(url).ok_or(ReachabilityError::InvalidUrl)?;
+
+    let started = Instant::now();
+    let addr = (host, port)
+        .to_socket_addrs()
+        .map_err(ReachabilityError::Dns)?
+        .next()
+        .ok_or_else(|| {
+            ReachabilityError::Dns(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "no addresses returned",
+            ))
+        })?;
+    let dns = started.elapsed();
+    info!("Resolved \"{host}\" to {addr} in {}ms", dns.as_millis());
+
+    let started = Instant::now();
+    TcpStream::connect_timeout(&addr, TCP_CONNECT_TIMEOUT)
+        .map_err(|e| ReachabilityError::Tcp(addr, e))?;
+    let tcp = started.elapsed();
+    info!("TCP connect to {addr} took {}ms", tcp.as_millis());
+
+    Ok(Reachability { dns, tcp })
+}
+
+/// Logs the outcome of [`check`] in a way that points at the likely culprit
+pub fn log_result(result: &Result<Reachability, ReachabilityError>) {
+    match result {
+        Ok(_) => info!(
+            "Broker is reachable; if MQTT still fails to connect, check the certificates and the IoT policy"
+        ),
+        Err(e @ ReachabilityError::InvalidUrl) => error!("Reachability check: {e}, check cfg.toml"),
+        Err(e @ ReachabilityError::Dns(_)) => {
+            error!("Reachability check: {e}; WiFi has no working DNS or the endpoint is misspelled")
+        }
+        Err(e @ ReachabilityError::Tcp(..)) => {
+            error!("Reachability check: {e}; the network blocks the broker port or has no internet access")
+        }
+    }
+}
+
+/// Extracts host and port from an MQTT URL such as `mqtts://xxx.amazonaws.com:8883`
+pub fn endpoint_host_port(url: &str) -> Option<(&str, u16)> {
+    let (scheme, rest) = url.split_once("://")?;
+    let authority = rest.split('/').next()?;
+
+    match authority.rsplit_once(':') {
+        Some((host, port)) => Some((host, port.parse().ok()?)),
+        None => Some((authority, if scheme.ends_with('s') { 8883 } else { 1883 })),
+    }
+}
@@ -0,0 +1,184 @@
+use serde::Deserialize;
+
+use log::*;
+
+use crate::i2c_bus::{self, BusOverride};
+use crate::imu;
+use crate::mqtt::MessageKind;
+use crate::power_profile::PowerProfile;
+use crate::settings::Settings;
+
+/// The sensor's output data rate with the low-pass filter enabled
+const MAX_SAMPLE_HZ: u32 = 1000;
+
+/// Retained document on the config topic; fields left out keep their current value
+#[derive(Deserialize)]
+struct ConfigDocument {
+    publish_interval_secs: Option<u32>,
+    sample_interval_secs: Option<u32>,
+    sample_hz: Option<u32>,
+    qos: Option<QosDocument>,
+    sensors: Option<SensorsDocument>,
+    dlpf: Option<DlpfDocument>,
+    /// Bus of the MPU6886, applied at the next boot
+    i2c: Option<BusOverride>,
+    /// Bus of the other sensors, applied at the next boot
+    i2c_secondary: Option<BusOverride>,
+    /// "performance", "balanced" or "low_power"
+    power_profile: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct QosDocument {
+    telemetry: Option<u8>,
+    alert: Option<u8>,
+    status: Option<u8>,
+}
+
+#[derive(Deserialize)]
+struct SensorsDocument {
+    gyro: Option<bool>,
+    acc: Option<bool>,
+    temp: Option<bool>,
+}
+
+/// Low-pass filter bandwidths in Hz
+#[derive(Deserialize)]
+struct DlpfDocument {
+    gyro_hz: Option<u16>,
+    accel_hz: Option<u16>,
+}
+
+/// Applies runtime configuration published (retained) on the config topic
+pub struct RemoteConfig {
+    topic: String,
+}
+
+impl RemoteConfig {
+    pub fn new(topic: String) -> Self {
+        Self { topic }
+    }
+
+    pub fn topic(&self) -> &str {
+        &self.topic
+    }
+
+    /// Called from the connection loop; returns `true` if the message was for the config topic
+    pub fn handle_message(&self, topic: &str, data: &[u8], settings: &Settings) -> bool {
+        if topic != self.topic {
+            return false;
+        }
+
+        // Clearing the retained message leaves the current settings alone
+        if data.is_empty() {
+            return true;
+        }
+
+        match serde_json::from_slice::<ConfigDocument>(data) {
+            Ok(doc) => apply(doc, settings),
+            Err(e) => warn!("Malformed config document: {e}"),
+        }
+
+        true
+    }
+}
+
+fn apply(doc: ConfigDocument, settings: &Settings) {
+    if let Some(secs) = doc.publish_interval_secs {
+        info!("Config: publish interval set to {secs}s");
+        settings.set_publish_interval_secs(secs);
+    }
+
+    if let Some(secs) = doc.sample_interval_secs {
+        info!("Config: sample interval set to {secs}s");
+        settings.set_sample_interval_secs(secs);
+    }
+
+    if let Some(hz) = doc.sample_hz {
+        if hz > MAX_SAMPLE_HZ {
+            warn!("Config: sample rate {hz}Hz is above {MAX_SAMPLE_HZ}Hz");
+        } else {
+            info!("Config: sample rate set to {hz}Hz");
+            settings.set_sample_hz(hz);
+        }
+    }
+
+    if let Some(qos) = doc.qos {
+        for (kind, level) in [
+            (MessageKind::Telemetry, qos.telemetry),
+            (MessageKind::Alert, qos.alert),
+            (MessageKind::Status, qos.status),
+        ] {
+            if let Some(level) = level {
+                info!("Config: QoS for {kind:?} set to {level}");
+                settings.set_qos(kind, level);
+            }
+        }
+    }
+
+    if let Some(sensors) = doc.sensors {
+        if let Some(enabled) = sensors.gyro {
+            info!("Config: gyro enabled = {enabled}");
+            settings.set_gyro_enabled(enabled);
+        }
+        if let Some(enabled) = sensors.acc {
+            info!("Config: accelerometer enabled = {enabled}");
+            settings.set_acc_enabled(enabled);
+        }
+        if let Some(enabled) = sensors.temp {
+            info!("Config: temperature enabled = {enabled}");
+            settings.set_temp_enabled(enabled);
+        }
+    }
+
+    if let Some(dlpf) = doc.dlpf {
+        let gyro_hz = dlpf.gyro_hz.unwrap_or(settings.gyro_dlpf_hz());
+        let accel_hz = dlpf.accel_hz.unwrap_or(settings.accel_dlpf_hz());
+
+        if imu::gyro_dlpf(gyro_hz).is_none() || imu::accel_dlpf(accel_hz).is_none() {
+            warn!("Config: unsupported DLPF bandwidth, gyro {gyro_hz}Hz / accel {accel_hz}Hz");
+        } else {
+            info!("Config: DLPF set to gyro {gyro_hz}Hz, accel {accel_hz}Hz");
+            settings.set_dlpf(gyro_hz, accel_hz);
+        }
+    }
+
+    if let Some(name) = doc.power_profile {
+        match PowerProfile::parse(&name) {
+            Some(profile) => {
+                info!("Config: power profile set to \"{name}\"");
+                settings.set_power_profile(profile);
+                if let Err(e) = profile.apply(settings) {
+                    warn!("Config: failed to apply the power profile: {e}");
+                }
+            }
+            None => warn!("Config: unknown power profile \"{name}\""),
+        }
+    }
+
+    for (secondary, bus) in [(false, doc.i2c), (true, doc.i2c_secondary)] {
+        let Some(bus) = bus else {
+            continue;
+        };
+
+        if bus.port.is_some_and(|port| port > 1) {
+            warn!(
+                "Config: there is no I2C port {}",
+                bus.port.unwrap_or_default()
+            );
+        } else if bus.baudrate_khz.is_some_and(|khz| khz == 0 || khz > 1000) {
+            warn!("Config: I2C baudrate has to be 1kHz to 1000kHz");
+        } else if [bus.sda_gpio, bus.scl_gpio]
+            .into_iter()
+            .flatten()
+            .any(|gpio| !i2c_bus::usable_gpio(gpio) && !(secondary && gpio < 0))
+        {
+            warn!("Config: I2C pins have to be GPIO0-21 or GPIO33-48");
+        } else if bus.sda_gpio.is_some() && bus.sda_gpio == bus.scl_gpio {
+            warn!("Config: I2C SDA and SCL have to be different pins");
+        } else {
+            info!("Config: I2C bus changed, takes effect after a reboot");
+            settings.set_i2c_bus(secondary, &bus);
+        }
+    }
+}
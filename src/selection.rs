@@ -0,0 +1,94 @@
+use serde_json::Value;
+
+use log::*;
+
+use crate::telemetry::Signal;
+use crate::Config;
+
+/// Which signals go into telemetry and to how many decimal places, to keep messages small on
+/// constrained links
+pub struct Selection {
+    /// Empty keeps every signal
+    fields: Vec<&'static str>,
+    /// Decimal places per field; `*` applies to the fields not listed
+    precision: Vec<(&'static str, u32)>,
+}
+
+impl Selection {
+    /// `None` unless `payload_fields` or `payload_precision` is set
+    pub fn from_config(app_config: &Config) -> Option<Self> {
+        let fields: Vec<_> = list(app_config.payload_fields).collect();
+
+        let precision: Vec<_> = list(app_config.payload_precision)
+            .filter_map(|entry| {
+                let parsed = entry
+                    .split_once(':')
+                    .and_then(|(field, places)| Some((field.trim(), places.trim().parse().ok()?)));
+                if parsed.is_none() {
+                    warn!("Invalid precision \"{entry}\", expected e.g. \"gyro:3\"");
+                }
+                parsed
+            })
+            .collect();
+
+        if fields.is_empty() && precision.is_empty() {
+            return None;
+        }
+
+        Some(Self { fields, precision })
+    }
+
+    /// Drops the signals not selected and rounds the others
+    pub fn apply(&self, signals: &mut Vec<Signal>) {
+        if !self.fields.is_empty() {
+            signals.retain(|signal| self.fields.contains(&signal.field));
+        }
+
+        for signal in signals {
+            let Some(places) = self.places(signal.field) else {
+                continue;
+            };
+
+            let factor = 10f64.powi(places as i32);
+            for value in &mut signal.axes {
+                *value = ((*value as f64 * factor).round() / factor) as f32;
+            }
+            round(&mut signal.value, factor);
+        }
+    }
+
+    fn places(&self, field: &str) -> Option<u32> {
+        let find = |name: &str| {
+            self.precision
+                .iter()
+                .find(|(listed, _)| *listed == name)
+                .map(|(_, places)| *places)
+        };
+
+        find(field).or_else(|| find("*"))
+    }
+}
+
+/// Rounds every fractional number in a JSON value, e.g. all of `{"mean": [..], "max": [..]}`
+fn round(value: &mut Value, factor: f64) {
+    match value {
+        Value::Number(number) if number.is_f64() => {
+            if let Some(rounded) = number
+                .as_f64()
+                .and_then(|x| serde_json::Number::from_f64((x * factor).round() / factor))
+            {
+                *number = rounded;
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(|value| round(value, factor)),
+        Value::Object(map) => map.values_mut().for_each(|value| round(value, factor)),
+        _ => (),
+    }
+}
+
+fn list(value: &'static str) -> impl Iterator<Item = &'static str> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+}
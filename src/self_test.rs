@@ -0,0 +1,167 @@
+use std::time::Duration;
+
+use mpu6886::device::{ACCEL_CONFIG, ACC_REGX_H, CONFIG, GYRO_CONFIG, GYRO_REGX_H, WHOAMI};
+use serde::Serialize;
+
+use log::*;
+
+use crate::imu::Imu;
+
+/// Registers 0-2 and 13-15, factory trim codes of the gyro and accelerometer self-test
+const SELF_TEST_GYRO: u8 = 0x00;
+const SELF_TEST_ACCEL: u8 = 0x0d;
+const SMPLRT_DIV: u8 = 0x19;
+const ACCEL_CONFIG2: u8 = 0x1d;
+/// WHO_AM_I of the MPU6886
+const CHIP_ID: u8 = 0x19;
+/// Readings averaged with and without self-test
+const SAMPLES: i32 = 200;
+/// Self-test response in LSB at ±250dps / ±2g for a trim code of 1
+const ST_BASE: f32 = 2620.0;
+/// Without a trim code the gyro has to respond with at least 60dps...
+const GYRO_MIN_RESPONSE: f32 = 60.0 * 131.0;
+/// ...and the accelerometer with 225mg to 675mg
+const ACCEL_MIN_RESPONSE: f32 = 0.225 * 16384.0;
+const ACCEL_MAX_RESPONSE: f32 = 0.675 * 16384.0;
+
+/// Outcome of the boot-time check, published with the first status message
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SelfTest {
+    pub who_am_i: bool,
+    pub gyro: bool,
+    pub accel: bool,
+}
+
+impl SelfTest {
+    pub fn passed(&self) -> bool {
+        self.who_am_i && self.gyro && self.accel
+    }
+
+    /// `{"passed": true, "who_am_i": true, "gyro": true, "accel": true}`
+    pub fn report(&self) -> SelfTestReport {
+        SelfTestReport {
+            passed: self.passed(),
+            who_am_i: self.who_am_i,
+            gyro: self.gyro,
+            accel: self.accel,
+        }
+    }
+}
+
+/// [`SelfTest`] as published in the status
+#[derive(Serialize)]
+pub struct SelfTestReport {
+    passed: bool,
+    who_am_i: bool,
+    gyro: bool,
+    accel: bool,
+}
+
+/// Checks WHO_AM_I, then compares the response to the built-in self-test against the factory
+/// trim values. Leaves the sensor at ±2g/±250dps with the self-test off; ranges and filters have
+/// to be configured afterwards.
+pub fn run(mpu: &mut Imu) -> SelfTest {
+    let mut result = SelfTest {
+        who_am_i: mpu.read_byte(WHOAMI).is_ok_and(|id| id == CHIP_ID),
+        ..Default::default()
+    };
+
+    match responses(mpu) {
+        Ok((gyro, accel)) => {
+            result.gyro = gyro;
+            result.accel = accel;
+        }
+        Err(e) => warn!("Sensor self-test failed to run: {e}"),
+    }
+
+    if result.passed() {
+        info!("Sensor self-test passed");
+    } else {
+        warn!("Sensor self-test failed: {result:?}");
+    }
+
+    result
+}
+
+fn responses(mpu: &mut Imu) -> Result<(bool, bool), String> {
+    // 1kHz, gyro DLPF 92Hz, accel DLPF 99Hz, ±250dps, ±2g
+    write_byte(mpu, SMPLRT_DIV, 0)?;
+    write_byte(mpu, CONFIG::ADDR, 0x02)?;
+    write_byte(mpu, ACCEL_CONFIG2, 0x02)?;
+    write_byte(mpu, GYRO_CONFIG::ADDR, 0x00)?;
+    write_byte(mpu, ACCEL_CONFIG::ADDR, 0x00)?;
+    std::thread::sleep(Duration::from_millis(20));
+
+    let (gyro_os, accel_os) = average(mpu)?;
+
+    // Self-test on for all axes
+    write_byte(mpu, GYRO_CONFIG::ADDR, 0xe0)?;
+    write_byte(mpu, ACCEL_CONFIG::ADDR, 0xe0)?;
+    std::thread::sleep(Duration::from_millis(20));
+
+    let averaged = average(mpu);
+
+    write_byte(mpu, GYRO_CONFIG::ADDR, 0x00)?;
+    write_byte(mpu, ACCEL_CONFIG::ADDR, 0x00)?;
+    std::thread::sleep(Duration::from_millis(20));
+
+    let (gyro_st, accel_st) = averaged?;
+
+    let mut gyro_codes = [0; 3];
+    let mut accel_codes = [0; 3];
+    mpu.read_bytes(SELF_TEST_GYRO, &mut gyro_codes)
+        .map_err(|e| format!("{e:?}"))?;
+    mpu.read_bytes(SELF_TEST_ACCEL, &mut accel_codes)
+        .map_err(|e| format!("{e:?}"))?;
+
+    let gyro = (0..3).all(|axis| {
+        let response = (gyro_st[axis] - gyro_os[axis]) as f32;
+        match factory_trim(gyro_codes[axis]) {
+            Some(trim) => response / trim > 0.5,
+            None => response.abs() >= GYRO_MIN_RESPONSE,
+        }
+    });
+    let accel = (0..3).all(|axis| {
+        let response = (accel_st[axis] - accel_os[axis]) as f32;
+        match factory_trim(accel_codes[axis]) {
+            Some(trim) => (0.5..1.5).contains(&(response / trim)),
+            None => (ACCEL_MIN_RESPONSE..=ACCEL_MAX_RESPONSE).contains(&response.abs()),
+        }
+    });
+
+    info!("Self-test gyro response {gyro_st:?} - {gyro_os:?}, accel {accel_st:?} - {accel_os:?}");
+
+    Ok((gyro, accel))
+}
+
+/// Expected self-test response in LSB, `None` if the unit has no trim value
+fn factory_trim(code: u8) -> Option<f32> {
+    (code != 0).then(|| ST_BASE * 1.01f32.powi(code as i32 - 1))
+}
+
+/// Raw gyro and accelerometer readings averaged over `SAMPLES`
+fn average(mpu: &mut Imu) -> Result<([i32; 3], [i32; 3]), String> {
+    let mut gyro = [0; 3];
+    let mut accel = [0; 3];
+
+    for _ in 0..SAMPLES {
+        for (reg, sum) in [(GYRO_REGX_H, &mut gyro), (ACC_REGX_H, &mut accel)] {
+            let mut buf = [0; 6];
+            mpu.read_bytes(reg, &mut buf)
+                .map_err(|e| format!("{e:?}"))?;
+            for (sum, bytes) in sum.iter_mut().zip(buf.chunks(2)) {
+                *sum += i16::from_be_bytes([bytes[0], bytes[1]]) as i32;
+            }
+        }
+        std::thread::sleep(Duration::from_millis(1));
+    }
+
+    Ok((
+        gyro.map(|sum| sum / SAMPLES),
+        accel.map(|sum| sum / SAMPLES),
+    ))
+}
+
+fn write_byte(mpu: &mut Imu, reg: u8, byte: u8) -> Result<(), String> {
+    mpu.write_byte(reg, byte).map_err(|e| format!("{e:?}"))
+}
@@ -0,0 +1,93 @@
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::clock::Stamp;
+use crate::telemetry::Signal;
+
+/// One SenML (RFC 8428) record; the base fields only appear in the first record of a pack
+#[derive(Default, Serialize)]
+struct Record<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bn: Option<String>,
+    /// Base time in seconds since the epoch
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bt: Option<f64>,
+    n: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    u: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    v: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    vs: Option<&'a str>,
+    /// Seconds relative to `bt`, or while the clock isn't set relative to now
+    t: f64,
+}
+
+/// One round of readings as a SenML pack, e.g. `[{"bn": "m5stack:", "bt": 1718000000.1,
+/// "n": "seq", "v": 42, "t": 0}, {"n": "gyro/x", "u": "rad/s", "v": 0.01, "t": 0}, ..]`.
+/// Multi-axis signals become one record per axis; aggregated ones carry their mean.
+pub fn pack(client_id: &str, seq: u32, stamp: Stamp, signals: &[Signal]) -> Vec<u8> {
+    let mut records = vec![Record {
+        bn: Some(format!("{client_id}:")),
+        bt: stamp.ts.map(|ts| ts as f64 / 1000.0),
+        n: "seq".to_string(),
+        v: Some(seq as f64),
+        ..Default::default()
+    }];
+
+    for signal in signals {
+        let unit = signal.unit.map(|u| unit(signal.field, u));
+
+        if let Value::String(text) = &signal.value {
+            records.push(Record {
+                n: signal.field.to_string(),
+                vs: Some(text),
+                ..Default::default()
+            });
+            continue;
+        }
+
+        let names = signal.axis_names();
+        for (i, value) in signal.axes.iter().enumerate() {
+            let n = match (signal.axes.len(), names.get(i)) {
+                (1, _) => signal.field.to_string(),
+                (_, Some(name)) => format!("{}/{name}", signal.field),
+                (_, None) => format!("{}/{i}", signal.field),
+            };
+            records.push(Record {
+                n,
+                u: unit,
+                v: Some(*value as f64),
+                ..Default::default()
+            });
+        }
+    }
+
+    serde_json::to_vec(&records).unwrap()
+}
+
+/// Several packs as one; every pack starts with its own base values, so they still apply
+pub fn merge(packs: &[Vec<u8>]) -> Vec<u8> {
+    let mut bytes = vec![b'['];
+    for (i, pack) in packs.iter().enumerate() {
+        if i > 0 {
+            bytes.push(b',');
+        }
+        // Without the brackets of each pack
+        bytes.extend_from_slice(&pack[1..pack.len() - 1]);
+    }
+    bytes.push(b']');
+    bytes
+}
+
+/// The SenML registry's name for a unit where it differs from ours; a percentage is relative
+/// humidity or remaining battery charge depending on the signal
+fn unit(field: &str, unit: &'static str) -> &'static str {
+    match (field, unit) {
+        (_, "m/s^2") => "m/s2",
+        (_, "°C") => "Cel",
+        ("humidity", "%") => "%RH",
+        ("battery_pct", "%") => "%EL",
+        (_, other) => other,
+    }
+}
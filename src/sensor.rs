@@ -0,0 +1,78 @@
+use std::sync::Mutex;
+
+use esp_idf_svc::sys::EspError;
+
+use log::*;
+
+use crate::adc::AnalogChannel;
+use crate::barometer::Barometer;
+use crate::battery::{Battery, BatteryMonitor};
+use crate::i2c_bus::SharedI2c;
+use crate::sht30::Sht30;
+use crate::telemetry::Signal;
+use crate::Config;
+
+/// The signals one sensor delivered; empty while its next reading isn't due
+pub type Reading = Vec<Signal>;
+
+/// A sensor publishing alongside the MPU6886. A new one only has to implement this and be
+/// added to [`Registry::from_config`].
+pub trait Sensor: Send {
+    /// For the logs
+    fn name(&self) -> &'static str;
+
+    fn sample(&mut self) -> Result<Reading, String>;
+}
+
+/// The sensors enabled in the config that answered at boot
+pub struct Registry {
+    sensors: Mutex<Vec<Box<dyn Sensor>>>,
+    battery: Option<BatteryMonitor>,
+}
+
+impl Registry {
+    pub fn from_config(app_config: &Config, i2c: &SharedI2c) -> Result<Self, EspError> {
+        let mut sensors: Vec<Box<dyn Sensor>> = Vec::new();
+
+        if let Some(sht30) = Sht30::from_config(app_config, i2c.clone()) {
+            sensors.push(Box::new(sht30));
+        }
+        if let Some(barometer) = Barometer::from_config(app_config, i2c.clone()) {
+            sensors.push(Box::new(barometer));
+        }
+        if let Some(analog) = AnalogChannel::from_config(app_config)? {
+            sensors.push(Box::new(analog));
+        }
+        let battery = match Battery::from_config(app_config)? {
+            Some((battery, monitor)) => {
+                sensors.push(Box::new(battery));
+                Some(monitor)
+            }
+            None => None,
+        };
+
+        Ok(Self {
+            sensors: Mutex::new(sensors),
+            battery,
+        })
+    }
+
+    /// Alerts on the battery readings, if a battery is monitored
+    pub fn battery(&self) -> Option<&BatteryMonitor> {
+        self.battery.as_ref()
+    }
+
+    /// Samples every sensor; one that fails is logged and left out
+    pub fn sample(&self) -> Vec<Signal> {
+        let mut signals = Vec::new();
+
+        for sensor in self.sensors.lock().unwrap().iter_mut() {
+            match sensor.sample() {
+                Ok(reading) => signals.extend(reading),
+                Err(e) => warn!("Failed to read the {}: {e}", sensor.name()),
+            }
+        }
+
+        signals
+    }
+}
@@ -0,0 +1,123 @@
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::time::Duration;
+
+use esp_idf_svc::hal::delay::Delay;
+use esp_idf_svc::sys::{self, esp};
+use mpu6886::device::WHOAMI;
+use serde::Serialize;
+
+use log::*;
+
+use crate::imu::{self, Imu};
+use crate::settings::Settings;
+
+/// WHO_AM_I of the MPU6886
+const CHIP_ID: u8 = 0x19;
+/// Reads of WHO_AM_I before the sensor counts as failed
+const PROBE_ATTEMPTS: u32 = 3;
+
+/// Outcome of a health check that changed the sensor's state
+pub enum HealthChange {
+    Failed(String),
+    Recovered,
+}
+
+#[derive(Serialize)]
+struct HealthReport<'a> {
+    state: &'static str,
+    error: Option<&'a str>,
+    errors: u32,
+    recoveries: u32,
+}
+
+/// Watches the sensor and brings it back after I2C errors, so a NACK or a sensor that got
+/// reset by a brown-out does not take the app down
+pub struct SensorHealth {
+    /// Port of the sensor's bus
+    port: sys::i2c_port_t,
+    failing: AtomicBool,
+    errors: AtomicU32,
+    recoveries: AtomicU32,
+}
+
+impl SensorHealth {
+    pub fn new(port: u8) -> Self {
+        Self {
+            port: port as sys::i2c_port_t,
+            failing: AtomicBool::new(false),
+            errors: AtomicU32::new(0),
+            recoveries: AtomicU32::new(0),
+        }
+    }
+
+    /// Probes the sensor, re-initializing it and the I2C FIFOs when it does not answer.
+    /// Returns the change to report, if any.
+    pub fn check(&self, mpu: &mut Imu, settings: &Settings) -> Option<HealthChange> {
+        match probe(mpu) {
+            Ok(()) => {
+                if !self.failing.swap(false, Ordering::Relaxed) {
+                    return None;
+                }
+
+                self.recoveries.fetch_add(1, Ordering::Relaxed);
+                info!("Sensor recovered");
+                Some(HealthChange::Recovered)
+            }
+            Err(e) => {
+                self.errors.fetch_add(1, Ordering::Relaxed);
+                warn!("Sensor not responding ({e}), re-initializing");
+                if let Err(e) = recover(self.port, mpu, settings) {
+                    warn!("Sensor re-initialization failed: {e}");
+                }
+
+                if self.failing.swap(true, Ordering::Relaxed) {
+                    None
+                } else {
+                    Some(HealthChange::Failed(e))
+                }
+            }
+        }
+    }
+
+    /// `{"state": "sensor_error", "error": "...", "errors": 3, "recoveries": 1}` or `"ok"`
+    pub fn to_json(&self, change: &HealthChange) -> String {
+        let (state, error) = match change {
+            HealthChange::Failed(e) => ("sensor_error", Some(e.as_str())),
+            HealthChange::Recovered => ("ok", None),
+        };
+
+        serde_json::to_string(&HealthReport {
+            state,
+            error,
+            errors: self.errors.load(Ordering::Relaxed),
+            recoveries: self.recoveries.load(Ordering::Relaxed),
+        })
+        .unwrap()
+    }
+}
+
+fn probe(mpu: &mut Imu) -> Result<(), String> {
+    let mut error = String::new();
+
+    for _ in 0..PROBE_ATTEMPTS {
+        match mpu.read_byte(WHOAMI) {
+            Ok(CHIP_ID) => return Ok(()),
+            Ok(id) => error = format!("unexpected chip id {id:#04x}"),
+            Err(e) => error = format!("{e:?}"),
+        }
+        std::thread::sleep(Duration::from_millis(5));
+    }
+
+    Err(error)
+}
+
+/// Clears what the I2C driver may still hold of a broken transfer, then wakes the sensor and
+/// programs our settings into it again, as after a power cycle
+fn recover(port: sys::i2c_port_t, mpu: &mut Imu, settings: &Settings) -> Result<(), String> {
+    esp!(unsafe { sys::i2c_reset_tx_fifo(port) }).map_err(|e| e.to_string())?;
+    esp!(unsafe { sys::i2c_reset_rx_fifo(port) }).map_err(|e| e.to_string())?;
+
+    mpu.init(&mut Delay::default())
+        .map_err(|e| format!("{e:?}"))?;
+    imu::configure(mpu, settings)
+}
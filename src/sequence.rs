@@ -0,0 +1,61 @@
+use std::sync::Mutex;
+
+use esp_idf_svc::nvs::{EspDefaultNvs, EspDefaultNvsPartition};
+use esp_idf_svc::sys::EspError;
+
+use log::*;
+
+const NVS_NAMESPACE: &str = "sequence";
+
+/// Sequence numbers reserved in NVS at once, so flash is not written for every message
+const RESERVE: u32 = 100;
+
+struct State {
+    next: u32,
+    /// First number not covered by the reservation persisted in NVS
+    reserved_until: u32,
+    nvs: EspDefaultNvs,
+}
+
+/// Monotonically increasing number, e.g. stamped on every telemetry payload so the backend can
+/// detect lost messages. Numbers are reserved in blocks, so after a reboot numbering
+/// continues past the last block instead of repeating; such a jump is not a loss.
+pub struct Sequence {
+    key: &'static str,
+    state: Mutex<State>,
+}
+
+impl Sequence {
+    /// `key` names the sequence in NVS, so independent sequences can coexist
+    pub fn new(partition: EspDefaultNvsPartition, key: &'static str) -> Result<Self, EspError> {
+        let nvs = EspDefaultNvs::new(partition, NVS_NAMESPACE, true)?;
+        let start = nvs.get_u32(key)?.unwrap_or(0);
+        info!("Sequence \"{key}\" continues at {start}");
+
+        Ok(Self {
+            key,
+            state: Mutex::new(State {
+                next: start,
+                reserved_until: start,
+                nvs,
+            }),
+        })
+    }
+
+    pub fn next(&self) -> u32 {
+        let mut state = self.state.lock().unwrap();
+
+        if state.next >= state.reserved_until {
+            state.reserved_until = state.next.saturating_add(RESERVE);
+            let reserved_until = state.reserved_until;
+            if let Err(e) = state.nvs.set_u32(self.key, reserved_until) {
+                warn!("Failed to persist sequence \"{}\": {e}", self.key);
+            }
+        }
+
+        let seq = state.next;
+        state.next = state.next.saturating_add(1);
+
+        seq
+    }
+}
@@ -0,0 +1,419 @@
+use core::sync::atomic::{AtomicBool, AtomicU16, AtomicU32, AtomicU8, Ordering};
+use core::time::Duration;
+use std::sync::Mutex;
+
+use esp_idf_svc::nvs::{EspDefaultNvs, EspDefaultNvsPartition};
+use esp_idf_svc::sys::EspError;
+
+use log::*;
+
+use crate::calibration::{Offsets, TempModel};
+use crate::i2c_bus::{self, BusOverride};
+use crate::imu::AxisRemap;
+use crate::mqtt::{MessageKind, QosSettings};
+use crate::power_policy::{self, IntervalPolicy};
+use crate::power_profile::PowerProfile;
+use crate::units::Units;
+use crate::wifi::PowerSave;
+use crate::Config;
+
+pub const NVS_NAMESPACE: &str = "settings";
+
+/// Settings which can change at runtime, e.g. through the device shadow or the config topic.
+/// Changes are persisted to NVS and take precedence over `cfg.toml` after a reboot.
+pub struct Settings {
+    publish_interval_secs: AtomicU32,
+    sample_interval_secs: AtomicU32,
+    sample_hz: AtomicU32,
+    buzzer_on: AtomicBool,
+    gyro_enabled: AtomicBool,
+    acc_enabled: AtomicBool,
+    temp_enabled: AtomicBool,
+    accel_range_g: AtomicU8,
+    gyro_range_dps: AtomicU16,
+    gyro_dlpf_hz: AtomicU16,
+    accel_dlpf_hz: AtomicU16,
+    low_power: AtomicBool,
+    /// From `cfg.toml` only
+    low_power_hz: u16,
+    /// Set when a sensor setting changed which still has to be programmed into the sensor
+    imu_changed: AtomicBool,
+    qos: QosSettings,
+    offsets: Mutex<Offsets>,
+    temp_model: Mutex<Option<TempModel>>,
+    battery_policy: Mutex<IntervalPolicy>,
+    /// [`PowerProfile::to_u8`], 0 without a profile
+    power_profile: AtomicU8,
+    /// From `cfg.toml` only, used without a power profile
+    wifi_power_save: PowerSave,
+    /// From `cfg.toml` only, as it follows from how the board is mounted
+    axis_remap: AxisRemap,
+    /// From `cfg.toml` only
+    units: Units,
+    nvs: Mutex<EspDefaultNvs>,
+}
+
+impl Settings {
+    pub fn new(app_config: &Config, partition: EspDefaultNvsPartition) -> Result<Self, EspError> {
+        let nvs = EspDefaultNvs::new(partition, NVS_NAMESPACE, true)?;
+
+        let qos = QosSettings::new(
+            nvs.get_u8("qos_telemetry")?
+                .unwrap_or(app_config.telemetry_qos),
+            nvs.get_u8("qos_alert")?.unwrap_or(app_config.alert_qos),
+            nvs.get_u8("qos_status")?.unwrap_or(app_config.status_qos),
+        );
+
+        let mut buf = [0; 24];
+        let offsets = nvs
+            .get_blob("calibration", &mut buf)?
+            .and_then(Offsets::from_bytes)
+            .unwrap_or_default();
+        let mut buf = [0; 16];
+        let temp_model = nvs
+            .get_blob("gyro_temp", &mut buf)?
+            .and_then(TempModel::from_bytes);
+        let mut buf = [0; power_policy::MAX_LEN];
+        let battery_policy = nvs
+            .get_blob("batt_policy", &mut buf)?
+            .and_then(IntervalPolicy::from_bytes)
+            .unwrap_or_else(|| IntervalPolicy::parse(app_config.battery_intervals));
+        let power_profile = match nvs.get_u8("profile")? {
+            Some(profile) => profile,
+            None if app_config.power_profile.is_empty() => 0,
+            None => PowerProfile::parse(app_config.power_profile).map_or_else(
+                || {
+                    warn!("Unknown power profile \"{}\"", app_config.power_profile);
+                    0
+                },
+                PowerProfile::to_u8,
+            ),
+        };
+
+        let axis_remap = AxisRemap::parse(app_config.axis_remap).unwrap_or_else(|| {
+            warn!(
+                "Invalid axis remap \"{}\", using the sensor's axes",
+                app_config.axis_remap
+            );
+            AxisRemap::default()
+        });
+
+        Ok(Self {
+            publish_interval_secs: AtomicU32::new(
+                nvs.get_u32("publish_secs")?
+                    .unwrap_or(app_config.publish_interval_secs),
+            ),
+            sample_interval_secs: AtomicU32::new(
+                nvs.get_u32("sample_secs")?
+                    .unwrap_or(app_config.sample_interval_secs),
+            ),
+            sample_hz: AtomicU32::new(nvs.get_u32("sample_hz")?.unwrap_or(app_config.sample_hz)),
+            buzzer_on: AtomicBool::new(false),
+            gyro_enabled: AtomicBool::new(nvs.get_u8("gyro")?.map_or(true, |on| on != 0)),
+            acc_enabled: AtomicBool::new(nvs.get_u8("acc")?.map_or(true, |on| on != 0)),
+            temp_enabled: AtomicBool::new(nvs.get_u8("temp")?.map_or(false, |on| on != 0)),
+            accel_range_g: AtomicU8::new(
+                nvs.get_u8("accel_range")?
+                    .unwrap_or(app_config.accel_range_g),
+            ),
+            gyro_range_dps: AtomicU16::new(
+                nvs.get_u16("gyro_range")?
+                    .unwrap_or(app_config.gyro_range_dps),
+            ),
+            gyro_dlpf_hz: AtomicU16::new(
+                nvs.get_u16("gyro_dlpf")?.unwrap_or(app_config.gyro_dlpf_hz),
+            ),
+            accel_dlpf_hz: AtomicU16::new(
+                nvs.get_u16("accel_dlpf")?
+                    .unwrap_or(app_config.accel_dlpf_hz),
+            ),
+            low_power: AtomicBool::new(
+                nvs.get_u8("low_power")?
+                    .map_or(app_config.low_power_accel, |on| on != 0),
+            ),
+            low_power_hz: app_config.low_power_accel_hz,
+            imu_changed: AtomicBool::new(false),
+            qos,
+            offsets: Mutex::new(offsets),
+            temp_model: Mutex::new(temp_model),
+            battery_policy: Mutex::new(battery_policy),
+            power_profile: AtomicU8::new(power_profile),
+            wifi_power_save: PowerSave::from_config(app_config.wifi_power_save),
+            axis_remap,
+            units: Units::from_config(app_config),
+            nvs: Mutex::new(nvs),
+        })
+    }
+
+    pub fn publish_interval_secs(&self) -> u32 {
+        self.publish_interval_secs.load(Ordering::Relaxed)
+    }
+
+    pub fn publish_interval(&self) -> Duration {
+        Duration::from_secs(self.publish_interval_secs() as u64)
+    }
+
+    pub fn set_publish_interval_secs(&self, secs: u32) {
+        let secs = secs.max(1);
+        self.publish_interval_secs.store(secs, Ordering::Relaxed);
+        self.persist_u32("publish_secs", secs);
+    }
+
+    /// How often the sensor is sampled while samples can't be published
+    pub fn sample_interval(&self) -> Duration {
+        Duration::from_secs(self.sample_interval_secs.load(Ordering::Relaxed) as u64)
+    }
+
+    pub fn set_sample_interval_secs(&self, secs: u32) {
+        let secs = secs.max(1);
+        self.sample_interval_secs.store(secs, Ordering::Relaxed);
+        self.persist_u32("sample_secs", secs);
+    }
+
+    /// Time between two samples aggregated into one publish, `None` when every publish
+    /// takes a single reading
+    pub fn sample_period(&self) -> Option<Duration> {
+        match self.sample_hz.load(Ordering::Relaxed) {
+            0 => None,
+            hz => Some(Duration::from_secs(1) / hz),
+        }
+    }
+
+    pub fn set_sample_hz(&self, hz: u32) {
+        self.sample_hz.store(hz, Ordering::Relaxed);
+        self.persist_u32("sample_hz", hz);
+    }
+
+    pub fn buzzer_on(&self) -> bool {
+        self.buzzer_on.load(Ordering::Relaxed)
+    }
+
+    /// Only mirrors the pin, so it isn't persisted; returns whether it changed
+    pub fn set_buzzer_on(&self, on: bool) -> bool {
+        self.buzzer_on.swap(on, Ordering::Relaxed) != on
+    }
+
+    pub fn gyro_enabled(&self) -> bool {
+        self.gyro_enabled.load(Ordering::Relaxed)
+    }
+
+    pub fn set_gyro_enabled(&self, enabled: bool) {
+        self.gyro_enabled.store(enabled, Ordering::Relaxed);
+        self.persist_u8("gyro", enabled as u8);
+    }
+
+    pub fn acc_enabled(&self) -> bool {
+        self.acc_enabled.load(Ordering::Relaxed)
+    }
+
+    pub fn set_acc_enabled(&self, enabled: bool) {
+        self.acc_enabled.store(enabled, Ordering::Relaxed);
+        self.persist_u8("acc", enabled as u8);
+    }
+
+    pub fn temp_enabled(&self) -> bool {
+        self.temp_enabled.load(Ordering::Relaxed)
+    }
+
+    pub fn set_temp_enabled(&self, enabled: bool) {
+        self.temp_enabled.store(enabled, Ordering::Relaxed);
+        self.persist_u8("temp", enabled as u8);
+    }
+
+    /// Accelerometer full-scale range in ±g
+    pub fn accel_range_g(&self) -> u8 {
+        self.accel_range_g.load(Ordering::Relaxed)
+    }
+
+    /// Gyro full-scale range in ±degrees per second
+    pub fn gyro_range_dps(&self) -> u16 {
+        self.gyro_range_dps.load(Ordering::Relaxed)
+    }
+
+    /// Only records the ranges; `imu::apply_ranges` programs them into the sensor
+    pub fn set_ranges(&self, accel_g: u8, gyro_dps: u16) {
+        self.accel_range_g.store(accel_g, Ordering::Relaxed);
+        self.gyro_range_dps.store(gyro_dps, Ordering::Relaxed);
+        self.persist_u8("accel_range", accel_g);
+
+        self.persist_u16("gyro_range", gyro_dps);
+    }
+
+    /// Gyro low-pass filter bandwidth in Hz
+    pub fn gyro_dlpf_hz(&self) -> u16 {
+        self.gyro_dlpf_hz.load(Ordering::Relaxed)
+    }
+
+    /// Accelerometer low-pass filter bandwidth in Hz
+    pub fn accel_dlpf_hz(&self) -> u16 {
+        self.accel_dlpf_hz.load(Ordering::Relaxed)
+    }
+
+    /// Programmed into the sensor by the publisher, see [`Self::take_imu_changed`]
+    pub fn set_dlpf(&self, gyro_hz: u16, accel_hz: u16) {
+        self.gyro_dlpf_hz.store(gyro_hz, Ordering::Relaxed);
+        self.accel_dlpf_hz.store(accel_hz, Ordering::Relaxed);
+        self.persist_u16("gyro_dlpf", gyro_hz);
+        self.persist_u16("accel_dlpf", accel_hz);
+        self.imu_changed.store(true, Ordering::Relaxed);
+    }
+
+    /// Accelerometer-only mode: gyro and temperature sensor off, accelerometer in cycle mode
+    pub fn low_power(&self) -> bool {
+        self.low_power.load(Ordering::Relaxed)
+    }
+
+    /// Accelerometer rate in low-power mode
+    pub fn low_power_hz(&self) -> u16 {
+        self.low_power_hz
+    }
+
+    /// Programmed into the sensor by the publisher, see [`Self::take_imu_changed`]
+    pub fn set_low_power(&self, on: bool) {
+        self.low_power.store(on, Ordering::Relaxed);
+        self.persist_u8("low_power", on as u8);
+        self.imu_changed.store(true, Ordering::Relaxed);
+    }
+
+    /// Returns `true` once after sensor settings changed from outside the publisher
+    pub fn take_imu_changed(&self) -> bool {
+        self.imu_changed.swap(false, Ordering::Relaxed)
+    }
+
+    pub fn qos(&self) -> &QosSettings {
+        &self.qos
+    }
+
+    pub fn set_qos(&self, kind: MessageKind, level: u8) {
+        self.qos.set_level(kind, level);
+
+        let key = match kind {
+            MessageKind::Telemetry => "qos_telemetry",
+            MessageKind::Alert => "qos_alert",
+            MessageKind::Status => "qos_status",
+        };
+        self.persist_u8(key, self.qos.level(kind));
+    }
+
+    /// Sensor offsets from the last calibration, zero if there was none
+    pub fn offsets(&self) -> Offsets {
+        *self.offsets.lock().unwrap()
+    }
+
+    /// Applied to every gyro and accelerometer reading after the offsets
+    pub fn axis_remap(&self) -> AxisRemap {
+        self.axis_remap
+    }
+
+    /// Units readings are published in
+    pub fn units(&self) -> Units {
+        self.units
+    }
+
+    pub fn set_offsets(&self, offsets: Offsets) {
+        *self.offsets.lock().unwrap() = offsets;
+
+        if let Err(e) = self
+            .nvs
+            .lock()
+            .unwrap()
+            .set_blob("calibration", &offsets.to_bytes())
+        {
+            warn!("Failed to persist calibration: {e}");
+        }
+    }
+
+    /// Gyro bias over temperature from the last temperature calibration
+    pub fn temp_model(&self) -> Option<TempModel> {
+        *self.temp_model.lock().unwrap()
+    }
+
+    pub fn set_temp_model(&self, model: Option<TempModel>) {
+        *self.temp_model.lock().unwrap() = model;
+
+        let nvs = self.nvs.lock().unwrap();
+        let res = match model {
+            Some(model) => nvs.set_blob("gyro_temp", &model.to_bytes()),
+            None => nvs.remove("gyro_temp").map(|_| ()),
+        };
+        if let Err(e) = res {
+            warn!("Failed to persist the gyro temperature model: {e}");
+        }
+    }
+
+    /// Publish intervals by battery charge, empty unless configured
+    pub fn battery_policy(&self) -> IntervalPolicy {
+        self.battery_policy.lock().unwrap().clone()
+    }
+
+    pub fn set_battery_policy(&self, policy: IntervalPolicy) {
+        if let Err(e) = self
+            .nvs
+            .lock()
+            .unwrap()
+            .set_blob("batt_policy", &policy.to_bytes())
+        {
+            warn!("Failed to persist the battery interval policy: {e}");
+        }
+
+        *self.battery_policy.lock().unwrap() = policy;
+    }
+
+    pub fn power_profile(&self) -> Option<PowerProfile> {
+        PowerProfile::from_u8(self.power_profile.load(Ordering::Relaxed))
+    }
+
+    /// Only records the profile; [`PowerProfile::apply`] switches to it
+    pub fn set_power_profile(&self, profile: PowerProfile) {
+        self.power_profile.store(profile.to_u8(), Ordering::Relaxed);
+        self.persist_u8("profile", profile.to_u8());
+    }
+
+    /// Modem power saving of the power profile, or `wifi_power_save` without one
+    pub fn wifi_power_save(&self) -> PowerSave {
+        self.power_profile()
+            .map_or(self.wifi_power_save, PowerProfile::wifi_power_save)
+    }
+
+    /// Only persisted, the buses are set up at boot from [`i2c_bus::BusConfig`]
+    pub fn set_i2c_bus(&self, secondary: bool, bus: &BusOverride) {
+        let [port, sda, scl, khz] = i2c_bus::nvs_keys(secondary);
+
+        if let Some(value) = bus.port {
+            self.persist_u8(port, value);
+        }
+        if let Some(value) = bus.sda_gpio {
+            self.persist_i32(sda, value);
+        }
+        if let Some(value) = bus.scl_gpio {
+            self.persist_i32(scl, value);
+        }
+        if let Some(value) = bus.baudrate_khz {
+            self.persist_u32(khz, value);
+        }
+    }
+
+    fn persist_i32(&self, key: &str, value: i32) {
+        if let Err(e) = self.nvs.lock().unwrap().set_i32(key, value) {
+            warn!("Failed to persist setting \"{key}\": {e}");
+        }
+    }
+
+    fn persist_u32(&self, key: &str, value: u32) {
+        if let Err(e) = self.nvs.lock().unwrap().set_u32(key, value) {
+            warn!("Failed to persist setting \"{key}\": {e}");
+        }
+    }
+
+    fn persist_u16(&self, key: &str, value: u16) {
+        if let Err(e) = self.nvs.lock().unwrap().set_u16(key, value) {
+            warn!("Failed to persist setting \"{key}\": {e}");
+        }
+    }
+
+    fn persist_u8(&self, key: &str, value: u8) {
+        if let Err(e) = self.nvs.lock().unwrap().set_u8(key, value) {
+            warn!("Failed to persist setting \"{key}\": {e}");
+        }
+    }
+}
@@ -0,0 +1,157 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use serde::{Deserialize, Serialize};
+
+use log::*;
+
+use crate::build_info::{BuildInfo, BUILD_INFO};
+use crate::power_policy::{IntervalPolicy, Step};
+use crate::settings::Settings;
+
+/// Fields of the shadow `desired` state the device knows how to apply
+#[derive(Deserialize, Default)]
+struct DesiredState {
+    publish_interval_secs: Option<u32>,
+    low_power_accel: Option<bool>,
+    /// `[{"min_pct": 50, "secs": 2}, {"min_pct": 0, "secs": 30}]`, empty to turn it off
+    battery_intervals: Option<Vec<Step>>,
+}
+
+/// Payload of `.../shadow/update/delta`
+#[derive(Deserialize)]
+struct DeltaDocument {
+    state: DesiredState,
+}
+
+/// Payload of `.../shadow/get/accepted`
+#[derive(Deserialize)]
+struct GetAcceptedDocument {
+    state: GetAcceptedState,
+}
+
+#[derive(Deserialize)]
+struct GetAcceptedState {
+    #[serde(default)]
+    delta: Option<DesiredState>,
+}
+
+#[derive(Serialize)]
+struct ReportedState {
+    publish_interval_secs: u32,
+    #[serde(flatten)]
+    build: BuildInfo,
+    buzzer_on: bool,
+    low_power_accel: bool,
+    battery_intervals: Vec<Step>,
+}
+
+#[derive(Serialize)]
+struct UpdateDocument {
+    state: UpdateState,
+}
+
+#[derive(Serialize)]
+struct UpdateState {
+    reported: ReportedState,
+}
+
+/// Classic (unnamed) AWS IoT Device Shadow of this thing
+pub struct Shadow {
+    update_topic: String,
+    delta_topic: String,
+    get_topic: String,
+    get_accepted_topic: String,
+    report_pending: AtomicBool,
+}
+
+impl Shadow {
+    pub fn new(thing_name: &str) -> Self {
+        let prefix = format!("$aws/things/{thing_name}/shadow");
+
+        Self {
+            update_topic: format!("{prefix}/update"),
+            delta_topic: format!("{prefix}/update/delta"),
+            get_topic: format!("{prefix}/get"),
+            get_accepted_topic: format!("{prefix}/get/accepted"),
+            report_pending: AtomicBool::new(true),
+        }
+    }
+
+    pub fn subscribe_topics(&self) -> [&str; 2] {
+        [&self.delta_topic, &self.get_accepted_topic]
+    }
+
+    pub fn update_topic(&self) -> &str {
+        &self.update_topic
+    }
+
+    /// Publishing an empty message here makes AWS IoT answer on `get/accepted`
+    pub fn get_topic(&self) -> &str {
+        &self.get_topic
+    }
+
+    /// Applies a delta received on one of the shadow topics; returns `false` for unrelated topics
+    pub fn handle_message(&self, topic: &str, data: &[u8], settings: &Settings) -> bool {
+        let desired = if topic == self.delta_topic {
+            serde_json::from_slice::<DeltaDocument>(data).map(|doc| Some(doc.state))
+        } else if topic == self.get_accepted_topic {
+            serde_json::from_slice::<GetAcceptedDocument>(data).map(|doc| doc.state.delta)
+        } else {
+            return false;
+        };
+
+        match desired {
+            Ok(Some(desired)) => self.apply(desired, settings),
+            Ok(None) => (),
+            Err(e) => warn!("Malformed shadow document on \"{topic}\": {e}"),
+        }
+
+        true
+    }
+
+    fn apply(&self, desired: DesiredState, settings: &Settings) {
+        if let Some(secs) = desired.publish_interval_secs {
+            info!("Shadow: publish interval set to {secs}s");
+            settings.set_publish_interval_secs(secs);
+        }
+
+        if let Some(on) = desired.low_power_accel {
+            info!("Shadow: low-power accelerometer mode = {on}");
+            settings.set_low_power(on);
+        }
+
+        if let Some(steps) = desired.battery_intervals {
+            let policy = IntervalPolicy::new(steps);
+            info!("Shadow: battery intervals set to {:?}", policy.steps());
+            settings.set_battery_policy(policy);
+        }
+
+        // Report back even if nothing changed, so the delta gets cleared
+        self.request_report();
+    }
+
+    pub fn request_report(&self) {
+        self.report_pending.store(true, Ordering::Relaxed);
+    }
+
+    /// The `reported` document to publish on the update topic, if anything changed since the last one
+    pub fn take_report(&self, settings: &Settings) -> Option<String> {
+        if !self.report_pending.swap(false, Ordering::Relaxed) {
+            return None;
+        }
+
+        let doc = UpdateDocument {
+            state: UpdateState {
+                reported: ReportedState {
+                    publish_interval_secs: settings.publish_interval_secs(),
+                    build: BUILD_INFO,
+                    buzzer_on: settings.buzzer_on(),
+                    low_power_accel: settings.low_power(),
+                    battery_intervals: settings.battery_policy().steps().to_vec(),
+                },
+            },
+        };
+
+        serde_json::to_string(&doc).ok()
+    }
+}
@@ -0,0 +1,150 @@
+use std::time::{Duration, Instant};
+
+use embedded_hal::i2c::I2c;
+use serde_json::json;
+
+use log::*;
+
+use crate::i2c_bus::SharedI2c;
+use crate::sensor::{Reading, Sensor};
+use crate::telemetry::Signal;
+use crate::units::Units;
+use crate::Config;
+
+/// Single shot, high repeatability, no clock stretching
+const MEASURE: [u8; 2] = [0x24, 0x00];
+const SOFT_RESET: [u8; 2] = [0x30, 0xa2];
+/// Measurement duration at high repeatability
+const MEASURE_TIME: Duration = Duration::from_millis(16);
+/// Readings closer together than this warm the sensor up, so in between the last one is kept
+const MIN_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Ambient temperature in °C and relative humidity in %
+#[derive(Clone, Copy, Debug)]
+pub struct Ambient {
+    pub temp: f32,
+    pub humidity: f32,
+}
+
+/// SHT30 of the M5Stack ENV unit on the Grove port, sharing the bus with the MPU6886
+pub struct Sht30 {
+    i2c: SharedI2c,
+    address: u8,
+    units: Units,
+    measured_at: Option<Instant>,
+}
+
+impl Sht30 {
+    /// `None` unless `sht30` is set and the unit answers, so boards without it still run
+    pub fn from_config(app_config: &Config, i2c: SharedI2c) -> Option<Self> {
+        if !app_config.sht30 {
+            return None;
+        }
+
+        let mut sht30 = Self {
+            i2c,
+            address: app_config.sht30_address,
+            units: Units::from_config(app_config),
+            measured_at: None,
+        };
+
+        match sht30.reset() {
+            Ok(()) => {
+                info!("SHT30 found at {:#04x}", sht30.address);
+                Some(sht30)
+            }
+            Err(e) => {
+                warn!(
+                    "No SHT30 at {:#04x}, continuing without it: {e}",
+                    sht30.address
+                );
+                None
+            }
+        }
+    }
+
+    fn reset(&mut self) -> Result<(), String> {
+        self.i2c
+            .write(self.address, &SOFT_RESET)
+            .map_err(|e| format!("{e:?}"))?;
+        std::thread::sleep(Duration::from_millis(2));
+
+        Ok(())
+    }
+
+    fn read(&mut self) -> Result<Ambient, String> {
+        self.i2c
+            .write(self.address, &MEASURE)
+            .map_err(|e| format!("{e:?}"))?;
+        std::thread::sleep(MEASURE_TIME);
+
+        let mut buf = [0; 6];
+        self.i2c
+            .read(self.address, &mut buf)
+            .map_err(|e| format!("{e:?}"))?;
+
+        let temp = word(&buf[0..3]).ok_or("temperature CRC mismatch")?;
+        let humidity = word(&buf[3..6]).ok_or("humidity CRC mismatch")?;
+
+        Ok(Ambient {
+            temp: -45.0 + 175.0 * temp as f32 / 65535.0,
+            humidity: 100.0 * humidity as f32 / 65535.0,
+        })
+    }
+}
+
+impl Sensor for Sht30 {
+    fn name(&self) -> &'static str {
+        "SHT30"
+    }
+
+    /// `ambient_temp` and `humidity`, unless the last reading is less than a second old
+    fn sample(&mut self) -> Result<Reading, String> {
+        if self
+            .measured_at
+            .is_some_and(|at| at.elapsed() < MIN_INTERVAL)
+        {
+            return Ok(Vec::new());
+        }
+        self.measured_at = Some(Instant::now());
+
+        let ambient = self.read()?;
+        let temp = self.units.temp(ambient.temp);
+
+        Ok(vec![
+            Signal {
+                field: "ambient_temp",
+                kind: "ambient_temp",
+                value: json!(temp),
+                range: None,
+                axes: vec![temp],
+                unit: Some(self.units.temp_unit()),
+            },
+            Signal {
+                field: "humidity",
+                kind: "humidity",
+                value: json!(ambient.humidity),
+                range: None,
+                axes: vec![ambient.humidity],
+                unit: Some("%"),
+            },
+        ])
+    }
+}
+
+/// Big-endian word followed by its CRC-8 (polynomial 0x31, initial value 0xff)
+fn word(bytes: &[u8]) -> Option<u16> {
+    let mut crc = 0xffu8;
+    for byte in &bytes[..2] {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x31
+            } else {
+                crc << 1
+            };
+        }
+    }
+
+    (crc == bytes[2]).then(|| u16::from_be_bytes([bytes[0], bytes[1]]))
+}
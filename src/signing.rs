@@ -0,0 +1,126 @@
+use esp_idf_svc::nvs::{EspDefaultNvs, EspDefaultNvsPartition};
+use esp_idf_svc::sys::{
+    mbedtls_md_hmac, mbedtls_md_info_from_type, mbedtls_md_type_t_MBEDTLS_MD_SHA256, EspError,
+};
+use serde::Serialize;
+
+use log::*;
+
+use crate::clock;
+use crate::Config;
+
+pub const NVS_NAMESPACE: &str = "signing";
+const NVS_KEY: &str = "hmac_key";
+const MAX_KEY_LEN: usize = 64;
+
+/// Messages parsed by something other than our backend are left alone: the AWS IoT services,
+/// e.g. the shadow, and Sparkplug host applications, which expect the protobuf payload
+const UNSIGNED_PREFIXES: [&str; 2] = ["$aws/things/", "spBv1.0/"];
+
+/// A message with its signature. Text payloads are kept as a string, so the backend gets the
+/// signed bytes back exactly; binary ones, e.g. CBOR or MessagePack telemetry, go in hex, so the
+/// backend has to decode them from the JSON.
+#[derive(Serialize)]
+struct Signed<'a> {
+    ts: u64,
+    sig: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    payload: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    payload_hex: Option<String>,
+}
+
+/// Signs every message with HMAC-SHA256, so the backend can check them even after they passed
+/// a broker without TLS. The signature covers the payload followed by `ts` as 8 bytes big-endian.
+pub struct Signer {
+    key: Vec<u8>,
+}
+
+impl Signer {
+    /// `None` unless `payload_signing` is on and a key is stored in NVS. A key given as hex in
+    /// `payload_signing_key` is stored if there is none yet, so it only needs to be in `cfg.toml`
+    /// for the first flash.
+    pub fn from_config(
+        app_config: &Config,
+        partition: EspDefaultNvsPartition,
+    ) -> Result<Option<Self>, EspError> {
+        if !app_config.payload_signing {
+            return Ok(None);
+        }
+
+        let nvs = EspDefaultNvs::new(partition, NVS_NAMESPACE, true)?;
+
+        let mut buf = [0; MAX_KEY_LEN];
+        if let Some(key) = nvs.get_blob(NVS_KEY, &mut buf)? {
+            return Ok(Some(Self { key: key.to_vec() }));
+        }
+
+        match parse_hex(app_config.payload_signing_key) {
+            Some(key) if !key.is_empty() && key.len() <= MAX_KEY_LEN => {
+                nvs.set_blob(NVS_KEY, &key)?;
+                info!("Stored the payload signing key");
+                Ok(Some(Self { key }))
+            }
+            _ => {
+                warn!("Payload signing is on, but no key is stored; publishing unsigned");
+                Ok(None)
+            }
+        }
+    }
+
+    /// `{"ts": .., "sig": "<hex>", "payload": "<original>"}`, or the payload as is on topics
+    /// the AWS services or Sparkplug hosts read
+    pub fn sign(&self, topic: &str, payload: &[u8]) -> Vec<u8> {
+        if UNSIGNED_PREFIXES
+            .iter()
+            .any(|prefix| topic.starts_with(prefix))
+        {
+            return payload.to_vec();
+        }
+
+        let ts = clock::epoch_ms().unwrap_or(0);
+
+        let mut signed = payload.to_vec();
+        signed.extend_from_slice(&ts.to_be_bytes());
+        let sig = hex(&self.hmac(&signed));
+
+        let text = core::str::from_utf8(payload).ok();
+        serde_json::to_vec(&Signed {
+            ts,
+            sig,
+            payload: text,
+            payload_hex: text.is_none().then(|| hex(payload)),
+        })
+        .unwrap()
+    }
+
+    fn hmac(&self, data: &[u8]) -> [u8; 32] {
+        let mut mac = [0; 32];
+        unsafe {
+            mbedtls_md_hmac(
+                mbedtls_md_info_from_type(mbedtls_md_type_t_MBEDTLS_MD_SHA256),
+                self.key.as_ptr(),
+                self.key.len(),
+                data.as_ptr(),
+                data.len(),
+                mac.as_mut_ptr(),
+            );
+        }
+        mac
+    }
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+pub fn parse_hex(text: &str) -> Option<Vec<u8>> {
+    if text.len() % 2 != 0 {
+        return None;
+    }
+
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
@@ -0,0 +1,112 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::clock::Stamp;
+use crate::telemetry::Signal;
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Entry {
+    entry_id: String,
+    property_alias: String,
+    property_values: Vec<PropertyValue>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PropertyValue {
+    value: Variant,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timestamp: Option<Timestamp>,
+    quality: String,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+enum Variant {
+    DoubleValue(f64),
+    StringValue(String),
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Timestamp {
+    time_in_seconds: u64,
+    offset_in_nanos: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Entries {
+    entries: Vec<Entry>,
+}
+
+/// One round of readings as `BatchPutAssetPropertyValue` entries, one per property:
+/// `{"entries": [{"entryId": "1", "propertyAlias": "/m5stack/gyro_x", "propertyValues":
+/// [{"value": {"doubleValue": 0.01}, "timestamp": {"timeInSeconds": .., "offsetInNanos": ..},
+/// "quality": "GOOD"}]}, ..]}`. The timestamp is left out while the clock isn't set, for the
+/// rule to fill in.
+pub fn entries(alias_prefix: &str, stamp: Stamp, signals: &[Signal]) -> Vec<u8> {
+    let timestamp = stamp.ts.map(|ts| Timestamp {
+        time_in_seconds: ts / 1000,
+        offset_in_nanos: ts % 1000 * 1_000_000,
+    });
+
+    let mut properties = Vec::new();
+    for signal in signals {
+        if let Value::String(text) = &signal.value {
+            properties.push((signal.field.to_string(), Variant::StringValue(text.clone())));
+            continue;
+        }
+
+        let names = signal.axis_names();
+        for (i, value) in signal.axes.iter().enumerate() {
+            let property = match (signal.axes.len(), names.get(i)) {
+                (1, _) => signal.field.to_string(),
+                (_, Some(name)) => format!("{}_{name}", signal.field),
+                (_, None) => format!("{}_{i}", signal.field),
+            };
+            properties.push((property, Variant::DoubleValue(*value as f64)));
+        }
+    }
+
+    let entries = properties
+        .into_iter()
+        .enumerate()
+        .map(|(i, (property, value))| Entry {
+            entry_id: (i + 1).to_string(),
+            property_alias: format!("{alias_prefix}/{property}"),
+            property_values: vec![PropertyValue {
+                value,
+                timestamp,
+                quality: "GOOD".to_string(),
+            }],
+        })
+        .collect();
+
+    serde_json::to_vec(&Entries { entries }).unwrap()
+}
+
+/// Several rounds as one message, with the values of each property gathered in its entry
+pub fn merge(samples: &[Vec<u8>]) -> Vec<u8> {
+    let mut merged: Vec<Entry> = Vec::new();
+
+    for sample in samples {
+        let Ok(sample) = serde_json::from_slice::<Entries>(sample) else {
+            continue;
+        };
+
+        for entry in sample.entries {
+            match merged
+                .iter_mut()
+                .find(|merged| merged.property_alias == entry.property_alias)
+            {
+                Some(merged) => merged.property_values.extend(entry.property_values),
+                None => merged.push(Entry {
+                    entry_id: (merged.len() + 1).to_string(),
+                    ..entry
+                }),
+            }
+        }
+    }
+
+    serde_json::to_vec(&Entries { entries: merged }).unwrap()
+}
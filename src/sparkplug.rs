@@ -0,0 +1,313 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use prost::Message;
+use serde_json::Value;
+
+use log::*;
+
+use crate::clock::{self, Stamp};
+use crate::telemetry::Signal;
+use crate::{thing_name, Config};
+
+const NAMESPACE: &str = "spBv1.0";
+const BD_SEQ: &str = "bdSeq";
+const REBIRTH: &str = "Node Control/Rebirth";
+
+/// Sparkplug B data types used here
+const UINT32: u32 = 7;
+const UINT64: u32 = 8;
+const FLOAT: u32 = 9;
+const BOOLEAN: u32 = 11;
+const STRING: u32 = 12;
+
+/// `org.eclipse.tahu.protobuf.Payload`, the fields used here
+#[derive(Clone, PartialEq, Message)]
+struct Payload {
+    #[prost(uint64, optional, tag = "1")]
+    timestamp: Option<u64>,
+    #[prost(message, repeated, tag = "2")]
+    metrics: Vec<Metric>,
+    #[prost(uint64, optional, tag = "3")]
+    seq: Option<u64>,
+}
+
+/// `org.eclipse.tahu.protobuf.Payload.Metric`, the fields used here
+#[derive(Clone, PartialEq, Message)]
+struct Metric {
+    #[prost(string, optional, tag = "1")]
+    name: Option<String>,
+    #[prost(uint64, optional, tag = "2")]
+    alias: Option<u64>,
+    #[prost(uint32, optional, tag = "4")]
+    datatype: Option<u32>,
+    #[prost(bool, optional, tag = "7")]
+    is_null: Option<bool>,
+    #[prost(oneof = "MetricValue", tags = "10, 11, 12, 14, 15")]
+    value: Option<MetricValue>,
+}
+
+#[derive(Clone, PartialEq, prost::Oneof)]
+enum MetricValue {
+    #[prost(uint32, tag = "10")]
+    Int(u32),
+    #[prost(uint64, tag = "11")]
+    Long(u64),
+    #[prost(float, tag = "12")]
+    Float(f32),
+    #[prost(bool, tag = "14")]
+    Boolean(bool),
+    #[prost(string, tag = "15")]
+    String(String),
+}
+
+impl MetricValue {
+    fn datatype(&self) -> u32 {
+        match self {
+            Self::Int(_) => UINT32,
+            Self::Long(_) => UINT64,
+            Self::Float(_) => FLOAT,
+            Self::Boolean(_) => BOOLEAN,
+            Self::String(_) => STRING,
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    /// Birth/death sequence of the current session, carried by NBIRTH and the NDEATH will
+    bd_seq: u64,
+    /// Message sequence, 0 in NBIRTH and wrapping after 255
+    seq: u64,
+    /// Name and data type of the metrics in the order they were announced; the alias of each
+    /// is its index + 1
+    metrics: Vec<(String, u32)>,
+    device_born: bool,
+}
+
+impl State {
+    fn next_seq(&mut self) -> u64 {
+        self.seq = (self.seq + 1) % 256;
+        self.seq
+    }
+
+    fn alias(&self, name: &str) -> Option<u64> {
+        self.metrics
+            .iter()
+            .position(|(metric, _)| metric == name)
+            .map(|i| i as u64 + 1)
+    }
+}
+
+/// Sparkplug B edge node with a single device carrying the IMU and sensor metrics, for SCADA
+/// hosts such as Ignition. The NDEATH goes in as the last will; once connected the node is
+/// born, the device follows with its first readings and after that only DDATA with metric
+/// aliases goes out.
+pub struct Sparkplug {
+    group_id: &'static str,
+    edge_node_id: &'static str,
+    device_id: &'static str,
+    state: Mutex<State>,
+    /// Set by a rebirth request on NCMD
+    rebirth: AtomicBool,
+}
+
+impl Sparkplug {
+    /// `None` unless `sparkplug_group_id` is set
+    pub fn from_config(app_config: &Config) -> Option<Self> {
+        if app_config.sparkplug_group_id.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            group_id: app_config.sparkplug_group_id,
+            edge_node_id: thing_name(app_config),
+            device_id: app_config.sparkplug_device_id,
+            state: Mutex::new(State::default()),
+            rebirth: AtomicBool::new(false),
+        })
+    }
+
+    fn node_topic(&self, message_type: &str) -> String {
+        format!(
+            "{NAMESPACE}/{}/{message_type}/{}",
+            self.group_id, self.edge_node_id
+        )
+    }
+
+    fn device_topic(&self, message_type: &str) -> String {
+        format!("{}/{}", self.node_topic(message_type), self.device_id)
+    }
+
+    /// Node commands, subscribed to for rebirth requests
+    pub fn command_topic(&self) -> String {
+        self.node_topic("NCMD")
+    }
+
+    /// Topic and payload of the NDEATH to register as the last will of a new session
+    pub fn will(&self) -> (String, Vec<u8>) {
+        let mut state = self.state.lock().unwrap();
+        state.bd_seq = (state.bd_seq + 1) % 256;
+
+        let payload = Payload {
+            timestamp: clock::epoch_ms(),
+            metrics: vec![bd_seq_metric(state.bd_seq)],
+            seq: None,
+        };
+
+        (self.node_topic("NDEATH"), payload.encode_to_vec())
+    }
+
+    /// The NBIRTH that starts every session; the device is born again with the next readings
+    pub fn node_birth(&self) -> (String, Vec<u8>) {
+        let mut state = self.state.lock().unwrap();
+        state.seq = 0;
+        state.device_born = false;
+
+        let payload = Payload {
+            timestamp: clock::epoch_ms(),
+            metrics: vec![
+                bd_seq_metric(state.bd_seq),
+                Metric {
+                    name: Some(REBIRTH.to_string()),
+                    datatype: Some(BOOLEAN),
+                    value: Some(MetricValue::Boolean(false)),
+                    ..Default::default()
+                },
+            ],
+            seq: Some(0),
+        };
+
+        (self.node_topic("NBIRTH"), payload.encode_to_vec())
+    }
+
+    /// DDATA for one round of readings, preceded by an NBIRTH when a rebirth was requested and
+    /// by a DBIRTH when the device isn't born yet or a metric showed up it wasn't born with
+    pub fn data(&self, signals: &[Signal]) -> Vec<(String, Vec<u8>)> {
+        let mut messages = Vec::new();
+
+        if self.rebirth.swap(false, Ordering::Relaxed) {
+            info!("Sparkplug rebirth requested");
+            messages.push(self.node_birth());
+        }
+
+        let stamp = Stamp::now();
+        let values = metric_values(signals);
+        let mut state = self.state.lock().unwrap();
+
+        for (name, value) in &values {
+            if state.alias(name).is_none() {
+                state.metrics.push((name.clone(), value.datatype()));
+                state.device_born = false;
+            }
+        }
+
+        if !state.device_born {
+            state.device_born = true;
+
+            // Metrics without a value in this round are born as null
+            let metrics = state
+                .metrics
+                .iter()
+                .enumerate()
+                .map(|(i, (name, datatype))| {
+                    let value = values
+                        .iter()
+                        .find(|(value_name, _)| value_name == name)
+                        .map(|(_, value)| value.clone());
+                    Metric {
+                        name: Some(name.clone()),
+                        alias: Some(i as u64 + 1),
+                        datatype: Some(*datatype),
+                        is_null: value.is_none().then_some(true),
+                        value,
+                    }
+                })
+                .collect();
+
+            let payload = Payload {
+                timestamp: stamp.ts,
+                metrics,
+                seq: Some(state.next_seq()),
+            };
+            messages.push((self.device_topic("DBIRTH"), payload.encode_to_vec()));
+        }
+
+        let metrics = values
+            .into_iter()
+            .map(|(name, value)| Metric {
+                alias: state.alias(&name),
+                value: Some(value),
+                ..Default::default()
+            })
+            .collect();
+
+        let payload = Payload {
+            timestamp: stamp.ts,
+            metrics,
+            seq: Some(state.next_seq()),
+        };
+        messages.push((self.device_topic("DDATA"), payload.encode_to_vec()));
+
+        messages
+    }
+
+    /// Called from the connection loop; returns `true` if the message was an NCMD
+    pub fn handle_message(&self, topic: &str, data: &[u8]) -> bool {
+        if topic != self.command_topic() {
+            return false;
+        }
+
+        match Payload::decode(data) {
+            Ok(payload) => {
+                let rebirth = payload.metrics.iter().any(|metric| {
+                    metric.name.as_deref() == Some(REBIRTH)
+                        && metric.value == Some(MetricValue::Boolean(true))
+                });
+                if rebirth {
+                    self.rebirth.store(true, Ordering::Relaxed);
+                }
+            }
+            Err(e) => warn!("Invalid Sparkplug command: {e}"),
+        }
+
+        true
+    }
+}
+
+fn bd_seq_metric(bd_seq: u64) -> Metric {
+    Metric {
+        name: Some(BD_SEQ.to_string()),
+        datatype: Some(UINT64),
+        value: Some(MetricValue::Long(bd_seq)),
+        ..Default::default()
+    }
+}
+
+/// Every signal as named metrics: one per axis, e.g. `acc/x`, and one for its range
+fn metric_values(signals: &[Signal]) -> Vec<(String, MetricValue)> {
+    let mut values = Vec::new();
+
+    for signal in signals {
+        if let Value::String(text) = &signal.value {
+            values.push((signal.field.to_string(), MetricValue::String(text.clone())));
+            continue;
+        }
+
+        let names = signal.axis_names();
+        for (i, value) in signal.axes.iter().enumerate() {
+            let name = match (signal.axes.len(), names.get(i)) {
+                (1, _) => signal.field.to_string(),
+                (_, Some(name)) => format!("{}/{name}", signal.field),
+                (_, None) => format!("{}/{i}", signal.field),
+            };
+            values.push((name, MetricValue::Float(*value)));
+        }
+
+        if let Some((name, range)) = signal.range {
+            values.push((name.to_string(), MetricValue::Int(range as u32)));
+        }
+    }
+
+    values
+}
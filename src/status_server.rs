@@ -0,0 +1,193 @@
+use std::sync::{Arc, Mutex};
+
+use esp_idf_svc::http::server::{Configuration as HttpConfiguration, EspHttpServer};
+use esp_idf_svc::http::Method;
+use esp_idf_svc::io::{EspIOError, Write};
+use esp_idf_svc::sys::EspError;
+use serde::Serialize;
+use serde_json::Value;
+
+use log::*;
+
+use crate::build_info::{BuildInfo, BUILD_INFO};
+use crate::clock;
+use crate::diagnostics::DiagnosticsState;
+use crate::memory::{self, MemoryReport};
+use crate::mqtt::MessageKind;
+use crate::net_stats::NetStats;
+use crate::power_policy::Step;
+use crate::power_profile::PowerProfile;
+use crate::settings::Settings;
+use crate::wifi;
+use crate::Config;
+
+/// The diagnostics AP's server keeps the default one
+const CTRL_PORT: u16 = 32769;
+
+/// `GET /status`
+#[derive(Serialize)]
+struct Status {
+    #[serde(flatten)]
+    build: BuildInfo,
+    uptime_secs: u64,
+    wifi: WifiStatus,
+    mqtt: MqttStatus,
+    /// The latest signals as published, `null` before the first sample
+    reading: Option<Value>,
+    memory: MemoryReport,
+}
+
+#[derive(Serialize)]
+struct WifiStatus {
+    connected: bool,
+    rssi: Option<i8>,
+}
+
+#[derive(Serialize)]
+struct MqttStatus {
+    connected: bool,
+    offline_secs: u64,
+    last_error: Option<String>,
+}
+
+/// `GET /config`, the settings in effect including what the config topic and the shadow changed
+#[derive(Serialize)]
+struct ConfigReport {
+    publish_interval_secs: u32,
+    sample_interval_secs: u64,
+    sample_hz: Option<f32>,
+    gyro: bool,
+    acc: bool,
+    temp: bool,
+    accel_range_g: u8,
+    gyro_range_dps: u16,
+    gyro_dlpf_hz: u16,
+    accel_dlpf_hz: u16,
+    low_power_accel: bool,
+    qos: QosReport,
+    power_profile: Option<&'static str>,
+    wifi_power_save: &'static str,
+    battery_intervals: Vec<Step>,
+}
+
+#[derive(Serialize)]
+struct QosReport {
+    telemetry: u8,
+    alert: u8,
+    status: u8,
+}
+
+fn status(diagnostics: &Mutex<DiagnosticsState>) -> Status {
+    let diagnostics = diagnostics.lock().unwrap();
+    let rssi = wifi::sta_rssi();
+
+    Status {
+        build: BUILD_INFO,
+        uptime_secs: clock::uptime_us() / 1_000_000,
+        wifi: WifiStatus {
+            connected: rssi.is_some(),
+            rssi,
+        },
+        mqtt: MqttStatus {
+            connected: diagnostics.mqtt_connected,
+            offline_secs: diagnostics.offline_for().as_secs(),
+            last_error: diagnostics.last_error.clone(),
+        },
+        reading: diagnostics
+            .last_reading
+            .as_deref()
+            .and_then(|reading| serde_json::from_str(reading).ok()),
+        memory: memory::report(),
+    }
+}
+
+fn config(settings: &Settings) -> ConfigReport {
+    let qos = settings.qos();
+
+    ConfigReport {
+        publish_interval_secs: settings.publish_interval_secs(),
+        sample_interval_secs: settings.sample_interval().as_secs(),
+        sample_hz: settings
+            .sample_period()
+            .map(|period| 1.0 / period.as_secs_f32()),
+        gyro: settings.gyro_enabled(),
+        acc: settings.acc_enabled(),
+        temp: settings.temp_enabled(),
+        accel_range_g: settings.accel_range_g(),
+        gyro_range_dps: settings.gyro_range_dps(),
+        gyro_dlpf_hz: settings.gyro_dlpf_hz(),
+        accel_dlpf_hz: settings.accel_dlpf_hz(),
+        low_power_accel: settings.low_power(),
+        qos: QosReport {
+            telemetry: qos.level(MessageKind::Telemetry),
+            alert: qos.level(MessageKind::Alert),
+            status: qos.level(MessageKind::Status),
+        },
+        power_profile: settings.power_profile().map(PowerProfile::as_str),
+        wifi_power_save: settings.wifi_power_save().as_str(),
+        battery_intervals: settings.battery_policy().steps().to_vec(),
+    }
+}
+
+/// Serves `/status`, `/config` and `/metrics` as JSON on the LAN, reachable on the station's
+/// address, so a device can be checked with a browser. Everything is read-only. None unless
+/// `http_port` is set.
+pub fn start(
+    app_config: &Config,
+    stats: Arc<NetStats>,
+    diagnostics: Arc<Mutex<DiagnosticsState>>,
+    settings: Arc<Settings>,
+) -> Result<Option<EspHttpServer<'static>>, EspError> {
+    if app_config.http_port == 0 {
+        return Ok(None);
+    }
+
+    let mut server = EspHttpServer::new(&HttpConfiguration {
+        http_port: app_config.http_port,
+        ctrl_port: CTRL_PORT,
+        ..Default::default()
+    })
+    .map_err(|e| e.0)?;
+
+    server.fn_handler(
+        "/status",
+        Method::Get,
+        move |req| -> Result<(), EspIOError> {
+            let body = serde_json::to_string(&status(&diagnostics)).unwrap();
+
+            let mut resp = req.into_response(200, None, &[("Content-Type", "application/json")])?;
+            resp.write_all(body.as_bytes())?;
+
+            Ok(())
+        },
+    )?;
+
+    server.fn_handler(
+        "/config",
+        Method::Get,
+        move |req| -> Result<(), EspIOError> {
+            let body = serde_json::to_string(&config(&settings)).unwrap();
+
+            let mut resp = req.into_response(200, None, &[("Content-Type", "application/json")])?;
+            resp.write_all(body.as_bytes())?;
+
+            Ok(())
+        },
+    )?;
+
+    server.fn_handler(
+        "/metrics",
+        Method::Get,
+        move |req| -> Result<(), EspIOError> {
+            let report = stats.to_json();
+
+            let mut resp = req.into_response(200, None, &[("Content-Type", "application/json")])?;
+            resp.write_all(report.as_bytes())?;
+
+            Ok(())
+        },
+    )?;
+    info!("Status served on port {}", app_config.http_port);
+
+    Ok(Some(server))
+}
@@ -0,0 +1,459 @@
+use std::time::{Duration, Instant};
+
+use serde::ser::SerializeMap;
+use serde::{Serialize, Serializer};
+use serde_json::{json, Value};
+
+use log::*;
+
+use crate::clock::Stamp;
+use crate::encoding::TelemetryEncoder;
+use crate::envelope::Envelope;
+use crate::imu::{self, Imu};
+use crate::sensor::Registry;
+use crate::sequence::Sequence;
+use crate::settings::Settings;
+use crate::topics::{Topics, TELEMETRY_IMU};
+use crate::Config;
+
+/// One reading of one sensor signal
+pub struct Signal {
+    /// Field name in the JSON payload
+    pub field: &'static str,
+    /// `{kind}` of its own telemetry topic when signals are published separately
+    pub kind: &'static str,
+    /// JSON value of the reading
+    pub value: Value,
+    /// Name and value of the full-scale range the reading was taken with
+    pub range: Option<(&'static str, u16)>,
+    /// The reading as numbers, one per axis
+    pub axes: Vec<f32>,
+    /// Unit of the numbers, listed in the payload's `units`
+    pub unit: Option<&'static str>,
+}
+
+impl Signal {
+    /// What the axes stand for, to name them where a format has no arrays
+    pub fn axis_names(&self) -> &'static [&'static str] {
+        match self.field {
+            "orientation" => &["roll", "pitch", "yaw"],
+            "angles" => &["roll", "pitch"],
+            _ => &["x", "y", "z"],
+        }
+    }
+}
+
+/// Reads every enabled signal once and logs it at debug level
+pub fn read(mpu: &mut Imu, sensors: &Registry, settings: &Settings) -> Vec<Signal> {
+    let signals = sample(mpu, sensors, settings);
+    for signal in &signals {
+        debug!("{}: {}", signal.field, signal.value);
+    }
+
+    signals
+}
+
+/// Reads every enabled signal once; a signal the sensor fails to deliver is left out.
+/// The other sensors only contribute when their next reading is due.
+pub fn sample(mpu: &mut Imu, sensors: &Registry, settings: &Settings) -> Vec<Signal> {
+    // Changes from the config topic are programmed in here, where the sensor is at hand
+    if settings.take_imu_changed() {
+        if let Err(e) = imu::configure(mpu, settings) {
+            warn!("Failed to configure the sensor: {e}");
+        }
+    }
+
+    let mut signals = Vec::new();
+    let units = settings.units();
+
+    // Low-power mode leaves only the accelerometer running
+    let low_power = settings.low_power();
+
+    if settings.gyro_enabled() && !low_power {
+        match imu::gyro(mpu, settings) {
+            Ok(gyro) => {
+                let gyro = gyro.map(|rate| rate * units.gyro_factor());
+                signals.push(Signal {
+                    field: "gyro",
+                    kind: "gyro",
+                    value: json!(gyro),
+                    range: Some(("gyro_range_dps", settings.gyro_range_dps())),
+                    axes: gyro.to_vec(),
+                    unit: Some(units.gyro_unit()),
+                });
+            }
+            Err(e) => warn!("Failed to read the gyro: {e:?}"),
+        }
+    }
+
+    if settings.acc_enabled() {
+        match imu::acc(mpu, settings) {
+            Ok(acc) => {
+                let acc = acc.map(|g| g * units.acc_factor());
+                signals.push(Signal {
+                    field: "acc",
+                    kind: "accel",
+                    value: json!(acc),
+                    range: Some(("acc_range_g", settings.accel_range_g() as u16)),
+                    axes: acc.to_vec(),
+                    unit: Some(units.acc_unit()),
+                });
+            }
+            Err(e) => warn!("Failed to read the accelerometer: {e:?}"),
+        }
+    }
+
+    if settings.temp_enabled() && !low_power {
+        match mpu.get_temp() {
+            Ok(temp) => {
+                let temp = units.temp(temp);
+                signals.push(Signal {
+                    field: "temp",
+                    kind: "temp",
+                    value: json!(temp),
+                    range: None,
+                    axes: vec![temp],
+                    unit: Some(units.temp_unit()),
+                });
+            }
+            Err(e) => warn!("Failed to read the temperature: {e:?}"),
+        }
+    }
+
+    signals.extend(sensors.sample());
+
+    signals
+}
+
+/// Running statistics of one signal within a window
+struct SignalStats {
+    field: &'static str,
+    kind: &'static str,
+    range: Option<(&'static str, u16)>,
+    unit: Option<&'static str>,
+    count: u32,
+    sum: Vec<f32>,
+    sum_sq: Vec<f32>,
+    min: Vec<f32>,
+    max: Vec<f32>,
+    /// Every sample, only kept when raw data is published too
+    raw: Vec<Vec<f32>>,
+}
+
+/// Value of an aggregated signal: `{"mean": [..], "min": [..], "max": [..], "n": 100}`
+#[derive(Default, Serialize)]
+struct Summary {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mean: Option<Vec<f32>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    min: Option<Vec<f32>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max: Option<Vec<f32>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stddev: Option<Vec<f32>>,
+    n: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    samples: Option<Vec<Vec<f32>>>,
+}
+
+impl SignalStats {
+    fn into_signal(self, stats: &[Stat], include_raw: bool) -> Signal {
+        let n = self.count as f32;
+        let mean: Vec<_> = self.sum.iter().map(|sum| sum / n).collect();
+
+        let mut summary = Summary {
+            n: self.count,
+            samples: include_raw.then_some(self.raw),
+            ..Default::default()
+        };
+        for stat in stats {
+            match stat {
+                Stat::Mean => summary.mean = Some(mean.clone()),
+                Stat::Min => summary.min = Some(self.min.clone()),
+                Stat::Max => summary.max = Some(self.max.clone()),
+                Stat::Stddev => {
+                    summary.stddev = Some(
+                        self.sum_sq
+                            .iter()
+                            .zip(&mean)
+                            .map(|(sum_sq, mean)| (sum_sq / n - mean * mean).max(0.0).sqrt())
+                            .collect(),
+                    )
+                }
+            }
+        }
+
+        Signal {
+            field: self.field,
+            kind: self.kind,
+            value: json!(summary),
+            range: self.range,
+            axes: mean,
+            unit: self.unit,
+        }
+    }
+}
+
+/// Statistics an aggregated signal can be published with
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Stat {
+    Mean,
+    Min,
+    Max,
+    Stddev,
+}
+
+impl Stat {
+    fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "mean" => Self::Mean,
+            "min" => Self::Min,
+            "max" => Self::Max,
+            "stddev" => Self::Stddev,
+            _ => return None,
+        })
+    }
+}
+
+/// Collects readings taken at `sample_hz` and sums them up per signal, either per publish
+/// interval or in fixed windows of their own
+pub struct Aggregator {
+    stats: Vec<Stat>,
+    include_raw: bool,
+    /// `None` closes the window at every publish
+    window: Option<Duration>,
+    opened_at: Option<Instant>,
+    current: Vec<SignalStats>,
+    closed: Vec<Vec<Signal>>,
+}
+
+impl Aggregator {
+    pub fn from_config(app_config: &Config) -> Self {
+        let mut stats: Vec<_> = app_config
+            .aggregate_stats
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .filter_map(|name| {
+                let stat = Stat::from_name(name);
+                if stat.is_none() {
+                    warn!("Unknown statistic \"{name}\"");
+                }
+                stat
+            })
+            .collect();
+        if stats.is_empty() {
+            stats.push(Stat::Mean);
+        }
+
+        Self {
+            stats,
+            include_raw: app_config.aggregate_include_raw,
+            window: (app_config.aggregate_window_secs > 0)
+                .then(|| Duration::from_secs(app_config.aggregate_window_secs)),
+            opened_at: None,
+            current: Vec::new(),
+            closed: Vec::new(),
+        }
+    }
+
+    /// Whether windows have a length of their own instead of following the publish interval
+    pub fn is_windowed(&self) -> bool {
+        self.window.is_some()
+    }
+
+    pub fn push(&mut self, signals: Vec<Signal>) {
+        let now = Instant::now();
+        if let (Some(window), Some(opened_at)) = (self.window, self.opened_at) {
+            if now - opened_at >= window {
+                self.close();
+            }
+        }
+        self.opened_at.get_or_insert(now);
+
+        for signal in signals {
+            let raw = if self.include_raw {
+                vec![signal.axes.clone()]
+            } else {
+                Vec::new()
+            };
+
+            let Some(stats) = self
+                .current
+                .iter_mut()
+                .find(|stats| stats.field == signal.field)
+            else {
+                self.current.push(SignalStats {
+                    field: signal.field,
+                    kind: signal.kind,
+                    range: signal.range,
+                    unit: signal.unit,
+                    count: 1,
+                    sum: signal.axes.clone(),
+                    sum_sq: signal.axes.iter().map(|value| value * value).collect(),
+                    min: signal.axes.clone(),
+                    max: signal.axes,
+                    raw,
+                });
+                continue;
+            };
+
+            stats.count += 1;
+            stats.range = signal.range;
+            stats.raw.extend(raw);
+            for (i, value) in signal.axes.into_iter().enumerate() {
+                stats.sum[i] += value;
+                stats.sum_sq[i] += value * value;
+                stats.min[i] = stats.min[i].min(value);
+                stats.max[i] = stats.max[i].max(value);
+            }
+        }
+    }
+
+    fn close(&mut self) {
+        self.opened_at = None;
+        if self.current.is_empty() {
+            return;
+        }
+
+        let signals = self
+            .current
+            .drain(..)
+            .map(|stats| stats.into_signal(&self.stats, self.include_raw))
+            .collect();
+        self.closed.push(signals);
+    }
+
+    /// One round of signals per window closed since the last call, with e.g.
+    /// `{"mean": [..], "min": [..], "max": [..], "n": 100}` as their values. Without a window
+    /// length of its own, the current window is closed and returned.
+    pub fn take(&mut self) -> Vec<Vec<Signal>> {
+        if self.window.is_none() {
+            self.close();
+        }
+
+        std::mem::take(&mut self.closed)
+    }
+}
+
+/// The signals as fields of one JSON object, each followed by its range if it has one
+struct Fields<'a>(&'a [Signal]);
+
+impl Serialize for Fields<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(None)?;
+        for signal in self.0 {
+            map.serialize_entry(signal.field, &signal.value)?;
+            if let Some((name, range)) = signal.range {
+                map.serialize_entry(name, &range)?;
+            }
+        }
+        map.end()
+    }
+}
+
+/// Units of the signals that have one, as a JSON object keyed by field
+struct UnitMap<'a>(&'a [Signal]);
+
+impl UnitMap<'_> {
+    fn is_empty(&self) -> bool {
+        self.0.iter().all(|signal| signal.unit.is_none())
+    }
+}
+
+impl Serialize for UnitMap<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(None)?;
+        for signal in self.0 {
+            if let Some(unit) = signal.unit {
+                map.serialize_entry(signal.field, unit)?;
+            }
+        }
+        map.end()
+    }
+}
+
+/// A telemetry message: `{"seq": 42, "ts": .., "mono_us": .., "gyro": [..], "units": {..}}`
+#[derive(Serialize)]
+struct Payload<'a> {
+    seq: u32,
+    #[serde(flatten)]
+    stamp: Stamp,
+    #[serde(flatten)]
+    fields: Fields<'a>,
+    #[serde(skip_serializing_if = "UnitMap::is_empty")]
+    units: UnitMap<'a>,
+}
+
+/// All signals as one JSON object, e.g. `{"gyro": [..], "gyro_range_dps": 250, "acc": [..], ...}`
+pub fn to_json(signals: &[Signal]) -> String {
+    serde_json::to_string(&Fields(signals)).unwrap()
+}
+
+/// Everything a telemetry message is built from, see [`TelemetryEncoder`]. Serializes like
+/// [`to_json`] with the sequence number and time in front and the units at the end, inside the
+/// device envelope if there is one.
+pub struct Reading<'a> {
+    pub topics: &'a Topics,
+    /// `{kind}` of the topic the message goes to
+    pub kind: &'a str,
+    pub seq: u32,
+    pub stamp: Stamp,
+    pub signals: &'a [Signal],
+    pub envelope: Option<&'a Envelope>,
+}
+
+impl Serialize for Reading<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let payload = Payload {
+            seq: self.seq,
+            stamp: self.stamp,
+            fields: Fields(self.signals),
+            units: UnitMap(self.signals),
+        };
+
+        match self.envelope {
+            Some(envelope) => envelope.wrap(&payload).serialize(serializer),
+            None => payload.serialize(serializer),
+        }
+    }
+}
+
+/// Topic and payload of every message for one round of readings: a single `imu` message,
+/// or when split one message per signal, so AWS IoT rules can route them independently.
+/// Every message takes the next sequence number; all are stamped with the current time, as
+/// rounds are handed over right after they are read. Protobuf topics end in the schema version.
+pub fn messages(
+    topics: &Topics,
+    signals: &[Signal],
+    sequence: &Sequence,
+    envelope: Option<&Envelope>,
+    encoder: &dyn TelemetryEncoder,
+) -> Vec<(String, Vec<u8>)> {
+    let stamp = Stamp::now();
+    let topic = |kind| topics.telemetry(kind) + &encoder.topic_suffix();
+    let encode = |kind, signals| {
+        encoder.encode(&Reading {
+            topics,
+            kind,
+            seq: sequence.next(),
+            stamp,
+            signals,
+            envelope,
+        })
+    };
+
+    if !topics.split_telemetry() {
+        return vec![(topic(TELEMETRY_IMU), encode(TELEMETRY_IMU, signals))];
+    }
+
+    signals
+        .iter()
+        .map(|signal| {
+            (
+                topic(signal.kind),
+                encode(signal.kind, core::slice::from_ref(signal)),
+            )
+        })
+        .collect()
+}
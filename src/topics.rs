@@ -0,0 +1,100 @@
+use crate::{thing_name, Config};
+
+/// Kind of the regular IMU telemetry message
+pub const TELEMETRY_IMU: &str = "imu";
+
+/// Expands the configured topic templates, so every device of a fleet gets its own topics.
+/// Templates may contain `{client_id}`, `{thing_name}` and `{kind}`.
+pub struct Topics {
+    client_id: &'static str,
+    thing_name: &'static str,
+    telemetry: &'static str,
+    status: &'static str,
+    command: &'static str,
+    control: &'static str,
+    config: &'static str,
+    events: &'static str,
+    split_telemetry: bool,
+    basic_ingest_rule: &'static str,
+    sitewise_alias: &'static str,
+}
+
+impl Topics {
+    pub fn new(app_config: &Config) -> Self {
+        Self {
+            client_id: app_config.aws_iot_client_id,
+            thing_name: thing_name(app_config),
+            telemetry: app_config.aws_iot_topic,
+            status: app_config.status_topic,
+            command: app_config.command_topic,
+            control: app_config.control_topic,
+            config: app_config.config_topic,
+            events: app_config.events_topic,
+            split_telemetry: app_config.split_telemetry,
+            basic_ingest_rule: app_config.basic_ingest_rule,
+            sitewise_alias: app_config.sitewise_alias_prefix,
+        }
+    }
+
+    pub fn expand(&self, template: &str, kind: &str) -> String {
+        template
+            .replace("{client_id}", self.client_id)
+            .replace("{thing_name}", self.thing_name)
+            .replace("{kind}", kind)
+    }
+
+    pub fn client_id(&self) -> &'static str {
+        self.client_id
+    }
+
+    /// Whether each signal gets its own telemetry topic instead of sharing the `imu` one
+    pub fn split_telemetry(&self) -> bool {
+        self.split_telemetry
+    }
+
+    /// Whether telemetry goes straight to an AWS IoT rule, bypassing the message broker
+    pub fn basic_ingest(&self) -> bool {
+        !self.basic_ingest_rule.is_empty()
+    }
+
+    /// Topic for sensor data, e.g. `imu`; with basic ingest it is prefixed with `$aws/rules/<rule>/`
+    pub fn telemetry(&self, kind: &str) -> String {
+        let topic = self.expand(self.telemetry, kind);
+
+        if self.basic_ingest() {
+            format!("$aws/rules/{}/{topic}", self.basic_ingest_rule)
+        } else {
+            topic
+        }
+    }
+
+    /// Topic for device state, e.g. `status` or `net-stats`
+    pub fn status(&self, kind: &str) -> String {
+        self.expand(self.status, kind)
+    }
+
+    /// Root of the command topics, requests arrive below it and acks go to `<root>/ack`
+    pub fn command(&self) -> String {
+        self.expand(self.command, "cmd")
+    }
+
+    /// Downlink for the buzzer and status LED
+    pub fn control(&self) -> String {
+        self.expand(self.control, "control")
+    }
+
+    /// Retained runtime configuration
+    pub fn config(&self) -> String {
+        self.expand(self.config, "config")
+    }
+
+    /// Prefix of the SiteWise property aliases of a telemetry kind, e.g. `/m5stack/imu`
+    pub fn sitewise_alias(&self, kind: &str) -> String {
+        self.expand(self.sitewise_alias, kind)
+    }
+
+    /// Discrete events, e.g. gestures
+    pub fn events(&self) -> String {
+        self.expand(self.events, "events")
+    }
+}
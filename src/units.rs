@@ -0,0 +1,85 @@
+use core::f32::consts::PI;
+
+use log::*;
+
+use crate::Config;
+
+/// Standard gravity in m/s²
+const STANDARD_GRAVITY: f32 = 9.80665;
+
+/// Units values are published in. Readings are taken in g, rad/s and °C and converted just
+/// before they become signals, so consumers get what the config asked for.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Units {
+    acc_si: bool,
+    gyro_deg: bool,
+    fahrenheit: bool,
+}
+
+impl Units {
+    pub fn from_config(app_config: &Config) -> Self {
+        let choose = |name: &str, value: &str, base: &str, other: &str| {
+            if value != base && value != other {
+                warn!("Unknown {name} unit \"{value}\", using {base}");
+            }
+            value == other
+        };
+
+        Self {
+            acc_si: choose("acceleration", app_config.acc_unit, "g", "m/s^2"),
+            gyro_deg: choose("angular rate", app_config.gyro_unit, "rad/s", "deg/s"),
+            fahrenheit: choose("temperature", app_config.temp_unit, "C", "F"),
+        }
+    }
+
+    /// Factor from g
+    pub fn acc_factor(&self) -> f32 {
+        if self.acc_si {
+            STANDARD_GRAVITY
+        } else {
+            1.0
+        }
+    }
+
+    pub fn acc_unit(&self) -> &'static str {
+        if self.acc_si {
+            "m/s^2"
+        } else {
+            "g"
+        }
+    }
+
+    /// Factor from rad/s
+    pub fn gyro_factor(&self) -> f32 {
+        if self.gyro_deg {
+            180.0 / PI
+        } else {
+            1.0
+        }
+    }
+
+    pub fn gyro_unit(&self) -> &'static str {
+        if self.gyro_deg {
+            "deg/s"
+        } else {
+            "rad/s"
+        }
+    }
+
+    /// Converts from °C
+    pub fn temp(&self, celsius: f32) -> f32 {
+        if self.fahrenheit {
+            celsius * 9.0 / 5.0 + 32.0
+        } else {
+            celsius
+        }
+    }
+
+    pub fn temp_unit(&self) -> &'static str {
+        if self.fahrenheit {
+            "°F"
+        } else {
+            "°C"
+        }
+    }
+}
@@ -0,0 +1,46 @@
+use core::ptr;
+use core::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use esp_idf_svc::sys::{
+    esp, esp_task_wdt_add, esp_task_wdt_config_t, esp_task_wdt_init, esp_task_wdt_reconfigure,
+    esp_task_wdt_reset, EspError, ESP_ERR_INVALID_STATE,
+};
+
+use log::*;
+
+/// The idle tasks of both cores stay watched, as ESP-IDF sets them up
+const IDLE_CORE_MASK: u32 = 0b11;
+
+/// Set once the main task is watched; feeding an unwatched task would log an error every time
+static WATCHING: AtomicBool = AtomicBool::new(false);
+
+/// Puts the calling task under the task watchdog. Unless [`feed`] is called within `timeout`,
+/// the chip panics with a backtrace of the hung task and restarts with reset reason
+/// `task_watchdog`, instead of hanging silently on, say, an I2C read that never returns.
+pub fn watch_current_task(timeout: Duration) -> Result<(), EspError> {
+    let config = esp_task_wdt_config_t {
+        timeout_ms: timeout.as_millis() as u32,
+        trigger_panic: true,
+        idle_core_mask: IDLE_CORE_MASK,
+    };
+    // Usually ESP-IDF started it already, with a shorter timeout
+    match esp!(unsafe { esp_task_wdt_reconfigure(&config) }) {
+        Err(e) if e.code() == ESP_ERR_INVALID_STATE as i32 => {
+            esp!(unsafe { esp_task_wdt_init(&config) })?
+        }
+        res => res?,
+    }
+    esp!(unsafe { esp_task_wdt_add(ptr::null_mut()) })?;
+    WATCHING.store(true, Ordering::Relaxed);
+
+    info!("Task watchdog armed, {}s", timeout.as_secs());
+    Ok(())
+}
+
+/// Tells the watchdog the calling task is still alive
+pub fn feed() {
+    if WATCHING.load(Ordering::Relaxed) {
+        unsafe { esp_task_wdt_reset() };
+    }
+}
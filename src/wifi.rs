@@ -0,0 +1,136 @@
+use esp_idf_svc::eventloop::EspSystemEventLoop;
+use esp_idf_svc::sys::{
+    esp, esp_mac_type_t_ESP_MAC_WIFI_STA, esp_read_mac, esp_wifi_set_ps, esp_wifi_sta_get_ap_info,
+    wifi_ap_record_t, wifi_ps_type_t, wifi_ps_type_t_WIFI_PS_MAX_MODEM,
+    wifi_ps_type_t_WIFI_PS_MIN_MODEM, wifi_ps_type_t_WIFI_PS_NONE, EspError,
+};
+use esp_idf_svc::timer::EspTaskTimerService;
+use esp_idf_svc::wifi::*;
+
+use log::*;
+
+use crate::Config;
+
+/// Modem power saving, trading receive latency for battery life
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PowerSave {
+    None,
+    MinModem,
+    MaxModem,
+}
+
+impl PowerSave {
+    /// Unknown values fall back to `None` so a typo never makes the device unreachable
+    pub fn from_config(value: &str) -> Self {
+        match value {
+            "none" | "" => Self::None,
+            "min_modem" => Self::MinModem,
+            "max_modem" => Self::MaxModem,
+            other => {
+                warn!("Unknown wifi_power_save \"{other}\", using \"none\"");
+                Self::None
+            }
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::None => "none",
+            Self::MinModem => "min_modem",
+            Self::MaxModem => "max_modem",
+        }
+    }
+
+    pub fn apply(self) -> Result<(), EspError> {
+        let ps: wifi_ps_type_t = match self {
+            Self::None => wifi_ps_type_t_WIFI_PS_NONE,
+            Self::MinModem => wifi_ps_type_t_WIFI_PS_MIN_MODEM,
+            Self::MaxModem => wifi_ps_type_t_WIFI_PS_MAX_MODEM,
+        };
+
+        esp!(unsafe { esp_wifi_set_ps(ps) })
+    }
+}
+
+pub async fn wifi_create(
+    esp_wifi: &mut EspWifi<'static>,
+    app_config: &Config,
+    power_save: PowerSave,
+    sys_loop: &EspSystemEventLoop,
+    timer_service: &EspTaskTimerService,
+) -> Result<(), EspError> {
+    let mut wifi = AsyncWifi::wrap(esp_wifi, sys_loop.clone(), timer_service.clone())?;
+
+    let mut client_config = ClientConfiguration {
+        ssid: app_config.wifi_ssid.try_into().unwrap(),
+        password: app_config.wifi_password.try_into().unwrap(),
+        ..Default::default()
+    };
+
+    wifi.set_configuration(&Configuration::Client(client_config.clone()))?;
+
+    wifi.start().await?;
+    info!("Wifi started");
+
+    // Several APs may broadcast our SSID, so pin the connection to the strongest one
+    match select_strongest_ap(&mut wifi, app_config.wifi_ssid).await {
+        Some(ap) => {
+            info!(
+                "Selected AP {:02x?} on channel {} (RSSI {} dBm)",
+                ap.bssid, ap.channel, ap.signal_strength
+            );
+            client_config.bssid = Some(ap.bssid);
+            client_config.channel = Some(ap.channel);
+            wifi.set_configuration(&Configuration::Client(client_config))?;
+        }
+        None => warn!(
+            "No AP found for SSID \"{}\" during scan, letting the driver choose",
+            app_config.wifi_ssid
+        ),
+    }
+
+    wifi.connect().await?;
+    info!("Wifi connected");
+
+    wifi.wait_netif_up().await?;
+    info!("Wifi netif up");
+
+    power_save.apply()?;
+    info!("Wifi power save mode: {}", power_save.as_str());
+
+    Ok(())
+}
+
+async fn select_strongest_ap(
+    wifi: &mut AsyncWifi<&mut EspWifi<'static>>,
+    ssid: &str,
+) -> Option<AccessPointInfo> {
+    let aps = match wifi.scan().await {
+        Ok(aps) => aps,
+        Err(e) => {
+            warn!("Wifi scan failed: {e}");
+            return None;
+        }
+    };
+
+    aps.into_iter()
+        .filter(|ap| ap.ssid.as_str() == ssid)
+        .max_by_key(|ap| ap.signal_strength)
+}
+
+/// RSSI of the AP the station is associated with, or `None` when not connected
+pub fn sta_rssi() -> Option<i8> {
+    let mut ap_info = wifi_ap_record_t::default();
+    esp!(unsafe { esp_wifi_sta_get_ap_info(&mut ap_info) }).ok()?;
+
+    Some(ap_info.rssi)
+}
+
+/// Station MAC address as lowercase hex without separators, unique per chip
+pub fn sta_mac_hex() -> String {
+    let mut mac = [0u8; 6];
+    // Reading the factory MAC from eFuse can't fail for a valid type
+    unsafe { esp_read_mac(mac.as_mut_ptr(), esp_mac_type_t_ESP_MAC_WIFI_STA) };
+
+    mac.iter().map(|byte| format!("{byte:02x}")).collect()
+}